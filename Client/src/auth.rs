@@ -0,0 +1,220 @@
+// Logging in to the servers. Registering gets the client a new ID from the
+// servers; logging in opens a session whose token goes with STATUS,
+// Request_DOS, Access_Control and image requests. Both hand the servers the
+// client's identity key, which other clients look up to check what it
+// signed. session.toml keeps the ID and token between runs, the password is
+// never stored.
+use crate::servers::ServerList;
+use crate::views;
+use protocol::signing::{self, MessageSignature};
+use protocol::{Message, SignedGrant, MAX_DATAGRAM};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+const SESSION_FILE: &str = "session.toml";
+// Servers hash the password and wait for the replicated log before answering
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+// Servers answer session checks and key lookups right away
+const CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+// A view is counted once the replicated log took it
+const VIEW_TIMEOUT: Duration = Duration::from_secs(6);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub client_id: String,
+    pub token: String,
+}
+
+pub fn load() -> Option<Session> {
+    let text = fs::read_to_string(SESSION_FILE).ok()?;
+    match toml::from_str(&text) {
+        Ok(session) => Some(session),
+        Err(e) => {
+            eprintln!("Ignoring unreadable {}: {}", SESSION_FILE, e);
+            None
+        }
+    }
+}
+
+fn store(session: &Session) -> io::Result<()> {
+    fs::write(SESSION_FILE, toml::to_string(session).map_err(io::Error::other)?)
+}
+
+// The servers turned the session down
+pub fn forget() -> io::Result<()> {
+    match fs::remove_file(SESSION_FILE) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn prompt(question: &str) -> io::Result<String> {
+    println!("{}", question);
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+// Registers or logs in as the user chooses. With `client_id` the user only
+// logs in again as that client. None if the user gave up.
+pub async fn authenticate(
+    socket: &UdpSocket,
+    servers: &ServerList,
+    client_id: Option<&str>,
+) -> io::Result<Option<Session>> {
+    let public_key = views::load_or_create_identity()?.verifying_key().to_bytes().to_vec();
+    loop {
+        let request = match client_id {
+            Some(client_id) => Message::Login {
+                client_id: client_id.to_string(),
+                password: prompt(&format!("Password for client {}:", client_id))?,
+                public_key: public_key.clone(),
+            },
+            None => match prompt("Register or login? (r/l):")?.to_lowercase().as_str() {
+                "r" => Message::Register {
+                    password: prompt("Choose a password:")?,
+                    public_key: public_key.clone(),
+                },
+                "l" => Message::Login {
+                    client_id: prompt("Enter your client ID:")?,
+                    password: prompt("Password:")?,
+                    public_key: public_key.clone(),
+                },
+                _ => return Ok(None),
+            },
+        };
+        match ask(socket, &servers.all(), &request).await? {
+            Message::LoggedIn { client_id, token } => {
+                println!("Logged in as client {}", client_id);
+                let session = Session { client_id, token };
+                store(&session)?;
+                return Ok(Some(session));
+            }
+            Message::AuthFailed { reason } => println!("Login failed: {}", reason),
+            other => println!("Unexpected answer to login: {:?}", other),
+        }
+        if !prompt("Try again? (y/n):")?.eq_ignore_ascii_case("y") {
+            return Ok(None);
+        }
+    }
+}
+
+// Sends `request` to the servers in turn until one answers. Answers come
+// from any port of the server.
+async fn ask(socket: &UdpSocket, servers: &[SocketAddr], request: &Message) -> io::Result<Message> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    for server in servers {
+        socket.send_to(&protocol::encode(request), server).await?;
+        loop {
+            match timeout(LOGIN_TIMEOUT, socket.recv_from(&mut buffer)).await {
+                Ok(received) => {
+                    let (size, _) = received?;
+                    match protocol::decode(&buffer[..size]) {
+                        Ok(answer @ (Message::LoggedIn { .. } | Message::AuthFailed { .. })) => return Ok(answer),
+                        // Late answers to earlier requests
+                        _ => continue,
+                    }
+                }
+                Err(_) => {
+                    println!("No answer from {}, asking the next server...", server);
+                    break;
+                }
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "No server answered"))
+}
+
+// Asks the servers in turn until one gives the answer `pick` is after
+async fn query<T>(servers: &[SocketAddr], request: &Message, pick: impl Fn(Message) -> Option<T>) -> io::Result<T> {
+    query_within(CHECK_TIMEOUT, servers, request, pick).await
+}
+
+async fn query_within<T>(
+    wait: Duration,
+    servers: &[SocketAddr],
+    request: &Message,
+    pick: impl Fn(Message) -> Option<T>,
+) -> io::Result<T> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let request = protocol::encode(request);
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    for server in servers {
+        socket.send_to(&request, server).await?;
+        if let Ok(received) = timeout(wait, socket.recv_from(&mut buffer)).await {
+            let (size, _) = received?;
+            if let Some(answer) = protocol::decode(&buffer[..size]).ok().and_then(&pick) {
+                return Ok(answer);
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "No server answered"))
+}
+
+fn refused(reason: String) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, reason)
+}
+
+// The identity key `client_id` registered, for checking its grants
+pub async fn peer_key(servers: &[SocketAddr], client_id: &str) -> io::Result<Vec<u8>> {
+    let request = Message::KeyQuery {
+        client_id: client_id.to_string(),
+    };
+    let public_key = query(servers, &request, |answer| match answer {
+        Message::ClientKey { client_id: owner, public_key } if owner == client_id => Some(public_key),
+        _ => None,
+    })
+    .await?
+    .ok_or_else(|| refused(format!("client {} has no account", client_id)))?;
+    Ok(public_key)
+}
+
+// Has the servers count a view under `grant`, answered with ViewRecorded,
+// ViewRefused or AuthFailed
+pub async fn record_view(servers: &[SocketAddr], token: &str, grant: &SignedGrant) -> io::Result<Message> {
+    let request = Message::RecordView {
+        grant: grant.clone(),
+        token: token.to_string(),
+    };
+    let image_id = grant.grant.image_id.clone();
+    query_within(VIEW_TIMEOUT, servers, &request, |answer| match answer {
+        Message::ViewRecorded { image_id: ref viewed, .. } | Message::ViewRefused { image_id: ref viewed, .. }
+            if *viewed == image_id =>
+        {
+            Some(answer)
+        }
+        Message::AuthFailed { .. } => Some(answer),
+        _ => None,
+    })
+    .await
+}
+
+// Checks that a peer's request comes from `client_id`: `token` has to be an
+// open session of it, as the first server to answer sees it, and the
+// request has to be signed with the key it registered
+pub async fn check_peer(
+    servers: &[SocketAddr],
+    client_id: &str,
+    token: &str,
+    purpose: &str,
+    content: &impl Serialize,
+    signature: &MessageSignature,
+) -> io::Result<()> {
+    let request = Message::CheckSession {
+        token: token.to_string(),
+    };
+    let owner = query(servers, &request, |answer| match answer {
+        Message::SessionOwner { token: checked, client_id } if checked == token => Some(client_id),
+        _ => None,
+    })
+    .await?;
+    if owner.as_deref() != Some(client_id) {
+        return Err(refused("invalid session".to_string()));
+    }
+    let public_key = peer_key(servers, client_id).await?;
+    signing::verify(&public_key, purpose, content, signature).map_err(|e| refused(format!("bad signature: {}", e)))
+}
@@ -0,0 +1,143 @@
+use crate::views::{self, ShareTerms};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+// Ports and identity that used to differ between the Client and Client2 copies
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientConfig {
+    // Replaced by the ID of the account the client logs in as
+    pub client_id: String,
+    // Other peers send REQUEST_IMAGE_FROM / CONTROL_UPDATE here
+    pub p2p_addr: SocketAddr,
+    // Talks to the servers (STATUS, Request_DOS, Access_Control, ...)
+    pub control_addr: SocketAddr,
+    // Asks the servers for the leader and uploads the image to it from here
+    pub leader_ack_addr: SocketAddr,
+    // Receives the encrypted image back from the leader
+    pub image_addr: SocketAddr,
+    // Control sockets of the servers
+    pub servers: Vec<SocketAddr>,
+    // Working directory holding images/, samples/, received_images/, ...
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    // Views a requester gets on an image the owner set no terms for
+    #[serde(default = "default_share_views")]
+    pub share_views: u32,
+    // And when it expires, as typed at the access control prompt
+    #[serde(default)]
+    pub share_expiry: String,
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_share_views() -> u32 {
+    5
+}
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+impl Default for ClientConfig {
+    // Same ports the original "Client" copy used
+    fn default() -> Self {
+        ClientConfig {
+            client_id: "1".to_string(),
+            p2p_addr: local(8079),
+            control_addr: local(8080),
+            leader_ack_addr: local(9080),
+            image_addr: local(2005),
+            servers: vec![local(8083), local(8084), local(2010)],
+            data_dir: default_data_dir(),
+            share_views: default_share_views(),
+            share_expiry: String::new(),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_addr(flag: &str, value: &str) -> io::Result<SocketAddr> {
+    value
+        .parse()
+        .map_err(|e| invalid(format!("Invalid address for {}: {} ({})", flag, value, e)))
+}
+
+impl ClientConfig {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| invalid(format!("Failed to parse {}: {}", path.display(), e)))
+    }
+
+    // Builds the config from `--config <file>` (or the defaults) and then applies
+    // any per-field flags on top, e.g. `--client-id 2 --p2p-addr 127.0.0.1:6999`
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> io::Result<Self> {
+        let args: Vec<String> = args.into_iter().collect();
+
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or_else(|| invalid("--config needs a file path".to_string()))?;
+                ClientConfig::from_file(Path::new(path))?
+            }
+            None => ClientConfig::default(),
+        };
+
+        // Repeated --server flags replace the list instead of extending it
+        let mut servers_from_flags = Vec::new();
+
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .ok_or_else(|| invalid(format!("{} needs a value", flag)))
+            };
+            match flag.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--client-id" => config.client_id = value()?.clone(),
+                "--p2p-addr" => config.p2p_addr = parse_addr(flag, value()?)?,
+                "--control-addr" => config.control_addr = parse_addr(flag, value()?)?,
+                "--leader-ack-addr" => config.leader_ack_addr = parse_addr(flag, value()?)?,
+                "--image-addr" => config.image_addr = parse_addr(flag, value()?)?,
+                "--server" => servers_from_flags.push(parse_addr(flag, value()?)?),
+                "--data-dir" => config.data_dir = PathBuf::from(value()?),
+                "--share-views" => {
+                    let value = value()?;
+                    config.share_views = value
+                        .parse()
+                        .map_err(|e| invalid(format!("Invalid value for --share-views: {} ({})", value, e)))?;
+                }
+                "--share-expiry" => config.share_expiry = value()?.clone(),
+                other => return Err(invalid(format!("Unknown argument: {}", other))),
+            }
+        }
+
+        if !servers_from_flags.is_empty() {
+            config.servers = servers_from_flags;
+        }
+        config.share_terms()?;
+
+        Ok(config)
+    }
+
+    // Terms for requesters the owner set none for
+    pub fn share_terms(&self) -> io::Result<ShareTerms> {
+        let expiry = views::parse_expiry(&self.share_expiry)
+            .map_err(|e| invalid(format!("Invalid share_expiry: {}", e)))?;
+        Ok(ShareTerms {
+            views: self.share_views,
+            expiry,
+        })
+    }
+}
@@ -0,0 +1,111 @@
+// Authenticated encryption of shared images. The owner seals every image it
+// shares with a fresh key before uploading it, so the leader only ever
+// embeds ciphertext into the mask and the carrier is useless without the key.
+// The key goes from the owner straight to the viewer it was shared with.
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+// Sealed payload: MAGIC, nonce, big-endian ciphertext length, ciphertext.
+// The length is needed because the carrier pads the payload up to its size.
+const MAGIC: &[u8; 4] = b"P2E1";
+const HEADER_LEN: usize = MAGIC.len() + NONCE_LEN + 4;
+
+const KEYS_DIR: &str = "image_keys";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+pub fn generate_key() -> [u8; KEY_LEN] {
+    ChaCha20Poly1305::generate_key(&mut OsRng).into()
+}
+
+// Encrypts `image` for `image_id`; the ID is authenticated too, so a payload
+// can't be passed off as another image
+pub fn seal(key: &[u8; KEY_LEN], image_id: &str, image: &[u8]) -> io::Result<Vec<u8>> {
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: image, aad: image_id.as_bytes() })
+        .map_err(|_| io::Error::other("Failed to encrypt image"))?;
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + ciphertext.len());
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+// Decrypts a payload produced by `seal`, ignoring any padding after it
+pub fn open(key: &[u8; KEY_LEN], image_id: &str, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < HEADER_LEN || &sealed[..MAGIC.len()] != MAGIC {
+        return Err(invalid("Image is not sealed"));
+    }
+    let nonce = Nonce::from_slice(&sealed[MAGIC.len()..MAGIC.len() + NONCE_LEN]);
+    let length = u32::from_be_bytes(sealed[HEADER_LEN - 4..HEADER_LEN].try_into().unwrap()) as usize;
+    let ciphertext = sealed
+        .get(HEADER_LEN..HEADER_LEN + length)
+        .ok_or_else(|| invalid("Sealed image is truncated"))?;
+
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(nonce, Payload { msg: ciphertext, aad: image_id.as_bytes() })
+        .map_err(|_| invalid("Image failed authentication, wrong key or altered content"))
+}
+
+pub fn key_path(image_id: &str) -> PathBuf {
+    PathBuf::from(KEYS_DIR).join(format!("{}.key", image_id))
+}
+
+// Keys of received images, kept next to received_images/
+pub fn store_key(image_id: &str, key: &[u8; KEY_LEN]) -> io::Result<()> {
+    fs::create_dir_all(KEYS_DIR)?;
+    fs::write(key_path(image_id), key)
+}
+
+pub fn load_key(image_id: &str) -> io::Result<[u8; KEY_LEN]> {
+    let key = fs::read(key_path(image_id))?;
+    key.try_into()
+        .map_err(|_| invalid("Stored image key has the wrong length"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_images_open_with_their_key_and_id() {
+        let key = generate_key();
+        let mut sealed = seal(&key, "1_cat", b"pixels").unwrap();
+        // The carrier pads the payload up to its size
+        sealed.extend_from_slice(&[0; 64]);
+        assert_eq!(open(&key, "1_cat", &sealed).unwrap(), b"pixels");
+    }
+
+    #[test]
+    fn wrong_key_id_or_content_fails() {
+        let key = generate_key();
+        let sealed = seal(&key, "1_cat", b"pixels").unwrap();
+        assert!(open(&generate_key(), "1_cat", &sealed).is_err());
+        assert!(open(&key, "1_dog", &sealed).is_err());
+        let mut altered = sealed.clone();
+        *altered.last_mut().unwrap() ^= 1;
+        assert!(open(&key, "1_cat", &altered).is_err());
+    }
+
+    #[test]
+    fn unsealed_or_truncated_payloads_fail() {
+        let key = generate_key();
+        let sealed = seal(&key, "1_cat", b"pixels").unwrap();
+        assert!(open(&key, "1_cat", b"a plain image").is_err());
+        assert!(open(&key, "1_cat", &sealed[..HEADER_LEN - 1]).is_err());
+        assert!(open(&key, "1_cat", &sealed[..sealed.len() - 1]).is_err());
+    }
+}
@@ -1,12 +1,8 @@
 use csv::Writer;
 use image::{DynamicImage, ImageFormat, RgbaImage};
-use rand::prelude::SliceRandom;
-use rand::thread_rng;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::collections::HashMap;
 use std::fs;
-use std::fs::read_to_string;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::{self, Cursor, Write, BufRead};
@@ -19,35 +15,30 @@ use tokio::net::UdpSocket;
 use tokio::signal;
 use tokio::time;
 use tokio::time::{sleep, timeout, Duration};
+mod auth;
+mod config;
+mod crypto;
 mod middleware;
+mod requests;
+mod servers;
+mod stego;
+mod views;
+use config::ClientConfig;
 use middleware::middleware;
 use middleware::request_image_by_id;
 use middleware::send_samples;
 use middleware::start_p2p_listener;
+use servers::ServerList;
+use protocol::{signing, ClientEndpoint, Expiry, Message, OnlineStatus, MAX_DATAGRAM};
 
 // struct for image stats
 
-// struct for online status
-#[derive(Serialize, Deserialize)]
-struct OnlineStatus {
-    ip: String,
-    status: bool,
-    client_id: String,
-}
-
-async fn parse_and_store_dos(dos_content: &str) -> HashMap<String, String> {
+async fn parse_and_store_dos(entries: &[OnlineStatus]) -> HashMap<String, String> {
     let mut client_map = HashMap::new();
 
-    for line in dos_content.lines().skip(1) {
-        // Skip the header
-        let parts: Vec<&str> = line.split(',').collect();
-        if parts.len() == 3 {
-            let ip_port = parts[0].trim().to_string();
-            let client_id = parts[1].trim().to_string();
-            let status = parts[2].trim();
-            if status == "true" {
-                client_map.insert(client_id, ip_port);
-            }
+    for entry in entries {
+        if entry.status {
+            client_map.insert(entry.client_id.clone(), entry.ip.clone());
         }
     }
 
@@ -55,106 +46,79 @@ async fn parse_and_store_dos(dos_content: &str) -> HashMap<String, String> {
     client_map
 }
 
-// The middleware function containing the logic for the client such as communicating with the servers and sending messages to the servers
-
-fn handle_auth() -> io::Result<bool> {
-    // get UID from directory of service from server instead (just a placeholder)
-    let mut uid = "127.0.0.1_1".to_string();
-    let path = Path::new("uid.txt");
-
-    if path.exists() {
-        println!("File exists!");
-        let contents = read_to_string("uid.txt")?;
-        if contents == uid {
-            return Ok(true);
-        }
-    } else {
-        println!("File does not exist!");
-    }
-
-    println!("Register or login? (r/l):");
-    let mut choice = String::new();
-    io::stdin()
-        .read_line(&mut choice)
-        .expect("Failed to read input!");
-
-    match choice.trim().to_lowercase().as_str() {
-        "r" => {
-            let mut file = File::create("uid.txt")?;
-            file.write_all(uid.as_bytes())?;
-            println!("UID: {}", uid);
-            Ok(true)
-        }
-        "l" => {
-            println!("Enter your UID:");
-            io::stdin()
-                .read_line(&mut uid)
-                .expect("Failed to read UID!");
-            // if UID matches what is in the directory of service, set flag to true and enter the loop
-            // otherwise terminate!
-            Ok(true)
-        }
-        _ => Ok(false),
-    }
-}
-
 #[tokio::main]
 pub async fn main() -> io::Result<()> {
-    /////////////////////////////////////////////////////////////////////////////
-    let client = "127.0.0.1"; // to be changed to the real client address
-
-    let server1 = "127.0.0.1";
-    let server2 = "127.0.0.1";
-    let server3 = "127.0.0.1";
-    /////////////////////////////////////////////////////////////////////////////
-    let server1_address = format!("{}:{}", server1, "8083");
-    let server2_addres = format!("{}:{}", server2, "8084");
-    let server3_address = format!("{}:{}", server3, "2010");
-    /////////////////////////////////////////////////////////////////////////////
+    let mut config = ClientConfig::from_args(std::env::args().skip(1))?;
+    // Every relative path below (images/, samples/, received_images/, ...) lives in the data dir
+    std::env::set_current_dir(&config.data_dir)?;
+    // Signs the view grants of the images this client shares and its control messages
+    let identity = views::load_or_create_identity()?;
+    // Cut short by a crash, they keep their ID if the image is asked for again
+    for request in requests::unfinished()? {
+        println!(
+            "Unfinished request {} for image {} to {}",
+            request.request_id, request.image_id, request.requester
+        );
+    }
+
     let mut count = 0;
-    let authenticated = handle_auth()?;
     let mut leader_address = String::new();
-    let servers: Vec<SocketAddr> = vec![
-        server1_address.parse().unwrap(),
-        server2_addres.parse().unwrap(),
-        server3_address.parse().unwrap(),
-    ];
-    println!("before");
-    let mut rng = thread_rng();
-    let mut assistant: SocketAddr = *servers.choose(&mut rng).unwrap();
-    println!("After");
-
-    if authenticated {
+    // Picks up servers that joined since the config was written
+    let servers = ServerList::new(config.servers.clone());
+    if let Err(e) = servers.refresh().await {
+        eprintln!("Using the configured servers: {}", e);
+    }
+    servers.spawn_refresh();
+    let mut assistant: SocketAddr = servers.choose();
+
+    // The servers hand out client IDs, the configured one is only used until the first login
+    let session = match auth::load() {
+        Some(session) => Some(session),
+        None => {
+            let socket = UdpSocket::bind(config.control_addr).await?;
+            auth::authenticate(&socket, &servers, None).await?
+        }
+    };
+    if let Some(session) = &session {
         println!("Authentication successful!");
+        config.client_id = session.client_id.clone();
     } else {
         println!("Authentication failed!");
     }
 
     let client_map = Arc::new(Mutex::new(HashMap::new()));
 
-    if authenticated {
-        let p2p_listener = format!("{}:{}", client, "8079");
+    if let Some(mut session) = session {
+        let p2p_listener = config.p2p_addr.to_string();
+        // Where the servers send this client's encrypted images
+        let endpoint = Some(ClientEndpoint {
+            image_addr: config.image_addr,
+            leader_ack_addr: config.leader_ack_addr,
+        });
         let mut samples_sent = false;
-        start_p2p_listener(&p2p_listener, &client).await?;
+        start_p2p_listener(&config, servers.clone()).await?;
         loop {
-            let clientaddress = format!("{}:{}", client, "8080");
-            let socket = UdpSocket::bind(clientaddress).await?;
+            let socket = UdpSocket::bind(config.control_addr).await?;
 
             let info = OnlineStatus {
                 ip: p2p_listener.to_string(),
                 status: true,
-                client_id: "1".to_string(),
+                client_id: config.client_id.clone(),
             };
 
-            let serialized_info = serde_json::to_string(&info).unwrap();
             // state a timeout for the client to send the status and receive STATUS_ACK, if not received send again after timeout
             let timeout = Duration::from_secs(1);
             let mut start_time = Instant::now();
             let mut received_acks = false;
-            let message_to_send = format!("STATUS:{}", serialized_info);
+            let mut message_to_send = protocol::encode(&Message::Status {
+                status: info.clone(),
+                endpoint: endpoint.clone(),
+                token: session.token.clone(),
+                signature: signing::sign(&identity, signing::STATUS, &(&info, &endpoint)),
+            });
             if count == 0 {
                 socket
-                    .send_to(message_to_send.as_bytes(), assistant)
+                    .send_to(&message_to_send, assistant)
                     .await?;
 
                 let mut received_acks = false;
@@ -165,8 +129,7 @@ pub async fn main() -> io::Result<()> {
 
                     match time::timeout(timeout_duration, socket.recv_from(&mut buf)).await {
                         Ok(Ok((size, _))) => {
-                            let received_message = String::from_utf8_lossy(&buf[..size]);
-                            if received_message.contains("STATUS_ACK") {
+                            if let Ok(Message::StatusAck { .. }) = protocol::decode(&buf[..size]) {
                                 println!("Received STATUS_ACK from server");
                                 received_acks = true;
 
@@ -177,16 +140,32 @@ pub async fn main() -> io::Result<()> {
                                     samples_sent = true; // Mark samples as sent
                                 }
                             }
+                            if let Ok(Message::AuthFailed { reason }) = protocol::decode(&buf[..size]) {
+                                // The session expired, log in again as the same client
+                                println!("Session refused: {}", reason);
+                                auth::forget()?;
+                                session = match auth::authenticate(&socket, &servers, Some(&info.client_id)).await? {
+                                    Some(session) => session,
+                                    None => return Ok(()),
+                                };
+                                message_to_send = protocol::encode(&Message::Status {
+                                    status: info.clone(),
+                                    endpoint: endpoint.clone(),
+                                    token: session.token.clone(),
+                                    signature: signing::sign(&identity, signing::STATUS, &(&info, &endpoint)),
+                                });
+                                socket.send_to(&message_to_send, assistant).await?;
+                            }
                         }
                         _ => {
                             // Timeout occurred
-                            assistant = *servers.choose(&mut rng).unwrap(); // Randomize IP
+                            assistant = servers.choose(); // Randomize IP
                             println!(
                                 "Timeout occurred, resending STATUS message to {}",
                                 assistant
                             );
                             socket
-                                .send_to(message_to_send.as_bytes(), assistant)
+                                .send_to(&message_to_send, assistant)
                                 .await?;
                         }
                     }
@@ -203,6 +182,7 @@ pub async fn main() -> io::Result<()> {
     |  3) If you want to exit,             please enter (E) or (e) |   
     |  4) If you want to view your images, please enter (V) or (v) |   
     |  5) If you want to Control access rights,   enter (C) or (c) |   
+    |  6) If you want to revoke an image,         enter (X) or (x) |   
      -------------------------------------------------------------- "
             );
             let mut input = String::new();
@@ -243,12 +223,15 @@ pub async fn main() -> io::Result<()> {
                         .expect("Failed to write to history_table.txt");
                 }
 
-                let client_map_locked = client_map.lock().unwrap();
+                // A copy, so the lock is not held while the request runs
+                let known_clients = client_map.lock().unwrap().clone();
                 request_image_by_id(
                     &socket,
+                    &servers.all(),
                     image_id.trim(),
-                    &*client_map_locked,
+                    &known_clients,
                     &info.client_id,
+                    &session.token,
                 )
                 .await?;
 
@@ -263,66 +246,64 @@ pub async fn main() -> io::Result<()> {
                 let info = OnlineStatus {
                     ip: p2p_listener.to_string(),
                     status: false,
-                    client_id: "1".to_string(),
+                    client_id: config.client_id.clone(),
                 };
-                let serialized_info = serde_json::to_string(&info).unwrap();
-                let message_to_send = format!("STATUS:{}", serialized_info);
+                let message_to_send = protocol::encode(&Message::Status {
+                    signature: signing::sign(&identity, signing::STATUS, &(&info, &endpoint)),
+                    status: info,
+                    endpoint: endpoint.clone(),
+                    token: session.token.clone(),
+                });
                 socket
-                    .send_to(&message_to_send.as_bytes(), assistant)
+                    .send_to(&message_to_send, assistant)
                     .await?;
                 break;
             } else if input.trim().eq_ignore_ascii_case("d")
                 || input.trim().eq_ignore_ascii_case("D")
             {
                 // Request DoS and samples
-                let message = "Request_DOS";
-                socket.send_to(message.as_bytes(), assistant).await?;
+                let message = protocol::encode(&Message::RequestDos {
+                    token: session.token.clone(),
+                });
+                socket.send_to(&message, assistant).await?;
                 println!("Requested DOS!");
 
                 // Wait to receive the DoS and samples
-                let mut buffer = [0u8; 4096];
+                let mut buffer = vec![0u8; MAX_DATAGRAM];
                 let received_samples_dir = "received_samples";
                 std::fs::create_dir_all(received_samples_dir)
                     .expect("Failed to create 'received_samples' directory");
 
-                let mut dos_content = String::new(); // Collect the DoS content
                 let mut samples_received = false; // Track if samples were received
 
                 loop {
                     let (amt, _) = socket.recv_from(&mut buffer).await?;
-                    let received_message = String::from_utf8_lossy(&buffer[..amt]).to_string();
 
-                    if received_message == "ACK" {
-                        // Ignore ACK messages
-                        println!("Received ACK, ignoring...");
-                        continue;
-                    } else if received_message.starts_with("DOS:") {
-                        // Process the directory of service
-                        dos_content = received_message
-                            .strip_prefix("DOS:")
-                            .unwrap_or("")
-                            .to_string();
-                        println!("Received DoS: {}", dos_content);
-
-                        // Parse and store DoS data
-                        let mut client_map_locked = client_map.lock().unwrap();
-                        *client_map_locked = parse_and_store_dos(&dos_content).await;
-                    } else if received_message.starts_with("SAMPLE:") {
-                        samples_received = true;
-
-                        // Process sample metadata
-                        let parts: Vec<&str> = received_message
-                            .strip_prefix("SAMPLE:")
-                            .unwrap_or("")
-                            .split(':')
-                            .collect();
-                        if parts.len() == 2 {
-                            let client_id = parts[0];
-                            let sample_name = parts[1];
+                    match protocol::decode(&buffer[..amt]) {
+                        Ok(Message::Ack { .. }) => {
+                            // Ignore ACK messages
+                            println!("Received ACK, ignoring...");
+                            continue;
+                        }
+                        Ok(Message::Dos { entries }) => {
+                            // Process the directory of service
+                            println!("Received DoS: {:?}", entries);
+
+                            // Parsed first, so the lock is not held across the await
+                            let parsed = parse_and_store_dos(&entries).await;
+                            *client_map.lock().unwrap() = parsed;
+                        }
+                        Ok(Message::Sample { client_id, name: sample_name, data }) => {
+                            samples_received = true;
                             println!("Receiving sample {} from client {}", sample_name, client_id);
 
-                            // Receive the sample data
-                            let (data_size, _) = socket.recv_from(&mut buffer).await?;
+                            // Legacy servers send the sample data in a separate datagram
+                            let data = if data.is_empty() {
+                                let (data_size, _) = socket.recv_from(&mut buffer).await?;
+                                buffer[..data_size].to_vec()
+                            } else {
+                                data
+                            };
                             let sample_path =
                                 format!("{}/{}/{}", received_samples_dir, client_id, sample_name);
 
@@ -334,18 +315,24 @@ pub async fn main() -> io::Result<()> {
                             );
 
                             // Save the received sample
-                            std::fs::write(&sample_path, &buffer[..data_size])
+                            std::fs::write(&sample_path, &data)
                                 .expect("Failed to write received sample");
                             println!("Saved received sample: {}", sample_path);
                         }
-                    } else if received_message == "NO_SAMPLES" {
-                        println!("No samples available on the server.");
-                        break;
-                    } else if received_message == "SAMPLES_DONE" {
-                        println!("All samples have been received.");
-                        break;
-                    } else {
-                        println!("Unknown message: {}", received_message);
+                        Ok(Message::NoSamples) => {
+                            println!("No samples available on the server.");
+                            break;
+                        }
+                        Ok(Message::SamplesDone) => {
+                            println!("All samples have been received.");
+                            break;
+                        }
+                        Ok(Message::AuthFailed { reason }) => {
+                            println!("Server refused the request: {}", reason);
+                            break;
+                        }
+                        Ok(other) => println!("Unknown message: {:?}", other),
+                        Err(e) => println!("Unknown message: {}", e),
                     }
                 }
 
@@ -365,13 +352,18 @@ pub async fn main() -> io::Result<()> {
 
                 // Open the image
                 println!("Opening image...");
-                let views_path = "views_count";
-                let file_path = format!("{}/{}_views.txt", views_path, image_id.trim());
-                let decrypted_path = "decrypted_image.png";
-                let views = fs::read_to_string(&file_path).expect("Failed to read views file");
+                let decrypted_path = format!("decrypted_images/{}.png", image_id.trim());
+                // The owner's grant and the views the servers left on it
+                let record = match views::load_views(image_id.trim(), &config.client_id) {
+                    Ok(record) => record,
+                    Err(e) => {
+                        eprintln!("Cannot view image: {}", e);
+                        continue;
+                    }
+                };
 
                 // Check for platform and run the appropriate command
-                if views == "0" {
+                if record.remaining == 0 {
                     eprintln!("You have no views left for this image.");
                     continue;
                 } else {
@@ -395,8 +387,37 @@ pub async fn main() -> io::Result<()> {
                         continue;
                     }
 
+                    // The servers use up the view, the views file only keeps their answer
+                    match auth::record_view(&servers.all(), &session.token, &record.grant).await {
+                        Ok(Message::ViewRecorded { remaining, first_viewed, .. }) => {
+                            views::record_view(image_id.trim(), record.grant, remaining, first_viewed)
+                                .expect("Failed to write new views count");
+                        }
+                        Ok(Message::ViewRefused { reason, gone, .. }) => {
+                            eprintln!("Cannot view image: {}", reason);
+                            if gone {
+                                if let Err(e) = views::remove_received(image_id.trim()) {
+                                    eprintln!("Failed to delete image: {}", e);
+                                }
+                            }
+                            continue;
+                        }
+                        Ok(Message::AuthFailed { reason }) => {
+                            eprintln!("Server refused the request: {}", reason);
+                            continue;
+                        }
+                        Ok(other) => {
+                            eprintln!("Unknown message: {:?}", other);
+                            continue;
+                        }
+                        Err(e) => {
+                            eprintln!("Cannot view image: {}", e);
+                            continue;
+                        }
+                    }
+
                     // Decrypt the image
-                    if let Err(e) = middleware::decrypt(image_path.clone()).await {
+                    if let Err(e) = middleware::decrypt(image_id.trim(), Path::new(&image_path), Path::new(&decrypted_path)) {
                         eprintln!("Failed to decrypt image: {}", e);
                         continue;
                     }
@@ -410,41 +431,29 @@ pub async fn main() -> io::Result<()> {
                     // MacOS uses 'open'
                     else if cfg!(target_os = "macos") {
                         Command::new("open")
-                            .arg(decrypted_path)
+                            .arg(&decrypted_path)
                             .spawn()
                             .expect("Failed to open image");
                     }
                     // Linux typically uses 'xdg-open'
                     else if cfg!(target_os = "linux") {
                         Command::new("xdg-open")
-                            .arg(decrypted_path)
+                            .arg(&decrypted_path)
                             .spawn()
                             .expect("Failed to open image");
                     } else {
                         eprintln!("Unsupported platform for image viewer");
                     }
 
-                    // Check if the views file exists if it doesn't, Raise an error
-                    if !Path::new(&file_path).exists() {
-                        eprintln!("Views count file not found. Please request the image first.");
-                        continue;
-                    }
-
-                    // Decrement the views count
-
-                    let views: i32 = views.trim().parse().expect("Failed to parse views count");
-                    let new_views = views - 1;
-
-                    // Write the new views count
-                    fs::write(&file_path, new_views.to_string())
-                        .expect("Failed to write new views count");
-
                     // delete temporary decrypted image
                     // fs::remove_file(decrypted_path).expect("Failed to delete decrypted image");
                 }
             } else if (input.trim().eq_ignore_ascii_case("c")
-                || input.trim().eq_ignore_ascii_case("C"))
+                || input.trim().eq_ignore_ascii_case("C")
+                || input.trim().eq_ignore_ascii_case("x"))
             {
+                // Revoking takes the image away and has the recipient delete its copy
+                let revoke = input.trim().eq_ignore_ascii_case("x");
                 println!("Enter the recipient ID:");
                 let mut recipient_id = String::new();
                 io::stdin()
@@ -459,20 +468,84 @@ pub async fn main() -> io::Result<()> {
                     .expect("Failed to read image ID part 1");
                 let image_id_part = image_id_part.trim();
 
-                println!("Enter the new view count:");
-                let mut new_views = String::new();
-                io::stdin()
-                    .read_line(&mut new_views)
-                    .expect("Failed to read new view count");
-                let new_views = new_views.trim();
+                let new_views: u32 = if revoke {
+                    0
+                } else {
+                    println!("Enter the new view count:");
+                    let mut new_views = String::new();
+                    io::stdin()
+                        .read_line(&mut new_views)
+                        .expect("Failed to read new view count");
+                    match new_views.trim().parse() {
+                        Ok(views) => views,
+                        Err(_) => {
+                            println!("Invalid view count. Please try again.");
+                            continue;
+                        }
+                    }
+                };
 
-                // Combine all inputs into the required format
-                let access_input = format!("{}_{}_{}", recipient_id, image_id_part, new_views);
+                let expiry = if revoke {
+                    Expiry::Never
+                } else {
+                    println!("Enter when the image expires (empty for never, +<minutes> after the first view, or YYYY-MM-DD HH:MM UTC):");
+                    let mut expiry = String::new();
+                    io::stdin()
+                        .read_line(&mut expiry)
+                        .expect("Failed to read expiry");
+                    match views::parse_expiry(&expiry) {
+                        Ok(expiry) => expiry,
+                        Err(e) => {
+                            println!("Invalid expiry: {}. Please try again.", e);
+                            continue;
+                        }
+                    }
+                };
 
-                // Send the formatted message to the server
-                let message = format!("Access_Control:{}", access_input);
-                socket.send_to(message.as_bytes(), assistant).await?;
-                println!("Sent access control request: {}", access_input);
+                // The recipient only accepts a count signed by us
+                let grant = if revoke {
+                    views::sign_revocation(&config.client_id, image_id_part, recipient_id)
+                } else {
+                    views::sign_grant(&config.client_id, image_id_part, recipient_id, new_views, expiry)
+                };
+                let grant = match grant {
+                    Ok(grant) => grant,
+                    Err(e) => {
+                        eprintln!("Failed to sign the new view count: {}", e);
+                        continue;
+                    }
+                };
+                // Refuse the recipient's requests for the image from now on, or again
+                if let Err(e) = views::set_revoked(image_id_part, recipient_id, revoke) {
+                    eprintln!("Failed to record the revocation: {}", e);
+                    continue;
+                }
+                // A recipient that requests the image later gets it on these terms
+                let terms = views::ShareTerms { views: new_views, expiry };
+                if let Err(e) = views::set_share_terms(image_id_part, recipient_id, terms) {
+                    eprintln!("Failed to record the terms: {}", e);
+                    continue;
+                }
+                let message = Message::AccessControl {
+                    client_id: recipient_id.to_string(),
+                    image_id: image_id_part.to_string(),
+                    views: new_views,
+                    grant: Some(grant),
+                    token: session.token.clone(),
+                };
+
+                // Send the access control request to the server
+                socket.send_to(&protocol::encode(&message), assistant).await?;
+                println!("Sent access control request: {:?}", message);
+                // Refused if the image is not ours, acknowledged if the recipient is online
+                let mut buffer = vec![0u8; MAX_DATAGRAM];
+                if let Ok(Ok((size, _))) = time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer)).await {
+                    match protocol::decode(&buffer[..size]) {
+                        Ok(Message::AuthFailed { reason }) => println!("Server refused the request: {}", reason),
+                        Ok(Message::AccessControlAck { client_ip }) => println!("Recipient is online at {}", client_ip),
+                        _ => {}
+                    }
+                }
             } else {
                 println!("Invalid input. Please try again.");
             }
@@ -3,237 +3,169 @@ use std::fs;
 use std::path::Path;
 use tokio::net::UdpSocket;
 
-use std::collections::HashMap;
-use tokio::time::{sleep, timeout, Duration};
-
-use image::{ImageBuffer, Rgba, RgbaImage};
-use std::collections::HashSet;
-use std::convert::TryInto;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{self, Cursor, Write};
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
-use steganography::decoder::Decoder;
-use steganography::encoder::Encoder;
-use steganography::util::bytes_to_str;
-use steganography::util::file_as_dynamic_image;
-use steganography::util::file_as_image_buffer;
-use steganography::util::save_image_buffer;
-use steganography::util::str_to_bytes;
-use tokio::signal;
-use tokio::time;
-
-struct ImageStats {
-    client_id: String, // Unique identifier
-    num_of_views: u8,  // Number of views (using unsigned 8-bit integer)
-}
-fn embed_data_in_image(
-    image_path: &str,
-    views: u32,
-    client_id: u32,
-    output_path: &str,
-) -> io::Result<()> {
-    // Load the image
-    let original_image = image::open(image_path)
-        .expect("Failed to open image")
-        .into_rgba8();
+use crate::auth;
+use crate::config::ClientConfig;
+use crate::crypto::{self, KEY_LEN};
+use crate::requests::{self, Request};
+use crate::servers::ServerList;
+use crate::stego;
+use crate::views;
+use protocol::signing;
+use protocol::transfer::{self, Inbox, Received, TransferConfig};
+use protocol::{EncryptRequest, EncryptResult, Message, SharedImage, SignedGrant, MAX_DATAGRAM};
 
-    let (width, height) = original_image.dimensions();
+use std::collections::HashMap;
+use tokio::time::{interval_at, sleep, sleep_until, timeout, Duration, Instant};
 
-    // Create a new image with an extra row
-    let mut new_image: RgbaImage = ImageBuffer::new(width, height + 1);
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
 
-    // Copy original image data
-    for y in 0..height {
-        for x in 0..width {
-            new_image.put_pixel(x, y, *original_image.get_pixel(x, y));
+// Rounds of leader queries over all servers before giving up
+const LEADER_QUERY_ROUNDS: usize = 3;
+// Leaders an image is sent to before the request is given up on
+const UPLOAD_ATTEMPTS: u32 = 5;
+// How long a leader gets to return the encrypted image
+const RESULT_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the servers are asked who leads while waiting for the result
+const LEADER_CHECK: Duration = Duration::from_secs(1);
+
+// Asks the servers in turn which one leads; any of them can answer
+async fn find_leader(socket: &UdpSocket, servers: &[SocketAddr]) -> io::Result<SocketAddr> {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    for server in servers.iter().cycle().take(servers.len() * LEADER_QUERY_ROUNDS) {
+        socket.send_to(&protocol::encode(&Message::LeaderQuery), server).await?;
+        let receive_result = timeout(Duration::from_secs(1), socket.recv_from(&mut buffer)).await;
+        match receive_result {
+            Ok(Ok((size, _))) => match protocol::decode(&buffer[..size]) {
+                Ok(Message::LeaderAck { leader: leader_address }) => {
+                    return leader_address.parse().map_err(|e| {
+                        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid leader address {}: {}", leader_address, e))
+                    });
+                }
+                other => println!("Received message without Leader_Ack: {:?}", other),
+            },
+            Ok(Err(e)) => eprintln!("Error receiving data: {:?}", e),
+            Err(_) => println!("No answer from {} about the leader, asking the next server...", server),
         }
     }
-
-    // Convert views and client_id to bytes
-    let views_bytes = views.to_be_bytes();
-    let client_id_bytes = client_id.to_be_bytes();
-
-    // Embed metadata in the extra row
-    // First 4 pixels store views, next 4 pixels store client_id
-    for i in 0..4u32 {
-        new_image.put_pixel(i, height, Rgba([views_bytes[i as usize], 0, 0, 255]));
-
-        new_image.put_pixel(
-            i + 4,
-            height,
-            Rgba([client_id_bytes[i as usize], 0, 0, 255]),
-        );
-    }
-
-    // Save the modified image
-    new_image
-        .save(output_path)
-        .expect("Failed to save the image");
-
-    println!("Data embedded in image and saved to {}", output_path);
-    Ok(())
-}
-
-fn extract_data_from_image(image_path: &str) -> (u32, u32) {
-    // Load the image
-    let image = image::open(image_path)
-        .expect("Failed to open image")
-        .into_rgba8();
-
-    let (_, height) = image.dimensions();
-    let metadata_row = height - 1;
-
-    // Extract views bytes
-    let mut views_bytes = [0u8; 4];
-    for i in 0..4usize {
-        views_bytes[i] = image.get_pixel(i.try_into().unwrap(), metadata_row)[0];
-    }
-
-    // Extract client_id bytes
-    let mut client_id_bytes = [0u8; 4];
-    for i in 0..4usize {
-        let x: u32 = (i + 4).try_into().unwrap();
-        client_id_bytes[i] = image.get_pixel(x, metadata_row)[0];
-    }
-
-    let views = u32::from_be_bytes(views_bytes);
-    let client_id = u32::from_be_bytes(client_id_bytes);
-
-    (views, client_id)
+    Err(io::Error::new(io::ErrorKind::TimedOut, "No server knows a leader"))
 }
 
-fn strip_metadata_row(image_path: &str, output_path: &str) -> io::Result<()> {
-    // Load the image
-    let image = image::open(image_path)
-        .expect("Failed to open image")
-        .into_rgba8();
-
-    let (width, height) = image.dimensions();
+// Has the image of `request` encrypted by the leader. When the leader fails
+// or takes too long, the request goes to the next leader under the same ID
+// and `requester` (the P2P socket and the peer's address) is told that the
+// image is delayed.
+pub async fn middleware(
+    socket6: &UdpSocket,
+    servers: &[SocketAddr],
+    request: &Request,
+    reinitiated: &SocketAddr,
+    grant: &SignedGrant,
+    key: &[u8; KEY_LEN],
+    requester: (&UdpSocket, SocketAddr),
+) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(reinitiated).await?;
+    let config = TransferConfig {
+        idle_timeout: RESULT_TIMEOUT,
+        ..TransferConfig::default()
+    };
+    // Kept across attempts, a slow leader's result is still welcome
+    let mut inbox = Inbox::new(&config);
+    for attempt in 0..UPLOAD_ATTEMPTS {
+        if attempt > 0 {
+            let delayed = Message::TransferDelayed {
+                image_id: request.image_id.clone(),
+                attempt,
+            };
+            if let Err(e) = requester.0.send_to(&protocol::encode(&delayed), requester.1).await {
+                eprintln!("Failed to tell {} about the delay: {:?}", requester.1, e);
+            }
+        }
 
-    // Create new image without the metadata row
-    let mut stripped_image: RgbaImage = ImageBuffer::new(width, height - 1);
+        let leader = match find_leader(&socket, servers).await {
+            Ok(leader) => leader,
+            Err(e) => {
+                println!("{}, trying again...", e);
+                continue;
+            }
+        };
+        println!("Leader identified at {}. Proceeding to connect...", leader);
+        if let Err(e) = send_image(&socket, leader, request.request_id, &request.image_id, key).await {
+            println!("Upload to {} failed: {}, waiting for the next leader...", leader, e);
+            sleep(LEADER_CHECK).await;
+            continue;
+        }
 
-    // Copy all rows except the last one
-    for y in 0..height - 1 {
-        for x in 0..width {
-            stripped_image.put_pixel(x, y, *image.get_pixel(x, y));
+        println!("Waiting for encrypted image from server...");
+        if let Some(encrypted_image_data) =
+            wait_for_result(&socket, socket6, &mut inbox, servers, leader, request.request_id).await?
+        {
+            println!("Encrypted image received completely from server.");
+            // The shared image carries the signed views grant in its metadata row
+            return stego::embed_data_in_image(&encrypted_image_data, &grant.to_bytes());
         }
     }
-
-    // Save the stripped image
-    stripped_image
-        .save(output_path)
-        .expect("Failed to save the image");
-    println!(
-        "Stripped metadata row from image and saved to {}",
-        output_path
-    );
-
-    Ok(())
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No leader returned image {} after {} attempts", request.image_id, UPLOAD_ATTEMPTS),
+    ))
 }
 
-pub async fn middleware(
+// Waits for the result of `request_id` on the image socket, asking the
+// servers who leads meanwhile. None if another server took over from
+// `leader`, or no result came in time: the request has to be sent again.
+async fn wait_for_result(
+    socket: &UdpSocket,
     socket6: &UdpSocket,
-    image_id: &str,
-    reinitiated: &str,
-    peer_id: &str,
-) -> io::Result<String> {
-    let mut buffer = [0u8; 2048];
-    let mut leader_address = String::new();
-
+    inbox: &mut Inbox,
+    servers: &[SocketAddr],
+    leader: SocketAddr,
+    request_id: u64,
+) -> io::Result<Option<Vec<u8>>> {
+    let deadline = Instant::now() + RESULT_TIMEOUT;
+    let mut check = interval_at(Instant::now() + LEADER_CHECK, LEADER_CHECK);
+    let mut next_server = servers.iter().cycle();
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
     loop {
-        // Set a timeout for receiving the leader address and acknowledgment
-        let socket = UdpSocket::bind(reinitiated).await?;
-
-        let receive_result = timeout(Duration::from_secs(5), socket.recv_from(&mut buffer)).await;
-        match receive_result {
-            Ok(Ok((size, _))) => {
-                let message = String::from_utf8_lossy(&buffer[..size]);
-                println!("{}", message);
-
-                if message.starts_with("LEADER_ACK:") {
-                    leader_address = message.replace("LEADER_ACK:", "").trim().to_string();
-                    println!(
-                        "Leader identified at {}. Proceeding to connect...",
-                        leader_address
-                    );
-                    socket.connect(&leader_address).await?; // make this the leader address after the ack is sent back from the server
-                    send_image(&socket, image_id).await?;
-                    break;
-                } else {
-                    println!("Received message without Leader_Ack, retrying...");
-                    println!("Received: {}", message);
+        tokio::select! {
+            received = inbox.recv(socket6) => match received {
+                Ok(Received::Transfer(completed)) => match EncryptResult::from_bytes(&completed.data) {
+                    Ok(result) if result.request_id == request_id => {
+                        // Keep acknowledging in case the server missed the last ACKs
+                        inbox.linger(socket6).await?;
+                        return Ok(Some(result.image));
+                    }
+                    Ok(result) => println!("Ignoring the result of earlier request {}", result.request_id),
+                    Err(e) => eprintln!("Invalid result from {}: {}", completed.from, e),
+                },
+                Ok(Received::Message(message, from)) => {
+                    println!("Unexpected message on the image socket from {}: {:?}", from, message)
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            },
+            received = socket.recv_from(&mut buffer) => {
+                let (size, _) = received?;
+                if let Ok(Message::LeaderAck { leader: current }) = protocol::decode(&buffer[..size]) {
+                    if current.parse::<SocketAddr>().is_ok_and(|current| current != leader) {
+                        println!("Server at {} leads now, sending the image again", current);
+                        return Ok(None);
+                    }
                 }
             }
-            Ok(Err(e)) => {
-                eprintln!("Error receiving data: {:?}", e);
+            _ = check.tick() => {
+                if let Some(server) = next_server.next() {
+                    socket.send_to(&protocol::encode(&Message::LeaderQuery), server).await?;
+                }
             }
-            Err(_) => {
-                // Timeout occurred
-                println!("Timeout waiting for Leader_Ack. Retrying...");
+            _ = sleep_until(deadline) => {
+                println!("No result from {} within {:?}", leader, RESULT_TIMEOUT);
+                return Ok(None);
             }
         }
     }
-    // Allows user to input image path (one-by-one)
-
-    println!("Waiting for encrypted image from server...");
-    let mut encrypted_image_data = Vec::new();
-    let mut buffer = [0u8; 2048];
-    let mut expected_sequence_num: u32 = 0;
-
-    loop {
-        let (len, addr) = socket6.recv_from(&mut buffer).await?;
-
-        if &buffer[..len] == b"END" {
-            println!("Encrypted image received completely from server.");
-            break;
-        }
-        // Extract the sequence number and data
-        let sequence_num = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-        let chunk_data = &buffer[4..len];
-
-        if sequence_num == expected_sequence_num {
-            encrypted_image_data.extend_from_slice(chunk_data);
-            // println!(
-            // "Received encrypted chunk with sequence number {}",
-            // sequence_num
-            // );
-            // Send ACK for the received chunk
-
-            let ack_message = format!("ACK {}", sequence_num);
-            socket6.send_to(ack_message.as_bytes(), addr).await?;
-            expected_sequence_num += 1;
-        } else {
-            // Send NACK if the sequence number is not as expected
-            let nack_message = format!("NACK {}", expected_sequence_num);
-            socket6.send_to(nack_message.as_bytes(), addr).await?;
-            println!("NACK sent for sequence number {}", expected_sequence_num);
-        }
-    }
-
-    // Save the received encrypted image as a PNG file
-    let encrypted_image_path = "encrypted_image_from_server.png";
-
-    let mut encrypted_image_file = File::create(encrypted_image_path)?;
-    encrypted_image_file.write_all(&encrypted_image_data)?;
-    println!("Encrypted image saved as PNG at {}", encrypted_image_path);
-
-    let views = 5;
-    // convert peer_id to int
-    let peer_id = u32::from_str_radix(peer_id, 10).unwrap();
-
-    embed_data_in_image(encrypted_image_path, views, peer_id, encrypted_image_path)?;
-    let encrypted_image = file_as_dynamic_image(encrypted_image_path.to_string()).to_rgba();
-    let decoder = Decoder::new(encrypted_image);
-    let decrypted_data = decoder.decode_alpha();
-    let output_path = "decrypted_image.png";
-    std::fs::write(output_path, &decrypted_data)?;
-    println!("Decrypted image saved successfully as PNG!");
-    Ok(leader_address)
 }
+
 pub async fn send_samples(
     socket: &UdpSocket,
     client_id: &str,
@@ -259,35 +191,47 @@ pub async fn send_samples(
                     .unwrap_or(&file.file_name().to_string_lossy())
                     .to_string();
 
-                // Send metadata
-                let metadata_message = format!("SAMPLE_UPLOAD:{}:{}", client_id, original_name);
+                // Send the sample together with its metadata
+                let file_data = fs::read(&file_path).expect("Failed to read file");
+                let upload_message = Message::SampleUpload {
+                    client_id: client_id.to_string(),
+                    image_id: original_name.clone(),
+                    data: file_data,
+                };
                 socket
-                    .send_to(metadata_message.as_bytes(), server_address)
+                    .send_to(&protocol::encode(&upload_message), server_address)
                     .await?;
-                println!("Sent metadata for sample: {}", original_name);
-
-                // Send file data
-                let file_data = fs::read(&file_path).expect("Failed to read file");
-                socket.send_to(&file_data, server_address).await?;
-                println!("Sent file data for sample: {}", original_name);
+                println!("Sent sample: {}", original_name);
             }
         }
     }
 
     // Notify the server that all samples are sent
-    socket.send_to(b"END_SAMPLES", server_address).await?;
+    socket
+        .send_to(&protocol::encode(&Message::EndSamples), server_address)
+        .await?;
     println!("Notified server that all samples are sent.");
     Ok(())
 }
 
-pub async fn send_image(socket: &UdpSocket, image_id: &str) -> io::Result<()> {
+// Uploads the image sealed with `key`, the leader only hides the ciphertext
+// in its mask and never sees the image itself
+pub async fn send_image(
+    socket: &UdpSocket,
+    leader: SocketAddr,
+    request_id: u64,
+    image_id: &str,
+    key: &[u8; KEY_LEN],
+) -> io::Result<()> {
     // Prompt the user for the image path
     // let mut input = String::new();
     // println!("Enter your Image Path to send to the server: ");
     // io::stdin()
     // .read_line(&mut input)
     // .expect("Failed to read line");
-    let image_path = format!("{}/{}.jpg", "images", image_id); // images/5
+    // Shared IDs look like <owner>_<image>, the file is images/<image>.jpg
+    let local_id = image_id.split('_').nth(1).unwrap_or(image_id);
+    let image_path = format!("{}/{}.jpg", "images", local_id); // images/5
 
     // Load the image from the given path
     let format = image::guess_format(
@@ -298,128 +242,19 @@ pub async fn send_image(socket: &UdpSocket, image_id: &str) -> io::Result<()> {
     let mut buf = Cursor::new(Vec::new());
     img.write_to(&mut buf, format)
         .expect("Failed to convert image to bytes");
-    let image_bytes = buf.into_inner();
-
-    // Image chunking setup
-    let chunk_size = 2044;
-    let total_chunks = (image_bytes.len() as f64 / chunk_size as f64).ceil() as usize;
-
-    let mut ack_buffer = [0u8; 1024];
-    let max_retries = 5;
-    let mut sequence_num: u32 = 0;
-
-    // Send image in chunks
-    for i in 0..total_chunks {
-        let start = i * chunk_size;
-        let end = std::cmp::min(start + chunk_size, image_bytes.len());
-        let chunk_data = &image_bytes[start..end];
-
-        // Prepare chunk with sequence number
-        let mut chunk = Vec::with_capacity(4 + chunk_data.len());
-        chunk.extend_from_slice(&sequence_num.to_be_bytes());
-        chunk.extend_from_slice(chunk_data);
-
-        socket.send(&chunk).await?;
-        println!(
-            "Sent chunk {} of {} with sequence number {}",
-            i + 1,
-            total_chunks,
-            sequence_num
-        );
-        if i == total_chunks - 1 {
-            // Send "END" message
-            let mut end_message = Vec::with_capacity(4 + 3);
-            println!(
-                "Sending END message... with sequence number {}",
-                sequence_num
-            );
-            end_message.extend_from_slice(&(sequence_num + 1).to_be_bytes());
-            end_message.extend_from_slice(b"END");
-            socket.send(&end_message).await?;
-
-            // Wait for ACK END
-            match timeout(Duration::from_secs(5), socket.recv(&mut ack_buffer)).await {
-                Ok(Ok(ack_size)) => {
-                    let ack_message = String::from_utf8_lossy(&ack_buffer[..ack_size]);
-                    if ack_message == "END" {
-                        println!("Final ACK received. Image transmission completed.");
-                        break;
-                    }
-                }
-                Ok(Err(e)) => println!("Failed to receive final ACK: {}", e),
-                Err(_) => println!("Timeout waiting for final ACK."),
-            }
-            break;
-        }
-
-        sequence_num += 1;
-
-        // Check for ACK or NACK every 10 chunks or at the end of transmission
-        if (i + 1) % 10 == 0 || i == total_chunks - 1 {
-            let mut retries = 0;
-            loop {
-                match timeout(Duration::from_secs(5), socket.recv(&mut ack_buffer)).await {
-                    Ok(Ok(ack_size)) => {
-                        let ack_message = String::from_utf8_lossy(&ack_buffer[..ack_size]);
-                        if ack_message.starts_with("ACK") {
-                            let ack_num: u32 = ack_message[4..].parse().unwrap();
-                            println!("ACK received for sequence number {}.", ack_num);
-                            if ack_num == sequence_num - 1 {
-                                break; // Successfully received ACK for this chunk
-                            }
-                        } else if ack_message.starts_with("NACK") {
-                            println!("NACK received. Resending last batch...");
-                            retries += 1;
-                            if retries >= max_retries {
-                                println!("Max retries reached for last batch. Aborting.");
-                                break;
-                            }
-                            resend_last_batch(i, chunk_size, &image_bytes, socket).await?;
-                        }
-                    }
-                    Ok(Err(e)) => {
-                        println!("Failed to receive ACK: {}", e);
-                        break;
-                    }
-                    Err(_) => {
-                        println!("Timeout waiting for ACK. Retrying last batch.");
-                        retries += 1;
-                        if retries >= max_retries {
-                            println!("Max retries reached for timeout on batch. Aborting.");
-                            break;
-                        }
-                        resend_last_batch(i, chunk_size, &image_bytes, socket).await?;
-                    }
-                }
-            }
-        }
-    }
+    let request = EncryptRequest {
+        request_id,
+        image: crypto::seal(key, image_id, &buf.into_inner())?,
+    };
+
+    // Upload the image to the leader
+    let transfer_id = transfer::new_transfer_id();
+    println!("Sending image {} (request {}) as transfer {}", image_id, request_id, transfer_id);
+    transfer::send(socket, leader, transfer_id, &request.to_bytes(), &TransferConfig::default()).await?;
+    println!("Image transmission completed.");
     Ok(())
 }
 
-// Helper function to resend the last 10 chunks or fewer if near the start
-pub async fn resend_last_batch(
-    current_index: usize,
-    chunk_size: usize,
-    image_bytes: &[u8],
-    socket: &UdpSocket,
-) -> io::Result<()> {
-    let start_chunk = (current_index + 1 - 10).max(0);
-    for j in start_chunk..=current_index {
-        let start = j * chunk_size;
-        let end = std::cmp::min(start + chunk_size, image_bytes.len());
-        let chunk_data = &image_bytes[start..end];
-
-        let mut chunk = Vec::with_capacity(4 + chunk_data.len());
-        chunk.extend_from_slice(&(j as u32).to_be_bytes());
-        chunk.extend_from_slice(chunk_data);
-
-        socket.send(&chunk).await?;
-        println!("Resent chunk {} with sequence number {}", j + 1, j);
-        sleep(Duration::from_millis(5)).await;
-    }
-    Ok(())
-}
 // Checks if a file has an image extension.
 pub fn is_image_file(file_name: &str) -> bool {
     let image_extensions = ["png", "jpg", "jpeg", "gif"];
@@ -453,26 +288,14 @@ pub fn get_image_paths(dir: &str) -> Result<Vec<String>, std::io::Error> {
     Ok(image_paths)
 }
 
-pub async fn start_p2p_listener(client_address: &str, client: &str) -> io::Result<()> {
-    let server1 = "127.0.0.1";
-    let server2 = "127.0.0.1";
-    let server3 = "127.0.0.1";
-    /////////////////////////////////////////////////////////////////////////////
-    let server1_address = format!("{}:{}", server1, "8083");
-    let server2_addres = format!("{}:{}", server2, "8084");
-    let server3_address = format!("{}:{}", server3, "2010");
-    /////////////////////////////////////////////////////////////////////////////
-    println!("P2P Listener running on {}", client_address);
-
-    let servers: Vec<SocketAddr> = vec![
-        server1_address.parse().unwrap(),
-        server2_addres.parse().unwrap(),
-        server3_address.parse().unwrap(),
-    ];
-
-    let client_election_and_image = format!("{}:{}", client, "9080"); // client address to send image for encryption
-    let client_encyrpted_image_back = format!("{}:{}", client, "2005"); // client address to send image for encryption
-    let socket = UdpSocket::bind(client_address).await?;
+pub async fn start_p2p_listener(config: &ClientConfig, servers: ServerList) -> io::Result<()> {
+    println!("P2P Listener running on {}", config.p2p_addr);
+
+    let client_election_and_image = config.leader_ack_addr; // client address to send image for encryption
+    let client_encyrpted_image_back = config.image_addr; // client address to receive the encrypted image on
+    let owner_id = config.client_id.clone();
+    let default_terms = config.share_terms()?;
+    let socket = UdpSocket::bind(config.p2p_addr).await?;
     let socket6 = UdpSocket::bind(client_encyrpted_image_back).await?; // socket for encrypted image recieving
 
     let samples_dir = "images";
@@ -487,20 +310,34 @@ pub async fn start_p2p_listener(client_address: &str, client: &str) -> io::Resul
                 }
             };
 
-            let received_message = String::from_utf8_lossy(&buffer[..amt]);
-            if received_message.starts_with("REQUEST_IMAGE_FROM") {
-                // Extract the part after "REQUEST_IMAGE_FROM" and remove the prefix
-                let after_prefix = received_message
-                    .strip_prefix("REQUEST_IMAGE_FROM")
-                    .unwrap_or("")
-                    .trim();
-
-                // Split the remaining part by ':' to separate the IP from the image ID
-                let parts: Vec<&str> = after_prefix.split(':').collect();
-
-                if parts.len() == 2 {
-                    let requester_ip = parts[0]; // The IP address of the requester
-                    let full_image_id = parts[1]; // The full image ID requested
+            let received_message = match protocol::decode(&buffer[..amt]) {
+                Ok(message) => message,
+                Err(e) => {
+                    println!("Invalid request format: {}", e);
+                    continue;
+                }
+            };
+            match received_message {
+                Message::RequestImage { requester_id, image_id: full_image_id, token, signature } => {
+                    // Only share with a requester logged in under the ID it
+                    // claims, that signed the request with its registered key
+                    let content = (&requester_id, &full_image_id);
+                    let checked =
+                        auth::check_peer(&servers.all(), &requester_id, &token, signing::REQUEST_IMAGE, &content, &signature)
+                            .await;
+                    let requester_ip = requester_id.as_str(); // The ID of the requester
+                    let full_image_id = full_image_id.as_str(); // The full image ID requested
+
+                    if let Err(e) = checked {
+                        println!("Refusing image request from {}: {}", requester_ip, e);
+                        let refusal = protocol::encode(&Message::AuthFailed {
+                            reason: e.to_string(),
+                        });
+                        if let Err(e) = socket.send_to(&refusal, peer_addr).await {
+                            eprintln!("Failed to refuse the request of {}: {:?}", requester_ip, e);
+                        }
+                        continue;
+                    }
 
                     // Remove the leading part before the underscore (e.g., "6_" part)
                     let image_id = full_image_id.split('_').nth(1).unwrap_or(full_image_id);
@@ -510,178 +347,14 @@ pub async fn start_p2p_listener(client_address: &str, client: &str) -> io::Resul
                         image_id, requester_ip
                     );
 
-                    // Send the ELECT message to the servers
-                    for addr in &servers {
-                        socket6.send_to(b"ELECT", addr).await.unwrap_or_else(|e| {
-                            eprintln!("Failed to send ELECT message to {}", addr);
-                            0
+                    // Check if the image exists in the images directory
+                    let image_path = format!("images/{}.jpg", image_id);
+                    if !Path::new(&image_path).exists() {
+                        let error_message = protocol::encode(&Message::ImageNotFound {
+                            image_id: image_id.to_string(),
                         });
-                        println!("Sent ELECT message to {}", addr);
-                    }
-                    middleware(
-                        &socket6,
-                        image_id,
-                        &client_election_and_image,
-                        &requester_ip,
-                    )
-                    .await;
-
-                    // Check if the image exists in the samples directory
-                    let image_path = format!("encrypted_image_from_server.png");
-                    if Path::new(&image_path).exists() {
-                        match fs::read(&image_path) {
-                            Ok(image_data) => {
-                                let chunk_size = 1024;
-                                let total_chunks = (image_data.len() + chunk_size - 1) / chunk_size;
-
-                                // Send the total number of chunks first
-                                let total_chunks_message = format!("TOTAL_CHUNKS:{}", total_chunks);
-                                socket
-                                    .send_to(total_chunks_message.as_bytes(), peer_addr)
-                                    .await
-                                    .unwrap_or_else(|e| {
-                                        eprintln!(
-                                            "Failed to send total chunks message for image '{}': {:?}",
-                                            image_id, e
-                                        );
-                                        0
-                                    });
-
-                                let mut transfer_successful = true;
-                                'chunk_loop: for (i, chunk) in
-                                    image_data.chunks(chunk_size).enumerate()
-                                {
-                                    let mut message = Vec::new();
-                                    message.extend_from_slice(&(i as u32).to_be_bytes());
-                                    message.extend_from_slice(chunk);
-
-                                    let mut retries = 0;
-                                    const MAX_RETRIES: u32 = 2;
-
-                                    while retries <= MAX_RETRIES {
-                                        socket.send_to(&message, peer_addr).await.unwrap_or_else(
-                                            |e| {
-                                                eprintln!(
-                                                    "Failed to send chunk {} for image '{}': {:?}",
-                                                    i, image_id, e
-                                                );
-                                                0
-                                            },
-                                        );
-
-                                        println!(
-                                            "Sent chunk {}/{} of image '{}' to {} (attempt {}/{})",
-                                            i + 1,
-                                            total_chunks,
-                                            image_id,
-                                            peer_addr,
-                                            retries + 1,
-                                            MAX_RETRIES + 1
-                                        );
-
-                                        let mut ack_buffer = [0u8; 128];
-                                        match tokio::time::timeout(
-                                            std::time::Duration::from_secs(2),
-                                            socket.recv_from(&mut ack_buffer),
-                                        )
-                                        .await
-                                        {
-                                            Ok(Ok((amt, _))) => {
-                                                let response =
-                                                    String::from_utf8_lossy(&ack_buffer[..amt]);
-                                                if response == format!("ACK:{}", i) {
-                                                    println!("Received ACK for chunk {}", i);
-                                                    continue 'chunk_loop;
-                                                } else if response == format!("NACK:{}", i) {
-                                                    println!(
-                                                        "Received NACK for chunk {}, retry {}/{}",
-                                                        i,
-                                                        retries + 1,
-                                                        MAX_RETRIES + 1
-                                                    );
-                                                    retries += 1;
-                                                    if retries > MAX_RETRIES {
-                                                        eprintln!(
-                                                            "Max retries exceeded for chunk {}",
-                                                            i
-                                                        );
-                                                        transfer_successful = false;
-                                                        break 'chunk_loop;
-                                                    }
-                                                    continue;
-                                                } else {
-                                                    eprintln!("Unexpected response: {}", response);
-                                                }
-                                            }
-                                            Ok(Err(e)) => {
-                                                eprintln!("Error receiving ACK/NACK: {:?}", e);
-                                                retries += 1;
-                                                if retries > MAX_RETRIES {
-                                                    eprintln!(
-                                                        "Max retries exceeded for chunk {}",
-                                                        i
-                                                    );
-                                                    transfer_successful = false;
-                                                    break 'chunk_loop;
-                                                }
-                                                continue;
-                                            }
-                                            Err(_) => {
-                                                println!(
-                                                    "Timeout waiting for ACK/NACK for chunk {}, retry {}/{}",
-                                                    i, retries + 1, MAX_RETRIES + 1
-                                                );
-                                                retries += 1;
-                                                if retries > MAX_RETRIES {
-                                                    eprintln!(
-                                                        "Max retries exceeded for chunk {}",
-                                                        i
-                                                    );
-                                                    transfer_successful = false;
-                                                    break 'chunk_loop;
-                                                }
-                                                continue;
-                                            }
-                                        }
-                                    }
-
-                                    if retries > MAX_RETRIES {
-                                        eprintln!(
-                                            "Failed to send chunk {} after {} retries",
-                                            i, MAX_RETRIES
-                                        );
-                                        transfer_successful = false;
-                                        break;
-                                    }
-                                }
-
-                                if transfer_successful {
-                                    println!(
-                                        "Successfully completed sending image '{}' to {}",
-                                        image_id, peer_addr
-                                    );
-                                } else {
-                                    eprintln!(
-                                        "Failed to complete image transfer for '{}' to {}",
-                                        image_id, peer_addr
-                                    );
-
-                                    // Optionally notify peer about transfer failure
-                                    let failure_message = format!("TRANSFER_FAILED:{}", image_id);
-                                    socket.send_to(failure_message.as_bytes(), peer_addr).await.unwrap_or_else(|e| {
-                                        eprintln!("Failed to send transfer failure notification: {:?}", e);
-                                        0
-                                    });
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to read image '{}': {:?}", image_id, e);
-                            }
-                        }
-                    } else {
-                        let error_message = format!("IMAGE_NOT_FOUND:{}", image_id);
                         socket
-                            .send_to(error_message.as_bytes(), peer_addr)
+                            .send_to(&error_message, peer_addr)
                             .await
                             .unwrap_or_else(|e| {
                                 eprintln!(
@@ -691,55 +364,122 @@ pub async fn start_p2p_listener(client_address: &str, client: &str) -> io::Resul
                                 0
                             });
                         println!("Image '{}' not found. Notified {}", image_id, peer_addr);
+                        continue;
                     }
-                } else {
-                    println!("Invalid request format: {}", received_message);
-                }
-            } else if received_message.starts_with("CONTROL_UPDATE") {
-                let update_data = received_message
-                    .strip_prefix("CONTROL_UPDATE:")
-                    .unwrap_or("")
-                    .to_string();
-                let parts: Vec<&str> = update_data.split(':').collect();
 
-                if parts.len() == 3 {
-                    let client_id = parts[0].to_string();
-                    let image_id = parts[1].to_string();
-                    let views = parts[2].to_string();
+                    // The owner took the image away from this requester
+                    if views::is_revoked(full_image_id, requester_ip) {
+                        println!("Image '{}' was revoked for {}, refusing", full_image_id, requester_ip);
+                        let refusal = protocol::encode(&Message::AuthFailed {
+                            reason: format!("access to image {} was revoked", full_image_id),
+                        });
+                        if let Err(e) = socket.send_to(&refusal, peer_addr).await {
+                            eprintln!("Failed to refuse the request of {}: {:?}", requester_ip, e);
+                        }
+                        continue;
+                    }
 
+                    // Every share gets its own key, only this requester receives it
+                    let key = crypto::generate_key();
+                    let request = match requests::open(full_image_id, requester_ip) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            eprintln!("Failed to record the request for '{}': {:?}", image_id, e);
+                            continue;
+                        }
+                    };
+                    // The terms the owner set for this requester, or the default ones
+                    let grant = views::share_terms(full_image_id, requester_ip, default_terms)
+                        .and_then(|terms| views::sign_grant(&owner_id, full_image_id, requester_ip, terms.views, terms.expiry));
+                    let sent = match grant {
+                        Ok(grant) => {
+                            let servers = servers.all();
+                            let requester = (&socket, peer_addr);
+                            middleware(&socket6, &servers, &request, &client_election_and_image, &grant, &key, requester)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    // Answered, or given up on and the requester told so below
+                    if let Err(e) = requests::finish(&request) {
+                        eprintln!("Failed to clear request {}: {:?}", request.request_id, e);
+                    }
+                    let sent = match sent {
+                        Ok(image_data) => {
+                            let shared = SharedImage {
+                                image_id: full_image_id.to_string(),
+                                key: key.to_vec(),
+                                image: image_data,
+                            };
+                            let transfer_id = transfer::new_transfer_id();
+                            println!(
+                                "Sending image '{}' to {} as transfer {}",
+                                image_id, peer_addr, transfer_id
+                            );
+                            transfer::send(&socket, peer_addr, transfer_id, &shared.to_bytes(), &TransferConfig::default()).await
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    match sent {
+                        Ok(()) => println!(
+                            "Successfully completed sending image '{}' to {}",
+                            image_id, peer_addr
+                        ),
+                        Err(e) => {
+                            eprintln!(
+                                "Failed to complete image transfer for '{}' to {}: {:?}",
+                                image_id, peer_addr, e
+                            );
+
+                            // Notify peer about transfer failure
+                            let failure_message = protocol::encode(&Message::TransferFailed {
+                                image_id: image_id.to_string(),
+                            });
+                            socket.send_to(&failure_message, peer_addr).await.unwrap_or_else(|e| {
+                                eprintln!("Failed to send transfer failure notification: {:?}", e);
+                                0
+                            });
+                        }
+                    }
+                }
+                Message::ControlUpdate { client_id, image_id, views, grant } => {
                     println!(
                         "Received control update - Client ID: {}, Image ID: {}, New Views: {}",
                         client_id, image_id, views
                     );
 
-                    let stored_client_id = client_id;
-                    let stored_image_id = image_id;
-                    let stored_views = views;
-
-                    // access received_images folder and update the views count
-                    let views_dir = "views_count";
-                    let views_file = format!("{}/{}_views.txt", views_dir, stored_image_id);
-
-                    // Open the file for writing and truncate (overwrite) its contents
-                    let mut file = OpenOptions::new()
-                        .write(true)
-                        .truncate(true) // This ensures the file is overwritten, not appended
-                        .open(views_file)
-                        .expect("Failed to open file");
-
-                    // Write the new view count to the file
-                    file.write_all(stored_views.as_bytes())
-                        .expect("Failed to write to file");
-
-                    // For now, we print them or store them for future use
-                    println!(
-                        "Stored client_id: {}, image_id: {}, views: {}",
-                        stored_client_id, stored_image_id, stored_views
-                    );
-                } else {
-                    println!("Received invalid CONTROL_UPDATE format.");
-                    println!("Received: {}", received_message);
+                    // Servers only relay updates from the image's owner, so
+                    // updates sent straight from another peer are dropped
+                    if !servers.all().contains(&peer_addr) {
+                        println!("Ignoring control update for image {} from {}, which is not a server", image_id, peer_addr);
+                        continue;
+                    }
+                    // Only a count signed by the image's owner is accepted
+                    let Some(grant) = grant else {
+                        println!("Ignoring unsigned control update for image {}", image_id);
+                        continue;
+                    };
+                    if grant.grant.views != views {
+                        println!("Ignoring control update for image {} that disagrees with its grant", image_id);
+                        continue;
+                    }
+                    // The grant has to carry the key the owner registered
+                    let owner_key = match auth::peer_key(&servers.all(), &grant.grant.owner).await {
+                        Ok(owner_key) => owner_key,
+                        Err(e) => {
+                            eprintln!("Rejected control update for image {}, no key of {}: {}", image_id, grant.grant.owner, e);
+                            continue;
+                        }
+                    };
+                    let revoked = grant.grant.revoked;
+                    match views::apply_update(&image_id, grant, &owner_id, &owner_key) {
+                        Ok(_) if revoked => println!("Image {} was revoked by its owner and deleted", image_id),
+                        Ok(views) => println!("Stored image_id: {}, views: {}", image_id, views),
+                        Err(e) => eprintln!("Rejected control update for image {}: {}", image_id, e),
+                    }
                 }
+                other => println!("Unexpected P2P message: {:?}", other),
             }
         }
     });
@@ -748,114 +488,129 @@ pub async fn start_p2p_listener(client_address: &str, client: &str) -> io::Resul
 
 pub async fn request_image_by_id(
     socket: &UdpSocket,
+    servers: &[SocketAddr],
     image_id: &str,
     client_map: &HashMap<String, String>,
     my_ip: &str,
+    token: &str,
 ) -> io::Result<()> {
     // Determine the client_id (folder name)
     let client_id = image_id.split('_').next().unwrap_or("").to_string();
     if let Some(peer_address) = client_map.get(&client_id) {
+        // The grant in the image has to carry the key the owner registered
+        let owner_key = match auth::peer_key(servers, &client_id).await {
+            Ok(owner_key) => owner_key,
+            Err(e) => {
+                println!("Not requesting image '{}', no key of {}: {}", image_id, client_id, e);
+                return Ok(());
+            }
+        };
+
         // Send the request to the correct peer
-        let request_message = format!("REQUEST_IMAGE_FROM{}:{}", my_ip, image_id);
-        socket
-            .send_to(request_message.as_bytes(), peer_address)
-            .await?;
+        let identity = views::load_or_create_identity()?;
+        let signature = signing::sign(&identity, signing::REQUEST_IMAGE, &(my_ip, image_id));
+        let request_message = protocol::encode(&Message::RequestImage {
+            requester_id: my_ip.to_string(),
+            image_id: image_id.to_string(),
+            token: token.to_string(),
+            signature,
+        });
+        socket.send_to(&request_message, peer_address).await?;
         println!("Requested image '{}' from peer {}", image_id, peer_address);
 
-        // Prepare to receive the total number of chunks
-        let mut buffer = [0u8; 4096];
-        let (amt, _) = socket.recv_from(&mut buffer).await?;
-
-        let total_chunks_message = String::from_utf8_lossy(&buffer[..amt]);
-        if total_chunks_message.starts_with("TOTAL_CHUNKS:") {
-            let total_chunks: usize = total_chunks_message
-                .strip_prefix("TOTAL_CHUNKS:")
-                .unwrap_or("0")
-                .trim()
-                .parse()
-                .unwrap_or(0);
-
-            println!("Expecting {} chunks for image '{}'", total_chunks, image_id);
-
-            // Prepare to collect chunks with ACK/NACK
-            let mut received_chunks = vec![None; total_chunks];
-            let mut missing_chunks: HashSet<usize> = (0..total_chunks).collect();
-
-            while !missing_chunks.is_empty() {
-                let (amt, src_addr) = socket.recv_from(&mut buffer).await?;
-                let chunk_number = u32::from_be_bytes(buffer[0..4].try_into().unwrap()) as usize;
-                let chunk_data = &buffer[4..amt];
-
-                if chunk_number < total_chunks && missing_chunks.contains(&chunk_number) {
-                    received_chunks[chunk_number] = Some(chunk_data.to_vec());
-                    missing_chunks.remove(&chunk_number);
-
-                    println!(
-                        "Received chunk {}/{} for image '{}'",
-                        chunk_number + 1,
-                        total_chunks,
-                        image_id
-                    );
-
-                    // Send an ACK for the received chunk
-                    let ack_message = format!("ACK:{}", chunk_number);
-                    socket.send_to(ack_message.as_bytes(), src_addr).await?;
-                } else {
-                    // Send a NACK if the chunk is invalid or duplicate
-                    let nack_message = format!("NACK:{}", chunk_number);
-                    socket.send_to(nack_message.as_bytes(), src_addr).await?;
+        let peer: SocketAddr = match peer_address.parse() {
+            Ok(peer) => peer,
+            Err(e) => {
+                println!("Invalid address '{}' for peer {}: {}", peer_address, client_id, e);
+                return Ok(());
+            }
+        };
+
+        // The peer has the image encrypted by the leader before sending it, so
+        // wait long enough for an election and an upload
+        let config = TransferConfig {
+            idle_timeout: Duration::from_secs(60),
+            ..TransferConfig::default()
+        };
+        let mut inbox = Inbox::new(&config);
+        let image_data = loop {
+            match inbox.recv(socket).await {
+                Ok(Received::Transfer(completed)) if completed.from == peer => break Some(completed.data),
+                Ok(Received::Message(Message::ImageNotFound { .. }, _)) => {
+                    println!("Peer responded: Image '{}' not found.", image_id);
+                    break None;
+                }
+                Ok(Received::Message(Message::AuthFailed { reason }, _)) => {
+                    println!("Peer refused the request for image '{}': {}", image_id, reason);
+                    break None;
+                }
+                Ok(Received::Message(Message::TransferFailed { .. }, _)) => {
+                    println!("Peer gave up sending image '{}'.", image_id);
+                    break None;
+                }
+                // Every notice restarts the idle timeout
+                Ok(Received::Message(Message::TransferDelayed { attempt, .. }, from)) if from == peer => {
+                    println!("Peer lost its leader, image '{}' is delayed (attempt {}).", image_id, attempt + 1);
+                }
+                Ok(other) => println!("Unexpected response from peer: {:?}", other),
+                Err(e) => {
+                    println!("No image received from peer: {}", e);
+                    break None;
                 }
             }
+        };
 
+        if let Some(shared) = image_data {
+            // Keep acknowledging in case the peer missed the last ACKs
+            inbox.linger(socket).await?;
             println!("All chunks received for image '{}'", image_id);
 
-            // Reassemble the image
-            let mut image_data = Vec::new();
-            for chunk in received_chunks.into_iter().flatten() {
-                image_data.extend_from_slice(&chunk);
-            }
+            let (image_data, key) = match SharedImage::from_bytes(&shared) {
+                Ok(shared) => match <[u8; KEY_LEN]>::try_from(shared.key) {
+                    Ok(key) => (shared.image, key),
+                    Err(_) => {
+                        println!("Received image '{}' came with an invalid key", image_id);
+                        return Ok(());
+                    }
+                },
+                Err(e) => {
+                    println!("Received image '{}' is invalid: {}", image_id, e);
+                    return Ok(());
+                }
+            };
+
+            // Read the signed grant and keep the image without it
+            let metadata = stego::extract_data_from_image(&image_data)
+                .and_then(|metadata| Ok(SignedGrant::from_bytes(&metadata)?))
+                .and_then(|grant| {
+                    views::check_grant(&grant, image_id, my_ip, &owner_key)?;
+                    Ok((grant, stego::strip_metadata_row(&image_data)?))
+                });
+            let (grant, image_data) = match metadata {
+                Ok(result) => result,
+                Err(e) => {
+                    println!("Received image '{}' is invalid: {}", image_id, e);
+                    return Ok(());
+                }
+            };
+            println!(
+                "Extracted Views: {}, granted by: {}",
+                grant.grant.views, grant.grant.owner
+            );
 
-            // Save the reassembled image
+            // Save the received image, it stays encrypted until it is viewed
             let received_images_dir = "received_images";
             std::fs::create_dir_all(received_images_dir)
                 .expect("Failed to create 'received_images' directory");
 
             let image_path = format!("{}/{}.png", received_images_dir, image_id);
             std::fs::write(&image_path, &image_data).expect("Failed to save received image");
+            crypto::store_key(image_id, &key)?;
             println!("Received and saved image '{}' from peer.", image_id);
 
-            // Decrypting the image
-            let encrypted_image = file_as_dynamic_image(image_path.to_string()).to_rgba();
-            ////////////////////////////////////////////////////////////////////////////////////////////////////
-            let (extracted_views, extracted_client_id) =
-                extract_data_from_image(&image_path.to_string());
-            println!(
-                "Extracted Views: {}, Extracted Client ID: {}",
-                extracted_views, extracted_client_id
-            );
-            ////////////////////////////////////////////////////////////////////////////////////////////////////
-
             // view file
-
-            let views_dir = "views_count";
-            std::fs::create_dir_all(views_dir).expect("Failed to create 'views' directory");
-
-            let views_file = format!("{}/{}_views.txt", views_dir, image_id);
-            let mut file = File::create(views_file).expect("Failed to create file");
-            file.write_all(extracted_views.to_string().as_bytes())
-                .expect("Failed to write to file");
-
-            strip_metadata_row(&image_path, &image_path).expect("Failed to strip metadata row");
-
-            let decoder = Decoder::new(encrypted_image);
-            let decrypted_data = decoder.decode_alpha();
-            let output_path = "decrypted_image.png";
-            std::fs::write(output_path, &decrypted_data)?;
-            println!("Decrypted image saved successfully as PNG!");
-        } else if total_chunks_message.starts_with("IMAGE_NOT_FOUND:") {
-            println!("Peer responded: Image '{}' not found.", image_id);
-        } else {
-            println!("Unexpected response from peer: {}", total_chunks_message);
+            let views = grant.grant.views;
+            views::store_views(image_id, grant, views)?;
         }
     } else {
         println!("No online peer found for client_id '{}'.", client_id);
@@ -864,12 +619,247 @@ pub async fn request_image_by_id(
     Ok(())
 }
 
-pub async fn decrypt(image_path: String) -> io::Result<()> {
-    let encrypted_image = file_as_dynamic_image(image_path.to_string()).to_rgba();
-    let decoder = Decoder::new(encrypted_image);
-    let decrypted_data = decoder.decode_alpha();
-    let output_path = "decrypted_image.png";
-    std::fs::write(output_path, &decrypted_data)?;
-    // println!("Decrypted image saved successfully as PNG!");
-    Ok(())
+// Recovers the sealed image hidden in a received carrier and decrypts it with
+// the key its owner sent, writing it to `output_path` for a viewer
+pub fn decrypt(image_id: &str, image_path: &Path, output_path: &Path) -> io::Result<()> {
+    let encrypted_image = fs::read(image_path)?;
+    let sealed = stego::decode_image(&encrypted_image)?;
+    let key = crypto::load_key(image_id)?;
+    let decrypted_data = crypto::open(&key, image_id, &sealed)?;
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(output_path, &decrypted_data)
+}
+
+// Uploads and peer to peer transfers through a proxy that loses, delays,
+// reorders, duplicates and truncates datagrams, and a request whose leader
+// fails
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::views::tests::in_scratch_dir;
+    use failure_simulation::proxy::{Impairment, Proxy, ProxyConfig};
+    use protocol::Expiry;
+    use image::{Rgb, RgbImage};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Bad enough that every transfer needs retransmissions
+    fn lossy(seed: u64) -> ProxyConfig {
+        ProxyConfig {
+            loss: 0.15,
+            latency: Duration::from_millis(5),
+            jitter: Duration::from_millis(20),
+            reorder: 0.1,
+            duplicate: 0.1,
+            truncate: 0.1,
+            mtu: 512,
+            seed,
+            ..ProxyConfig::default()
+        }
+    }
+
+    // Noise compresses badly, so the image takes many chunks
+    fn noisy_image(width: u32, height: u32) -> RgbImage {
+        let mut rng = StdRng::seed_from_u64(u64::from(width * height));
+        RgbImage::from_fn(width, height, |_, _| Rgb(rng.gen()))
+    }
+
+    async fn local_socket() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    // The identity both sides share in these tests
+    fn own_key() -> Vec<u8> {
+        views::load_or_create_identity().unwrap().verifying_key().to_bytes().to_vec()
+    }
+
+    // A server that only answers key lookups, with `own_key`
+    async fn key_server() -> SocketAddr {
+        let socket = local_socket().await;
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            while let Ok((size, from)) = socket.recv_from(&mut buffer).await {
+                if let Ok(Message::KeyQuery { client_id }) = protocol::decode(&buffer[..size]) {
+                    let answer = Message::ClientKey {
+                        client_id,
+                        public_key: Some(own_key()),
+                    };
+                    socket.send_to(&protocol::encode(&answer), from).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    async fn proxy_to(server: &UdpSocket, config: ProxyConfig) -> Proxy {
+        Proxy::start("127.0.0.1:0".parse().unwrap(), server.local_addr().unwrap(), config)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upload_arrives_intact() {
+        in_scratch_dir();
+        fs::create_dir_all("images").unwrap();
+        noisy_image(200, 200).save("images/upload.jpg").unwrap();
+        let leader = local_socket().await;
+        let proxy = proxy_to(&leader, lossy(1)).await;
+        let client = local_socket().await;
+        let key = crypto::generate_key();
+        let config = TransferConfig::default();
+
+        let (sent, received) = tokio::join!(
+            send_image(&client, proxy.addr(), 42, "7_upload", &key),
+            transfer::receive(&leader, None, &config)
+        );
+        sent.unwrap();
+        let request = EncryptRequest::from_bytes(&received.unwrap().data).unwrap();
+        assert_eq!(request.request_id, 42);
+        let sealed = request.image;
+
+        // The image as send_image encodes it, byte for byte
+        let mut expected = Cursor::new(Vec::new());
+        image::open("images/upload.jpg")
+            .unwrap()
+            .write_to(&mut expected, ImageFormat::Jpeg)
+            .unwrap();
+        assert_eq!(crypto::open(&key, "7_upload", &sealed).unwrap(), expected.into_inner());
+    }
+
+    #[tokio::test]
+    async fn requested_image_arrives_intact() {
+        in_scratch_dir();
+        // The request is sent only once, so take a seed that lets the first
+        // datagram through
+        let seed = (0..)
+            .find(|seed| !Impairment::new(lossy(*seed)).apply(&[0]).is_empty())
+            .unwrap();
+        let owner = local_socket().await;
+        let proxy = proxy_to(&owner, lossy(seed)).await;
+        let requester = local_socket().await;
+        let (image_id, viewer) = ("8_shared", "9");
+
+        // What the owner sends: the carrier with the grant in its metadata
+        // row, and the key
+        let mut png = Cursor::new(Vec::new());
+        noisy_image(240, 120).write_to(&mut png, ImageFormat::Png).unwrap();
+        let grant = views::sign_grant("8", image_id, viewer, 5, Expiry::Never).unwrap();
+        let carrier = stego::embed_data_in_image(&png.into_inner(), &grant.to_bytes()).unwrap();
+        let shared = SharedImage {
+            image_id: image_id.to_string(),
+            key: crypto::generate_key().to_vec(),
+            image: carrier.clone(),
+        };
+        let owner_side = async {
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            let (size, from) = owner.recv_from(&mut buffer).await.unwrap();
+            let Ok(Message::RequestImage { requester_id, image_id: requested, token, signature }) =
+                protocol::decode(&buffer[..size])
+            else {
+                panic!("expected an image request");
+            };
+            assert_eq!((requester_id.as_str(), requested.as_str(), token.as_str()), (viewer, image_id, "token"));
+            signing::verify(&own_key(), signing::REQUEST_IMAGE, &(viewer, image_id), &signature).unwrap();
+            transfer::send(&owner, from, transfer::new_transfer_id(), &shared.to_bytes(), &TransferConfig::default())
+                .await
+                .unwrap();
+        };
+        let client_map = HashMap::from([("8".to_string(), proxy.addr().to_string())]);
+        let servers = [key_server().await];
+        let requested = request_image_by_id(&requester, &servers, image_id, &client_map, viewer, "token");
+        let (_, requested) = tokio::join!(owner_side, requested);
+        requested.unwrap();
+
+        let saved = fs::read(format!("received_images/{}.png", image_id)).unwrap();
+        assert_eq!(saved, stego::strip_metadata_row(&carrier).unwrap());
+        assert_eq!(crypto::load_key(image_id).unwrap().to_vec(), shared.key);
+        assert_eq!(views::load_views(image_id, viewer).unwrap().remaining, 5);
+
+        // Revoking deletes everything the viewer kept of the image
+        fs::create_dir_all("decrypted_images").unwrap();
+        fs::write(format!("decrypted_images/{}.png", image_id), b"decrypted").unwrap();
+        let revocation = views::sign_revocation("8", image_id, viewer).unwrap();
+        assert!(views::apply_update(image_id, revocation.clone(), viewer, &[7; 32]).is_err());
+        assert_eq!(views::apply_update(image_id, revocation, viewer, &own_key()).unwrap(), 0);
+        assert!(!Path::new(&format!("received_images/{}.png", image_id)).exists());
+        assert!(!Path::new(&format!("decrypted_images/{}.png", image_id)).exists());
+        assert!(views::load_views(image_id, viewer).is_err());
+        assert!(crypto::load_key(image_id).is_err());
+    }
+
+    #[tokio::test]
+    async fn request_goes_to_the_next_leader() {
+        in_scratch_dir();
+        fs::create_dir_all("images").unwrap();
+        noisy_image(40, 40).save("images/retry.jpg").unwrap();
+        let (control, first, second) = (local_socket().await, local_socket().await, local_socket().await);
+        let (socket6, p2p, requester) = (local_socket().await, local_socket().await, local_socket().await);
+        let (first_addr, second_addr) = (first.local_addr().unwrap(), second.local_addr().unwrap());
+        let servers = [control.local_addr().unwrap()];
+        let request = requests::open("7_retry", "9").unwrap();
+        assert_eq!(requests::open("7_retry", "9").unwrap(), request);
+        let grant = views::sign_grant("7", "7_retry", "9", 5, Expiry::Never).unwrap();
+        let key = crypto::generate_key();
+        let mut png = Cursor::new(Vec::new());
+        noisy_image(240, 30).write_to(&mut png, ImageFormat::Png).unwrap();
+        let png = png.into_inner();
+
+        // The first leader to be asked, then the one that took over from it
+        let answering = tokio::spawn(async move {
+            let mut buffer = vec![0u8; MAX_DATAGRAM];
+            let mut leader = first_addr;
+            loop {
+                let (size, from) = control.recv_from(&mut buffer).await.unwrap();
+                if protocol::decode(&buffer[..size]).unwrap() == Message::LeaderQuery {
+                    let ack = Message::LeaderAck { leader: leader.to_string() };
+                    control.send_to(&protocol::encode(&ack), from).await.unwrap();
+                    leader = second_addr;
+                }
+            }
+        });
+        // The first leader takes the upload and fails, the second answers
+        let cluster_side = async {
+            let config = TransferConfig::default();
+            let lost = transfer::receive(&first, None, &config).await.unwrap();
+            let lost = EncryptRequest::from_bytes(&lost.data).unwrap();
+            let retried = transfer::receive(&second, None, &config).await.unwrap();
+            let retried = EncryptRequest::from_bytes(&retried.data).unwrap();
+            let result = EncryptResult {
+                request_id: retried.request_id,
+                image: png.clone(),
+            };
+            let socket6_addr = socket6.local_addr().unwrap();
+            transfer::send(&second, socket6_addr, transfer::new_transfer_id(), &result.to_bytes(), &config)
+                .await
+                .unwrap();
+            (lost.request_id, retried.request_id)
+        };
+        let reinitiated = "127.0.0.1:0".parse().unwrap();
+        let requester_addr = requester.local_addr().unwrap();
+        let owner_side = middleware(&socket6, &servers, &request, &reinitiated, &grant, &key, (&p2p, requester_addr));
+        let (shared, (lost, retried)) = tokio::join!(owner_side, cluster_side);
+        answering.abort();
+
+        // Both leaders saw the same request, and the result carries the grant
+        assert_eq!((lost, retried), (request.request_id, request.request_id));
+        let shared = shared.unwrap();
+        assert_eq!(stego::extract_data_from_image(&shared).unwrap(), grant.to_bytes());
+
+        // The requester heard that the image is delayed
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        let (size, _) = requester.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(
+            protocol::decode(&buffer[..size]).unwrap(),
+            Message::TransferDelayed {
+                image_id: "7_retry".to_string(),
+                attempt: 1
+            }
+        );
+
+        requests::finish(&request).unwrap();
+        assert_ne!(requests::open("7_retry", "9").unwrap().request_id, request.request_id);
+    }
 }
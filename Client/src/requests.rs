@@ -0,0 +1,72 @@
+// Encryption requests this client sent a leader and has not had the result
+// of yet, one file per request in requests/. A request keeps its ID across
+// retries and restarts of the client, so the servers' job journal knows a
+// request sent to the next leader is the same one.
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+const REQUESTS_DIR: &str = "requests";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Request {
+    pub request_id: u64,
+    pub image_id: String,
+    // Who the encrypted image is for
+    pub requester: String,
+}
+
+fn request_path(request_id: u64) -> PathBuf {
+    PathBuf::from(REQUESTS_DIR).join(format!("{}.toml", request_id))
+}
+
+// The unfinished request for `image_id` to `requester`, or a new one
+pub fn open(image_id: &str, requester: &str) -> io::Result<Request> {
+    let unfinished = unfinished()?
+        .into_iter()
+        .find(|request| request.image_id == image_id && request.requester == requester);
+    if let Some(request) = unfinished {
+        println!("Resuming request {} for image {}", request.request_id, image_id);
+        return Ok(request);
+    }
+
+    let request = Request {
+        // TOML integers are signed
+        request_id: OsRng.next_u64() >> 1,
+        image_id: image_id.to_string(),
+        requester: requester.to_string(),
+    };
+    fs::create_dir_all(REQUESTS_DIR)?;
+    let text = toml::to_string(&request).map_err(io::Error::other)?;
+    fs::write(request_path(request.request_id), text)?;
+    Ok(request)
+}
+
+// The request got its answer, or was given up on
+pub fn finish(request: &Request) -> io::Result<()> {
+    match fs::remove_file(request_path(request.request_id)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn unfinished() -> io::Result<Vec<Request>> {
+    let entries = match fs::read_dir(REQUESTS_DIR) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut requests = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        match fs::read_to_string(&path).map(|text| toml::from_str::<Request>(&text)) {
+            Ok(Ok(request)) => requests.push(request),
+            Ok(Err(e)) => eprintln!("Skipping unreadable request {}: {}", path.display(), e),
+            Err(e) => eprintln!("Skipping unreadable request {}: {}", path.display(), e),
+        }
+    }
+    Ok(requests)
+}
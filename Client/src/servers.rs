@@ -0,0 +1,81 @@
+// The servers this client talks to. Starts from `servers` in the config and
+// is refreshed from any server's SERVER_LIST, so servers that joined the
+// cluster later are used too and servers that left are dropped.
+use protocol::{Message, MAX_DATAGRAM};
+use rand::seq::SliceRandom;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout, Duration};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct ServerList {
+    servers: Arc<Mutex<Vec<SocketAddr>>>,
+}
+
+impl ServerList {
+    pub fn new(servers: Vec<SocketAddr>) -> Self {
+        ServerList {
+            servers: Arc::new(Mutex::new(servers)),
+        }
+    }
+
+    pub fn all(&self) -> Vec<SocketAddr> {
+        self.servers.lock().unwrap().clone()
+    }
+
+    // A random server, to spread the clients over the cluster
+    pub fn choose(&self) -> SocketAddr {
+        *self
+            .servers
+            .lock()
+            .unwrap()
+            .choose(&mut rand::thread_rng())
+            .expect("the server list is never empty")
+    }
+
+    // Asks the known servers in turn for the current list
+    pub async fn refresh(&self) -> io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let query = protocol::encode(&Message::ServerListQuery);
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        for server in self.all() {
+            socket.send_to(&query, server).await?;
+            let (size, _) = match timeout(QUERY_TIMEOUT, socket.recv_from(&mut buffer)).await {
+                Ok(received) => received?,
+                Err(_) => continue,
+            };
+            let Ok(Message::ServerList { servers }) = protocol::decode(&buffer[..size]) else {
+                continue;
+            };
+            let servers: Vec<SocketAddr> = servers.iter().filter_map(|addr| addr.parse().ok()).collect();
+            if servers.is_empty() {
+                continue;
+            }
+            let mut known = self.servers.lock().unwrap();
+            if *known != servers {
+                println!("Servers are now {:?}", servers);
+                *known = servers;
+            }
+            return Ok(());
+        }
+        Err(io::Error::new(io::ErrorKind::TimedOut, "No server sent the server list"))
+    }
+
+    // Keeps refreshing the list in the background
+    pub fn spawn_refresh(&self) {
+        let list = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(REFRESH_INTERVAL).await;
+                if let Err(e) = list.refresh().await {
+                    eprintln!("Failed to refresh the server list: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,131 @@
+// In-memory handling of the images exchanged between peers. Everything takes
+// and returns encoded image bytes (PNG out), nothing touches the disk.
+use image::{ImageBuffer, ImageFormat, Rgba, RgbaImage};
+use std::io::{self, Cursor};
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn load(image: &[u8]) -> io::Result<RgbaImage> {
+    image::load_from_memory(image)
+        .map(|image| image.into_rgba8())
+        .map_err(|e| invalid(format!("Failed to read image: {}", e)))
+}
+
+fn to_png(image: &RgbaImage) -> io::Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageFormat::Png)
+        .map_err(|e| io::Error::other(format!("Failed to encode image: {}", e)))?;
+    Ok(png.into_inner())
+}
+
+// Adds an extra row below the image holding `metadata` in the red channel:
+// the first 4 pixels store its length, the following pixels one byte each
+pub fn embed_data_in_image(image: &[u8], metadata: &[u8]) -> io::Result<Vec<u8>> {
+    let original_image = load(image)?;
+    let (width, height) = original_image.dimensions();
+    if (width as usize) < 4 + metadata.len() {
+        return Err(invalid(format!(
+            "Image is too narrow for {} bytes of metadata ({} px)",
+            metadata.len(),
+            width
+        )));
+    }
+
+    // Create a new image with an extra row
+    let mut new_image: RgbaImage = ImageBuffer::new(width, height + 1);
+    for (x, y, pixel) in original_image.enumerate_pixels() {
+        new_image.put_pixel(x, y, *pixel);
+    }
+
+    let length_bytes = (metadata.len() as u32).to_be_bytes();
+    for (x, byte) in length_bytes.iter().chain(metadata).enumerate() {
+        new_image.put_pixel(x as u32, height, Rgba([*byte, 0, 0, 255]));
+    }
+
+    to_png(&new_image)
+}
+
+// Reads back the metadata stored in the last row
+pub fn extract_data_from_image(image: &[u8]) -> io::Result<Vec<u8>> {
+    let image = load(image)?;
+    let (width, height) = image.dimensions();
+    if width < 4 || height < 2 {
+        return Err(invalid("Image has no metadata row".to_string()));
+    }
+    let metadata_row = height - 1;
+    let byte_at = |x: u32| image.get_pixel(x, metadata_row)[0];
+
+    let length = u32::from_be_bytes([byte_at(0), byte_at(1), byte_at(2), byte_at(3)]);
+    if length > width - 4 {
+        return Err(invalid(format!("Metadata length {} does not fit the image", length)));
+    }
+    Ok((4..4 + length).map(byte_at).collect())
+}
+
+// Returns the image without its metadata row
+pub fn strip_metadata_row(image: &[u8]) -> io::Result<Vec<u8>> {
+    let image = load(image)?;
+    let (width, height) = image.dimensions();
+    if height < 2 {
+        return Err(invalid("Image has no metadata row".to_string()));
+    }
+
+    let mut stripped_image: RgbaImage = ImageBuffer::new(width, height - 1);
+    for (x, y, pixel) in image.enumerate_pixels().filter(|(_, y, _)| *y < height - 1) {
+        stripped_image.put_pixel(x, y, *pixel);
+    }
+
+    to_png(&stripped_image)
+}
+
+// Recovers the bytes the server hid in the alpha channel, the same way
+// steganography's `Decoder::decode_alpha` does. Its decoder only accepts
+// buffers of the `image` version it is built on, which can only be loaded
+// from a file, so the alpha channel is read here instead.
+pub fn decode_image(carrier: &[u8]) -> io::Result<Vec<u8>> {
+    let carrier = load(carrier)?;
+    Ok(carrier.pixels().map(|pixel| pixel[3]).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 7, (x + y) as u8]));
+        to_png(&image).unwrap()
+    }
+
+    #[test]
+    fn metadata_comes_back_and_strips_off() {
+        let original = png(16, 3);
+        let with_metadata = embed_data_in_image(&original, b"1_cat,5").unwrap();
+        assert_eq!(extract_data_from_image(&with_metadata).unwrap(), b"1_cat,5");
+        let stripped = strip_metadata_row(&with_metadata).unwrap();
+        assert_eq!(load(&stripped).unwrap(), load(&original).unwrap());
+    }
+
+    #[test]
+    fn metadata_must_fit_the_width() {
+        assert!(embed_data_in_image(&png(8, 2), b"12345").is_err());
+        assert!(embed_data_in_image(&png(8, 2), b"1234").is_ok());
+    }
+
+    #[test]
+    fn a_bad_length_is_refused() {
+        // A length claiming more bytes than the image is wide
+        let mut image = load(&embed_data_in_image(&png(8, 2), b"ab").unwrap()).unwrap();
+        image.put_pixel(3, 2, Rgba([200, 0, 0, 255]));
+        assert!(extract_data_from_image(&to_png(&image).unwrap()).is_err());
+        assert!(extract_data_from_image(&png(8, 1)).is_err());
+        assert!(extract_data_from_image(b"not an image").is_err());
+    }
+
+    #[test]
+    fn hidden_bytes_are_the_alpha_channel() {
+        assert_eq!(decode_image(&png(3, 2)).unwrap(), vec![0, 1, 2, 1, 2, 3]);
+    }
+}
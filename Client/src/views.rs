@@ -0,0 +1,452 @@
+// Signed view grants. The owner signs (image_id, owner, viewer, views, nonce)
+// with its Ed25519 identity key. The viewer checks that signature against
+// the key the owner registered with the servers, looked up when the image or
+// a CONTROL_UPDATE arrives, so neither the metadata embedded in the image nor
+// a CONTROL_UPDATE can raise the views. The servers count the views: the
+// viewer has them use up one under the grant before every view, and they
+// check the grant against the owner's registered key then (see
+// `auth::record_view`).
+// views_count/<id>_views.txt keeps the grant and what the servers answered
+// last, so editing or restoring it gives no views back.
+use crate::crypto;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use chrono::NaiveDateTime;
+use protocol::{Expiry, SignedGrant, ViewGrant};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const IDENTITY_FILE: &str = "keys/identity.key";
+const VIEWS_DIR: &str = "views_count";
+// Owner side, revoked/<image_id>/<viewer> for every viewer an image was taken from
+const REVOKED_DIR: &str = "revoked";
+// Owner side, shares/<image_id>/<viewer>.json with the terms the owner set
+// for a viewer, used when that viewer requests the image
+const SHARES_DIR: &str = "shares";
+// Viewer side, everything kept of a received image
+const RECEIVED_DIR: &str = "received_images";
+const DECRYPTED_DIR: &str = "decrypted_images";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn unix_now() -> io::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_secs())
+}
+
+// The client's signing key, created on first run
+pub fn load_or_create_identity() -> io::Result<SigningKey> {
+    let path = Path::new(IDENTITY_FILE);
+    if path.exists() {
+        let seed: [u8; 32] = fs::read(path)?
+            .try_into()
+            .map_err(|_| invalid(format!("{} has the wrong length", IDENTITY_FILE)))?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+
+    let mut seed = [0u8; 32];
+    OsRng.fill_bytes(&mut seed);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, seed)?;
+    println!("Created a new identity key in {}", IDENTITY_FILE);
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+// Grants `viewer` `views` views on `image_id` until `expiry`, signed by this client
+pub fn sign_grant(owner: &str, image_id: &str, viewer: &str, views: u32, expiry: Expiry) -> io::Result<SignedGrant> {
+    sign(owner, image_id, viewer, views, false, expiry)
+}
+
+// Takes `image_id` away from `viewer`
+pub fn sign_revocation(owner: &str, image_id: &str, viewer: &str) -> io::Result<SignedGrant> {
+    sign(owner, image_id, viewer, 0, true, Expiry::Never)
+}
+
+fn sign(
+    owner: &str,
+    image_id: &str,
+    viewer: &str,
+    views: u32,
+    revoked: bool,
+    expiry: Expiry,
+) -> io::Result<SignedGrant> {
+    let identity = load_or_create_identity()?;
+    // Nanoseconds since the epoch, so every new grant has a larger nonce
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(io::Error::other)?
+        .as_nanos() as u64;
+
+    let grant = ViewGrant {
+        image_id: image_id.to_string(),
+        owner: owner.to_string(),
+        viewer: viewer.to_string(),
+        views,
+        nonce,
+        revoked,
+        expiry,
+    };
+    let signature = identity.sign(&grant.signing_bytes());
+    Ok(SignedGrant {
+        grant,
+        owner_key: identity.verifying_key().to_bytes().to_vec(),
+        signature: signature.to_bytes().to_vec(),
+    })
+}
+
+// Checks that `signed` is a grant on `image_id` for `viewer`, made by the
+// image's owner with `owner_key`, the key it registered with the servers
+pub fn check_grant(signed: &SignedGrant, image_id: &str, viewer: &str, owner_key: &[u8]) -> io::Result<()> {
+    let grant = &signed.grant;
+    if grant.image_id != image_id || grant.viewer != viewer {
+        return Err(invalid(format!(
+            "Grant is for image {} and viewer {}, not {} and {}",
+            grant.image_id, grant.viewer, image_id, viewer
+        )));
+    }
+    // Shared IDs look like <owner>_<image>
+    let image_owner = image_id.split('_').next().unwrap_or(image_id);
+    if grant.owner != image_owner {
+        return Err(invalid(format!("Grant was made by {}, not by owner {}", grant.owner, image_owner)));
+    }
+    if signed.owner_key != owner_key {
+        return Err(invalid(format!("Grant is not signed with the registered key of {}", grant.owner)));
+    }
+
+    let owner_key: [u8; 32] = owner_key
+        .try_into()
+        .map_err(|_| invalid("Grant has an invalid owner key".to_string()))?;
+    let owner_key = VerifyingKey::from_bytes(&owner_key)
+        .map_err(|e| invalid(format!("Grant has an invalid owner key: {}", e)))?;
+    let signature = Signature::from_slice(&signed.signature)
+        .map_err(|e| invalid(format!("Grant has an invalid signature: {}", e)))?;
+    owner_key
+        .verify_strict(&grant.signing_bytes(), &signature)
+        .map_err(|_| invalid("Grant signature does not match its content".to_string()))
+}
+
+// What the owner typed for an expiry: nothing for none, +<minutes> for a
+// time after the first view, or a UTC time as YYYY-MM-DD HH:MM
+pub fn parse_expiry(input: &str) -> Result<Expiry, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(Expiry::Never);
+    }
+    if let Some(minutes) = input.strip_prefix('+') {
+        return match minutes.trim().parse::<u64>() {
+            Ok(minutes) if minutes > 0 => Ok(Expiry::AfterFirstView(minutes * 60)),
+            _ => Err(format!("'{}' is not a number of minutes", minutes)),
+        };
+    }
+    NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M")
+        .map(|at| Expiry::At(at.and_utc().timestamp().max(0) as u64))
+        .map_err(|e| format!("'{}' is not a time as YYYY-MM-DD HH:MM: {}", input, e))
+}
+
+// Contents of views_count/<id>_views.txt
+#[derive(Serialize, Deserialize)]
+pub struct ViewRecord {
+    pub grant: SignedGrant,
+    // As the servers counted them last
+    pub remaining: u32,
+    // When the viewer first opened the image, for grants that expire after it
+    pub first_viewed: Option<u64>,
+}
+
+fn views_path(image_id: &str) -> PathBuf {
+    PathBuf::from(VIEWS_DIR).join(format!("{}_views.txt", image_id))
+}
+
+fn write_record(image_id: &str, grant: SignedGrant, remaining: u32, first_viewed: Option<u64>) -> io::Result<()> {
+    let record = ViewRecord {
+        grant,
+        remaining,
+        first_viewed,
+    };
+    fs::create_dir_all(VIEWS_DIR)?;
+    fs::write(views_path(image_id), serde_json::to_vec(&record)?)
+}
+
+// Starts the views on a new grant, which has not been viewed yet
+pub fn store_views(image_id: &str, grant: SignedGrant, remaining: u32) -> io::Result<()> {
+    write_record(image_id, grant, remaining, None)
+}
+
+// Keeps what the servers answered when they counted a view
+pub fn record_view(image_id: &str, grant: SignedGrant, remaining: u32, first_viewed: u64) -> io::Result<()> {
+    write_record(image_id, grant, remaining, Some(first_viewed))
+}
+
+fn read_record(image_id: &str, viewer: &str) -> io::Result<ViewRecord> {
+    let content = fs::read(views_path(image_id))?;
+    let record: ViewRecord = serde_json::from_slice(&content)
+        .map_err(|e| invalid(format!("Views file of {} is corrupt: {}", image_id, e)))?;
+    // Whether the key is still the owner's is up to the servers at view time
+    check_grant(&record.grant, image_id, viewer, &record.grant.owner_key)?;
+    Ok(record)
+}
+
+// Reads the grant on `image_id` and the views the servers left on it. An
+// image whose grant expired is deleted like a revoked one.
+pub fn load_views(image_id: &str, viewer: &str) -> io::Result<ViewRecord> {
+    let record = read_record(image_id, viewer)?;
+    if let Some(deadline) = record.grant.grant.expiry.deadline(record.first_viewed) {
+        if unix_now()? >= deadline {
+            remove_received(image_id)?;
+            return Err(invalid(format!("Image {} has expired and was deleted", image_id)));
+        }
+    }
+    Ok(record)
+}
+
+// Replaces the views on `image_id` with a new grant from its owner, signed
+// with `owner_key`. Grants older than the current one are refused so they
+// can't be replayed. A revoked grant deletes the image, its key, its views
+// file and the decrypted copy instead.
+pub fn apply_update(image_id: &str, signed: SignedGrant, viewer: &str, owner_key: &[u8]) -> io::Result<u32> {
+    check_grant(&signed, image_id, viewer, owner_key)?;
+    // Even an altered record still holds a genuine grant to compare against
+    if let Ok(current) = read_record(image_id, viewer) {
+        if signed.grant.nonce <= current.grant.grant.nonce {
+            return Err(invalid(format!("Update for {} is older than the current grant", image_id)));
+        }
+    }
+
+    if signed.grant.revoked {
+        remove_received(image_id)?;
+        return Ok(0);
+    }
+    let views = signed.grant.views;
+    store_views(image_id, signed, views)?;
+    Ok(views)
+}
+
+// Deletes everything kept of a received image
+pub fn remove_received(image_id: &str) -> io::Result<()> {
+    let paths = [
+        PathBuf::from(RECEIVED_DIR).join(format!("{}.png", image_id)),
+        PathBuf::from(DECRYPTED_DIR).join(format!("{}.png", image_id)),
+        views_path(image_id),
+        crypto::key_path(image_id),
+    ];
+    for path in paths {
+        match fs::remove_file(&path) {
+            Ok(()) => println!("Deleted {}", path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn revoked_path(image_id: &str, viewer: &str) -> PathBuf {
+    PathBuf::from(REVOKED_DIR).join(image_id).join(viewer)
+}
+
+// Remembers that the owner took `image_id` away from `viewer`, or gave it
+// back with a new grant
+pub fn set_revoked(image_id: &str, viewer: &str, revoked: bool) -> io::Result<()> {
+    let path = revoked_path(image_id, viewer);
+    if revoked {
+        fs::create_dir_all(PathBuf::from(REVOKED_DIR).join(image_id))?;
+        return fs::write(path, b"");
+    }
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn is_revoked(image_id: &str, viewer: &str) -> bool {
+    revoked_path(image_id, viewer).exists()
+}
+
+// Views and expiry the owner gives a viewer with an image
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ShareTerms {
+    pub views: u32,
+    pub expiry: Expiry,
+}
+
+fn share_path(image_id: &str, viewer: &str) -> PathBuf {
+    PathBuf::from(SHARES_DIR).join(image_id).join(format!("{}.json", viewer))
+}
+
+// Remembers the terms the owner set for `viewer` on `image_id`, so the image
+// is sent with them from the start
+pub fn set_share_terms(image_id: &str, viewer: &str, terms: ShareTerms) -> io::Result<()> {
+    fs::create_dir_all(PathBuf::from(SHARES_DIR).join(image_id))?;
+    fs::write(share_path(image_id, viewer), serde_json::to_vec(&terms)?)
+}
+
+// The terms the owner set for `viewer` on `image_id`, or `default`
+pub fn share_terms(image_id: &str, viewer: &str, default: ShareTerms) -> io::Result<ShareTerms> {
+    match fs::read(share_path(image_id, viewer)) {
+        Ok(content) => serde_json::from_slice(&content)
+            .map_err(|e| invalid(format!("Share terms of {} for {} are corrupt: {}", image_id, viewer, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(default),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use std::sync::OnceLock;
+    use tempfile::TempDir;
+
+    // The client keeps its files relative to the working directory, the
+    // tests share one
+    pub(crate) fn in_scratch_dir() {
+        static SCRATCH: OnceLock<TempDir> = OnceLock::new();
+        SCRATCH.get_or_init(|| {
+            let dir = tempfile::tempdir().unwrap();
+            std::env::set_current_dir(dir.path()).unwrap();
+            dir
+        });
+    }
+
+    fn own_key() -> Vec<u8> {
+        load_or_create_identity().unwrap().verifying_key().to_bytes().to_vec()
+    }
+
+    // A received image with its key and `grant` stored
+    fn received(image_id: &str, grant: SignedGrant) {
+        crypto::store_key(image_id, &crypto::generate_key()).unwrap();
+        fs::create_dir_all(RECEIVED_DIR).unwrap();
+        fs::write(PathBuf::from(RECEIVED_DIR).join(format!("{}.png", image_id)), b"png").unwrap();
+        let views = grant.grant.views;
+        store_views(image_id, grant, views).unwrap();
+    }
+
+    #[test]
+    fn grants_must_match_image_viewer_owner_and_key() {
+        in_scratch_dir();
+        let image_id = "1_views_grant";
+        let signed = sign_grant("1", image_id, "2", 3, Expiry::Never).unwrap();
+        assert!(check_grant(&signed, image_id, "2", &own_key()).is_ok());
+        assert!(check_grant(&signed, image_id, "3", &own_key()).is_err());
+        assert!(check_grant(&signed, "1_other", "2", &own_key()).is_err());
+
+        let other_key = SigningKey::from_bytes(&[7; 32]).verifying_key().to_bytes();
+        assert!(check_grant(&signed, image_id, "2", &other_key).is_err());
+
+        // Only the owner in the image ID can grant views on it
+        let not_owner = sign_grant("3", image_id, "2", 3, Expiry::Never).unwrap();
+        assert!(check_grant(&not_owner, image_id, "2", &own_key()).is_err());
+
+        let mut raised = signed.clone();
+        raised.grant.views = 100;
+        assert!(check_grant(&raised, image_id, "2", &own_key()).is_err());
+    }
+
+    #[test]
+    fn views_keep_what_the_servers_counted() {
+        in_scratch_dir();
+        let image_id = "1_views_count";
+        let signed = sign_grant("1", image_id, "2", 3, Expiry::AfterFirstView(60)).unwrap();
+        received(image_id, signed.clone());
+
+        let record = load_views(image_id, "2").unwrap();
+        assert_eq!((record.remaining, record.first_viewed), (3, None));
+        let now = unix_now().unwrap();
+        record_view(image_id, signed.clone(), 2, now).unwrap();
+        let record = load_views(image_id, "2").unwrap();
+        assert_eq!((record.remaining, record.first_viewed), (2, Some(now)));
+
+        // The servers' first view starts the expiry
+        record_view(image_id, signed, 1, now - 60).unwrap();
+        assert!(load_views(image_id, "2").is_err());
+        assert!(!views_path(image_id).exists());
+    }
+
+    #[test]
+    fn updates_must_be_newer_and_revocations_delete() {
+        in_scratch_dir();
+        let image_id = "1_views_update";
+        let first = sign_grant("1", image_id, "2", 3, Expiry::Never).unwrap();
+        received(image_id, first.clone());
+
+        let more = sign_grant("1", image_id, "2", 5, Expiry::Never).unwrap();
+        assert_eq!(apply_update(image_id, more, "2", &own_key()).unwrap(), 5);
+        assert_eq!(load_views(image_id, "2").unwrap().remaining, 5);
+        assert!(apply_update(image_id, first, "2", &own_key()).is_err());
+
+        let revoked = sign_revocation("1", image_id, "2").unwrap();
+        assert_eq!(apply_update(image_id, revoked, "2", &own_key()).unwrap(), 0);
+        assert!(!views_path(image_id).exists());
+        assert!(!crypto::key_path(image_id).exists());
+        assert!(!PathBuf::from(RECEIVED_DIR).join(format!("{}.png", image_id)).exists());
+    }
+
+    #[test]
+    fn expired_images_are_deleted_when_opened() {
+        in_scratch_dir();
+        let image_id = "1_views_expired";
+        received(image_id, sign_grant("1", image_id, "2", 3, Expiry::At(1)).unwrap());
+        assert!(load_views(image_id, "2").is_err());
+        assert!(!views_path(image_id).exists());
+        assert!(!crypto::key_path(image_id).exists());
+    }
+
+    #[test]
+    fn expiries_parse() {
+        assert_eq!(parse_expiry(" "), Ok(Expiry::Never));
+        assert_eq!(parse_expiry("+60"), Ok(Expiry::AfterFirstView(3600)));
+        assert!(parse_expiry("+0").is_err());
+        assert_eq!(parse_expiry("1970-01-02 00:00"), Ok(Expiry::At(86400)));
+        assert!(parse_expiry("tomorrow").is_err());
+    }
+
+    #[test]
+    fn owners_remember_revocations_and_terms() {
+        in_scratch_dir();
+        let image_id = "1_views_owner";
+        assert!(!is_revoked(image_id, "2"));
+        set_revoked(image_id, "2", true).unwrap();
+        assert!(is_revoked(image_id, "2"));
+        set_revoked(image_id, "2", false).unwrap();
+        assert!(!is_revoked(image_id, "2"));
+
+        let default = ShareTerms {
+            views: 5,
+            expiry: Expiry::Never,
+        };
+        assert_eq!(share_terms(image_id, "2", default).unwrap(), default);
+        let once = ShareTerms {
+            views: 1,
+            expiry: Expiry::AfterFirstView(3600),
+        };
+        set_share_terms(image_id, "2", once).unwrap();
+        assert_eq!(share_terms(image_id, "2", default).unwrap(), once);
+        assert_eq!(share_terms(image_id, "3", default).unwrap(), default);
+
+        // Viewers the owner set nothing for get the terms from the config
+        let config = ClientConfig {
+            share_views: 2,
+            share_expiry: "+10".to_string(),
+            ..ClientConfig::default()
+        };
+        let configured = ShareTerms {
+            views: 2,
+            expiry: Expiry::AfterFirstView(600),
+        };
+        assert_eq!(config.share_terms().unwrap(), configured);
+        let invalid = ClientConfig {
+            share_expiry: "soon".to_string(),
+            ..ClientConfig::default()
+        };
+        assert!(invalid.share_terms().is_err());
+    }
+}
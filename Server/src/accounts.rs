@@ -0,0 +1,261 @@
+// Client accounts. Registering gives a client the next free numeric ID and
+// keeps an Argon2 hash of its password, with a random salt, in the
+// replicated tables. Logging in checks the password and opens a session
+// whose token the client sends with its requests. Sessions are replicated
+// too, so every server accepts a token any of them issued. Both wait until
+// the change reached this server's tables, so the client can use its ID and
+// token right away. The account also keeps the client's Ed25519 identity
+// key, replaced whenever the client logs in with another one, which the
+// servers and other clients check its signed messages against.
+use crate::replication::Replicator;
+use crate::storage::{Account, Change, Session, Storage};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use protocol::signing::{self, MessageSignature};
+use protocol::Message;
+use serde::Serialize;
+use rand::RngCore;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::{sleep, Instant};
+
+const SESSION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// How long a registration or login waits for its change to be committed
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+const COMMIT_POLL: Duration = Duration::from_millis(50);
+// Client IDs tried when other servers register clients at the same time
+const REGISTER_ATTEMPTS: usize = 3;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+// Hashing is slow on purpose, so it runs off the async threads
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> io::Result<T> {
+    tokio::task::spawn_blocking(work).await.map_err(io::Error::other)
+}
+
+pub fn hash_password(password: &str) -> io::Result<String> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|e| io::Error::other(e.to_string()))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+pub fn verify_password(password_hash: &str, password: &str) -> bool {
+    PasswordHash::new(password_hash)
+        .is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+fn new_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    URL_SAFE_NO_PAD.encode(token)
+}
+
+// One more than the highest numeric client ID in use
+pub fn next_client_id(storage: &Storage) -> String {
+    let highest = storage
+        .client_ids()
+        .iter()
+        .filter_map(|id| id.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0);
+    (highest + 1).to_string()
+}
+
+// The client `token` belongs to, if its session is open
+pub fn session_client(storage: &Storage, token: &str) -> Option<String> {
+    storage.session_client(token, unix_now())
+}
+
+async fn until_applied(storage: &Storage, applied: impl Fn(&Storage) -> bool) -> io::Result<()> {
+    let deadline = Instant::now() + COMMIT_TIMEOUT;
+    while !applied(storage) {
+        if Instant::now() >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "change was not committed in time"));
+        }
+        sleep(COMMIT_POLL).await;
+    }
+    Ok(())
+}
+
+fn failed(reason: &str) -> Message {
+    Message::AuthFailed {
+        reason: reason.to_string(),
+    }
+}
+
+// Registers a client under a new ID and logs it in
+pub async fn register(
+    storage: &Storage,
+    replicator: &Replicator,
+    password: String,
+    public_key: Vec<u8>,
+) -> io::Result<Message> {
+    if password.is_empty() {
+        return Ok(failed("empty password"));
+    }
+    if !signing::valid_key(&public_key) {
+        return Ok(failed("invalid identity key"));
+    }
+    let password_hash = blocking(move || hash_password(&password)).await??;
+    for _ in 0..REGISTER_ATTEMPTS {
+        let account = Account {
+            client_id: next_client_id(storage),
+            password_hash: password_hash.clone(),
+            public_key: public_key.clone(),
+        };
+        let client_id = account.client_id.clone();
+        replicator.propose(Change::Register(account)).await?;
+        until_applied(storage, |storage| storage.account(&client_id).is_some()).await?;
+        // Another server may have given the same ID to its client first
+        if storage.account(&client_id).is_some_and(|account| account.password_hash == password_hash) {
+            println!("Registered client {}", client_id);
+            return start_session(storage, replicator, client_id).await;
+        }
+    }
+    Ok(failed("no free client ID, try again"))
+}
+
+pub async fn login(
+    storage: &Storage,
+    replicator: &Replicator,
+    client_id: String,
+    password: String,
+    public_key: Vec<u8>,
+) -> io::Result<Message> {
+    if !signing::valid_key(&public_key) {
+        return Ok(failed("invalid identity key"));
+    }
+    let Some(account) = storage.account(&client_id) else {
+        return Ok(failed("unknown client ID or wrong password"));
+    };
+    let password_hash = account.password_hash.clone();
+    if !blocking(move || verify_password(&password_hash, &password)).await? {
+        return Ok(failed("unknown client ID or wrong password"));
+    }
+    if account.public_key != public_key {
+        println!("Client {} logged in with a new identity key", client_id);
+        let change = Change::SetKey {
+            client_id: client_id.clone(),
+            public_key: public_key.clone(),
+        };
+        replicator.propose(change).await?;
+        until_applied(storage, |storage| {
+            storage.account(&client_id).is_some_and(|account| account.public_key == public_key)
+        })
+        .await?;
+    }
+    start_session(storage, replicator, client_id).await
+}
+
+// The identity key `client_id` registered
+pub fn public_key(storage: &Storage, client_id: &str) -> Option<Vec<u8>> {
+    storage.account(client_id).map(|account| account.public_key)
+}
+
+// Checks that `client_id` signed `content` with its registered key
+pub fn check_signature(
+    storage: &Storage,
+    client_id: &str,
+    purpose: &str,
+    content: &impl Serialize,
+    signature: &MessageSignature,
+) -> io::Result<()> {
+    let public_key = public_key(storage, client_id)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("client {} has no account", client_id)))?;
+    signing::verify(&public_key, purpose, content, signature)
+}
+
+async fn start_session(storage: &Storage, replicator: &Replicator, client_id: String) -> io::Result<Message> {
+    let now = unix_now();
+    let session = Session {
+        token: new_token(),
+        client_id: client_id.clone(),
+        expires: now + SESSION_TTL.as_secs(),
+    };
+    let token = session.token.clone();
+    replicator.propose(Change::StartSession { session, now }).await?;
+    until_applied(storage, |storage| session_client(storage, &token).is_some()).await?;
+    Ok(Message::LoggedIn { client_id, token })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::OnlineStatus;
+
+    #[test]
+    fn hashes_are_salted_and_verify() {
+        let first = hash_password("secret").unwrap();
+        let second = hash_password("secret").unwrap();
+        assert_ne!(first, second);
+        assert!(first.starts_with("$argon2id$"));
+        assert!(verify_password(&first, "secret"));
+        assert!(verify_password(&second, "secret"));
+        assert!(!verify_password(&first, "Secret"));
+        assert!(!verify_password("not a hash", "secret"));
+    }
+
+    #[test]
+    fn ids_and_sessions_come_from_the_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::open(dir.path()).unwrap();
+        assert_eq!(next_client_id(&storage), "1");
+
+        // Clients from before accounts keep their IDs
+        let status = OnlineStatus {
+            ip: "127.0.0.1:7001".to_string(),
+            status: true,
+            client_id: "4".to_string(),
+        };
+        storage.apply_replicated(1, Change::SetStatus(status)).unwrap();
+        let account = Account {
+            client_id: "5".to_string(),
+            password_hash: "hash".to_string(),
+            public_key: vec![5; 32],
+        };
+        storage.apply_replicated(2, Change::Register(account.clone())).unwrap();
+        assert_eq!(next_client_id(&storage), "6");
+        // Taken IDs stay with their first owner
+        let taken = Account {
+            password_hash: "other".to_string(),
+            ..account.clone()
+        };
+        storage.apply_replicated(3, Change::Register(taken)).unwrap();
+        assert_eq!(storage.account("5"), Some(account));
+        // Logging in from another machine replaces the key
+        let change = Change::SetKey {
+            client_id: "5".to_string(),
+            public_key: vec![6; 32],
+        };
+        storage.apply_replicated(4, change).unwrap();
+        assert_eq!(public_key(&storage, "5"), Some(vec![6; 32]));
+        assert_eq!(public_key(&storage, "4"), None);
+
+        let session = |token: &str, expires| Session {
+            token: token.to_string(),
+            client_id: "5".to_string(),
+            expires,
+        };
+        let change = Change::StartSession { session: session("old", 100), now: 50 };
+        storage.apply_replicated(5, change).unwrap();
+        assert_eq!(storage.session_client("old", 99), Some("5".to_string()));
+        assert_eq!(storage.session_client("old", 100), None);
+        assert_eq!(storage.session_client("other", 99), None);
+
+        // Expired sessions go when the next one opens
+        let change = Change::StartSession { session: session("new", 300), now: 200 };
+        storage.apply_replicated(6, change).unwrap();
+        assert_eq!(storage.tables().sessions, vec![session("new", 300)]);
+    }
+}
@@ -1,116 +1,914 @@
-use std::io::{self};
-use std::process;
-use std::sync::Arc;
-use std::time::Duration;
-use sysinfo::{ProcessExt, System, SystemExt};
+// Bully election of the server that encrypts images for clients. A server
+// that wants a leader starts a new term and sends ELECTION to the others.
+// Every server that outranks it answers OK and runs for the same term
+// itself; a candidate that gets no OK in time announces itself with
+// COORDINATOR. If two servers claim one term (after lost messages), the
+// higher-ranked claim wins, so every term ends with a single leader.
+//
+// The leader then repeats COORDINATOR as a heartbeat. Each heartbeat gives
+// the followers a lease on the leader, and only a follower whose lease ran
+// out starts a new election. A leader that is still alive when someone
+// else starts one keeps its place and announces itself in a newer term.
+// The leader holds a lease of its own: it only counts as leader while a
+// majority of the servers, itself included, answered a heartbeat within
+// one, and steps down once they haven't for a whole lease. A leader cut
+// off from the others stops answering as one while they elect another.
+//
+// Followers answer every heartbeat with their load score, so the leader
+// also knows which servers are alive and how busy they are. The failure
+// detector can cut the lease short: a follower starts an election as soon as
+// its leader is considered dead. A server back from a failure forgets the
+// leader it knew and bids with the worst score until it runs again, so it
+// doesn't take the lead while it is still catching up.
+use crate::lifecycle::State;
+use crate::load::LoadMetric;
+use crate::membership::Liveness;
+use protocol::{faults, Message, MAX_DATAGRAM};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tokio::time::{timeout, Instant};
-
-pub async fn server_election(socket: &Arc<Mutex<UdpSocket>>, peers: Vec<&str>) -> io::Result<bool> {
-    let cpu_usage = match get_cpu_usage() {
-        Some(usage) => usage,
-        None => {
-            eprintln!("Failed to retrieve CPU usage.");
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Failed to retrieve CPU usage.",
-            ));
-        }
-    };
-
-    let server_id = process::id();
-    println!("CPU Usage: {:.2}%", cpu_usage);
-    println!("Server Process ID: {}", server_id);
-
-    // Convert CPU usage and server ID to bytes
-    let cpu_bytes = cpu_usage.to_be_bytes();
-    let id_bytes = server_id.to_be_bytes();
-    // Concatenate CPU usage and server ID into a single message
-    let message = [&cpu_bytes[..], &id_bytes[..]].concat();
-
-    // Broadcast CPU usage and server ID to all peers
-    for peer_address in &peers {
-        socket.lock().await.send_to(&message, peer_address).await?;
-    }
-
-    // Collect responses from peers
-    let mut received_responses = Vec::new();
-    let mut buffer = [0u8; 32];
-    let expected_responses = peers.len();
-    let timeout_duration = Duration::from_secs(5);
-    let start_time = Instant::now();
-
-    while received_responses.len() < expected_responses && start_time.elapsed() < timeout_duration {
-        // Calculate the remaining time for the timeout
-        let remaining_time = timeout_duration
-            .checked_sub(start_time.elapsed())
-            .unwrap_or_default();
-        let result = timeout(remaining_time, socket.lock().await.recv_from(&mut buffer)).await;
-
-        match result {
-            Ok(Ok((size, _))) => {
-                let message = &buffer[..size];
-                if message.len() >= 8 {
-                    let peer_cpu_usage = f32::from_be_bytes(message[0..4].try_into().unwrap());
-                    let peer_id = u32::from_be_bytes(message[4..8].try_into().unwrap());
-                    received_responses.push((peer_cpu_usage, peer_id));
+use tokio::sync::Notify;
+
+// How often the load score carried in election messages is sampled
+const LOAD_INTERVAL: Duration = Duration::from_secs(1);
+// Score of a server that is recovering, it ranks below every running one
+const STANDBY_SCORE: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct ElectionConfig {
+    // How long a candidate waits for an OK from a server that outranks it
+    pub answer_timeout: Duration,
+    // How long a server that got an OK waits for the COORDINATOR
+    pub coordinator_timeout: Duration,
+    // How often the leader repeats COORDINATOR
+    pub heartbeat: Duration,
+    // How long a follower trusts the leader after a heartbeat
+    pub lease: Duration,
+}
+
+impl Default for ElectionConfig {
+    fn default() -> Self {
+        ElectionConfig {
+            answer_timeout: Duration::from_millis(500),
+            coordinator_timeout: Duration::from_secs(2),
+            heartbeat: Duration::from_millis(500),
+            lease: Duration::from_secs(2),
+        }
+    }
+}
+
+// Position of a server in one term: lower load score first, then higher ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rank {
+    pub score: u32,
+    pub id: u32,
+}
+
+impl Ord for Rank {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.score.cmp(&self.score).then(self.id.cmp(&other.id))
+    }
+}
+
+impl PartialOrd for Rank {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leader {
+    pub term: u64,
+    pub id: u32,
+    // Upload address handed to clients in LEADER_ACK
+    pub addr: String,
+}
+
+// A server the leader can hand encryption jobs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Load {
+    pub id: u32,
+    pub score: u32,
+    // Where the server takes encryption jobs
+    pub job_addr: String,
+}
+
+enum Phase {
+    // Follows the known leader, or waits to hear from one, until the lease
+    // runs out at `until`
+    Following { until: Instant },
+    // Sent ELECTION, becomes leader unless someone answers before `until`
+    Electing { until: Instant },
+    // A server that outranks this one is running, starts over if it has not
+    // announced itself by `until`
+    Waiting { until: Instant },
+    // Leads the term since `since`, the next heartbeat is due at `until`
+    Leading { since: Instant, until: Instant },
+}
+
+type Outgoing = Vec<(SocketAddr, Message)>;
+
+// The election state machine of one server. Like the replication `Node` it
+// never touches the network: every call returns the messages to send.
+pub struct Election {
+    id: u32,
+    addr: String,
+    job_addr: String,
+    peers: Vec<SocketAddr>,
+    config: ElectionConfig,
+    term: u64,
+    // This server's rank in the current term, fixed when it joins the term
+    rank: Rank,
+    // Latest load score, used for the next term joined
+    score: u32,
+    phase: Phase,
+    leader: Option<Leader>,
+    leader_rank: Option<Rank>,
+    // Load reported by each follower while this server leads, with the time
+    // it arrived
+    reports: HashMap<u32, (Load, Instant)>,
+    // What the failure detector says about each server
+    liveness: HashMap<u32, Liveness>,
+}
+
+impl Election {
+    // A new server listens for a heartbeat for one lease before it starts an
+    // election itself
+    pub fn new(
+        id: u32,
+        addr: String,
+        job_addr: String,
+        peers: Vec<SocketAddr>,
+        config: ElectionConfig,
+        now: Instant,
+    ) -> Self {
+        let until = now + config.lease;
+        Election {
+            id,
+            addr,
+            job_addr,
+            peers,
+            config,
+            term: 0,
+            rank: Rank { score: 0, id },
+            score: 0,
+            phase: Phase::Following { until },
+            leader: None,
+            leader_rank: None,
+            reports: HashMap::new(),
+            liveness: HashMap::new(),
+        }
+    }
+
+    pub fn set_score(&mut self, score: u32) {
+        self.score = score;
+    }
+
+    // Takes the failure detector's view. A follower whose leader is dead
+    // starts an election on the next tick instead of waiting out the lease.
+    pub fn set_liveness(&mut self, liveness: HashMap<u32, Liveness>, now: Instant) {
+        self.liveness = liveness;
+        if let (Phase::Following { until }, Some(leader)) = (&self.phase, &self.leader) {
+            if *until > now && self.liveness.get(&leader.id) == Some(&Liveness::Dead) {
+                println!("Election: leader {} is dead", leader.id);
+                self.phase = Phase::Following { until: now };
+            }
+        }
+    }
+
+    // Election sockets of the other servers, after one joined or left
+    pub fn set_peers(&mut self, peers: Vec<SocketAddr>) {
+        self.peers = peers;
+    }
+
+    // Back from a failure: what this server knew is stale, so it listens for
+    // a heartbeat for one lease like a new server
+    pub fn restart(&mut self, now: Instant) {
+        self.leader = None;
+        self.leader_rank = None;
+        self.reports.clear();
+        self.follow(now);
+    }
+
+    fn is_alive(&self, id: u32) -> bool {
+        self.liveness.get(&id).is_none_or(|liveness| *liveness == Liveness::Alive)
+    }
+
+    // The leader, as long as its lease holds
+    pub fn leader(&self, now: Instant) -> Option<Leader> {
+        match self.phase {
+            Phase::Leading { .. } if self.holds_lease(now) => self.leader.clone(),
+            Phase::Following { until } if until > now => self.leader.clone(),
+            _ => None,
+        }
+    }
+
+    // While leading: this server and every follower heard from within a
+    // lease and not suspected by the failure detector, least loaded first.
+    // Empty on followers.
+    pub fn loads(&self, now: Instant) -> Vec<Load> {
+        if !matches!(self.phase, Phase::Leading { .. }) {
+            return Vec::new();
+        }
+        let mut loads = vec![Load {
+            id: self.id,
+            score: self.score,
+            job_addr: self.job_addr.clone(),
+        }];
+        loads.extend(
+            self.reports
+                .values()
+                .filter(|(load, at)| now.duration_since(*at) < self.config.lease && self.is_alive(load.id))
+                .map(|(load, _)| load.clone()),
+        );
+        loads.sort_by_key(|load| (load.score, load.id != self.id));
+        loads
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        match self.phase {
+            Phase::Following { until }
+            | Phase::Electing { until }
+            | Phase::Waiting { until }
+            | Phase::Leading { until, .. } => until,
+        }
+    }
+
+    // Whether a majority of the servers, this one included, answered the
+    // leader's heartbeats within a lease
+    fn holds_lease(&self, now: Instant) -> bool {
+        let heard = self
+            .reports
+            .values()
+            .filter(|(_, at)| now.duration_since(*at) < self.config.lease)
+            .count();
+        (heard + 1) * 2 > self.peers.len() + 1
+    }
+
+    fn enter_term(&mut self, term: u64) {
+        self.term = term;
+        self.rank = Rank { score: self.score, id: self.id };
+        self.leader = None;
+        self.leader_rank = None;
+    }
+
+    fn broadcast(&self, message: Message) -> Outgoing {
+        self.peers.iter().map(|peer| (*peer, message.clone())).collect()
+    }
+
+    // COORDINATOR of the leader of the current term, if there is one
+    fn announcement(&self) -> Option<Message> {
+        let (leader, rank) = (self.leader.as_ref()?, self.leader_rank?);
+        Some(Message::Coordinator {
+            term: leader.term,
+            id: leader.id,
+            score: rank.score,
+            leader: leader.addr.clone(),
+        })
+    }
+
+    fn ok(&self) -> Message {
+        Message::ElectionOk { term: self.term, id: self.id }
+    }
+
+    // Starts an election in a new term
+    pub fn start(&mut self, now: Instant) -> Outgoing {
+        self.enter_term(self.term + 1);
+        println!("Election: starting term {} with rank {:?}", self.term, self.rank);
+        self.run_for_leader(now)
+    }
+
+    fn run_for_leader(&mut self, now: Instant) -> Outgoing {
+        if self.peers.is_empty() {
+            return self.become_leader(now);
+        }
+        self.phase = Phase::Electing {
+            until: now + self.config.answer_timeout,
+        };
+        self.broadcast(Message::Election {
+            term: self.term,
+            id: self.id,
+            score: self.rank.score,
+        })
+    }
+
+    fn become_leader(&mut self, now: Instant) -> Outgoing {
+        println!("Election: leading term {}", self.term);
+        self.leader = Some(Leader {
+            term: self.term,
+            id: self.id,
+            addr: self.addr.clone(),
+        });
+        self.leader_rank = Some(self.rank);
+        self.reports.clear();
+        self.phase = Phase::Leading {
+            since: now,
+            until: now + self.config.heartbeat,
+        };
+        self.broadcast(self.announcement().unwrap())
+    }
+
+    fn follow(&mut self, now: Instant) {
+        self.phase = Phase::Following {
+            until: now + self.config.lease,
+        };
+    }
+
+    pub fn tick(&mut self, now: Instant) -> Outgoing {
+        if self.next_deadline() > now {
+            return Vec::new();
+        }
+        match self.phase {
+            Phase::Following { .. } => {
+                match &self.leader {
+                    Some(leader) => println!("Election: lost the heartbeat of server {}", leader.id),
+                    None => println!("Election: no leader known"),
                 }
+                self.start(now)
             }
-            Ok(Err(e)) => {
-                eprintln!("Error receiving message from peer: {:?}", e);
+            Phase::Electing { .. } => self.become_leader(now),
+            Phase::Waiting { .. } => {
+                println!("Election: no coordinator for term {}, starting over", self.term);
+                self.start(now)
             }
-            Err(_) => {
-                // If the timeout is reached, we simply break out of the loop
-                eprintln!("Timeout reached, no more messages received.");
-                break;
+            Phase::Leading { since, .. } => {
+                // The followers had a whole lease to answer
+                if now.duration_since(since) >= self.config.lease && !self.holds_lease(now) {
+                    println!("Election: no majority answered within the lease, stepping down from term {}", self.term);
+                    self.leader = None;
+                    self.leader_rank = None;
+                    self.follow(now);
+                    return Vec::new();
+                }
+                self.phase = Phase::Leading {
+                    since,
+                    until: now + self.config.heartbeat,
+                };
+                self.broadcast(self.announcement().unwrap())
             }
         }
     }
-    // Check if any responses were received, if not, assume leadership
-   
 
-    // Add own server's data to the list
-    received_responses.push((cpu_usage, server_id as u32));
+    pub fn step(&mut self, from: SocketAddr, message: Message, now: Instant) -> Outgoing {
+        // Only configured servers and those that joined take part
+        if !self.peers.contains(&from) {
+            eprintln!("Election: ignoring message from {}, which is not a peer", from);
+            return Vec::new();
+        }
+        match message {
+            Message::Election { term, id, score } => self.on_election(from, term, Rank { score, id }, now),
+            Message::ElectionOk { term, .. } => {
+                self.on_ok(term, now);
+                Vec::new()
+            }
+            Message::Coordinator { term, id, score, leader } => {
+                self.on_coordinator(from, term, Rank { score, id }, leader, now)
+            }
+            Message::LoadReport {
+                term,
+                id,
+                score,
+                job_addr,
+            } => {
+                if term == self.term && matches!(self.phase, Phase::Leading { .. }) {
+                    self.reports.insert(id, (Load { id, score, job_addr }, now));
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
 
-    // Determine the leader by sorting
-    received_responses.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then_with(|| b.1.cmp(&a.1)));
+    fn wait_for_coordinator(&mut self, now: Instant) {
+        self.phase = Phase::Waiting {
+            until: now + self.config.coordinator_timeout,
+        };
+    }
 
-    let leader = received_responses[0].1 == server_id as u32;
-    if leader {
-        println!(
-            "This server (Process ID: {}) is elected as the leader.",
-            server_id
-        );
-    } else {
-        println!("Server (Process ID: {}) is not the leader.", server_id);
+    fn on_election(&mut self, from: SocketAddr, term: u64, sender: Rank, now: Instant) -> Outgoing {
+        if term < self.term {
+            // Bring the sender up to date: the leader of this term, or this
+            // server's own bid so the sender can run in this term
+            let reply = self.announcement().unwrap_or(Message::Election {
+                term: self.term,
+                id: self.id,
+                score: self.rank.score,
+            });
+            return vec![(from, reply)];
+        }
+        if term > self.term {
+            if matches!(self.phase, Phase::Leading { .. }) {
+                // Still alive: keep leading, in a term after the sender's in
+                // case it already announced itself in that one
+                self.enter_term(term + 1);
+                return self.become_leader(now);
+            }
+            self.enter_term(term);
+            self.phase = Phase::Following { until: now };
+        }
+        if let Some(announcement) = self.announcement() {
+            return vec![(from, announcement)];
+        }
+
+        if sender > self.rank {
+            // Leave the term to the sender
+            self.wait_for_coordinator(now);
+            return Vec::new();
+        }
+        let mut outgoing = vec![(from, self.ok())];
+        if !matches!(self.phase, Phase::Electing { .. } | Phase::Waiting { .. }) {
+            outgoing.extend(self.run_for_leader(now));
+        }
+        outgoing
     }
 
+    fn on_ok(&mut self, term: u64, now: Instant) {
+        if term == self.term && matches!(self.phase, Phase::Electing { .. }) {
+            self.wait_for_coordinator(now);
+        }
+    }
 
-    if received_responses.is_empty() {
-        println!(
-            "No responses received. This server (Process ID: {}) assumes leadership.",
-            server_id
-        );
-        return Ok(true);
+    fn on_coordinator(&mut self, from: SocketAddr, term: u64, sender: Rank, addr: String, now: Instant) -> Outgoing {
+        if term > self.term {
+            self.enter_term(term);
+        }
+
+        // A leader of an older term, or a second claim for this one that
+        // ranks below the first: tell the sender who leads
+        let outranked = self.leader_rank.is_some_and(|current| current > sender);
+        if term < self.term || outranked {
+            return self.announcement().map(|announcement| vec![(from, announcement)]).unwrap_or_default();
+        }
+
+        // A leader that is alive is followed even if this server outranks it
+        // by now, so the leader only changes when its heartbeat is lost
+        if self.leader.as_ref().map(|leader| leader.id) != Some(sender.id) {
+            println!("Election: server {} leads term {}", sender.id, term);
+        }
+        self.leader = Some(Leader { term, id: sender.id, addr });
+        self.leader_rank = Some(sender);
+        self.follow(now);
+        vec![(
+            from,
+            Message::LoadReport {
+                term,
+                id: self.id,
+                score: self.score,
+                job_addr: self.job_addr.clone(),
+            },
+        )]
     }
-    if leader {
-        for peer_address in &peers {
-            socket
-                .lock()
-                .await
-                .send_to(b"Leader Confirmed", peer_address)
-                .await?;
+}
+
+// Runs an `Election` on its own UDP socket
+pub struct Elector {
+    node: Mutex<Election>,
+    socket: UdpSocket,
+    metric: Mutex<Box<dyn LoadMetric>>,
+    state: Mutex<State>,
+    // Wakes `run` when a deadline may have moved
+    wake: Notify,
+}
+
+impl Elector {
+    pub fn new(
+        socket: UdpSocket,
+        id: u32,
+        addr: String,
+        job_addr: String,
+        peers: Vec<SocketAddr>,
+        metric: Box<dyn LoadMetric>,
+        config: ElectionConfig,
+    ) -> Self {
+        println!("Election: bidding with the {} load metric", metric.name());
+        Elector {
+            node: Mutex::new(Election::new(id, addr, job_addr, peers, config, Instant::now())),
+            socket,
+            metric: Mutex::new(metric),
+            state: Mutex::new(State::Running),
+            wake: Notify::new(),
+        }
+    }
+
+    // The current leader, `None` while an election runs
+    pub fn leader(&self) -> Option<Leader> {
+        self.node.lock().unwrap().leader(Instant::now())
+    }
+
+    pub fn set_peers(&self, peers: Vec<SocketAddr>) {
+        self.node.lock().unwrap().set_peers(peers);
+    }
+
+    pub fn set_liveness(&self, liveness: HashMap<u32, Liveness>) {
+        self.node.lock().unwrap().set_liveness(liveness, Instant::now());
+        self.wake.notify_one();
+    }
+
+    // Stops while the server is failed, and bids with the standby score
+    // while it recovers
+    pub fn set_state(&self, state: State) {
+        let previous = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        let mut node = self.node.lock().unwrap();
+        if previous == State::Failed && state != State::Failed {
+            node.restart(Instant::now());
+        }
+        if state == State::Recovering {
+            node.set_score(STANDBY_SCORE);
+        }
+        drop(node);
+        self.wake.notify_one();
+    }
+
+    fn state(&self) -> State {
+        *self.state.lock().unwrap()
+    }
+
+    // Servers to hand encryption jobs to, see `Election::loads`
+    pub fn loads(&self) -> Vec<Load> {
+        self.node.lock().unwrap().loads(Instant::now())
+    }
+
+    async fn send_all(&self, outgoing: Outgoing) {
+        for (peer, message) in outgoing {
+            if let Err(e) = faults::send_to(&self.socket, &protocol::encode(&message), peer).await {
+                eprintln!("Election: failed to send to {}: {}", peer, e);
+            }
         }
     }
 
-    Ok(leader)
+    // Only returns if the socket fails
+    pub async fn run(&self) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        let mut sample = tokio::time::interval(LOAD_INTERVAL);
+        loop {
+            let deadline = self.node.lock().unwrap().next_deadline();
+            let failed = self.state() == State::Failed;
+            let outgoing = tokio::select! {
+                received = faults::recv_from(&self.socket, &mut buffer) => {
+                    let (size, from) = received?;
+                    if self.state() == State::Failed {
+                        continue;
+                    }
+                    match protocol::decode(&buffer[..size]) {
+                        Ok(message) => self.node.lock().unwrap().step(from, message, Instant::now()),
+                        Err(e) => {
+                            eprintln!("Election: bad message from {}: {}", from, e);
+                            continue;
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.into()), if !failed => {
+                    self.node.lock().unwrap().tick(Instant::now())
+                }
+                _ = sample.tick() => {
+                    let score = match self.state() {
+                        State::Running => self.metric.lock().unwrap().score(),
+                        _ => STANDBY_SCORE,
+                    };
+                    self.node.lock().unwrap().set_score(score);
+                    continue;
+                }
+                _ = self.wake.notified() => continue,
+            };
+            self.send_all(outgoing).await;
+        }
+    }
 }
 
-fn get_cpu_usage() -> Option<f32> {
-    let mut sys = System::new_all();
-    sys.refresh_all();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    fn config() -> ElectionConfig {
+        ElectionConfig {
+            answer_timeout: Duration::from_millis(50),
+            coordinator_timeout: Duration::from_millis(200),
+            heartbeat: Duration::from_millis(50),
+            lease: Duration::from_millis(200),
+        }
+    }
+
+    fn addr(i: usize) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 9100 + i as u16))
+    }
+
+    // Servers exchanging messages over a simulated network that delays,
+    // reorders and drops them, driven by a fake clock
+    struct Cluster {
+        nodes: Vec<Election>,
+        // Servers that neither send nor receive
+        down: Vec<bool>,
+        // A server that runs, but whose messages to and from the others are lost
+        isolated: Option<usize>,
+        in_flight: BTreeMap<(Instant, u64), (usize, SocketAddr, Message)>,
+        sent: u64,
+        now: Instant,
+        rng: StdRng,
+        drop_rate: f64,
+    }
+
+    impl Cluster {
+        // One server per (id, score)
+        fn new(servers: &[(u32, u32)], drop_rate: f64, seed: u64) -> Self {
+            let now = Instant::now();
+            let nodes = (0..servers.len())
+                .map(|i| {
+                    let peers = (0..servers.len()).filter(|j| *j != i).map(addr).collect();
+                    let job_addr = format!("job{}", i);
+                    let mut node = Election::new(servers[i].0, addr(i).to_string(), job_addr, peers, config(), now);
+                    node.set_score(servers[i].1);
+                    node
+                })
+                .collect();
+            Cluster {
+                nodes,
+                down: vec![false; servers.len()],
+                isolated: None,
+                in_flight: BTreeMap::new(),
+                sent: 0,
+                now,
+                rng: StdRng::seed_from_u64(seed),
+                drop_rate,
+            }
+        }
+
+        fn send(&mut self, from: usize, outgoing: Outgoing) {
+            for (to, message) in outgoing {
+                let to = (to.port() - 9100) as usize;
+                let cut_off = self.isolated.is_some_and(|isolated| isolated == from || isolated == to);
+                if self.down[from] || self.down[to] || cut_off || self.rng.gen_bool(self.drop_rate) {
+                    continue;
+                }
+                let at = self.now + Duration::from_millis(self.rng.gen_range(1..30));
+                self.sent += 1;
+                self.in_flight.insert((at, self.sent), (to, addr(from), message));
+            }
+        }
+
+        fn start(&mut self, i: usize) {
+            let outgoing = self.nodes[i].start(self.now);
+            self.send(i, outgoing);
+        }
+
+        // Delivers messages and fires timers for `duration` of simulated time
+        fn run(&mut self, duration: Duration) {
+            let end = self.now + duration;
+            loop {
+                let next_timer = (0..self.nodes.len())
+                    .filter(|i| !self.down[*i])
+                    .map(|i| (self.nodes[i].next_deadline(), i))
+                    .min()
+                    .unwrap();
+                let next_message = self.in_flight.keys().next().copied();
+                match next_message {
+                    Some(key) if key.0 <= next_timer.0 && key.0 <= end => {
+                        let (to, from, message) = self.in_flight.remove(&key).unwrap();
+                        self.now = self.now.max(key.0);
+                        if !self.down[to] {
+                            let outgoing = self.nodes[to].step(from, message, self.now);
+                            self.send(to, outgoing);
+                        }
+                    }
+                    _ if next_timer.0 <= end => {
+                        self.now = self.now.max(next_timer.0);
+                        let outgoing = self.nodes[next_timer.1].tick(self.now);
+                        self.send(next_timer.1, outgoing);
+                    }
+                    _ => break,
+                }
+            }
+            self.now = end;
+        }
 
-    let pid = sysinfo::get_current_pid().ok()?;
-    sys.process(pid).map(|process| process.cpu_usage())
+        fn leading(&self) -> Vec<&Election> {
+            (0..self.nodes.len())
+                .filter(|i| !self.down[*i] && matches!(self.nodes[*i].phase, Phase::Leading { .. }))
+                .map(|i| &self.nodes[i])
+                .collect()
+        }
+
+        // The leader every running server agrees on
+        fn agreed_leader(&self) -> Leader {
+            let running: Vec<&Election> = (0..self.nodes.len())
+                .filter(|i| !self.down[*i])
+                .map(|i| &self.nodes[i])
+                .collect();
+            let leader = running[0].leader(self.now).expect("no leader");
+            for node in &running {
+                assert_eq!(node.leader(self.now), Some(leader.clone()), "servers disagree on the leader");
+                assert_eq!(node.term, leader.term);
+            }
+            assert_eq!(self.leading().len(), 1);
+            leader
+        }
+    }
+
+    #[test]
+    fn lowest_score_then_highest_id_wins() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 3), (3, 3)], 0.0, 1);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 1);
+
+        let mut cluster = Cluster::new(&[(1, 2), (2, 2), (3, 2)], 0.0, 1);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+    }
+
+    #[test]
+    fn concurrent_elections_agree_on_one_leader() {
+        for seed in 0..50 {
+            let mut cluster = Cluster::new(&[(1, 1), (2, 1), (3, 1), (4, 1)], 0.0, seed);
+            // Servers that already went through different numbers of terms
+            for i in 0..4 {
+                for _ in 0..(seed as usize + i) % 3 {
+                    let node = &mut cluster.nodes[i];
+                    node.enter_term(node.term + 1);
+                }
+            }
+            for i in 0..4 {
+                cluster.start(i);
+            }
+            cluster.run(Duration::from_secs(1));
+            assert_eq!(cluster.agreed_leader().id, 4, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn elections_survive_message_loss() {
+        for seed in 0..50 {
+            let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.3, seed);
+            cluster.start(seed as usize % 3);
+            cluster.start((seed as usize + 1) % 3);
+            cluster.run(Duration::from_secs(3));
+
+            // Once messages get through again everyone settles on one leader
+            cluster.drop_rate = 0.0;
+            cluster.run(Duration::from_secs(2));
+            cluster.agreed_leader();
+        }
+    }
+
+    #[test]
+    fn heartbeats_keep_the_leader() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 3);
+        cluster.run(Duration::from_secs(1));
+        let leader = cluster.agreed_leader();
+        cluster.run(Duration::from_secs(10));
+        assert_eq!(cluster.agreed_leader(), leader);
+
+        // An election started anyway leaves the leader in place
+        cluster.start(0);
+        cluster.run(Duration::from_secs(1));
+        let after = cluster.agreed_leader();
+        assert_eq!(after.id, leader.id);
+        assert!(after.term > leader.term);
+
+        // So does a server that would outrank it now
+        cluster.nodes[1].set_score(0);
+        cluster.nodes[2].set_score(5);
+        cluster.start(1);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, leader.id);
+    }
+
+    #[test]
+    fn next_server_takes_over_when_the_heartbeat_stops() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 7);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+
+        cluster.down[2] = true;
+        cluster.run(Duration::from_millis(100));
+        // Followers still trust the leader within the lease
+        assert_eq!(cluster.nodes[0].leader(cluster.now).map(|leader| leader.id), Some(3));
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 2);
+    }
+
+    #[test]
+    fn dead_leader_is_replaced_before_the_lease_runs_out() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 11);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+
+        cluster.down[2] = true;
+        cluster.run(Duration::from_millis(10));
+        let dead = HashMap::from([(3, Liveness::Dead)]);
+        for i in 0..2 {
+            cluster.nodes[i].set_liveness(dead.clone(), cluster.now);
+        }
+        // Well within the lease
+        cluster.run(Duration::from_millis(100));
+        assert_eq!(cluster.agreed_leader().id, 2);
+    }
+
+    #[test]
+    fn recovered_leader_follows_its_replacement() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 13);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+
+        cluster.down[2] = true;
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 2);
+
+        // Back from the failure with the standby score while it catches up
+        cluster.down[2] = false;
+        let now = cluster.now;
+        cluster.nodes[2].restart(now);
+        cluster.nodes[2].set_score(STANDBY_SCORE);
+        assert_eq!(cluster.nodes[2].leader(now), None);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 2);
+
+        // Running again, it doesn't take the lead back
+        cluster.nodes[2].set_score(0);
+        cluster.run(Duration::from_secs(2));
+        assert_eq!(cluster.agreed_leader().id, 2);
+    }
+
+    #[test]
+    fn leader_collects_the_load_of_live_servers() {
+        let mut cluster = Cluster::new(&[(1, 4), (2, 0), (3, 0)], 0.0, 5);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+        assert!(cluster.nodes[0].loads(cluster.now).is_empty());
+
+        // Scores reported after the election reach the leader with the next
+        // heartbeat
+        cluster.nodes[1].set_score(2);
+        cluster.nodes[2].set_score(3);
+        cluster.run(Duration::from_secs(1));
+        let order: Vec<(u32, u32)> = cluster.nodes[2]
+            .loads(cluster.now)
+            .iter()
+            .map(|load| (load.id, load.score))
+            .collect();
+        assert_eq!(order, vec![(2, 2), (3, 3), (1, 4)]);
+        assert_eq!(cluster.nodes[2].loads(cluster.now)[0].job_addr, "job1");
+
+        // A server that stops answering drops out after a lease
+        cluster.down[1] = true;
+        cluster.run(Duration::from_secs(1));
+        let ids: Vec<u32> = cluster.nodes[2].loads(cluster.now).iter().map(|load| load.id).collect();
+        assert_eq!(ids, vec![3, 1]);
+
+        // So does one the failure detector suspects
+        cluster.nodes[2].set_liveness(HashMap::from([(1, Liveness::Suspect)]), cluster.now);
+        let ids: Vec<u32> = cluster.nodes[2].loads(cluster.now).iter().map(|load| load.id).collect();
+        assert_eq!(ids, vec![3]);
+    }
+
+    #[test]
+    fn strangers_cannot_take_the_lead() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 19);
+        cluster.run(Duration::from_secs(1));
+        let leader = cluster.agreed_leader();
+
+        let stranger = addr(7);
+        let term = leader.term + 1;
+        let now = cluster.now;
+        let node = &mut cluster.nodes[0];
+        assert!(node.step(stranger, Message::Election { term, id: 99, score: 0 }, now).is_empty());
+        let coordinator = Message::Coordinator { term, id: 99, score: 0, leader: stranger.to_string() };
+        assert!(node.step(stranger, coordinator.clone(), now).is_empty());
+        assert_eq!(node.leader(now), Some(leader));
+
+        // Once it joined, it is one of the servers
+        node.set_peers(vec![addr(1), addr(2), stranger]);
+        node.step(stranger, coordinator, now);
+        assert_eq!(node.leader(now).map(|leader| leader.id), Some(99));
+    }
+
+    #[test]
+    fn cut_off_leader_steps_down() {
+        let mut cluster = Cluster::new(&[(1, 0), (2, 0), (3, 0)], 0.0, 17);
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.agreed_leader().id, 3);
+
+        // The old leader stops answering as one once its lease is up, while
+        // the others elect a new one
+        cluster.isolated = Some(2);
+        cluster.run(Duration::from_millis(100));
+        assert_eq!(cluster.nodes[2].leader(cluster.now).map(|leader| leader.id), Some(3));
+        cluster.run(Duration::from_secs(1));
+        assert_eq!(cluster.nodes[0].leader(cluster.now).map(|leader| leader.id), Some(2));
+        assert_eq!(cluster.nodes[1].leader(cluster.now).map(|leader| leader.id), Some(2));
+        for _ in 0..20 {
+            cluster.run(Duration::from_millis(50));
+            assert_eq!(cluster.nodes[2].leader(cluster.now), None);
+        }
+
+        // Back in touch, it follows whoever leads
+        cluster.isolated = None;
+        cluster.run(Duration::from_secs(2));
+        cluster.agreed_leader();
+    }
 }
@@ -0,0 +1,434 @@
+// Servers joining and leaving a running cluster. A new server sends JOIN to
+// any member, which adds it to the replicated server list and answers with a
+// snapshot of its tables and sample files. A server that shuts down sends
+// LEAVE to the others, which take it off the list. Every server follows the
+// list for its replication, election and sample peers, on top of the peers
+// in its config file, so no other server has to restart. JOIN and LEAVE
+// carry a proof made with the cluster key every server is configured with,
+// and the snapshot, which holds password hashes and session tokens, is
+// sealed with it.
+use crate::bully_election::Elector;
+use crate::membership::Gossiper;
+use crate::replication::Replicator;
+use crate::storage::{Change, SampleEntry, Storage, Tables};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use protocol::signing::{MessageSignature, MAX_AGE};
+use protocol::transfer::{self, TransferConfig};
+use protocol::{faults, MemberAddrs, Message, ServerInfo};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+// How long a joining server waits for the snapshot before asking again
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+const JOIN_ATTEMPTS: usize = 3;
+// How long a leaving server waits to be taken off the list
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(5);
+const MEMBERS_POLL: Duration = Duration::from_millis(500);
+
+// What each proof is for, so one can't be passed off as another
+const JOIN: &str = "P2P-JOIN";
+const LEAVE: &str = "P2P-LEAVE";
+const SNAPSHOT: &str = "P2P-SNAPSHOT";
+const NONCE_LEN: usize = 12;
+
+// What a member sends a joining server
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    tables: Tables,
+    samples: Vec<(SampleEntry, Vec<u8>)>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+fn proof_mac(cluster_key: &str, purpose: &str, content: &impl Serialize, signed_at: u64) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(cluster_key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(purpose.as_bytes());
+    mac.update(&signed_at.to_be_bytes());
+    mac.update(&bincode::serialize(content).expect("proved content is always serializable"));
+    mac
+}
+
+fn prove(cluster_key: &str, purpose: &str, content: &impl Serialize) -> MessageSignature {
+    let signed_at = unix_now();
+    MessageSignature {
+        signed_at,
+        signature: proof_mac(cluster_key, purpose, content, signed_at).finalize().into_bytes().to_vec(),
+    }
+}
+
+// Checks that `proof` was made recently over `content` with the cluster key
+fn check_proof(cluster_key: &str, purpose: &str, content: &impl Serialize, proof: &MessageSignature) -> io::Result<()> {
+    if unix_now().abs_diff(proof.signed_at) > MAX_AGE.as_secs() {
+        return Err(invalid("proof is too old or from the future"));
+    }
+    proof_mac(cluster_key, purpose, content, proof.signed_at)
+        .verify_slice(&proof.signature)
+        .map_err(|_| invalid("proof was not made with the cluster key"))
+}
+
+fn snapshot_cipher(cluster_key: &str) -> ChaCha20Poly1305 {
+    let key = Sha256::new().chain_update(SNAPSHOT).chain_update(cluster_key).finalize();
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+// The nonce followed by the encrypted snapshot
+fn seal(cluster_key: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let sealed = snapshot_cipher(cluster_key)
+        .encrypt(Nonce::from_slice(&nonce), data)
+        .map_err(|_| io::Error::other("failed to seal the snapshot"))?;
+    Ok([nonce.as_slice(), &sealed].concat())
+}
+
+fn open(cluster_key: &str, sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        return Err(invalid("snapshot is too short"));
+    }
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    snapshot_cipher(cluster_key)
+        .decrypt(Nonce::from_slice(nonce), sealed)
+        .map_err(|_| invalid("snapshot was not sealed with the cluster key"))
+}
+
+fn no_cluster_key() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "no cluster_key configured")
+}
+
+// Asks `seed` to add this server to the cluster and installs the snapshot it
+// answers with. Must run before the replicator opens the store.
+pub async fn join(
+    seed: SocketAddr,
+    me: &ServerInfo,
+    cluster_key: Option<&str>,
+    storage: &Storage,
+    samples_root: &Path,
+) -> io::Result<()> {
+    let cluster_key = cluster_key.ok_or_else(no_cluster_key)?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let request = Message::Join {
+        server: me.clone(),
+        proof: prove(cluster_key, JOIN, me),
+    };
+    let request = protocol::encode(&request);
+    for attempt in 1..=JOIN_ATTEMPTS {
+        println!("Joining the cluster through {} (attempt {})", seed, attempt);
+        faults::send_to(&socket, &request, seed).await?;
+        match timeout(JOIN_TIMEOUT, receive_snapshot(&socket, seed)).await {
+            Ok(completed) => return install(&open(cluster_key, &completed?)?, storage, samples_root),
+            Err(_) => println!("No snapshot from {}", seed),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("{} did not answer JOIN", seed),
+    ))
+}
+
+// The first transfer from the seed's host. The seed sends it from a port of
+// its own, not from its control socket.
+async fn receive_snapshot(socket: &UdpSocket, seed: SocketAddr) -> io::Result<Vec<u8>> {
+    loop {
+        let completed = transfer::receive(socket, None, &TransferConfig::default()).await?;
+        if completed.from.ip() == seed.ip() {
+            return Ok(completed.data);
+        }
+        println!("Ignoring a transfer from {}, which is not {}", completed.from, seed);
+    }
+}
+
+fn install(data: &[u8], storage: &Storage, samples_root: &Path) -> io::Result<()> {
+    let mut snapshot: Snapshot =
+        bincode::deserialize(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    // The IDs become file names under the samples folder
+    snapshot.tables.samples.retain(|sample| sample.is_safe());
+    for (sample, data) in &snapshot.samples {
+        if !sample.is_safe() {
+            eprintln!("Skipping sample {:?}, its IDs are not valid file names", sample);
+            continue;
+        }
+        let path = sample.path(samples_root);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, data)?;
+    }
+    println!(
+        "Joined with {} directory entries, {} samples and {} servers",
+        snapshot.tables.directory.len(),
+        snapshot.samples.len(),
+        snapshot.tables.servers.len()
+    );
+    storage.install(snapshot.tables)
+}
+
+// Adds `server` to the replicated list and sends it the snapshot to `to`,
+// if `proof` shows it knows the cluster key
+pub async fn serve_join(
+    server: ServerInfo,
+    proof: MessageSignature,
+    to: SocketAddr,
+    cluster_key: Option<&str>,
+    storage: &Storage,
+    samples_root: &Path,
+    replicator: &Replicator,
+) -> io::Result<()> {
+    let cluster_key = cluster_key.ok_or_else(no_cluster_key)?;
+    check_proof(cluster_key, JOIN, &server, &proof)?;
+    println!("Server {} is joining from {}", server.id, to);
+    replicator.propose(Change::AddServer(server)).await?;
+
+    let tables = storage.tables();
+    let samples = tables
+        .samples
+        .iter()
+        .filter_map(|sample| Some((sample.clone(), fs::read(sample.path(samples_root)).ok()?)))
+        .collect();
+    let data = bincode::serialize(&Snapshot { tables, samples }).map_err(io::Error::other)?;
+    let data = seal(cluster_key, &data)?;
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    transfer::send(&socket, to, transfer::new_transfer_id(), &data, &TransferConfig::default()).await?;
+    println!("Snapshot sent to {} ({} bytes)", to, data.len());
+    Ok(())
+}
+
+// The change LEAVE asks for, if `proof` shows the sender knows the cluster key
+pub fn check_leave(id: u32, proof: &MessageSignature, cluster_key: Option<&str>) -> io::Result<Change> {
+    check_proof(cluster_key.ok_or_else(no_cluster_key)?, LEAVE, &id, proof)?;
+    Ok(Change::RemoveServer { id })
+}
+
+// Asks the other servers to take this one off the list and waits until the
+// change reached this server
+pub async fn leave(id: u32, cluster_key: Option<&str>, storage: &Storage, peers: &[SocketAddr]) -> io::Result<()> {
+    let cluster_key = cluster_key.ok_or_else(no_cluster_key)?;
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let deadline = Instant::now() + LEAVE_TIMEOUT;
+    while Instant::now() < deadline {
+        if !storage.servers().0.iter().any(|server| server.id == id) {
+            println!("Left the cluster");
+            return Ok(());
+        }
+        // Made again each round, so it never gets too old
+        let request = protocol::encode(&Message::Leave {
+            id,
+            proof: prove(cluster_key, LEAVE, &id),
+        });
+        for peer in peers {
+            faults::send_to(&socket, &request, *peer).await?;
+        }
+        sleep(MEMBERS_POLL).await;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "still on the server list"))
+}
+
+// Configured peers plus the servers on the list, without the ones that left
+// and without this server
+fn peer_addrs(
+    configured: &[SocketAddr],
+    servers: &[ServerInfo],
+    left: &[ServerInfo],
+    me: u32,
+    addr: impl Fn(&MemberAddrs) -> &String,
+) -> Vec<SocketAddr> {
+    let parse = |server: &ServerInfo| addr(&server.addrs).parse::<SocketAddr>().ok();
+    let gone: Vec<SocketAddr> = left.iter().filter_map(parse).collect();
+    let own = servers.iter().filter(|server| server.id == me).filter_map(parse);
+    let listed = servers.iter().filter(|server| server.id != me).filter_map(parse);
+
+    let mut peers: Vec<SocketAddr> = Vec::new();
+    for peer in configured.iter().copied().chain(listed) {
+        if !gone.contains(&peer) && !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+    let own: Vec<SocketAddr> = own.collect();
+    peers.retain(|peer| !own.contains(peer));
+    peers
+}
+
+// The peers each part of the server talks to
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peers {
+    pub control: Vec<SocketAddr>,
+    pub election: Vec<SocketAddr>,
+    pub replication: Vec<SocketAddr>,
+}
+
+impl Peers {
+    fn of(configured: &Peers, servers: &[ServerInfo], left: &[ServerInfo], me: u32) -> Peers {
+        Peers {
+            control: peer_addrs(&configured.control, servers, left, me, |addrs| &addrs.control),
+            election: peer_addrs(&configured.election, servers, left, me, |addrs| &addrs.election),
+            replication: peer_addrs(&configured.replication, servers, left, me, |addrs| &addrs.replication),
+        }
+    }
+}
+
+// Keeps the peers of replication, election and sample distribution in line
+// with the replicated server list, and makes the failure detector forget the
+// servers that left
+pub async fn follow_members(
+    me: u32,
+    configured: Peers,
+    storage: Arc<Storage>,
+    replicator: Arc<Replicator>,
+    elector: Arc<Elector>,
+    gossiper: Arc<Gossiper>,
+    control_peers: Arc<Mutex<Vec<SocketAddr>>>,
+) {
+    let mut current = configured.clone();
+    let mut forgotten = Vec::new();
+    loop {
+        sleep(MEMBERS_POLL).await;
+        let (servers, left) = storage.servers();
+        for server in &left {
+            if !forgotten.contains(&server.id) {
+                gossiper.forget(server.id);
+                forgotten.push(server.id);
+            }
+        }
+        forgotten.retain(|id| left.iter().any(|server| server.id == *id));
+
+        let peers = Peers::of(&configured, &servers, &left, me);
+        if peers == current {
+            continue;
+        }
+        if peers.replication != current.replication {
+            replicator.set_peers(peers.replication.clone());
+        }
+        if peers.election != current.election {
+            elector.set_peers(peers.election.clone());
+        }
+        *control_peers.lock().unwrap() = peers.control.clone();
+        current = peers;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server(id: u32) -> ServerInfo {
+        ServerInfo {
+            id,
+            addrs: MemberAddrs {
+                control: format!("127.0.0.1:{}", 9100 + id),
+                gossip: format!("127.0.0.1:{}", 9200 + id),
+                election: format!("127.0.0.1:{}", 9300 + id),
+                replication: format!("127.0.0.1:{}", 9400 + id),
+            },
+        }
+    }
+
+    fn control(id: u32) -> SocketAddr {
+        server(id).addrs.control.parse().unwrap()
+    }
+
+    #[test]
+    fn listed_servers_are_added_to_the_configured_peers() {
+        let configured = vec![control(2), control(3)];
+        let servers = vec![server(1), server(2), server(3), server(4)];
+        let peers = peer_addrs(&configured, &servers, &[], 1, |addrs| &addrs.control);
+        assert_eq!(peers, vec![control(2), control(3), control(4)]);
+    }
+
+    #[test]
+    fn servers_that_left_are_dropped_even_if_configured() {
+        let configured = vec![control(2), control(3)];
+        let servers = vec![server(1), server(2)];
+        let peers = peer_addrs(&configured, &servers, &[server(3)], 1, |addrs| &addrs.control);
+        assert_eq!(peers, vec![control(2)]);
+    }
+
+    #[test]
+    fn joined_server_gets_the_snapshot() {
+        let from = tempfile::tempdir().unwrap();
+        let to = tempfile::tempdir().unwrap();
+        let storage = Storage::open(&from.path().join("store")).unwrap();
+        fs::create_dir_all(from.path().join("samples/7")).unwrap();
+        fs::write(from.path().join("samples/7/cat.jpg"), b"jpeg").unwrap();
+        storage.add_sample("7", "cat").unwrap();
+        let mut tables = storage.tables();
+        tables.servers = vec![server(1), server(2)];
+        tables.applied = 12;
+
+        let data = bincode::serialize(&Snapshot {
+            samples: vec![(tables.samples[0].clone(), b"jpeg".to_vec())],
+            tables: tables.clone(),
+        })
+        .unwrap();
+        let joined = Storage::open(&to.path().join("store")).unwrap();
+        let sealed = seal("secret", &data).unwrap();
+        assert!(open("other", &sealed).is_err());
+        install(&open("secret", &sealed).unwrap(), &joined, &to.path().join("samples")).unwrap();
+
+        assert_eq!(fs::read(to.path().join("samples/7/cat.jpg")).unwrap(), b"jpeg");
+        // And it survives a restart
+        let reopened = Storage::open(&to.path().join("store")).unwrap();
+        assert_eq!(reopened.tables(), tables);
+        assert_eq!(reopened.applied(), 12);
+    }
+
+    #[test]
+    fn join_and_leave_need_the_cluster_key() {
+        let proof = prove("secret", JOIN, &server(4));
+        check_proof("secret", JOIN, &server(4), &proof).unwrap();
+        assert!(check_proof("other", JOIN, &server(4), &proof).is_err());
+        assert!(check_proof("secret", JOIN, &server(5), &proof).is_err());
+        assert!(check_proof("secret", LEAVE, &server(4), &proof).is_err());
+
+        let leave = prove("secret", LEAVE, &4u32);
+        assert_eq!(check_leave(4, &leave, Some("secret")).unwrap(), Change::RemoveServer { id: 4 });
+        assert!(check_leave(3, &leave, Some("secret")).is_err());
+        assert!(check_leave(4, &leave, None).is_err());
+        let stale = MessageSignature {
+            signed_at: leave.signed_at - MAX_AGE.as_secs() - 1,
+            ..leave
+        };
+        assert!(check_leave(4, &stale, Some("secret")).is_err());
+    }
+
+    #[test]
+    fn samples_outside_the_samples_folder_are_skipped() {
+        let to = tempfile::tempdir().unwrap();
+        let escaping = SampleEntry {
+            client_id: "..".to_string(),
+            image_id: "escaped".to_string(),
+        };
+        let nested = SampleEntry {
+            client_id: "7".to_string(),
+            image_id: "../../escaped".to_string(),
+        };
+        let data = bincode::serialize(&Snapshot {
+            tables: Tables::default(),
+            samples: vec![(escaping, b"jpeg".to_vec()), (nested, b"jpeg".to_vec())],
+        })
+        .unwrap();
+        let joined = Storage::open(&to.path().join("store")).unwrap();
+        install(&data, &joined, &to.path().join("root/samples")).unwrap();
+        assert!(!to.path().join("root/escaped.jpg").exists());
+        assert!(!to.path().join("escaped.jpg").exists());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::jobs::Scheduling;
+use crate::load::LoadMetricKind;
+pub use protocol::ClientEndpoint;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+// Only read once, to import them into the store
+pub const DIRECTORY_OF_SERVICE: &str = "directory_of_service.csv";
+pub const OFFLINE_REQUESTS: &str = "offline_access_control_requests.csv";
+pub const SAMPLES_DIR: &str = "samples";
+// Snapshot and log of the directory, pending requests and samples index
+pub const STORE_DIR: &str = "store";
+// Term, vote and entries of the replicated log
+pub const RAFT_DIR: &str = "raft";
+// Uploads waiting to be encrypted, one file per client transfer
+pub const UPLOADS_DIR: &str = "uploads";
+
+// Everything that used to be hard-coded per server copy
+#[derive(Deserialize, Debug, Clone)]
+pub struct ServerConfig {
+    // Unique per server, breaks ties between equally loaded servers in elections
+    pub server_id: u32,
+    // Receives image uploads from clients, advertised in LEADER_ACK / STATUS_ACK
+    pub upload_addr: SocketAddr,
+    // Control socket: ELECT, STATUS, DIR_OF_SERV, Access_Control, ...
+    pub control_addr: SocketAddr,
+    // Sends LEADER_ACK back to clients
+    pub callback_addr: SocketAddr,
+    // Sends the encrypted image back to the owning client
+    pub image_addr: SocketAddr,
+    // Receives FAIL from failure_simulation
+    pub failure_addr: SocketAddr,
+    // Control sockets of the other servers
+    #[serde(default)]
+    pub peers: Vec<SocketAddr>,
+    // Runs the leader election with the other servers
+    pub election_addr: SocketAddr,
+    // Election sockets of the other servers
+    #[serde(default)]
+    pub election_peers: Vec<SocketAddr>,
+    // Replicates the directory of service with the other servers
+    pub replication_addr: SocketAddr,
+    // Replication sockets of the other servers
+    #[serde(default)]
+    pub replication_peers: Vec<SocketAddr>,
+    // Gossips heartbeats with the other servers for failure detection
+    pub gossip_addr: SocketAddr,
+    // Gossip sockets of servers to contact first, the rest is learned
+    #[serde(default)]
+    pub gossip_peers: Vec<SocketAddr>,
+    // Control socket of any member, for a server joining a running cluster
+    #[serde(default)]
+    pub join: Option<SocketAddr>,
+    // Secret every server of the cluster shares. JOIN and LEAVE must prove
+    // they know it and the snapshot a joining server gets is sealed with it;
+    // without it the server neither joins nor lets others join.
+    #[serde(default)]
+    pub cluster_key: Option<String>,
+    // Takes encryption jobs from the leader, advertised in LOAD_REPORT
+    pub job_addr: SocketAddr,
+    // Clients known before they send STATUS, the others are looked up in
+    // the endpoints they reported
+    #[serde(default)]
+    pub clients: Vec<ClientEndpoint>,
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf,
+    #[serde(default = "default_mask_image")]
+    pub mask_image: PathBuf,
+    #[serde(default = "default_timings_file")]
+    pub timings_file: String,
+    // Blocking workers encrypting uploads in parallel
+    #[serde(default = "default_encryption_workers")]
+    pub encryption_workers: usize,
+    // Load score bid in elections, the same on every server
+    #[serde(default)]
+    pub load_metric: LoadMetricKind,
+    // Whether the leader encrypts uploads itself or hands them out by load
+    #[serde(default)]
+    pub scheduling: Scheduling,
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_mask_image() -> PathBuf {
+    PathBuf::from("images/mask.jpg")
+}
+
+fn default_timings_file() -> String {
+    "encryption_times.csv".to_string()
+}
+
+fn default_encryption_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|workers| workers.get())
+        .unwrap_or(4)
+}
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+impl Default for ServerConfig {
+    // Same ports the original "Server" copy used
+    fn default() -> Self {
+        ServerConfig {
+            server_id: 1,
+            upload_addr: local(8082),
+            control_addr: local(8083),
+            callback_addr: local(8086),
+            image_addr: local(2002),
+            failure_addr: local(9000),
+            peers: vec![local(8084), local(2010)],
+            election_addr: local(8096),
+            election_peers: vec![local(8097), local(8098)],
+            replication_addr: local(8093),
+            replication_peers: vec![local(8094), local(8095)],
+            gossip_addr: local(8102),
+            gossip_peers: vec![local(8103), local(8104)],
+            join: None,
+            cluster_key: None,
+            job_addr: local(8099),
+            clients: vec![
+                ClientEndpoint {
+                    image_addr: local(2005),
+                    leader_ack_addr: local(9080),
+                },
+                ClientEndpoint {
+                    image_addr: local(7001),
+                    leader_ack_addr: local(7005),
+                },
+            ],
+            data_dir: default_data_dir(),
+            mask_image: default_mask_image(),
+            timings_file: default_timings_file(),
+            encryption_workers: default_encryption_workers(),
+            load_metric: LoadMetricKind::default(),
+            scheduling: Scheduling::default(),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+fn parse_addr(flag: &str, value: &str) -> io::Result<SocketAddr> {
+    value
+        .parse()
+        .map_err(|e| invalid(format!("Invalid address for {}: {} ({})", flag, value, e)))
+}
+
+impl ServerConfig {
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        toml::from_str(&content)
+            .map_err(|e| invalid(format!("Failed to parse {}: {}", path.display(), e)))
+    }
+
+    // Builds the config from `--config <file>` (or the defaults) and then applies
+    // any per-field flags on top, e.g. `--control-addr 127.0.0.1:8084 --peer ...`
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> io::Result<Self> {
+        let args: Vec<String> = args.into_iter().collect();
+
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => {
+                let path = args
+                    .get(i + 1)
+                    .ok_or_else(|| invalid("--config needs a file path".to_string()))?;
+                ServerConfig::from_file(Path::new(path))?
+            }
+            None => ServerConfig::default(),
+        };
+
+        // Repeated --peer / --election-peer / --replication-peer / --gossip-peer / --client flags replace the lists instead of extending them
+        let mut peers_from_flags = Vec::new();
+        let mut election_peers_from_flags = Vec::new();
+        let mut replication_peers_from_flags = Vec::new();
+        let mut gossip_peers_from_flags = Vec::new();
+        let mut clients_from_flags = Vec::new();
+
+        let mut iter = args.iter();
+        while let Some(flag) = iter.next() {
+            let mut value = || {
+                iter.next()
+                    .ok_or_else(|| invalid(format!("{} needs a value", flag)))
+            };
+            match flag.as_str() {
+                "--config" => {
+                    value()?;
+                }
+                "--server-id" => {
+                    let value = value()?;
+                    config.server_id = value
+                        .parse()
+                        .map_err(|e| invalid(format!("Invalid value for --server-id: {} ({})", value, e)))?;
+                }
+                "--upload-addr" => config.upload_addr = parse_addr(flag, value()?)?,
+                "--control-addr" => config.control_addr = parse_addr(flag, value()?)?,
+                "--callback-addr" => config.callback_addr = parse_addr(flag, value()?)?,
+                "--image-addr" => config.image_addr = parse_addr(flag, value()?)?,
+                "--failure-addr" => config.failure_addr = parse_addr(flag, value()?)?,
+                "--peer" => peers_from_flags.push(parse_addr(flag, value()?)?),
+                "--election-addr" => config.election_addr = parse_addr(flag, value()?)?,
+                "--election-peer" => election_peers_from_flags.push(parse_addr(flag, value()?)?),
+                "--replication-addr" => config.replication_addr = parse_addr(flag, value()?)?,
+                "--replication-peer" => replication_peers_from_flags.push(parse_addr(flag, value()?)?),
+                "--gossip-addr" => config.gossip_addr = parse_addr(flag, value()?)?,
+                "--gossip-peer" => gossip_peers_from_flags.push(parse_addr(flag, value()?)?),
+                "--join" => config.join = Some(parse_addr(flag, value()?)?),
+                "--cluster-key" => config.cluster_key = Some(value()?.clone()),
+                "--job-addr" => config.job_addr = parse_addr(flag, value()?)?,
+                "--client" => {
+                    // --client <image_addr>,<leader_ack_addr>
+                    let value = value()?;
+                    let (image_addr, leader_ack_addr) = value.split_once(',').ok_or_else(|| {
+                        invalid(format!("--client expects <image_addr>,<leader_ack_addr>, got {}", value))
+                    })?;
+                    clients_from_flags.push(ClientEndpoint {
+                        image_addr: parse_addr(flag, image_addr)?,
+                        leader_ack_addr: parse_addr(flag, leader_ack_addr)?,
+                    });
+                }
+                "--data-dir" => config.data_dir = PathBuf::from(value()?),
+                "--mask-image" => config.mask_image = PathBuf::from(value()?),
+                "--timings-file" => config.timings_file = value()?.clone(),
+                "--encryption-workers" => {
+                    let value = value()?;
+                    config.encryption_workers = value.parse().map_err(|e| {
+                        invalid(format!("Invalid value for --encryption-workers: {} ({})", value, e))
+                    })?;
+                }
+                "--load-metric" => config.load_metric = value()?.parse()?,
+                "--scheduling" => config.scheduling = value()?.parse()?,
+                other => return Err(invalid(format!("Unknown argument: {}", other))),
+            }
+        }
+
+        if !peers_from_flags.is_empty() {
+            config.peers = peers_from_flags;
+        }
+        if !election_peers_from_flags.is_empty() {
+            config.election_peers = election_peers_from_flags;
+        }
+        if !replication_peers_from_flags.is_empty() {
+            config.replication_peers = replication_peers_from_flags;
+        }
+        if !gossip_peers_from_flags.is_empty() {
+            config.gossip_peers = gossip_peers_from_flags;
+        }
+        if !clients_from_flags.is_empty() {
+            config.clients = clients_from_flags;
+        }
+
+        Ok(config)
+    }
+
+    pub fn data_path(&self, file_name: &str) -> PathBuf {
+        self.data_dir.join(file_name)
+    }
+
+    // Client whose ELECT came from `addr`
+    pub fn client_by_image_addr(&self, addr: &SocketAddr) -> Option<&ClientEndpoint> {
+        self.clients.iter().find(|client| client.image_addr == *addr)
+    }
+
+    // Client whose image upload came from `addr`
+    pub fn client_by_leader_ack_addr(&self, addr: &SocketAddr) -> Option<&ClientEndpoint> {
+        self.clients.iter().find(|client| client.leader_ack_addr == *addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // The fields a config file can't leave out
+    const MINIMAL: &str = r#"
+        server_id = 7
+        upload_addr = "127.0.0.1:9182"
+        control_addr = "127.0.0.1:9183"
+        callback_addr = "127.0.0.1:9186"
+        image_addr = "127.0.0.1:9102"
+        failure_addr = "127.0.0.1:9100"
+        election_addr = "127.0.0.1:9196"
+        replication_addr = "127.0.0.1:9193"
+        gossip_addr = "127.0.0.1:9202"
+        job_addr = "127.0.0.1:9199"
+    "#;
+
+    #[test]
+    fn shipped_configs_parse() {
+        for id in 1..=4 {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(format!("config/server{}.toml", id));
+            let config = ServerConfig::from_file(&path).unwrap();
+            assert_eq!(config.server_id, id);
+            assert_eq!(config.data_dir, PathBuf::from(format!("data/server{}", id)));
+        }
+
+        let config = ServerConfig::from_file(&Path::new(env!("CARGO_MANIFEST_DIR")).join("config/server1.toml")).unwrap();
+        assert_eq!(config.control_addr, local(8083));
+        assert_eq!(config.peers, vec![local(8084), local(2010)]);
+        assert_eq!(config.cluster_key.as_deref(), Some("p2p-demo-cluster-key"));
+        assert_eq!(config.client_by_leader_ack_addr(&local(7005)).unwrap().image_addr, local(7001));
+        assert_eq!(config.client_by_image_addr(&local(2005)).unwrap().leader_ack_addr, local(9080));
+        assert!(config.client_by_leader_ack_addr(&local(7001)).is_none());
+    }
+
+    #[test]
+    fn left_out_fields_take_their_defaults() {
+        let config: ServerConfig = toml::from_str(MINIMAL).unwrap();
+        assert_eq!(config.server_id, 7);
+        assert!(config.peers.is_empty());
+        assert!(config.clients.is_empty());
+        assert!(config.join.is_none());
+        assert_eq!(config.data_dir, default_data_dir());
+        assert_eq!(config.mask_image, default_mask_image());
+        assert_eq!(config.timings_file, "encryption_times.csv");
+        assert_eq!(config.load_metric, LoadMetricKind::default());
+        assert_eq!(config.scheduling, Scheduling::default());
+        assert!(config.encryption_workers >= 1);
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.toml");
+        let file = format!("{}peers = [\"127.0.0.1:1\", \"127.0.0.1:2\"]\n", MINIMAL);
+        fs::write(&path, file).unwrap();
+        let path = path.to_str().unwrap();
+
+        // Flags win wherever they stand relative to --config
+        let config = ServerConfig::from_args(args(&[
+            "--server-id",
+            "9",
+            "--config",
+            path,
+            "--control-addr",
+            "127.0.0.1:5000",
+            "--peer",
+            "127.0.0.1:3",
+            "--peer",
+            "127.0.0.1:4",
+            "--client",
+            "127.0.0.1:6001,127.0.0.1:6002",
+            "--scheduling",
+            "least_loaded",
+        ]))
+        .unwrap();
+        assert_eq!(config.server_id, 9);
+        assert_eq!(config.control_addr, local(5000));
+        assert_eq!(config.upload_addr, local(9182));
+        // Repeated list flags replace the list from the file
+        assert_eq!(config.peers, vec![local(3), local(4)]);
+        assert_eq!(config.clients, vec![ClientEndpoint { image_addr: local(6001), leader_ack_addr: local(6002) }]);
+        assert_eq!(config.scheduling, Scheduling::LeastLoaded);
+
+        // Without --config the flags go on top of the built-in defaults
+        let config = ServerConfig::from_args(args(&["--data-dir", "elsewhere"])).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("elsewhere"));
+        assert_eq!(config.control_addr, ServerConfig::default().control_addr);
+    }
+
+    #[test]
+    fn bad_configs_are_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing_field = dir.path().join("missing_field.toml");
+        fs::write(&missing_field, MINIMAL.replace("server_id = 7", "")).unwrap();
+        let bad_addr = dir.path().join("bad_addr.toml");
+        fs::write(&bad_addr, MINIMAL.replace("127.0.0.1:9183", "localhost")).unwrap();
+        for path in [&missing_field, &bad_addr] {
+            let error = ServerConfig::from_file(path).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", path.display());
+        }
+        let error = ServerConfig::from_file(&dir.path().join("absent.toml")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        for bad in [
+            &["--config"][..],
+            &["--control-addr"],
+            &["--control-addr", "127.0.0.1"],
+            &["--server-id", "first"],
+            &["--client", "127.0.0.1:6001"],
+            &["--load-metric", "temperature"],
+            &["--scheduling", "random"],
+            &["--encryption-workers", "-1"],
+            &["--verbose"],
+        ] {
+            let error = ServerConfig::from_args(args(bad)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{:?}", bad);
+        }
+    }
+}
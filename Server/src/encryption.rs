@@ -0,0 +1,220 @@
+use csv::Writer;
+use image::{ImageFormat, RgbaImage};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use steganography::encoder::Encoder;
+use steganography::util::file_as_dynamic_image;
+use tokio::sync::Semaphore;
+
+const TIMINGS_HEADER: &str = "encryption_time,queue_depth,job_latency";
+
+pub(crate) type EncodeFn = dyn Fn(&[u8]) -> io::Result<Vec<u8>> + Send + Sync;
+
+// Timings of one job, as written to the timing CSV
+#[derive(Debug, Clone, Copy)]
+pub struct JobStats {
+    // Time a worker spent encoding the image
+    pub encryption_time: Duration,
+    // Jobs already waiting for a worker when this one was queued
+    pub queue_depth: usize,
+    // From queueing the job to the encrypted PNG being ready
+    pub latency: Duration,
+}
+
+// Encrypts uploads on a bounded number of blocking workers so several
+// clients can be served at once without starving the async tasks
+#[derive(Clone)]
+pub struct EncryptionPool {
+    encode: Arc<EncodeFn>,
+    workers: Arc<Semaphore>,
+    worker_count: usize,
+    waiting: Arc<AtomicUsize>,
+    timings_file: Arc<Mutex<PathBuf>>,
+}
+
+impl EncryptionPool {
+    // Loads the mask once, every job encodes into its own copy of it
+    pub fn new(mask_path: &Path, workers: usize, timings_file: PathBuf) -> io::Result<Self> {
+        // steganography panics on a mask it can't open, so it is opened here first
+        image::open(mask_path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot open the mask image {}: {}", mask_path.display(), e),
+            )
+        })?;
+        let mask = file_as_dynamic_image(mask_path.to_string_lossy().to_string());
+
+        let encode = move |data: &[u8]| -> io::Result<Vec<u8>> {
+            let encrypted = Encoder::new(data, mask.clone()).encode_alpha();
+
+            // steganography is built on its own `image` version, so the pixels
+            // are moved over raw before encoding the PNG
+            let (width, height) = encrypted.dimensions();
+            let encrypted = RgbaImage::from_raw(width, height, encrypted.into_raw())
+                .ok_or_else(|| io::Error::other("Encoder returned a truncated image"))?;
+
+            // PNG to avoid lossy compression
+            let mut png = Cursor::new(Vec::new());
+            encrypted
+                .write_to(&mut png, ImageFormat::Png)
+                .map_err(io::Error::other)?;
+            Ok(png.into_inner())
+        };
+        Ok(Self::from_encoder(Arc::new(encode), workers, timings_file))
+    }
+
+    pub(crate) fn from_encoder(encode: Arc<EncodeFn>, workers: usize, timings_file: PathBuf) -> Self {
+        EncryptionPool {
+            encode,
+            workers: Arc::new(Semaphore::new(workers.max(1))),
+            worker_count: workers.max(1),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            timings_file: Arc::new(Mutex::new(timings_file)),
+        }
+    }
+
+    // Jobs waiting for a free worker
+    pub fn queue_depth(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    // Workers encrypting right now
+    pub fn busy_workers(&self) -> usize {
+        self.worker_count - self.workers.available_permits()
+    }
+
+    // Encrypts `image_data` into a PNG once a worker is free and records the
+    // job in the timing CSV
+    pub async fn encrypt(&self, image_data: Vec<u8>) -> io::Result<(Vec<u8>, JobStats)> {
+        let queued_at = Instant::now();
+        let queue_depth = self.waiting.fetch_add(1, Ordering::SeqCst);
+        let permit = Arc::clone(&self.workers).acquire_owned().await;
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        let permit = permit.map_err(io::Error::other)?;
+
+        let encode = Arc::clone(&self.encode);
+        let (encrypted, encryption_time) = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let start = Instant::now();
+            let encrypted = encode(&image_data);
+            (encrypted, start.elapsed())
+        })
+        .await
+        .map_err(io::Error::other)?;
+
+        let stats = JobStats {
+            encryption_time,
+            queue_depth,
+            latency: queued_at.elapsed(),
+        };
+        if let Err(e) = self.record(&stats) {
+            eprintln!("Failed to record encryption timing: {}", e);
+        }
+
+        Ok((encrypted?, stats))
+    }
+
+    fn record(&self, stats: &JobStats) -> io::Result<()> {
+        let timings_file = self.timings_file.lock().unwrap();
+        append_timing(&timings_file, stats)
+    }
+}
+
+// Appends one row to the timing CSV. The one-column CSVs of older servers,
+// encryption times without a header, get the header and empty columns for
+// the rest first, once.
+fn append_timing(path: &Path, stats: &JobStats) -> io::Result<()> {
+    let file_exists = path.exists();
+    if file_exists {
+        let mut header = String::new();
+        BufReader::new(File::open(path)?).read_line(&mut header)?;
+        if header.trim_end() != TIMINGS_HEADER {
+            migrate_timings(path)?;
+        }
+    }
+    let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+    if !file_exists {
+        writeln!(file, "{}", TIMINGS_HEADER)?;
+    }
+
+    let mut wtr = Writer::from_writer(file);
+    wtr.write_record(&[
+        format!("{:.2}", stats.encryption_time.as_secs_f64()),
+        stats.queue_depth.to_string(),
+        format!("{:.2}", stats.latency.as_secs_f64()),
+    ])?;
+    wtr.flush()
+}
+
+// Rewrites a one-column timing CSV with the header, through a temporary file
+// so a crash leaves either layout whole
+fn migrate_timings(path: &Path) -> io::Result<()> {
+    let old = fs::read_to_string(path)?;
+    if old.lines().any(|line| line.contains(',')) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a timing CSV of this or an older version", path.display()),
+        ));
+    }
+    let mut migrated = format!("{}\n", TIMINGS_HEADER);
+    for line in old.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        migrated.push_str(&format!("{},,\n", line));
+    }
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, migrated)?;
+    fs::rename(&temporary, path)?;
+    println!("Added the new columns to {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> JobStats {
+        JobStats {
+            encryption_time: Duration::from_millis(1500),
+            queue_depth: 2,
+            latency: Duration::from_millis(2250),
+        }
+    }
+
+    #[test]
+    fn a_missing_mask_is_an_error() {
+        let root = tempfile::tempdir().unwrap();
+        let missing = root.path().join("mask.jpg");
+        assert!(EncryptionPool::new(&missing, 1, root.path().join("encryption_times.csv")).is_err());
+    }
+
+    #[test]
+    fn timings_get_a_header_and_three_columns() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("encryption_times.csv");
+        append_timing(&path, &stats()).unwrap();
+        append_timing(&path, &stats()).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, format!("{}\n1.50,2,2.25\n1.50,2,2.25\n", TIMINGS_HEADER));
+    }
+
+    #[test]
+    fn old_one_column_files_get_the_header_once() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("server1_encryption_times.csv");
+        std::fs::write(&path, "2.02\n2.05\n").unwrap();
+        append_timing(&path, &stats()).unwrap();
+        append_timing(&path, &stats()).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, format!("{}\n2.02,,\n2.05,,\n1.50,2,2.25\n1.50,2,2.25\n", TIMINGS_HEADER));
+
+        // Anything else is not rewritten
+        let other = root.path().join("other.csv");
+        std::fs::write(&other, "a,b\n").unwrap();
+        assert!(append_timing(&other, &stats()).is_err());
+        assert_eq!(std::fs::read_to_string(&other).unwrap(), "a,b\n");
+    }
+}
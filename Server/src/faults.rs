@@ -0,0 +1,218 @@
+// The faults failure_simulation asked this server to apply, each until its
+// time is up. Installed as the process' fault hook, so every socket of the
+// server honors them. Other servers are recognised by the addresses they
+// gossip; traffic of clients only matches rules for all traffic.
+use crate::membership::View;
+use protocol::faults::{FaultHook, FaultKind, FaultRule};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Active {
+    rule: FaultRule,
+    until: Instant,
+    rng: StdRng,
+}
+
+#[derive(Default)]
+struct State {
+    active: Vec<Active>,
+    members: HashMap<u32, Vec<SocketAddr>>,
+}
+
+#[derive(Default)]
+pub struct Faults {
+    state: Mutex<State>,
+}
+
+impl Faults {
+    pub fn add(&self, rule: FaultRule, now: Instant) {
+        let active = Active {
+            until: now + Duration::from_millis(rule.duration_ms),
+            rng: StdRng::seed_from_u64(rule.seed),
+            rule,
+        };
+        self.state.lock().unwrap().active.push(active);
+    }
+
+    // Learns the addresses of the servers in the failure detector's view
+    pub fn set_members(&self, view: &View) {
+        let members = view
+            .members
+            .iter()
+            .map(|(id, member)| {
+                let addrs = &member.addrs;
+                let parsed = [&addrs.control, &addrs.gossip, &addrs.election, &addrs.replication]
+                    .into_iter()
+                    .filter_map(|addr| addr.parse().ok())
+                    .collect();
+                (*id, parsed)
+            })
+            .collect();
+        self.state.lock().unwrap().members = members;
+    }
+
+    pub fn outgoing_at(&self, peer: SocketAddr, now: Instant) -> Vec<Duration> {
+        let mut copies = vec![Duration::ZERO];
+        self.each_matching(peer, now, |kind, rng| match kind {
+            FaultKind::Crash => copies.clear(),
+            FaultKind::Drop { percent } => {
+                if rng.gen_bool(probability(*percent)) {
+                    copies.clear();
+                }
+            }
+            FaultKind::Delay { ms, jitter_ms } => {
+                let jitter = rng.gen_range(0..=2 * jitter_ms);
+                let delay = Duration::from_millis((ms + jitter).saturating_sub(*jitter_ms));
+                copies.iter_mut().for_each(|copy| *copy += delay);
+            }
+            FaultKind::Duplicate { percent } => {
+                if rng.gen_bool(probability(*percent)) {
+                    if let Some(last) = copies.last() {
+                        copies.push(*last);
+                    }
+                }
+            }
+        });
+        copies
+    }
+
+    // Only a crash keeps datagrams out, the other faults act on the sender's side
+    pub fn incoming_at(&self, peer: SocketAddr, now: Instant) -> bool {
+        let mut allowed = true;
+        self.each_matching(peer, now, |kind, _| {
+            if *kind == FaultKind::Crash {
+                allowed = false;
+            }
+        });
+        allowed
+    }
+
+    // Calls `apply` for the rules that hit traffic with `peer`, in the order
+    // they arrived, and forgets the expired ones
+    fn each_matching(&self, peer: SocketAddr, now: Instant, mut apply: impl FnMut(&FaultKind, &mut StdRng)) {
+        let mut state = self.state.lock().unwrap();
+        let State { active, members } = &mut *state;
+        active.retain(|fault| fault.until > now);
+        for fault in active.iter_mut() {
+            let hit = fault.rule.peers.is_empty()
+                || fault
+                    .rule
+                    .peers
+                    .iter()
+                    .any(|id| members.get(id).is_some_and(|addrs| addrs.contains(&peer)));
+            if hit {
+                apply(&fault.rule.kind, &mut fault.rng);
+            }
+        }
+    }
+}
+
+fn probability(percent: f64) -> f64 {
+    (percent / 100.0).clamp(0.0, 1.0)
+}
+
+impl FaultHook for Faults {
+    fn outgoing(&self, peer: SocketAddr) -> Vec<Duration> {
+        self.outgoing_at(peer, Instant::now())
+    }
+
+    fn incoming(&self, peer: SocketAddr) -> bool {
+        self.incoming_at(peer, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::membership::{Liveness, Member};
+    use protocol::MemberAddrs;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    // Servers 2 and 3, with every socket on 92xx and 93xx
+    fn faults() -> Faults {
+        let faults = Faults::default();
+        let mut view = View::default();
+        for id in [2, 3] {
+            let port = |base: u16| addr(base + id as u16).to_string();
+            let addrs = MemberAddrs {
+                control: port(9200),
+                gossip: port(9210),
+                election: port(9220),
+                replication: port(9230),
+            };
+            view.members.insert(id, Member { addrs, liveness: Liveness::Alive });
+        }
+        faults.set_members(&view);
+        faults
+    }
+
+    fn rule(kind: FaultKind, peers: Vec<u32>) -> FaultRule {
+        FaultRule {
+            kind,
+            peers,
+            duration_ms: 10_000,
+            seed: 7,
+        }
+    }
+
+    #[test]
+    fn crash_cuts_all_traffic_until_it_ends() {
+        let faults = faults();
+        let now = Instant::now();
+        faults.add(rule(FaultKind::Crash, vec![]), now);
+        assert!(faults.outgoing_at(addr(9202), now).is_empty());
+        assert!(!faults.incoming_at(addr(5000), now));
+
+        let later = now + Duration::from_secs(10);
+        assert_eq!(faults.outgoing_at(addr(9202), later), vec![Duration::ZERO]);
+        assert!(faults.incoming_at(addr(5000), later));
+    }
+
+    #[test]
+    fn rules_only_hit_the_listed_servers() {
+        let faults = faults();
+        let now = Instant::now();
+        faults.add(rule(FaultKind::Drop { percent: 100.0 }, vec![2]), now);
+        // Any socket of server 2
+        assert!(faults.outgoing_at(addr(9222), now).is_empty());
+        assert!(faults.outgoing_at(addr(9232), now).is_empty());
+        assert_eq!(faults.outgoing_at(addr(9203), now).len(), 1);
+        assert_eq!(faults.outgoing_at(addr(5000), now).len(), 1);
+        // Drops happen on the sending side only
+        assert!(faults.incoming_at(addr(9202), now));
+    }
+
+    #[test]
+    fn same_seed_drops_the_same_datagrams() {
+        let now = Instant::now();
+        let run = || {
+            let faults = faults();
+            faults.add(rule(FaultKind::Drop { percent: 30.0 }, vec![]), now);
+            (0..1000).map(|_| faults.outgoing_at(addr(9202), now).is_empty()).collect::<Vec<bool>>()
+        };
+        let dropped = run();
+        assert_eq!(dropped, run());
+        let count = dropped.iter().filter(|dropped| **dropped).count();
+        assert!((200..400).contains(&count), "{} dropped", count);
+    }
+
+    #[test]
+    fn delays_and_duplicates_add_up() {
+        let faults = faults();
+        let now = Instant::now();
+        faults.add(rule(FaultKind::Duplicate { percent: 100.0 }, vec![3]), now);
+        faults.add(rule(FaultKind::Delay { ms: 100, jitter_ms: 20 }, vec![3]), now);
+        let copies = faults.outgoing_at(addr(9203), now);
+        assert_eq!(copies.len(), 2);
+        for copy in copies {
+            assert!(copy >= Duration::from_millis(80) && copy <= Duration::from_millis(120));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+// Encryption jobs. With `least_loaded` scheduling the leader hands each
+// upload to the server with the lowest load score it heard from, which
+// encrypts it and sends the result straight to the client's image socket.
+// A job that is not confirmed with JOB_DONE in time goes to the next server,
+// and the leader encrypts it itself as a last resort.
+use crate::bully_election::Load;
+use crate::encryption::EncryptionPool;
+use crate::lifecycle::{Lifecycle, State};
+use crate::load::ActiveTransfers;
+use protocol::transfer::{self, Inbox, Received, TransferConfig};
+use protocol::{faults, EncryptJob, EncryptResult, Message, MAX_DATAGRAM};
+use serde::Deserialize;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+// From handing a job out until JOB_DONE, so it covers the transfer to the
+// server, the encryption and the transfer to the client
+const JOB_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Who encrypts uploads, `scheduling` in the server config
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Scheduling {
+    // The leader encrypts every upload itself
+    #[default]
+    Leader,
+    // The leader hands each upload to the least loaded server
+    LeastLoaded,
+}
+
+impl FromStr for Scheduling {
+    type Err = io::Error;
+
+    fn from_str(value: &str) -> io::Result<Self> {
+        match value {
+            "leader" => Ok(Scheduling::Leader),
+            "least_loaded" => Ok(Scheduling::LeastLoaded),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown scheduling {} (leader or least_loaded)", other),
+            )),
+        }
+    }
+}
+
+// Encrypts images on this server and sends them to clients from its image
+// socket
+#[derive(Clone)]
+pub struct Encryptor {
+    pub pool: EncryptionPool,
+    pub transfers: ActiveTransfers,
+    pub socket6: Arc<tokio::sync::Mutex<UdpSocket>>,
+    pub lifecycle: Lifecycle,
+}
+
+impl Encryptor {
+    pub async fn encrypt_and_send(&self, job_id: u64, image: Vec<u8>, client: SocketAddr) -> io::Result<()> {
+        self.check_running()?;
+        println!(
+            "Queueing job {} for {} ({} jobs waiting)",
+            job_id,
+            client,
+            self.pool.queue_depth()
+        );
+        let (encrypted_data, stats) = self.pool.encrypt(image).await?;
+        println!(
+            "Encryption Time: {:?} (latency {:?}, queue depth {})",
+            stats.encryption_time, stats.latency, stats.queue_depth
+        );
+
+        // The server may have failed while the job waited for a worker
+        self.check_running()?;
+
+        // Send the encrypted image back to the client
+        let result = EncryptResult {
+            request_id: job_id,
+            image: encrypted_data,
+        };
+        let transfer_id = transfer::new_transfer_id();
+        println!("Sending encrypted image to {} as transfer {}", client, transfer_id);
+        let _sending = self.transfers.start_send();
+        let socket = self.socket6.lock().await;
+        transfer::send(&socket, client, transfer_id, &result.to_bytes(), &TransferConfig::default()).await?;
+        println!("Encrypted image transmission completed.");
+        Ok(())
+    }
+
+    fn check_running(&self) -> io::Result<()> {
+        match self.lifecycle.state() {
+            State::Running => Ok(()),
+            state => Err(io::Error::new(io::ErrorKind::Interrupted, format!("server is {:?}", state))),
+        }
+    }
+}
+
+// Runs a job on the servers in `loads` (least loaded first) until one
+// confirms it. Servers ranked after this one are never asked: this server
+// is as good a choice and needs no transfer.
+pub async fn dispatch(
+    encryptor: &Encryptor,
+    server_id: u32,
+    loads: Vec<Load>,
+    job_id: u64,
+    image: Vec<u8>,
+    client: SocketAddr,
+) -> io::Result<()> {
+    dispatch_within(JOB_TIMEOUT, encryptor, server_id, loads, job_id, image, client).await
+}
+
+async fn dispatch_within(
+    job_timeout: Duration,
+    encryptor: &Encryptor,
+    server_id: u32,
+    loads: Vec<Load>,
+    job_id: u64,
+    image: Vec<u8>,
+    client: SocketAddr,
+) -> io::Result<()> {
+    let mut job = EncryptJob {
+        job_id,
+        reply_to: client.to_string(),
+        image,
+    };
+    for load in loads {
+        if load.id == server_id {
+            break;
+        }
+        let job_addr = match load.job_addr.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(e) => {
+                eprintln!("Invalid job address {} of server {}: {}", load.job_addr, load.id, e);
+                continue;
+            }
+        };
+        println!("Handing job {} to server {} (score {}) at {}", job_id, load.id, load.score, job_addr);
+        match run_remote(encryptor, job_addr, &job, job_timeout).await {
+            Ok(()) => {
+                println!("Server {} finished job {}", load.id, job_id);
+                return Ok(());
+            }
+            Err(e) => eprintln!("Job {} failed on server {}: {}, trying the next server", job_id, load.id, e),
+        }
+    }
+    let image = std::mem::take(&mut job.image);
+    encryptor.encrypt_and_send(job_id, image, client).await
+}
+
+async fn run_remote(
+    encryptor: &Encryptor,
+    job_addr: SocketAddr,
+    job: &EncryptJob,
+    job_timeout: Duration,
+) -> io::Result<()> {
+    // A socket per job, so JOB_DONE can't be confused with another job's
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let _sending = encryptor.transfers.start_send();
+    let run = async {
+        transfer::send(&socket, job_addr, transfer::new_transfer_id(), &job.to_bytes(), &TransferConfig::default())
+            .await?;
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (size, from) = faults::recv_from(&socket, &mut buffer).await?;
+            match protocol::decode(&buffer[..size]) {
+                Ok(Message::JobDone { job_id }) if from == job_addr && job_id == job.job_id => return Ok(()),
+                // Late ACKs of the job transfer
+                _ => continue,
+            }
+        }
+    };
+    timeout(job_timeout, run)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no JOB_DONE in time"))?
+}
+
+// Takes jobs from the leader on the job socket. Each is encrypted and sent to
+// its client in its own task, then confirmed to the leader.
+pub async fn receive_jobs(socket: Arc<UdpSocket>, encryptor: Encryptor) -> io::Result<()> {
+    let mut inbox = Inbox::new(&TransferConfig::default());
+
+    loop {
+        let completed = match inbox.recv(&socket).await {
+            Ok(Received::Transfer(completed)) => completed,
+            Ok(Received::Message(message, addr)) => {
+                println!("Unexpected message on job socket from {}: {:?}", addr, message);
+                continue;
+            }
+            // Nothing arrived for a while, keep waiting
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("Failed to receive job: {:?}", e);
+                continue;
+            }
+        };
+        let job = match EncryptJob::from_bytes(&completed.data) {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("Bad job from {}: {}", completed.from, e);
+                continue;
+            }
+        };
+        let client = match job.reply_to.parse::<SocketAddr>() {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("Invalid client address {} in job {}: {}", job.reply_to, job.job_id, e);
+                continue;
+            }
+        };
+        println!("Job {} from {} for {}", job.job_id, completed.from, client);
+
+        let socket = Arc::clone(&socket);
+        let encryptor = encryptor.clone();
+        tokio::spawn(async move {
+            if let Err(e) = encryptor.encrypt_and_send(job.job_id, job.image, client).await {
+                // No JOB_DONE, the leader gives the job to another server
+                eprintln!("Failed to run job {}: {:?}", job.job_id, e);
+                return;
+            }
+            let done = protocol::encode(&Message::JobDone { job_id: job.job_id });
+            if let Err(e) = faults::send_to(&socket, &done, completed.from).await {
+                eprintln!("Failed to confirm job {} to {}: {}", job.job_id, completed.from, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+
+    const ME: u32 = 1;
+
+    async fn encryptor(timings: &Path) -> Encryptor {
+        Encryptor {
+            pool: EncryptionPool::from_encoder(Arc::new(|data: &[u8]| Ok(data.to_vec())), 1, timings.to_path_buf()),
+            transfers: ActiveTransfers::default(),
+            socket6: Arc::new(tokio::sync::Mutex::new(UdpSocket::bind("127.0.0.1:0").await.unwrap())),
+            lifecycle: Lifecycle::default(),
+        }
+    }
+
+    fn load(id: u32, job_addr: impl ToString) -> Load {
+        Load {
+            id,
+            score: 0,
+            job_addr: job_addr.to_string(),
+        }
+    }
+
+    // A client image socket, collecting every result that reaches it
+    async fn client() -> (SocketAddr, Arc<Mutex<Vec<EncryptResult>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&results);
+        tokio::spawn(async move {
+            let mut inbox = Inbox::new(&TransferConfig::default());
+            loop {
+                if let Ok(Received::Transfer(completed)) = inbox.recv(&socket).await {
+                    received.lock().unwrap().push(EncryptResult::from_bytes(&completed.data).unwrap());
+                }
+            }
+        });
+        (addr, results)
+    }
+
+    async fn worker(timings: &Path) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(receive_jobs(Arc::new(socket), encryptor(timings).await));
+        addr
+    }
+
+    // A worker that sends the result to the client, but whose JOB_DONE never
+    // reaches the leader
+    async fn silent_worker(timings: &Path) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let encryptor = encryptor(timings).await;
+        tokio::spawn(async move {
+            let mut inbox = Inbox::new(&TransferConfig::default());
+            loop {
+                if let Ok(Received::Transfer(completed)) = inbox.recv(&socket).await {
+                    let job = EncryptJob::from_bytes(&completed.data).unwrap();
+                    let encryptor = encryptor.clone();
+                    tokio::spawn(async move {
+                        let client = job.reply_to.parse().unwrap();
+                        encryptor.encrypt_and_send(job.job_id, job.image, client).await.unwrap();
+                    });
+                }
+            }
+        });
+        addr
+    }
+
+    async fn results_after_a_while(results: &Mutex<Vec<EncryptResult>>) -> Vec<(u64, Vec<u8>)> {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        results
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|result| (result.request_id, result.image.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn confirmed_jobs_run_once_on_the_least_loaded_server() {
+        let root = tempfile::tempdir().unwrap();
+        let leader = encryptor(&root.path().join("leader.csv")).await;
+        let worker = worker(&root.path().join("worker.csv")).await;
+        let (client, results) = client().await;
+
+        let loads = vec![load(2, worker), load(ME, "127.0.0.1:1")];
+        dispatch_within(Duration::from_secs(5), &leader, ME, loads, 7, vec![1, 2, 3], client)
+            .await
+            .unwrap();
+        assert_eq!(results_after_a_while(&results).await, vec![(7, vec![1, 2, 3])]);
+        // The leader encrypted nothing itself
+        assert!(!root.path().join("leader.csv").exists());
+    }
+
+    #[tokio::test]
+    async fn servers_ranked_below_this_one_or_unreachable_get_nothing() {
+        let root = tempfile::tempdir().unwrap();
+        let leader = encryptor(&root.path().join("leader.csv")).await;
+        let below = silent_worker(&root.path().join("below.csv")).await;
+        let (client, results) = client().await;
+
+        let loads = vec![load(3, "not an address"), load(ME, "127.0.0.1:1"), load(2, below)];
+        dispatch_within(Duration::from_secs(5), &leader, ME, loads, 7, vec![1, 2, 3], client)
+            .await
+            .unwrap();
+        assert_eq!(results_after_a_while(&results).await, vec![(7, vec![1, 2, 3])]);
+        assert!(root.path().join("leader.csv").exists());
+        assert!(!root.path().join("below.csv").exists());
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_jobs_run_again_and_the_client_may_get_the_result_twice() {
+        let root = tempfile::tempdir().unwrap();
+        let leader = encryptor(&root.path().join("leader.csv")).await;
+        let silent = silent_worker(&root.path().join("silent.csv")).await;
+        let (client, results) = client().await;
+
+        let loads = vec![load(2, silent), load(ME, "127.0.0.1:1")];
+        dispatch_within(Duration::from_secs(1), &leader, ME, loads, 7, vec![1, 2, 3], client)
+            .await
+            .unwrap();
+        // Both copies carry the request ID, so the client keeps the first
+        // and ignores the other
+        assert_eq!(
+            results_after_a_while(&results).await,
+            vec![(7, vec![1, 2, 3]), (7, vec![1, 2, 3])]
+        );
+        assert!(root.path().join("leader.csv").exists());
+        assert!(root.path().join("silent.csv").exists());
+    }
+}
@@ -0,0 +1,184 @@
+// The job journal. The leader records every upload it takes on in the
+// replicated store, under the request ID the client chose, and drops the
+// record once the result reached the client. When a leader fails mid-job the
+// client sends the request again to the next one, which finds the record
+// and knows it is a retry. A request already running on this server is not
+// started a second time. The image itself is not kept, so a record only
+// helps while the client still retries: the leader drops records that saw no
+// attempt for `EXPIRY`, such as those of a client that gave up.
+use crate::bully_election::Elector;
+use crate::replication::Replicator;
+use crate::storage::{Change, JobRecord, Storage};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Clients give up after 5 attempts of 30 seconds, well within this
+const EXPIRY: Duration = Duration::from_secs(600);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// Requests of `jobs` with no attempt for `EXPIRY`, other than those running here
+fn expired(jobs: &[JobRecord], running: &HashSet<u64>, now: u64) -> Vec<u64> {
+    jobs.iter()
+        .filter(|job| job.started + EXPIRY.as_secs() <= now && !running.contains(&job.request_id))
+        .map(|job| job.request_id)
+        .collect()
+}
+
+pub struct Journal {
+    server_id: u32,
+    storage: Arc<Storage>,
+    replicator: Arc<Replicator>,
+    // Requests this server is working on
+    running: Mutex<HashSet<u64>>,
+}
+
+// A request this server took on, no longer running here once dropped
+pub struct Running<'a> {
+    journal: &'a Journal,
+    request_id: u64,
+}
+
+impl Journal {
+    pub fn new(server_id: u32, storage: Arc<Storage>, replicator: Arc<Replicator>) -> Self {
+        Journal {
+            server_id,
+            storage,
+            replicator,
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    // Records the request, or returns None if it is running here already.
+    // The job goes ahead even if the journal can't be updated.
+    pub async fn start(&self, request_id: u64, reply_to: SocketAddr) -> Option<Running<'_>> {
+        if !self.running.lock().unwrap().insert(request_id) {
+            return None;
+        }
+        let running = Running {
+            journal: self,
+            request_id,
+        };
+        if let Some(job) = self.storage.job(request_id) {
+            println!(
+                "Request {} was cut short on server {} ({} attempts), running it again",
+                request_id, job.server, job.attempts
+            );
+        }
+        let job = JobRecord {
+            request_id,
+            reply_to: reply_to.to_string(),
+            server: self.server_id,
+            attempts: 1,
+            started: unix_now(),
+        };
+        if let Err(e) = self.replicator.propose(Change::StartJob(job)).await {
+            eprintln!("Failed to journal request {}: {}", request_id, e);
+        }
+        Some(running)
+    }
+
+    // Drops the records of requests no client retries any more
+    async fn expire(&self) {
+        let running = self.running.lock().unwrap().clone();
+        for request_id in expired(&self.storage.jobs(), &running, unix_now()) {
+            println!("Request {} was not retried for {:?}, dropping it from the journal", request_id, EXPIRY);
+            if let Err(e) = self.replicator.propose(Change::FinishJob { request_id }).await {
+                eprintln!("Failed to expire request {}: {}", request_id, e);
+            }
+        }
+    }
+
+    // Expires records while this server leads, right away when it takes over
+    pub async fn run(&self, elector: &Elector) {
+        let mut leading = false;
+        loop {
+            let now_leading = elector.leader().is_some_and(|leader| leader.id == self.server_id);
+            if now_leading {
+                if !leading {
+                    let unfinished = self.storage.jobs().len();
+                    if unfinished > 0 {
+                        println!("Taking over the journal with {} unfinished requests", unfinished);
+                    }
+                }
+                self.expire().await;
+            }
+            leading = now_leading;
+            tokio::time::sleep(SWEEP_INTERVAL).await;
+        }
+    }
+}
+
+impl Running<'_> {
+    // The result reached the client
+    pub async fn finish(self) {
+        let change = Change::FinishJob {
+            request_id: self.request_id,
+        };
+        if let Err(e) = self.journal.replicator.propose(change).await {
+            eprintln!("Failed to journal the end of request {}: {}", self.request_id, e);
+        }
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.journal.running.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(request_id: u64, started: u64) -> JobRecord {
+        JobRecord {
+            request_id,
+            reply_to: "127.0.0.1:9000".to_string(),
+            server: 1,
+            attempts: 1,
+            started,
+        }
+    }
+
+    #[test]
+    fn records_without_retries_expire() {
+        let now = 10_000;
+        let jobs = vec![
+            job(1, now - EXPIRY.as_secs()),
+            job(2, now - 1),
+            job(3, now - 2 * EXPIRY.as_secs()),
+        ];
+        assert_eq!(expired(&jobs, &HashSet::new(), now), vec![1, 3]);
+    }
+
+    #[test]
+    fn records_running_here_stay() {
+        let jobs = vec![job(1, 0), job(2, 0)];
+        assert_eq!(expired(&jobs, &HashSet::from([1]), EXPIRY.as_secs()), vec![2]);
+    }
+
+    #[test]
+    fn retries_restart_the_clock_until_the_job_finishes() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = Storage::open(root.path()).unwrap();
+        storage.apply_replicated(1, Change::StartJob(job(7, 100))).unwrap();
+        let mut retry = job(7, 500);
+        retry.server = 2;
+        storage.apply_replicated(2, Change::StartJob(retry)).unwrap();
+        let record = storage.job(7).unwrap();
+        assert_eq!((record.server, record.attempts, record.started), (2, 2, 500));
+        assert!(expired(&storage.jobs(), &HashSet::new(), 100 + EXPIRY.as_secs()).is_empty());
+
+        storage.apply_replicated(3, Change::FinishJob { request_id: 7 }).unwrap();
+        assert_eq!(storage.job(7), None);
+    }
+}
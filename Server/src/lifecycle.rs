@@ -0,0 +1,106 @@
+// Whether this server takes part in the cluster. FAIL, or a crash fault from
+// failure_simulation, makes it `Failed`: every task drops what arrives and
+// sends nothing, and no image is encrypted. Once the failure is over it is
+// `Recovering`: replication, election and gossip run again and catch up
+// with the peers, but clients get no answers until the server heard from
+// the current leaders and applied everything they committed. Then it is
+// `Running` again.
+use crate::bully_election::Elector;
+use crate::replication::Replicator;
+use protocol::MAX_DATAGRAM;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+use tokio::time::{sleep, Instant};
+
+// How long a recovering server waits for its peers before it runs anyway,
+// e.g. because it is the only one left
+const RESYNC_TIMEOUT: Duration = Duration::from_secs(10);
+const RESYNC_POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Failed,
+    Recovering,
+}
+
+#[derive(Clone)]
+pub struct Lifecycle {
+    state: watch::Sender<State>,
+}
+
+impl Default for Lifecycle {
+    fn default() -> Self {
+        Lifecycle {
+            state: watch::Sender::new(State::Running),
+        }
+    }
+}
+
+impl Lifecycle {
+    pub fn state(&self) -> State {
+        *self.state.borrow()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state() == State::Running
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<State> {
+        self.state.subscribe()
+    }
+
+    fn set(&self, state: State) {
+        if self.state.send_replace(state) != state {
+            println!("Server is now {:?}", state);
+        }
+    }
+
+    pub async fn until_running(&self) {
+        // The sender lives in `self`, so the channel can't close
+        let _ = self.subscribe().wait_for(|state| *state == State::Running).await;
+    }
+
+    pub async fn until_stopped(&self) {
+        let _ = self.subscribe().wait_for(|state| *state != State::Running).await;
+    }
+
+    // Reads and drops whatever arrives on `socket` until the server runs, so
+    // nothing stale is left to handle afterwards
+    pub async fn drain_until_running(&self, socket: &UdpSocket) {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            tokio::select! {
+                _ = self.until_running() => return,
+                _ = socket.recv_from(&mut buffer) => {}
+            }
+        }
+    }
+
+    // Fails the server for `duration`, then resyncs it with its peers before
+    // it takes work again. A server that is not running ignores it.
+    pub async fn fail_for(&self, duration: Duration, replicator: &Replicator, elector: &Elector) {
+        if !self.is_running() {
+            println!("Server is already {:?}", self.state());
+            return;
+        }
+        self.set(State::Failed);
+        sleep(duration).await;
+        self.set(State::Recovering);
+
+        let deadline = Instant::now() + RESYNC_TIMEOUT;
+        loop {
+            if replicator.caught_up() && elector.leader().is_some() {
+                println!("Caught up with the cluster");
+                break;
+            }
+            if Instant::now() >= deadline {
+                println!("No leader to catch up with, running anyway");
+                break;
+            }
+            sleep(RESYNC_POLL).await;
+        }
+        self.set(State::Running);
+    }
+}
@@ -0,0 +1,276 @@
+// How busy a server is, as the score it bids with in elections. A lower
+// score ranks higher. Scores of different metrics can't be compared, so
+// every server of a deployment has to use the same one.
+use crate::encryption::EncryptionPool;
+use serde::Deserialize;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use sysinfo::{CpuExt, System, SystemExt};
+
+// Percentages are compared in steps of this size, so noise between two
+// servers doing the same work does not decide the election
+const PERCENT_STEP: f32 = 10.0;
+// Weight of the newest sample in the smoothed CPU usage
+const CPU_SMOOTHING: f32 = 0.3;
+
+pub trait LoadMetric: Send {
+    fn name(&self) -> &'static str;
+    // Takes a new sample, called about once a second
+    fn score(&mut self) -> u32;
+}
+
+// The metric a deployment elects with, `load_metric` in the server config
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadMetricKind {
+    #[default]
+    Cpu,
+    EncryptionQueue,
+    Memory,
+    Transfers,
+}
+
+impl FromStr for LoadMetricKind {
+    type Err = io::Error;
+
+    fn from_str(value: &str) -> io::Result<Self> {
+        match value {
+            "cpu" => Ok(LoadMetricKind::Cpu),
+            "encryption_queue" => Ok(LoadMetricKind::EncryptionQueue),
+            "memory" => Ok(LoadMetricKind::Memory),
+            "transfers" => Ok(LoadMetricKind::Transfers),
+            other => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown load metric {} (cpu, encryption_queue, memory or transfers)", other),
+            )),
+        }
+    }
+}
+
+pub fn build(kind: LoadMetricKind, pool: &EncryptionPool, transfers: &ActiveTransfers) -> Box<dyn LoadMetric> {
+    match kind {
+        LoadMetricKind::Cpu => Box::new(SystemCpu::new()),
+        LoadMetricKind::EncryptionQueue => Box::new(EncryptionQueue { pool: pool.clone() }),
+        LoadMetricKind::Memory => Box::new(MemoryPressure { system: System::new() }),
+        LoadMetricKind::Transfers => Box::new(Transfers {
+            transfers: transfers.clone(),
+        }),
+    }
+}
+
+fn percent_step(percent: f32) -> u32 {
+    (percent / PERCENT_STEP).round() as u32
+}
+
+fn smooth(previous: Option<f32>, usage: f32) -> f32 {
+    match previous {
+        Some(previous) => previous + CPU_SMOOTHING * (usage - previous),
+        None => usage,
+    }
+}
+
+// CPU usage of the whole machine, smoothed over the last samples
+pub struct SystemCpu {
+    system: System,
+    smoothed: Option<f32>,
+}
+
+impl SystemCpu {
+    pub fn new() -> Self {
+        let mut system = System::new();
+        // Usage is measured between two refreshes, this is the first one
+        system.refresh_cpu();
+        SystemCpu { system, smoothed: None }
+    }
+}
+
+impl LoadMetric for SystemCpu {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn score(&mut self) -> u32 {
+        self.system.refresh_cpu();
+        let usage = self.system.global_cpu_info().cpu_usage();
+        let smoothed = smooth(self.smoothed, usage);
+        self.smoothed = Some(smoothed);
+        percent_step(smoothed)
+    }
+}
+
+// Uploads waiting for or being encrypted
+pub struct EncryptionQueue {
+    pool: EncryptionPool,
+}
+
+impl LoadMetric for EncryptionQueue {
+    fn name(&self) -> &'static str {
+        "encryption_queue"
+    }
+
+    fn score(&mut self) -> u32 {
+        (self.pool.queue_depth() + self.pool.busy_workers()) as u32
+    }
+}
+
+// Share of memory that is not available to new work
+pub struct MemoryPressure {
+    system: System,
+}
+
+impl LoadMetric for MemoryPressure {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn score(&mut self) -> u32 {
+        self.system.refresh_memory();
+        let total = self.system.total_memory();
+        if total == 0 {
+            return 0;
+        }
+        let used = total.saturating_sub(self.system.available_memory());
+        percent_step(used as f32 * 100.0 / total as f32)
+    }
+}
+
+// Image transfers in progress on this server, both ways
+#[derive(Clone, Default)]
+pub struct ActiveTransfers {
+    uploads: Arc<AtomicUsize>,
+    sends: Arc<AtomicUsize>,
+}
+
+impl ActiveTransfers {
+    // Uploads still being reassembled
+    pub fn set_uploads(&self, uploads: usize) {
+        self.uploads.store(uploads, Ordering::SeqCst);
+    }
+
+    // Counts a send until the returned guard is dropped
+    pub fn start_send(&self) -> SendGuard {
+        self.sends.fetch_add(1, Ordering::SeqCst);
+        SendGuard(Arc::clone(&self.sends))
+    }
+
+    pub fn count(&self) -> usize {
+        self.uploads.load(Ordering::SeqCst) + self.sends.load(Ordering::SeqCst)
+    }
+}
+
+pub struct SendGuard(Arc<AtomicUsize>);
+
+impl Drop for SendGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub struct Transfers {
+    transfers: ActiveTransfers,
+}
+
+impl LoadMetric for Transfers {
+    fn name(&self) -> &'static str {
+        "transfers"
+    }
+
+    fn score(&mut self) -> u32 {
+        self.transfers.count() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncodeFn;
+    use std::sync::mpsc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[test]
+    fn metric_names_parse() {
+        for kind in ["cpu", "encryption_queue", "memory", "transfers"] {
+            let parsed: LoadMetricKind = kind.parse().unwrap();
+            let pool = EncryptionPool::from_encoder(Arc::new(|data: &[u8]| Ok(data.to_vec())), 1, "unused.csv".into());
+            assert_eq!(build(parsed, &pool, &ActiveTransfers::default()).name(), kind);
+        }
+        assert!("disk".parse::<LoadMetricKind>().is_err());
+    }
+
+    #[test]
+    fn percentages_round_to_steps() {
+        assert_eq!(percent_step(0.0), 0);
+        assert_eq!(percent_step(14.9), 1);
+        assert_eq!(percent_step(15.0), 2);
+        assert_eq!(percent_step(100.0), 10);
+    }
+
+    #[test]
+    fn a_single_cpu_spike_moves_the_score_part_way() {
+        let first = smooth(None, 20.0);
+        assert_eq!(first, 20.0);
+        let spiked = smooth(Some(first), 100.0);
+        assert_eq!(percent_step(spiked), 4);
+        let settled = (0..20).fold(spiked, |smoothed, _| smooth(Some(smoothed), 100.0));
+        assert_eq!(percent_step(settled), 10);
+    }
+
+    #[test]
+    fn memory_is_a_percentage() {
+        let mut memory = MemoryPressure { system: System::new() };
+        assert!(memory.score() <= 10);
+    }
+
+    #[test]
+    fn transfers_count_uploads_and_sends() {
+        let transfers = ActiveTransfers::default();
+        let mut metric = Transfers {
+            transfers: transfers.clone(),
+        };
+        transfers.set_uploads(2);
+        let first = transfers.start_send();
+        let second = transfers.start_send();
+        assert_eq!(metric.score(), 4);
+        drop(first);
+        transfers.set_uploads(0);
+        assert_eq!(metric.score(), 1);
+        drop(second);
+        assert_eq!(metric.score(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_and_running_jobs_count() {
+        let root = tempfile::tempdir().unwrap();
+        // Each job blocks its worker until the test lets it go
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let encode: Arc<EncodeFn> = Arc::new(move |data: &[u8]| {
+            released.lock().unwrap().recv().unwrap();
+            Ok(data.to_vec())
+        });
+        let pool = EncryptionPool::from_encoder(encode, 1, root.path().join("job_timings.csv"));
+        let mut metric = EncryptionQueue { pool: pool.clone() };
+        assert_eq!(metric.score(), 0);
+
+        let jobs: Vec<_> = (0..3)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move { pool.encrypt(vec![i]).await })
+            })
+            .collect();
+        // One job on the only worker, two waiting for it
+        while metric.score() < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        for _ in 0..3 {
+            release.send(()).unwrap();
+        }
+        for job in jobs {
+            job.await.unwrap().unwrap();
+        }
+        assert_eq!(metric.score(), 0);
+    }
+}
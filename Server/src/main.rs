@@ -1,9 +1,27 @@
 use std::io;
+mod accounts;
 mod bully_election;
+mod cluster;
+mod config;
+mod encryption;
+mod faults;
+mod jobs;
+mod journal;
+mod lifecycle;
+mod load;
+mod membership;
 mod middleware;
+mod ownership;
+mod replication;
+mod storage;
+mod uploads;
+mod view_counts;
+
+use config::ServerConfig;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    middleware::middleware().await?;
+    let config = ServerConfig::from_args(std::env::args().skip(1))?;
+    middleware::middleware(config).await?;
     Ok(())
 }
@@ -0,0 +1,458 @@
+// Failure detection between servers. Every server counts a heartbeat up and
+// gossips its table of counters to the others, so a server is heard from as
+// long as any path to it works. How long a counter has not moved is turned
+// into a suspicion level (phi accrual): phi = elapsed / mean interval *
+// log10(e), the confidence that the server is gone assuming exponentially
+// distributed arrivals. Crossing `suspect_phi` makes a server suspect,
+// crossing `dead_phi` makes it dead; a newer counter makes it alive again.
+use crate::lifecycle::State;
+use protocol::{faults, GossipEntry, MemberAddrs, Message, MAX_DATAGRAM};
+use rand::seq::SliceRandom;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+#[derive(Debug, Clone)]
+pub struct MembershipConfig {
+    // How often a server counts its heartbeat up and gossips
+    pub interval: Duration,
+    // Servers gossiped to per round
+    pub fanout: usize,
+    // Heartbeat intervals kept per server to estimate the mean
+    pub window: usize,
+    pub suspect_phi: f64,
+    pub dead_phi: f64,
+}
+
+impl Default for MembershipConfig {
+    // At a 100ms interval a server is suspect after about 0.7s of silence
+    // and dead after about 1.4s, before an election lease runs out
+    fn default() -> Self {
+        MembershipConfig {
+            interval: Duration::from_millis(100),
+            fanout: 3,
+            window: 100,
+            suspect_phi: 3.0,
+            dead_phi: 6.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Liveness {
+    Alive,
+    Suspect,
+    Dead,
+}
+
+// A server as the detector currently sees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub addrs: MemberAddrs,
+    pub liveness: Liveness,
+}
+
+// Every server heard of so far, this one included
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct View {
+    pub members: BTreeMap<u32, Member>,
+}
+
+impl View {
+    pub fn liveness(&self) -> HashMap<u32, Liveness> {
+        self.members.iter().map(|(id, member)| (*id, member.liveness)).collect()
+    }
+
+    // Control sockets of the servers that are alive, this one included
+    pub fn live_control_addrs(&self) -> Vec<String> {
+        self.members
+            .values()
+            .filter(|member| member.liveness == Liveness::Alive)
+            .map(|member| member.addrs.control.clone())
+            .collect()
+    }
+
+    // Replication sockets of the servers that are dead
+    pub fn dead_replication_addrs(&self) -> HashSet<SocketAddr> {
+        self.members
+            .values()
+            .filter(|member| member.liveness == Liveness::Dead)
+            .filter_map(|member| member.addrs.replication.parse().ok())
+            .collect()
+    }
+}
+
+struct Peer {
+    entry: GossipEntry,
+    // When the counter last moved, and the intervals between moves
+    last_heard: Instant,
+    intervals: VecDeque<Duration>,
+    liveness: Liveness,
+}
+
+type Outgoing = Vec<(SocketAddr, Message)>;
+
+// The detector of one server. Like the election and replication state
+// machines it never touches the network: every call returns the messages to
+// send.
+pub struct Membership {
+    me: GossipEntry,
+    // Gossip sockets to contact before anything is known about them
+    seeds: Vec<SocketAddr>,
+    config: MembershipConfig,
+    peers: HashMap<u32, Peer>,
+    // Servers that left, with the generation they left in. Gossip about them
+    // is ignored until they come back in a newer generation.
+    left: HashMap<u32, u64>,
+}
+
+impl Membership {
+    // `generation` must grow between restarts of the same server
+    pub fn new(id: u32, generation: u64, addrs: MemberAddrs, seeds: Vec<SocketAddr>, config: MembershipConfig) -> Self {
+        Membership {
+            me: GossipEntry {
+                id,
+                generation,
+                heartbeat: 0,
+                addrs,
+            },
+            seeds,
+            config,
+            peers: HashMap::new(),
+            left: HashMap::new(),
+        }
+    }
+
+    // Drops a server that left the cluster
+    pub fn forget(&mut self, id: u32) {
+        if let Some(peer) = self.peers.remove(&id) {
+            println!("Membership: server {} left", id);
+            self.left.insert(id, peer.entry.generation);
+        }
+    }
+
+    fn phi(&self, peer: &Peer, now: Instant) -> f64 {
+        let mean = match peer.intervals.len() {
+            0 => self.config.interval,
+            n => peer.intervals.iter().sum::<Duration>() / n as u32,
+        }
+        .max(self.config.interval);
+        let elapsed = now.saturating_duration_since(peer.last_heard);
+        elapsed.as_secs_f64() / mean.as_secs_f64() * std::f64::consts::LOG10_E
+    }
+
+    // Counts the heartbeat up and gossips the table to a few servers
+    pub fn tick(&mut self) -> Outgoing {
+        self.me.heartbeat += 1;
+        let mut entries = vec![self.me.clone()];
+        entries.extend(self.peers.values().map(|peer| peer.entry.clone()));
+
+        let known: Vec<SocketAddr> = self
+            .peers
+            .values()
+            .filter_map(|peer| peer.entry.addrs.gossip.parse().ok())
+            .collect();
+        let mut targets: Vec<SocketAddr> = known.clone();
+        targets.shuffle(&mut rand::thread_rng());
+        targets.truncate(self.config.fanout);
+        // Seeds nobody has heard from yet are always tried
+        targets.extend(self.seeds.iter().filter(|seed| !known.contains(seed)));
+
+        let message = Message::Gossip { entries };
+        targets.into_iter().map(|target| (target, message.clone())).collect()
+    }
+
+    pub fn step(&mut self, message: Message, now: Instant) {
+        let Message::Gossip { entries } = message else {
+            return;
+        };
+        for entry in entries {
+            if entry.id == self.me.id || self.left.get(&entry.id).is_some_and(|left| entry.generation <= *left) {
+                continue;
+            }
+            match self.peers.get_mut(&entry.id) {
+                None => {
+                    println!("Membership: discovered server {} at {}", entry.id, entry.addrs.gossip);
+                    self.peers.insert(
+                        entry.id,
+                        Peer {
+                            entry,
+                            last_heard: now,
+                            intervals: VecDeque::new(),
+                            liveness: Liveness::Alive,
+                        },
+                    );
+                }
+                Some(peer) if (entry.generation, entry.heartbeat) > (peer.entry.generation, peer.entry.heartbeat) => {
+                    // A restart starts a new history
+                    if entry.generation > peer.entry.generation {
+                        peer.intervals.clear();
+                    } else {
+                        if peer.intervals.len() == self.config.window {
+                            peer.intervals.pop_front();
+                        }
+                        peer.intervals.push_back(now.saturating_duration_since(peer.last_heard));
+                    }
+                    peer.entry = entry;
+                    peer.last_heard = now;
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // Back from a failure: the silence was this server's own, so every
+    // server's clock starts over instead of all of them looking dead
+    pub fn resume(&mut self, now: Instant) {
+        for peer in self.peers.values_mut() {
+            peer.last_heard = now;
+        }
+    }
+
+    // Updates every server's liveness, returns the ones that changed
+    pub fn refresh(&mut self, now: Instant) -> Vec<(u32, Liveness)> {
+        let levels: Vec<(u32, f64)> = self.peers.iter().map(|(id, peer)| (*id, self.phi(peer, now))).collect();
+        let mut changed = Vec::new();
+        for (id, phi) in levels {
+            let liveness = if phi >= self.config.dead_phi {
+                Liveness::Dead
+            } else if phi >= self.config.suspect_phi {
+                Liveness::Suspect
+            } else {
+                Liveness::Alive
+            };
+            let peer = self.peers.get_mut(&id).unwrap();
+            if peer.liveness != liveness {
+                peer.liveness = liveness;
+                changed.push((id, liveness));
+            }
+        }
+        changed.sort_by_key(|(id, _)| *id);
+        changed
+    }
+
+    pub fn view(&self) -> View {
+        let mut members: BTreeMap<u32, Member> = self
+            .peers
+            .iter()
+            .map(|(id, peer)| {
+                let member = Member {
+                    addrs: peer.entry.addrs.clone(),
+                    liveness: peer.liveness,
+                };
+                (*id, member)
+            })
+            .collect();
+        members.insert(
+            self.me.id,
+            Member {
+                addrs: self.me.addrs.clone(),
+                liveness: Liveness::Alive,
+            },
+        );
+        View { members }
+    }
+}
+
+// Runs a `Membership` on its own UDP socket and publishes the view whenever
+// a server changes state
+pub struct Gossiper {
+    node: Mutex<Membership>,
+    socket: UdpSocket,
+    view: watch::Sender<View>,
+    // Set while the server is failed, it then neither gossips nor listens
+    silent: AtomicBool,
+}
+
+impl Gossiper {
+    pub fn new(socket: UdpSocket, id: u32, addrs: MemberAddrs, seeds: Vec<SocketAddr>, config: MembershipConfig) -> Self {
+        // Start time in milliseconds, so a restarted server has a newer generation
+        let generation = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0);
+        let node = Membership::new(id, generation, addrs, seeds, config);
+        let (view, _) = watch::channel(node.view());
+        Gossiper {
+            node: Mutex::new(node),
+            socket,
+            view,
+            silent: AtomicBool::new(false),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<View> {
+        self.view.subscribe()
+    }
+
+    pub fn live_control_addrs(&self) -> Vec<String> {
+        self.view.borrow().live_control_addrs()
+    }
+
+    pub fn forget(&self, id: u32) {
+        self.node.lock().unwrap().forget(id);
+    }
+
+    pub fn set_state(&self, state: State) {
+        let silent = state == State::Failed;
+        if self.silent.swap(silent, Ordering::SeqCst) && !silent {
+            self.node.lock().unwrap().resume(Instant::now());
+        }
+    }
+
+    // Only returns if the socket fails
+    pub async fn run(&self) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        let interval = self.node.lock().unwrap().config.interval;
+        let mut round = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                received = faults::recv_from(&self.socket, &mut buffer) => {
+                    let (size, from) = received?;
+                    if self.silent.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    match protocol::decode(&buffer[..size]) {
+                        Ok(message) => self.node.lock().unwrap().step(message, Instant::now()),
+                        Err(e) => eprintln!("Membership: bad message from {}: {}", from, e),
+                    }
+                }
+                _ = round.tick() => {
+                    if self.silent.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let outgoing = self.node.lock().unwrap().tick();
+                    for (peer, message) in outgoing {
+                        if let Err(e) = faults::send_to(&self.socket, &protocol::encode(&message), peer).await {
+                            eprintln!("Membership: failed to send to {}: {}", peer, e);
+                        }
+                    }
+                }
+            }
+
+            let (changed, view) = {
+                let mut node = self.node.lock().unwrap();
+                (node.refresh(Instant::now()), node.view())
+            };
+            for (id, liveness) in &changed {
+                println!("Membership: server {} is {:?}", id, liveness);
+            }
+            self.view.send_if_modified(|current| {
+                let modified = *current != view;
+                *current = view;
+                modified
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(id: u32) -> MemberAddrs {
+        MemberAddrs {
+            control: format!("127.0.0.1:{}", 9100 + id),
+            gossip: format!("127.0.0.1:{}", 9200 + id),
+            election: format!("127.0.0.1:{}", 9300 + id),
+            replication: format!("127.0.0.1:{}", 9400 + id),
+        }
+    }
+
+    fn node(id: u32, generation: u64) -> Membership {
+        Membership::new(id, generation, addrs(id), Vec::new(), MembershipConfig::default())
+    }
+
+    // One gossip round of `from` that reaches `to`
+    fn gossip(from: &mut Membership, to: &mut Membership, now: Instant) {
+        from.tick();
+        let mut entries = vec![from.me.clone()];
+        entries.extend(from.peers.values().map(|peer| peer.entry.clone()));
+        to.step(Message::Gossip { entries }, now);
+    }
+
+    fn liveness(node: &Membership, id: u32) -> Liveness {
+        node.view().members[&id].liveness
+    }
+
+    #[test]
+    fn silent_server_becomes_suspect_then_dead() {
+        let mut start = Instant::now();
+        let mut a = node(1, 1);
+        let mut b = node(2, 1);
+        for _ in 0..20 {
+            start += Duration::from_millis(100);
+            gossip(&mut b, &mut a, start);
+            a.refresh(start);
+        }
+        assert_eq!(liveness(&a, 2), Liveness::Alive);
+
+        assert!(a.refresh(start + Duration::from_millis(300)).is_empty());
+        assert_eq!(a.refresh(start + Duration::from_millis(800)), vec![(2, Liveness::Suspect)]);
+        assert_eq!(a.refresh(start + Duration::from_millis(1500)), vec![(2, Liveness::Dead)]);
+        assert_eq!(a.view().dead_replication_addrs(), HashSet::from(["127.0.0.1:9402".parse().unwrap()]));
+
+        // Heard from again
+        gossip(&mut b, &mut a, start + Duration::from_millis(1600));
+        assert_eq!(a.refresh(start + Duration::from_millis(1600)), vec![(2, Liveness::Alive)]);
+    }
+
+    #[test]
+    fn server_heard_through_another_stays_alive() {
+        let mut now = Instant::now();
+        let (mut a, mut b, mut c) = (node(1, 1), node(2, 1), node(3, 1));
+        // The link between A and C is down, B relays C's counter
+        for _ in 0..30 {
+            now += Duration::from_millis(100);
+            gossip(&mut c, &mut b, now);
+            gossip(&mut b, &mut a, now);
+            a.refresh(now);
+        }
+        assert_eq!(liveness(&a, 3), Liveness::Alive);
+        assert_eq!(a.view().members.len(), 3);
+    }
+
+    #[test]
+    fn restarted_server_is_alive_again() {
+        let mut now = Instant::now();
+        let mut a = node(1, 1);
+        let mut b = node(2, 1);
+        for _ in 0..10 {
+            now += Duration::from_millis(100);
+            gossip(&mut b, &mut a, now);
+        }
+        now += Duration::from_secs(5);
+        a.refresh(now);
+        assert_eq!(liveness(&a, 2), Liveness::Dead);
+
+        // A new generation counts from zero again
+        let mut b = node(2, 2);
+        gossip(&mut b, &mut a, now);
+        a.refresh(now);
+        assert_eq!(liveness(&a, 2), Liveness::Alive);
+    }
+
+    #[test]
+    fn server_that_left_is_not_brought_back_by_gossip() {
+        let now = Instant::now();
+        let (mut a, mut b, mut c) = (node(1, 1), node(2, 1), node(3, 1));
+        gossip(&mut c, &mut b, now);
+        gossip(&mut b, &mut a, now);
+        assert_eq!(a.view().members.len(), 3);
+
+        // B still has C in its table
+        a.forget(3);
+        gossip(&mut b, &mut a, now);
+        assert!(!a.view().members.contains_key(&3));
+
+        // Unless C joins again after a restart
+        let mut c = node(3, 2);
+        gossip(&mut c, &mut a, now);
+        assert!(a.view().members.contains_key(&3));
+    }
+}
@@ -1,125 +1,132 @@
-use crate::bully_election::server_election;
-use csv::Writer;
-use csv::WriterBuilder;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use crate::accounts;
+use crate::bully_election::{ElectionConfig, Elector};
+use crate::cluster::{self, Peers};
+use crate::config::{
+    ServerConfig, DIRECTORY_OF_SERVICE, OFFLINE_REQUESTS, RAFT_DIR, SAMPLES_DIR, STORE_DIR, UPLOADS_DIR,
+};
+use crate::encryption::EncryptionPool;
+use crate::faults::Faults;
+use crate::jobs::{self, Encryptor, Scheduling};
+use crate::journal::Journal;
+use crate::lifecycle::{Lifecycle, State};
+use crate::load::{self, ActiveTransfers};
+use crate::membership::{Gossiper, MembershipConfig};
+use crate::ownership;
+use crate::replication::{ReplicationConfig, Replicator};
+use crate::storage::{self, Change, KnownEndpoint, PendingRequest, SampleEntry, Storage};
+use crate::uploads::{receive_uploads, Upload};
+use crate::view_counts;
+use protocol::transfer::TransferConfig;
+use protocol::signing::{self, MessageSignature};
+use protocol::{
+    faults, ClientEndpoint, EncryptRequest, MemberAddrs, Message, OnlineStatus, ServerInfo, SignedGrant, MAX_DATAGRAM,
+};
+use std::collections::hash_map::{Entry, HashMap};
 use std::fs;
-use std::fs::OpenOptions;
-use std::io::{Cursor, Write};
-use std::os::unix::fs::OpenOptionsExt;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Instant;
-use steganography::encoder::*;
-use steganography::util::file_as_dynamic_image;
-use tokio::fs::File;
 use std::net::SocketAddr;
-use tokio::io::{self, AsyncWriteExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
-use tokio::time::{sleep, timeout, Duration};
-// use google_drive3::{DriveHub, hyper, hyper_rustls, oauth2, oauth2::InstalledFlowAuthenticator, oauth2::InstalledFlowReturnMethod};
-
-// struct for online status
-#[derive(Serialize, Deserialize, Debug)]
-struct OnlineStatus {
-    ip : String,
-    status: bool,
-    client_id: String,
+use tokio::time::{timeout, Duration};
+
+// How long FAIL takes a server down
+const FAIL_DURATION: Duration = Duration::from_secs(30);
+// Sample datagrams of one client waiting for its receiver
+const SAMPLE_FEED_CAPACITY: usize = 64;
+
+async fn send_message(socket: &UdpSocket, message: &Message, addr: SocketAddr) -> io::Result<usize> {
+    faults::send_to(socket, &protocol::encode(message), addr).await
 }
 
+// Datagrams of the clients sending their samples, by address. The control
+// loop hands them to the task receiving that client's samples, so the other
+// requests are not held up meanwhile.
+type SampleFeeds = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
 pub async fn receive_samples(
-    socket: &Arc<tokio::sync::Mutex<UdpSocket>>,
+    socket: &UdpSocket,
+    feed: &mut mpsc::Receiver<Vec<u8>>,
     client_id: &str,
-    client_address: &SocketAddr,
-    peers: &[String], // List of peer addresses
+    peers: &[SocketAddr], // List of peer addresses
+    samples_root: &Path,
+    storage: &Storage,
+    replicator: &Replicator,
 ) -> io::Result<()> {
     println!("Waiting for samples from client: {}", client_id);
 
-    let samples_dir = format!("samples/{}", client_id);
-    std::fs::create_dir_all(&samples_dir).expect("Failed to create client-specific 'samples/' directory");
-
-    let mut buffer = [0u8; 4096]; // Buffer for receiving data
     let timeout_duration = Duration::from_secs(30); // Timeout duration
     let mut received_files = Vec::new(); // Track received files for syncing
 
     loop {
-        let recv_result = timeout(timeout_duration, socket.lock().await.recv_from(&mut buffer)).await;
-
-        match recv_result {
-            Ok(Ok((size, addr))) => {
-                if addr != *client_address {
-                    println!("Unexpected message from {}, ignoring.", addr);
-                    continue;
-                }
-
-                if size == 0 {
-                    continue;
-                }
-
-                let message = String::from_utf8_lossy(&buffer[..size]);
-
-                if message.starts_with("SAMPLE_UPLOAD:") {
-                    // Parse metadata
-                    println!("Received message: {}", message);
-                    let parts: Vec<&str> = message.split(':').collect();
-                    if parts.len() < 3 {
-                        eprintln!("Invalid SAMPLE_UPLOAD message: {}", message);
-                        continue;
-                    }
-                    let image_id = parts[2]; // Use the provided image ID
-
-                    // Await the image data
-                    let mut buffer = [0u8; 4096];
-                    let recv_image_result = timeout(timeout_duration, socket.lock().await.recv_from(&mut buffer)).await;
-
-                    match recv_image_result {
-                        Ok(Ok((image_size, image_addr))) => {
-                            if image_addr != *client_address {
-                                println!("Unexpected image data from {}, ignoring.", image_addr);
-                                continue;
-                            }
+        let datagram = match timeout(timeout_duration, feed.recv()).await {
+            Ok(Some(datagram)) => datagram,
+            Ok(None) => break,
+            Err(_) => {
+                eprintln!("Timeout while waiting for samples from client: {}", client_id);
+                break;
+            }
+        };
 
-                            // Save the image
-                            let image_path = format!("{}/{}.jpg", samples_dir, image_id); // Use image_id without extension
-                            std::fs::write(&image_path, &buffer[..image_size])?;
-                            println!("Saved sample image: {}", image_path);
+        if datagram.is_empty() {
+            continue;
+        }
 
-                            received_files.push((image_id.to_string(), buffer[..image_size].to_vec()));
+        match protocol::decode(&datagram) {
+            Ok(Message::SampleUpload { image_id, data, .. }) => {
+                println!("Received sample upload: {}", image_id);
 
-                            // Send acknowledgment
-                            
-                            // socket.lock().await.send_to(b"idkk", client_address).await?;
-                            println!("ACK sent for sample: {}", image_id);
-                        }
-                        Ok(Err(e)) => {
-                            eprintln!("Failed to receive image data: {:?}", e);
-                            continue;
-                        }
+                // Legacy clients send the image data in a separate datagram
+                let data = if data.is_empty() {
+                    match timeout(timeout_duration, feed.recv()).await {
+                        Ok(Some(image_data)) => image_data,
+                        Ok(None) => break,
                         Err(_) => {
                             eprintln!("Timeout while waiting for image data from client: {}", client_id);
                             break;
                         }
                     }
-                } else if message == "END_SAMPLES" {
-                    println!("All samples received from client: {}", client_id);
-
-                    // Distribute samples to peers
-                    distribute_samples_to_peers(socket, peers, client_id, &received_files).await?;
-                    break;
                 } else {
-                    println!("Unknown message: {}", message);
+                    data
+                };
+
+                // The IDs become part of the path, so they can't leave the samples folder
+                let sample = SampleEntry {
+                    client_id: client_id.to_string(),
+                    image_id: image_id.clone(),
+                };
+                if !sample.is_safe() {
+                    println!("Dropping sample {:?} from client {:?}, not a valid sample name", image_id, client_id);
+                    continue;
                 }
+
+                // Save the image
+                std::fs::create_dir_all(samples_root.join(client_id))?;
+                let image_path = sample.path(samples_root);
+                std::fs::write(&image_path, &data)?;
+                println!("Saved sample image: {}", image_path.display());
+                storage.add_sample(client_id, &image_id)?;
+                // Uploading the sample makes the client the image's owner
+                let shared_id = ownership::shared_id(client_id, &image_id);
+                if storage.image_owner(&shared_id).is_none() {
+                    if let Err(e) = replicator.propose(ownership::claim(client_id, &shared_id)).await {
+                        eprintln!("Failed to record the owner of image {}: {}", shared_id, e);
+                    }
+                }
+
+                received_files.push((image_id, data));
+                println!("ACK sent for sample: {}", received_files.len());
             }
-            Ok(Err(e)) => {
-                eprintln!("Failed to receive data: {:?}", e);
-                continue;
-            }
-            Err(_) => {
-                eprintln!("Timeout while waiting for samples from client: {}", client_id);
+            Ok(Message::EndSamples) => {
+                println!("All samples received from client: {}", client_id);
+
+                // Distribute samples to peers
+                distribute_samples_to_peers(socket, peers, client_id, &received_files).await?;
                 break;
             }
+            Ok(other) => println!("Unknown message: {:?}", other),
+            Err(e) => println!("Unknown message: {}", e),
         }
     }
 
@@ -127,1062 +134,975 @@ pub async fn receive_samples(
     Ok(())
 }
 
-
-// Function to distribute samples to peers
 // Function to distribute samples to peers
 async fn distribute_samples_to_peers(
-    socket: &Arc<tokio::sync::Mutex<UdpSocket>>,
-    peers: &[String],
+    socket: &UdpSocket,
+    peers: &[SocketAddr],
     client_id: &str,
     received_files: &[(String, Vec<u8>)], // File names (image_id) and their data
 ) -> io::Result<()> {
     for (image_id, image_data) in received_files {
-        for peer in peers.clone() {
-            let metadata_message = format!("SAMPLE_SYNC:{}:{}", client_id, image_id);
-            socket.lock().await.send_to(metadata_message.as_bytes(), peer).await?;
-            // println!("Sent SAMPLE_SYNC metadata to {}", peer);
-
-            socket.lock().await.send_to(image_data, peer).await?;
-            // println!("Sent file {} to {}", image_id, peer);
+        for peer in peers {
+            let sync_message = Message::SampleSync {
+                client_id: client_id.to_string(),
+                image_id: image_id.clone(),
+                data: image_data.clone(),
+            };
+            send_message(socket, &sync_message, *peer).await?;
         }
     }
 
     Ok(())
 }
 
+// Answers a request handled in its own task. The control socket is held by
+// the control loop while it waits for the next datagram, so the answer goes
+// out from a socket of its own.
+async fn answer_from_task(answer: io::Result<Message>, to: SocketAddr) {
+    let answer = answer.unwrap_or_else(|e| {
+        eprintln!("Failed to handle the request from {}: {}", to, e);
+        Message::AuthFailed {
+            reason: "server error, try again".to_string(),
+        }
+    });
+    let sent = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(socket) => faults::send_to(&socket, &protocol::encode(&answer), to).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = sent {
+        eprintln!("Failed to answer {}: {}", to, e);
+    }
+}
 
-pub async fn middleware() -> io::Result<()> {
-    let my_address = "127.0.0.1";
-    let client1 = "127.0.0.1";
-    let client2 = "127.0.0.1";
+// Encrypts one upload, here or on the server the scheduling picks, and
+// sends the result back to the client it came from
+async fn encrypt_and_return(
+    upload: Upload,
+    encryptor: &Encryptor,
+    elector: &Elector,
+    journal: &Journal,
+    storage: &Storage,
+    config: &ServerConfig,
+) {
+    let client_addr = upload.from;
+    if !encryptor.lifecycle.is_running() {
+        println!("Dropping upload {} from {}, server is {:?}", upload.transfer_id, client_addr, encryptor.lifecycle.state());
+        upload.remove();
+        return;
+    }
+    let image_data = match upload.read() {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read upload {}: {:?}", upload.path.display(), e);
+            return;
+        }
+    };
+    upload.remove();
+
+    let request = match EncryptRequest::from_bytes(&image_data) {
+        Ok(request) => request,
+        Err(e) => {
+            eprintln!("Invalid upload from {}, dropping it: {}", client_addr, e);
+            return;
+        }
+    };
+    // Uploads are sealed by their owner, so there is no image format to check
+    if request.image.is_empty() {
+        eprintln!("Empty upload from {}, dropping it.", client_addr);
+        return;
+    }
 
-    
-    let mysocket = format!("{}:8082", my_address);
-    let peers = vec!["127.0.0.1:8084", "127.0.0.1:2010"];
-    let client_address1 = format!("{}:2005",client1);
-    let client_address2 = format!("{}:7001",client2);
-    let socket_client = Arc::new(tokio::sync::Mutex::new(UdpSocket::bind(mysocket.clone()).await?));
+    let known = config.client_by_leader_ack_addr(&client_addr).cloned();
+    let client = match known.or_else(|| storage.client_by_leader_ack_addr(&client_addr)) {
+        Some(endpoint) => endpoint.image_addr,
+        None => {
+            eprintln!("Image from unknown client {}, dropping it.", client_addr);
+            return;
+        }
+    };
+
+    // A client that lost patience sends the request again
+    let job_id = request.request_id;
+    let Some(running) = journal.start(job_id, client).await else {
+        println!("Request {} from {} is running already, dropping the retry", job_id, client_addr);
+        return;
+    };
+    let image_data = request.image;
+    let result = match config.scheduling {
+        Scheduling::Leader => encryptor.encrypt_and_send(job_id, image_data, client).await,
+        Scheduling::LeastLoaded => {
+            jobs::dispatch(encryptor, config.server_id, elector.loads(), job_id, image_data, client).await
+        }
+    };
+    match result {
+        Ok(()) => running.finish().await,
+        // Left in the journal, the client tries the next leader
+        Err(e) => eprintln!("Failed to return upload {} to {}: {:?}", job_id, client, e),
+    }
+}
 
-    let socket6 = Arc::new(tokio::sync::Mutex::new(
-        UdpSocket::bind(format!("{}:2002",my_address)).await?,
-    ));
-    let socket_election = Arc::new(tokio::sync::Mutex::new(
-        UdpSocket::bind(format!("{}:8083", my_address)).await?,
+// What the control requests handled in their own task share
+#[derive(Clone)]
+struct Control {
+    socket: Arc<UdpSocket>,
+    storage: Arc<Storage>,
+    replicator: Arc<Replicator>,
+    peers: Arc<Mutex<Vec<SocketAddr>>>,
+    samples_root: PathBuf,
+    sample_feeds: SampleFeeds,
+    // Upload address, sent back in STATUS_ACK
+    upload_addr: String,
+}
+
+async fn access_control(
+    control: &Control,
+    addr: SocketAddr,
+    sender: String,
+    client_id: String,
+    image_id: String,
+    views: u32,
+    grant: Option<SignedGrant>,
+) {
+    let (storage, replicator, socket) = (&control.storage, &control.replicator, &*control.socket);
+    println!(
+        "Received Access Control request for client ID: {}, image ID: {}, new views: {}",
+        client_id, image_id, views
+    );
+
+    // Only the image's owner can change who sees it
+    if let Err(reason) = ownership::check_access(storage, &sender, &image_id, grant.as_ref()) {
+        println!("Refusing access control from client {}: {}", sender, reason);
+        let refused = Message::AuthFailed { reason };
+        if let Err(e) = send_message(socket, &refused, addr).await {
+            eprintln!("Failed to refuse the access control from {}: {}", addr, e);
+        }
+        return;
+    }
+
+    // Older grants stop counting, even if this one never reaches the viewer
+    if let Some(grant) = &grant {
+        let newest = Change::NewGrant {
+            image_id: image_id.clone(),
+            viewer: grant.grant.viewer.clone(),
+            nonce: grant.grant.nonce,
+        };
+        if let Err(e) = replicator.propose(newest).await {
+            eprintln!("Failed to record the new grant on {}: {}", image_id, e);
+        }
+    }
+
+    // Map client ID to IP address from directory of service
+    match storage.client(&client_id) {
+        None => println!("Client ID not found in directory of service."),
+        Some(entry) if !entry.status => {
+            println!("Client ID is offline.");
+
+            let request = PendingRequest {
+                client_id: client_id.clone(),
+                image_id: image_id.clone(),
+                views,
+                grant: grant.clone(),
+            };
+            // Replicated to the other servers, whichever the client comes back to
+            if let Err(e) = replicator.propose(Change::AddPending(request)).await {
+                eprintln!("Failed to store offline request: {}", e);
+                return;
+            }
+            println!(
+                "Offline request stored: client_id={}, image_id={}, views={}",
+                client_id, image_id, views
+            );
+        }
+        Some(entry) => {
+            let client_ip = entry.ip;
+            println!("Client ID is online at IP: {}", client_ip);
+
+            // Send the IP address back to the client
+            let message_to_client = Message::AccessControlAck {
+                client_ip: client_ip.clone(),
+            };
+            match send_message(socket, &message_to_client, addr).await {
+                Ok(_) => println!("Access control ACK sent to {}", addr),
+                Err(e) => eprintln!("Failed to send the access control ACK to {}: {}", addr, e),
+            }
+
+            // Send control update to target client
+            let update_message = Message::ControlUpdate {
+                client_id,
+                image_id,
+                views,
+                grant,
+            };
+            match client_ip.parse::<SocketAddr>() {
+                Ok(client_addr) => {
+                    match send_message(socket, &update_message, client_addr).await {
+                        Ok(_) => println!("Control update sent to client at IP: {}", client_ip),
+                        Err(e) => eprintln!("Failed to send the control update to {}: {}", client_ip, e),
+                    }
+                }
+                Err(e) => eprintln!("Invalid client address {}: {}", client_ip, e),
+            }
+        }
+    }
+}
+
+async fn status(
+    control: &Control,
+    addr: SocketAddr,
+    sender: Option<String>,
+    online_status: OnlineStatus,
+    endpoint: Option<ClientEndpoint>,
+    signature: MessageSignature,
+) {
+    let (storage, replicator, socket) = (&control.storage, &control.replicator, &*control.socket);
+    println!("Received OnlineStatus: {:?}", online_status);
+    // Clients can only report their own status
+    if sender.as_deref() != Some(online_status.client_id.as_str()) {
+        println!("Session of {:?} used for client {}", sender, online_status.client_id);
+        let refused = Message::AuthFailed {
+            reason: "session belongs to another client".to_string(),
+        };
+        if let Err(e) = send_message(socket, &refused, addr).await {
+            eprintln!("Failed to refuse the STATUS from {}: {}", addr, e);
+        }
+        return;
+    }
+    let client_id = &online_status.client_id;
+    let signed = (&online_status, &endpoint);
+    if let Err(e) = accounts::check_signature(storage, client_id, signing::STATUS, &signed, &signature) {
+        println!("Refusing STATUS for client {}: {}", client_id, e);
+        let refused = Message::AuthFailed {
+            reason: format!("bad signature: {}", e),
+        };
+        if let Err(e) = send_message(socket, &refused, addr).await {
+            eprintln!("Failed to refuse the STATUS from {}: {}", addr, e);
+        }
+        return;
+    }
+    match replicator.propose(Change::SetStatus(online_status.clone())).await {
+        Ok(()) => println!(
+            "Directory update handed to the replicated log (leader: {:?})",
+            replicator.leader()
+        ),
+        Err(e) => eprintln!("Failed to update directory of service: {}", e),
+    }
+    // Where its encrypted images go, for clients not in the config
+    if let Some(endpoint) = endpoint.filter(|endpoint| storage.endpoint(client_id).as_ref() != Some(endpoint)) {
+        let known = Change::SetEndpoint(KnownEndpoint {
+            client_id: client_id.clone(),
+            endpoint,
+        });
+        if let Err(e) = replicator.propose(known).await {
+            eprintln!("Failed to record the endpoint of client {}: {}", client_id, e);
+        }
+    }
+
+    // The client sends its samples once acknowledged. A STATUS sent
+    // again while they come in doesn't start another receiver.
+    let mut feed = None;
+    if online_status.status {
+        if let Entry::Vacant(entry) = control.sample_feeds.lock().unwrap().entry(addr) {
+            let (tx, rx) = mpsc::channel(SAMPLE_FEED_CAPACITY);
+            entry.insert(tx);
+            feed = Some(rx);
+        }
+    }
+
+    // Acknowledge the message
+    let message_to_client = Message::StatusAck {
+        server: control.upload_addr.clone(),
+    };
+    match send_message(socket, &message_to_client, addr).await {
+        Ok(_) => println!("Ack sent to {}", addr),
+        Err(e) => eprintln!("Failed to acknowledge the STATUS from {}: {}", addr, e),
+    }
+
+    // Deliver one pending request now that the client is online
+    if online_status.status {
+        let client_addr = online_status.ip.parse::<SocketAddr>();
+        match (storage.pending_for(&online_status.client_id), client_addr) {
+            (Some(request), Ok(client_addr)) => {
+                println!(
+                    "Processing offline request for client_id={}, image_id={}, views={}",
+                    request.client_id, request.image_id, request.views
+                );
+                let taken = Change::TakePending {
+                    client_id: request.client_id.clone(),
+                };
+                if let Err(e) = replicator.propose(taken).await {
+                    eprintln!("Failed to remove offline request: {}", e);
+                }
+
+                // Send CONTROL_UPDATE to the client
+                let update_message = Message::ControlUpdate {
+                    client_id: request.client_id,
+                    image_id: request.image_id,
+                    views: request.views,
+                    grant: request.grant,
+                };
+                println!("Sending CONTROL_UPDATE to client: {:?}", update_message);
+
+                match send_message(socket, &update_message, client_addr).await {
+                    Ok(_) => println!("Sent CONTROL_UPDATE to {}", client_addr),
+                    Err(e) => eprintln!("Failed to send CONTROL_UPDATE to {}: {}", client_addr, e),
+                }
+            }
+            (None, _) => {}
+            (Some(_), Err(e)) => eprintln!(
+                "Invalid address {} for client_id={}: {}",
+                online_status.ip, online_status.client_id, e
+            ),
+        }
+    }
+    if let Some(mut feed) = feed {
+        let peers = control.peers.lock().unwrap().clone();
+        let received = receive_samples(
+            socket,
+            &mut feed,
+            &online_status.client_id,
+            &peers,
+            &control.samples_root,
+            storage,
+            replicator,
+        );
+        if let Err(e) = received.await {
+            eprintln!("Failed to receive samples: {:?}", e);
+        }
+        control.sample_feeds.lock().unwrap().remove(&addr);
+    }
+}
+
+pub async fn middleware(config: ServerConfig) -> io::Result<()> {
+    std::fs::create_dir_all(&config.data_dir)?;
+    let config = Arc::new(config);
+    // Every socket of the server honors the faults failure_simulation sends
+    let faults = Arc::new(Faults::default());
+    faults::install(Arc::clone(&faults) as Arc<dyn faults::FaultHook>)?;
+
+    let mysocket = config.upload_addr.to_string();
+    let samples_root = config.data_path(SAMPLES_DIR);
+    let storage = Arc::new(Storage::open(&config.data_path(STORE_DIR))?);
+    let me = ServerInfo {
+        id: config.server_id,
+        addrs: MemberAddrs {
+            control: config.control_addr.to_string(),
+            gossip: config.gossip_addr.to_string(),
+            election: config.election_addr.to_string(),
+            replication: config.replication_addr.to_string(),
+        },
+    };
+    // A new server starts from a member's snapshot instead of the CSV files
+    if let (Some(seed), true) = (config.join, storage.is_empty()) {
+        cluster::join(seed, &me, config.cluster_key.as_deref(), &storage, &samples_root).await?;
+    }
+    // The CSV files are left until their changes commit, so a server that
+    // stopped before that imports them again
+    let directory_csv = config.data_path(DIRECTORY_OF_SERVICE);
+    let offline_csv = config.data_path(OFFLINE_REQUESTS);
+    let imported = match storage.is_empty() || storage::legacy_left(&directory_csv, &offline_csv) {
+        true => storage::import_legacy(&storage, &directory_csv, &offline_csv, &samples_root)?,
+        false => Vec::new(),
+    };
+
+    // The directory and pending requests only change through the replicated log
+    let replicator = Arc::new(Replicator::new(
+        UdpSocket::bind(config.replication_addr).await?,
+        config.replication_peers.clone(),
+        &config.data_path(RAFT_DIR),
+        Arc::clone(&storage),
+        ReplicationConfig::default(),
+    )?);
+    let replicator_run = Arc::clone(&replicator);
+    tokio::spawn(async move {
+        if let Err(e) = replicator_run.run().await {
+            eprintln!("Replication stopped: {}", e);
+        }
+    });
+    // Proposals wait for a leader, which the servers elect once they all run
+    let replicator_start = Arc::clone(&replicator);
+    let listed = storage.servers().0.contains(&me);
+    let me_start = me.clone();
+    tokio::spawn(async move {
+        for change in imported {
+            while let Err(e) = replicator_start.propose(change.clone()).await {
+                eprintln!("Failed to replicate the imported data, trying again: {}", e);
+            }
+        }
+        if let Err(e) = storage::finish_import(&directory_csv, &offline_csv) {
+            eprintln!("Failed to rename the imported CSV files: {}", e);
+        }
+        // Lets the servers that have this one in their config but not on the
+        // list find it. Others only take it in through JOIN.
+        if !listed {
+            if let Err(e) = replicator_start.propose(Change::AddServer(me_start)).await {
+                eprintln!("Could not add this server to the list, start it with --join: {}", e);
+            }
+        }
+    });
+    let pool = EncryptionPool::new(
+        &config.mask_image,
+        config.encryption_workers,
+        config.data_path(&config.timings_file),
+    )?;
+    println!("Encrypting with {} workers", config.encryption_workers);
+    let transfers = ActiveTransfers::default();
+
+    let elector = Arc::new(Elector::new(
+        UdpSocket::bind(config.election_addr).await?,
+        config.server_id,
+        mysocket.clone(),
+        config.job_addr.to_string(),
+        config.election_peers.clone(),
+        load::build(config.load_metric, &pool, &transfers),
+        ElectionConfig::default(),
     ));
-    
-    let socketsendipback = Arc::new(tokio::sync::Mutex::new(
-        UdpSocket::bind(format!("{}:8086", my_address)).await?,
+    let elector_run = Arc::clone(&elector);
+    let elector_jobs = Arc::clone(&elector);
+    tokio::spawn(async move {
+        if let Err(e) = elector_run.run().await {
+            eprintln!("Election stopped: {}", e);
+        }
+    });
+
+    // The failure detector's view decides when the leaders are given up on
+    let gossiper = Arc::new(Gossiper::new(
+        UdpSocket::bind(config.gossip_addr).await?,
+        config.server_id,
+        me.addrs.clone(),
+        config.gossip_peers.clone(),
+        MembershipConfig::default(),
     ));
-    
-    let failure_socket = Arc::new(tokio::sync::Mutex::new(
-        UdpSocket::bind(format!("{}:9000", my_address)).await?,
+    let gossiper_run = Arc::clone(&gossiper);
+    tokio::spawn(async move {
+        if let Err(e) = gossiper_run.run().await {
+            eprintln!("Failure detector stopped: {}", e);
+        }
+    });
+    let mut view = gossiper.subscribe();
+    let elector_view = Arc::clone(&elector);
+    let replicator_view = Arc::clone(&replicator);
+    let faults_view = Arc::clone(&faults);
+    tokio::spawn(async move {
+        while view.changed().await.is_ok() {
+            let current = view.borrow_and_update().clone();
+            elector_view.set_liveness(current.liveness());
+            replicator_view.set_dead(current.dead_replication_addrs());
+            faults_view.set_members(&current);
+        }
+    });
+
+    // Every task follows the lifecycle: nothing while failed, catching up
+    // while recovering
+    let lifecycle = Lifecycle::default();
+    let mut state = lifecycle.subscribe();
+    let replicator_state = Arc::clone(&replicator);
+    let elector_state = Arc::clone(&elector);
+    let gossiper_state = Arc::clone(&gossiper);
+    tokio::spawn(async move {
+        while state.changed().await.is_ok() {
+            let current = *state.borrow_and_update();
+            replicator_state.set_state(current);
+            elector_state.set_state(current);
+            gossiper_state.set_state(current);
+        }
+    });
+
+    // Peers follow the replicated server list from here on
+    let control_peers = Arc::new(Mutex::new(config.peers.clone()));
+    let configured = Peers {
+        control: config.peers.clone(),
+        election: config.election_peers.clone(),
+        replication: config.replication_peers.clone(),
+    };
+    tokio::spawn(cluster::follow_members(
+        config.server_id,
+        configured,
+        Arc::clone(&storage),
+        Arc::clone(&replicator),
+        Arc::clone(&elector),
+        Arc::clone(&gossiper),
+        Arc::clone(&control_peers),
     ));
-    
-    let directory_socket = Arc::new(tokio::sync::Mutex::new(
-        UdpSocket::bind(format!("{}:2011", my_address)).await?,
+    let journal = Arc::new(Journal::new(config.server_id, Arc::clone(&storage), Arc::clone(&replicator)));
+    let journal_sweep = Arc::clone(&journal);
+    let elector_sweep = Arc::clone(&elector);
+    tokio::spawn(async move { journal_sweep.run(&elector_sweep).await });
+    let storage_leave = Arc::clone(&storage);
+    let server_id = config.server_id;
+    let cluster_key = config.cluster_key.clone();
+    let gossiper_control = Arc::clone(&gossiper);
+    let peers_control = Arc::clone(&control_peers);
+
+    let socket_client = Arc::new(tokio::sync::Mutex::new(UdpSocket::bind(config.upload_addr).await?));
+
+    let socket6 = Arc::new(tokio::sync::Mutex::new(
+        UdpSocket::bind(config.image_addr).await?,
     ));
-    
-    let fail_flag = Arc::new(Mutex::new(false));
-    let fail_flag_clone = Arc::clone(&fail_flag);
+    let encryptor = Encryptor {
+        pool,
+        transfers: transfers.clone(),
+        socket6,
+        lifecycle: lifecycle.clone(),
+    };
+    println!("Scheduling encryption jobs: {:?}", config.scheduling);
+
+    // Jobs handed out by the leader, used with `least_loaded` scheduling
+    let job_socket = Arc::new(UdpSocket::bind(config.job_addr).await?);
+    let job_encryptor = encryptor.clone();
+    let lifecycle_jobs = lifecycle.clone();
+    tokio::spawn(async move {
+        loop {
+            lifecycle_jobs.until_running().await;
+            tokio::select! {
+                result = jobs::receive_jobs(Arc::clone(&job_socket), job_encryptor.clone()) => {
+                    if let Err(e) = result {
+                        eprintln!("Job receiver stopped: {:?}", e);
+                    }
+                    return;
+                }
+                _ = lifecycle_jobs.until_stopped() => {}
+            }
+            // Half-received jobs are lost, the leader hands them to another server
+            lifecycle_jobs.drain_until_running(&job_socket).await;
+        }
+    });
+    // Requests that wait on the replicated log or on a client are handled in
+    // tasks of their own, which answer from this socket too
+    let socket_election = Arc::new(UdpSocket::bind(config.control_addr).await?);
+
+    let socketsendipback = UdpSocket::bind(config.callback_addr).await?;
 
-    let mut leader = false;
-    let fail_flag_clone_for_failure = Arc::clone(&fail_flag);
+    let failure_socket = Arc::new(tokio::sync::Mutex::new(
+        UdpSocket::bind(config.failure_addr).await?,
+    ));
 
-    let socket_clone = Arc::clone(&socket_client);
+    let lifecycle_control = lifecycle.clone();
+    let lifecycle_failure = lifecycle.clone();
+    let replicator_failure = Arc::clone(&replicator);
+    let elector_failure = Arc::clone(&elector_jobs);
+    let config_election = Arc::clone(&config);
+    let storage_uploads = Arc::clone(&storage);
+    let control = Control {
+        socket: Arc::clone(&socket_election),
+        storage: Arc::clone(&storage),
+        replicator: Arc::clone(&replicator),
+        peers: Arc::clone(&peers_control),
+        samples_root: samples_root.clone(),
+        sample_feeds: SampleFeeds::default(),
+        upload_addr: mysocket.clone(),
+    };
     tokio::spawn(async move {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
         loop {
-            let mut buffer = [0u8; 2048];
-            let (size, addr) = socket_election
-                .lock()
-                .await
-                .recv_from(&mut buffer)
-                .await
-                .unwrap();
-            let message = String::from_utf8_lossy(&buffer[..size]);
-            let fail_flag_value = *fail_flag_clone.lock().unwrap();
-        
-            if message == "ELECT" && !fail_flag_value {
-                println!(
-                    "Election request received from {}. Initiating election...",
-                    addr
-                );
-
-                match server_election(&socket_election, peers.clone()).await {
-                    Ok(is_leader) => {
-                        if is_leader {
-                            println!("This server is the leader.");
-                            leader = true;
-
-                            let message_to_client = format!("LEADER_ACK:{}", mysocket.clone());
-                            if addr.to_string() == client_address1{
-                                println!("Client 1 wesel");
-                                socketsendipback
-                                    .lock()
-                                    .await
-                                    .send_to(message_to_client.as_bytes(),format!("{}:9080",client1))
-                                    .await
-                                    .unwrap();
-                                } else if addr.to_string() == client_address2{
-                                    println!("Client 2 wesel");
-                                    socketsendipback
-                                    .lock()
-                                    .await
-                                    .send_to(message_to_client.as_bytes(), format!("{}:7005",client2))
-                                    .await
-                                    .unwrap();
-                                }
-                        } else {
-                            println!("This server is not the leader.");
-                            leader = false;
-                        }
+            let (size, addr) = match faults::recv_from(&socket_election, &mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive on the control socket: {}", e);
+                    continue;
+                }
+            };
+            let decoded = protocol::decode(&buffer[..size]);
+            // Samples, and the raw image data legacy clients send after each,
+            // go to the task receiving that client's samples
+            let feed = control.sample_feeds.lock().unwrap().get(&addr).cloned();
+            if let Some(feed) = feed {
+                if matches!(decoded, Ok(Message::SampleUpload { .. } | Message::EndSamples) | Err(_)) {
+                    if let Err(e) = feed.try_send(buffer[..size].to_vec()) {
+                        eprintln!("Dropping a sample datagram from {}: {}", addr, e);
                     }
-                    Err(e) => eprintln!("Election failed: {:?}", e),
+                    continue;
                 }
-            } 
-
-            else if message.starts_with("Access_Control:") {
-                // Extract the message content after "Access_Control:"
-                let control_data = message.strip_prefix("Access_Control:").unwrap_or("").to_string();
-            
-                // Split the message into components (client_id, image_part1, image_part2, views)
-                let parts: Vec<&str> = control_data.split('_').collect();
-                if parts.len() == 4 {
-                    let client_id = parts[0].to_string();
-                    let image_part1 = parts[1].to_string();
-                    let image_part2 = parts[2].to_string();
-                    let views = parts[3].to_string();  // The new number of views
-            
-                    println!("Received Access Control request for client ID: {}, image ID: {}_{} (combined), new views: {}", client_id, image_part1, image_part2, views);
-            
-                    // Store the values in variables for further use
-                    let stored_client_id = client_id;
-                    let stored_image_id = format!("{}_{}", image_part1, image_part2);  // Combine the two parts of the image ID
-                    let stored_views = views;
-            
-                    // Print the stored variables for debugging
-                    println!("Stored client_id: {}, image_id: {}, views: {}", stored_client_id, stored_image_id, stored_views);
-            
-                    // Map client ID to IP address from directory of service
-                    let file_path = "directory_of_service.csv";
-                    let mut client_ip = String::new();
-                    let mut client_status = false;
-            
-                    if Path::new(file_path).exists() {
-                        if let Ok(content) = fs::read_to_string(file_path) {
-                            for line in content.lines() {
-                                // Skip the header
-                                if line.starts_with("uid,client_id,status") {
-                                    continue;
-                                }
-            
-                                let fields: Vec<&str> = line.split(',').collect();
-                                if fields.len() == 3 {
-                                    if fields[1] == stored_client_id {
-                                        client_ip = fields[0].to_string();
-                                        client_status = fields[2] == "true";
-                                        break;
-                                    }
-                                }
+            }
+            let message = match decoded {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("Failed to decode message from {}: {}", addr, e);
+                    continue;
+                }
+            };
+            // A recovering server only takes in what the other servers send
+            // and waits with clients' requests until it caught up
+            match (lifecycle_control.state(), &message) {
+                (State::Running, _) => {}
+                (
+                    State::Recovering,
+                    Message::SampleSync { .. }
+                    | Message::DirOfServ(_)
+                    | Message::OfflineWanted { .. }
+                    | Message::Leave { .. },
+                ) => {}
+                (state, _) => {
+                    println!("Server is {:?}, dropping message from {}", state, addr);
+                    continue;
+                }
+            }
+
+            // Requests from clients need an open session, which tells who sent them
+            let sender = match &message {
+                Message::Status { token, .. }
+                | Message::RequestDos { token }
+                | Message::AccessControl { token, .. }
+                | Message::RecordView { token, .. } => {
+                    match accounts::session_client(&storage, token) {
+                        Some(client_id) => Some(client_id),
+                        None => {
+                            println!("Request from {} without a valid session", addr);
+                            let refused = Message::AuthFailed {
+                                reason: "no valid session, log in again".to_string(),
+                            };
+                            if let Err(e) = send_message(&socket_election, &refused, addr).await {
+                                eprintln!("Failed to refuse the request from {}: {}", addr, e);
                             }
+                            continue;
                         }
                     }
-            
-                    // Check if the client is online
-                    if client_ip.is_empty() {
-                        println!("Client ID not found in directory of service.");
-                    } 
-                    else if !client_status {
-                        println!("Client ID is offline.");
-
-                        // Define the path to the offline requests file
-                        let offline_requests_file = "offline_access_control_requests.csv";
-                    
-                        // Check if the file exists, and create it with a header if it doesn't
-                        if !Path::new(offline_requests_file).exists() {
-                            let mut file = File::create(offline_requests_file)
-                                .await
-                                .expect("Failed to create offline requests file");
-                            file.write_all(b"client_id,image_id,views\n")
-                                .await
-                                .expect("Failed to write header to offline requests file");
-                        }
-                    
-                        // Append the new request to the file
-                        let mut file = OpenOptions::new()
-                            .write(true)
-                            .append(true)
-                            .open(offline_requests_file)
-                            .expect("Failed to open offline requests file for appending");
-                    
-                        writeln!(
-                            file,
-                            "{},{},{}",
-                            stored_client_id, stored_image_id, stored_views
-                        )
-                        .expect("Failed to write offline request to file");
-                    
-                        println!(
-                            "Offline request stored: client_id={}, image_id={}, views={}",
-                            stored_client_id, stored_image_id, stored_views
-                        );
-                    
-                        // Send "OFFLINE_WANTED" message to other servers using the `peers` list
-                        let offline_message = format!(
-                            "OFFLINE_WANTED:{}:{}:{}",
-                            stored_client_id, stored_image_id, stored_views
-                        );
-                    
-                        for peer in &peers {
-                            socket_election
-                                .lock()
-                                .await
-                                .send_to(offline_message.as_bytes(), peer)
-                                .await
-                                .unwrap();
-                            println!("Sent OFFLINE_WANTED to server at {}", peer);
-                        }
+                }
+                _ => None,
+            };
+            // What other servers forward is only taken from them
+            let from_server = matches!(
+                message,
+                Message::DirOfServ(_) | Message::OfflineWanted { .. } | Message::SampleSync { .. }
+            );
+            if from_server && !peers_control.lock().unwrap().contains(&addr) {
+                println!("Dropping a server update from {}, which is not a known server", addr);
+                continue;
+            }
 
-                    }  else {
-                        println!("Client ID is online at IP: {}", client_ip);
-            
-                        // Send the IP address back to the client
-                        let message_to_client = format!("Access_Control_ACK:{}", client_ip);
-                        socket_election
-                            .lock()
-                            .await
-                            .send_to(message_to_client.as_bytes(), addr)
-                            .await
-                            .unwrap();
-                        println!("Access control ACK sent to {}", addr);
-
-                        // Send control update to target client
-                        let update_message = format!("CONTROL_UPDATE:{}:{}:{}", stored_client_id, stored_image_id, stored_views);
-                        socket_election
-                            .lock()
-                            .await
-                            .send_to(update_message.as_bytes(), client_ip.clone())
-                            .await
-                            .unwrap();
-                        println!("Control update sent to client at IP: {}", client_ip);
+            match message {
+                Message::Register { password, public_key } => {
+                    let storage = Arc::clone(&storage);
+                    let replicator = Arc::clone(&replicator);
+                    tokio::spawn(async move {
+                        let answer = accounts::register(&storage, &replicator, password, public_key).await;
+                        answer_from_task(answer, addr).await;
+                    });
+                }
+
+                Message::Login { client_id, password, public_key } => {
+                    let storage = Arc::clone(&storage);
+                    let replicator = Arc::clone(&replicator);
+                    tokio::spawn(async move {
+                        let answer = accounts::login(&storage, &replicator, client_id, password, public_key).await;
+                        answer_from_task(answer, addr).await;
+                    });
+                }
+
+                Message::RecordView { grant, .. } => {
+                    let storage = Arc::clone(&storage);
+                    let replicator = Arc::clone(&replicator);
+                    let viewer = sender.unwrap_or_default();
+                    tokio::spawn(async move {
+                        let answer = view_counts::record(&storage, &replicator, &viewer, grant).await;
+                        answer_from_task(answer, addr).await;
+                    });
+                }
 
+                Message::CheckSession { token } => {
+                    let client_id = accounts::session_client(&storage, &token);
+                    if let Err(e) = send_message(&socket_election, &Message::SessionOwner { token, client_id }, addr).await {
+                        eprintln!("Failed to answer the session check from {}: {}", addr, e);
                     }
-            
-                    // Now, you can add your access control logic here:
-                    // For example, check the `stored_client_id`, `stored_image_id`, and update the image's view count
-                } else {
-                    println!("Invalid access control format received.");
                 }
-            }
-            
-            
-            
-            else if message.starts_with("DIR_OF_SERV") {
-                // Extract the JSON payload from the message
-                if let Some(json_payload) = message.strip_prefix("DIR_OF_SERV:") {
-                    match serde_json::from_str::<OnlineStatus>(json_payload) {
-                        Ok(online_status) => {
-                            println!("Received OnlineStatus from peer: {:?}", online_status);
-            
-                            let file_path = "directory_of_service.csv";
-                            let mut is_duplicate = false;
-                            let mut needs_update = false;
-                            let mut updated_records = Vec::new();
-            
-                            // Check if the file exists and process existing records
-                            if Path::new(file_path).exists() {
-                                if let Ok(content) = fs::read_to_string(file_path) {
-                                    for line in content.lines() {
-                                        // Skip the header
-                                        if line.starts_with("uid,client_id,status") {
-                                            updated_records.push(line.to_string());
-                                            continue;
-                                        }
-            
-                                        let mut fields: Vec<&str> = line.split(',').collect();
-                                        if fields.len() == 3 {
-                                            if fields[0] == online_status.ip {
-                                                is_duplicate = true;
-            
-                                                // Check if the status has changed
-                                                let existing_status = fields[2] == "true";
-                                                if existing_status != online_status.status {
-                                                    fields[2] = if online_status.status {
-                                                        "true"
-                                                    } else {
-                                                        "false"
-                                                    };
-                                                    needs_update = true;
-                                                    println!(
-                                                        "Updating record for UID/IP: {} from {} to {}",
-                                                        online_status.ip,
-                                                        existing_status,
-                                                        online_status.status
-                                                    );
-                                                }
-                                            }
-                                        }
-            
-                                        // Add either the updated or original record to the list
-                                        updated_records.push(fields.join(","));
-                                    }
-                                }
-                            }
-            
-                            // Write updated data back to the CSV if needed
-                            if is_duplicate && needs_update {
-                                let mut file = match OpenOptions::new()
-                                    .write(true)
-                                    .truncate(true)
-                                    .open(file_path)
-                                {
-                                    Ok(file) => file,
-                                    Err(e) => {
-                                        eprintln!("Failed to open file for updating: {}", e);
-                                        return;
-                                    }
-                                };
-            
-                                for record in &updated_records {
-                                    if let Err(e) = writeln!(file, "{}", record) {
-                                        eprintln!("Failed to write record: {}", e);
-                                    }
-                                }
-                                println!("CSV file updated successfully.");
-                            } else if !is_duplicate {
-                                // Append the new record if it's not a duplicate
-                                let record = format!(
-                                    "{},{},{}\n",
-                                    online_status.ip,
-                                    online_status.client_id,
-                                    online_status.status
-                                );
-            
-                                let file_exists = Path::new(file_path).exists();
-                                let mut wtr = match OpenOptions::new()
-                                    .create(true)
-                                    .append(true)
-                                    .open(file_path)
-                                {
-                                    Ok(file) => file,
-                                    Err(e) => {
-                                        eprintln!("Failed to open file: {}", e);
-                                        return;
-                                    }
-                                };
-            
-                                if !file_exists {
-                                    if let Err(e) = wtr.write_all(b"uid,client_id,status\n") {
-                                        eprintln!("Failed to write header to file: {}", e);
-                                        return;
-                                    }
-                                }
-            
-                                if let Err(e) = wtr.write_all(record.as_bytes()) {
-                                    eprintln!("Failed to write record to file: {}", e);
-                                } else {
-                                    println!("New record appended to CSV: {:?}", online_status);
+
+                Message::KeyQuery { client_id } => {
+                    let public_key = accounts::public_key(&storage, &client_id);
+                    if let Err(e) = send_message(&socket_election, &Message::ClientKey { client_id, public_key }, addr).await {
+                        eprintln!("Failed to answer the key query from {}: {}", addr, e);
+                    }
+                }
+
+                Message::Elect => {
+                    // The leader is kept between requests, nothing to elect
+                    match elector.leader() {
+                        Some(leader) if leader.id == config_election.server_id => {
+                            let message_to_client = Message::LeaderAck {
+                                leader: mysocket.clone(),
+                            };
+                            let known = config_election.client_by_image_addr(&addr).cloned();
+                            if let Some(client) = known.or_else(|| storage.client_by_image_addr(&addr)) {
+                                if let Err(e) = send_message(&socketsendipback, &message_to_client, client.leader_ack_addr).await {
+                                    eprintln!("Failed to send LEADER_ACK to {}: {}", client.leader_ack_addr, e);
                                 }
                             } else {
-                                println!(
-                                    "Duplicate record found for UID/IP: {}. No changes needed.",
-                                    online_status.ip
-                                );
-                            }
-            
-                            // Remove records for this client_id from the offline access control requests file
-                            let offline_requests_file = "offline_access_control_requests.csv";
-                            if Path::new(offline_requests_file).exists() {
-                                if let Ok(content) = fs::read_to_string(offline_requests_file) {
-                                    let mut updated_requests = Vec::new();
-                                    for line in content.lines() {
-                                        if line.starts_with("client_id,image_id,views") {
-                                            updated_requests.push(line.to_string()); // Retain header
-                                            continue;
-                                        }
-            
-                                        let parts: Vec<&str> = line.split(',').collect();
-                                        if parts.len() == 3 && parts[0] != online_status.client_id {
-                                            updated_requests.push(line.to_string()); // Retain non-matching entries
-                                        } else if parts.len() == 3 {
-                                            println!(
-                                                "Removing offline request for client_id={} from offline requests file.",
-                                                parts[0]
-                                            );
-                                        }
-                                    }
-            
-                                    // Rewrite the file with the updated requests
-                                    let mut file = File::create(offline_requests_file)
-                                        .await
-                                        .expect("Failed to recreate offline requests file");
-                                    for request in updated_requests {
-                                        file.write_all(format!("{}\n", request).as_bytes())
-                                            .await
-                                            .expect("Failed to write to offline requests file");
-                                    }
-            
-                                    println!(
-                                        "Offline requests file updated after processing client_id={}.",
-                                        online_status.client_id
-                                    );
-                                }
+                                println!("ELECT from unknown client {}, no LEADER_ACK sent", addr);
                             }
                         }
-                        Err(e) => eprintln!("Failed to parse OnlineStatus: {}", e),
+                        Some(leader) => println!("ELECT from {}: server {} is the leader.", addr, leader.id),
+                        None => println!("ELECT from {}: election in progress.", addr),
                     }
-                } else {
-                    eprintln!("Malformed DIR_OF_SERV message: {}", message);
                 }
-            }            
-
-            else if message.starts_with("OFFLINE_WANTED:") {
-                let offline_data = message.strip_prefix("OFFLINE_WANTED:").unwrap_or("").to_string();
-                let parts: Vec<&str> = offline_data.split(':').collect();
-            
-                if parts.len() == 3 {
-                    let client_id = parts[0].to_string();
-                    let image_id = parts[1].to_string();
-                    let views = parts[2].to_string();
-            
-                    println!(
-                        "Received OFFLINE_WANTED - Client ID: {}, Image ID: {}, Views: {}",
-                        client_id, image_id, views
-                    );
-            
-                    // Append the received request to the offline requests CSV
-                    let offline_requests_file = "offline_access_control_requests.csv";
-                    if !Path::new(offline_requests_file).exists() {
-                        let mut file = File::create(offline_requests_file)
-                            .await
-                            .expect("Failed to create offline requests file");
-                        file.write_all(b"client_id,image_id,views\n")
-                            .await
-                            .expect("Failed to write header to offline requests file");
+
+                Message::LeaderQuery => match elector.leader() {
+                    Some(leader) => {
+                        if let Err(e) = send_message(&socket_election, &Message::LeaderAck { leader: leader.addr }, addr).await {
+                            eprintln!("Failed to answer the leader query from {}: {}", addr, e);
+                        }
                     }
-            
-                    let mut file = OpenOptions::new()
-                        .write(true)
-                        .append(true)
-                        .open(offline_requests_file)
-                        .expect("Failed to open offline requests file for appending");
-            
-                    writeln!(file, "{},{},{}", client_id, image_id, views)
-                        .expect("Failed to write to offline requests file");
-            
+                    // The client asks another server
+                    None => println!("Leader query from {}: election in progress.", addr),
+                },
+
+                Message::AccessControl { client_id, image_id, views, grant, .. } => {
+                    let control = control.clone();
+                    let sender = sender.unwrap_or_default();
+                    tokio::spawn(async move {
+                        access_control(&control, addr, sender, client_id, image_id, views, grant).await;
+                    });
+                }
+
+                // Only sent by servers without replication
+                Message::DirOfServ(online_status) => {
+                    println!("Received OnlineStatus from peer: {:?}", online_status);
+                    let replicator = Arc::clone(&replicator);
+                    tokio::spawn(async move {
+                        if let Err(e) = replicator.propose(Change::SetStatus(online_status)).await {
+                            eprintln!("Failed to update directory of service: {}", e);
+                        }
+                    });
+                }
+
+                // Only sent by servers without replication
+                Message::OfflineWanted { client_id, image_id, views, grant } => {
                     println!(
-                        "Stored OFFLINE_WANTED in file - Client ID: {}, Image ID: {}, Views: {}",
+                        "Received OFFLINE_WANTED - Client ID: {}, Image ID: {}, Views: {}",
                         client_id, image_id, views
                     );
-                } else {
-                    println!("Invalid OFFLINE_WANTED format: {}", offline_data);
+
+                    let request = PendingRequest {
+                        client_id: client_id.clone(),
+                        image_id: image_id.clone(),
+                        views,
+                        grant,
+                    };
+                    let replicator = Arc::clone(&replicator);
+                    tokio::spawn(async move {
+                        match replicator.propose(Change::AddPending(request)).await {
+                            Ok(()) => println!(
+                                "Stored OFFLINE_WANTED - Client ID: {}, Image ID: {}, Views: {}",
+                                client_id, image_id, views
+                            ),
+                            Err(e) => eprintln!("Failed to store OFFLINE_WANTED: {}", e),
+                        }
+                    });
                 }
-            }
-            
-            else if message.starts_with("STATUS:") {
-                if let Some(json_payload) = message.strip_prefix("STATUS:") {
-                    match serde_json::from_str::<OnlineStatus>(json_payload) {
-                        Ok(online_status) => {
-                            println!("Received OnlineStatus: {:?}", online_status);
-
-                            let file_path = "directory_of_service.csv";
-                            let mut is_duplicate = false;
-                            let mut needs_update = false;
-                            let mut updated_records = Vec::new();
-
-                            // Check if the file exists and process existing records
-                            if Path::new(file_path).exists() {
-                                if let Ok(content) = fs::read_to_string(file_path) {
-                                    for line in content.lines() {
-                                        // Skip the header
-                                        if line.starts_with("uid,client_id,status") {
-                                            updated_records.push(line.to_string());
-                                            continue;
-                                        }
-
-                                        let mut fields: Vec<&str> = line.split(',').collect();
-                                        if fields.len() == 3 {
-                                            if fields[0] == online_status.ip {
-                                                is_duplicate = true;
-
-                                                // Check if the status has changed
-                                                let existing_status = fields[2] == "true";
-                                                if existing_status != online_status.status {
-                                                    fields[2] = if online_status.status {
-                                                        "true"
-                                                    } else {
-                                                        "false"
-                                                    };
-                                                    needs_update = true;
-                                                    println!(
-                                                        "Updating record for UID/IP: {} from {} to {}",
-                                                        online_status.ip,
-                                                        existing_status,
-                                                        online_status.status
-                                                    );
-                                                }
-                                            }
-                                        }
-
-                                        // Add either the updated or original record to the list
-                                        updated_records.push(fields.join(","));
-                                    }
-                                }
-                            }
 
-                            // Write updated data back to the CSV if needed
-                            if is_duplicate && needs_update {
-                                let mut file = match OpenOptions::new()
-                                    .write(true)
-                                    .truncate(true)
-                                    .open(file_path)
-                                {
-                                    Ok(file) => file,
-                                    Err(e) => {
-                                        eprintln!("Failed to open file for updating: {}", e);
-                                        return;
-                                    }
-                                };
-
-                                for record in &updated_records {
-                                    if let Err(e) = writeln!(file, "{}", record) {
-                                        eprintln!("Failed to write record: {}", e);
-                                    }
-                                }
-                                println!("CSV file updated successfully.");
-                            } else if !is_duplicate {
-                                // Append the new record if it's not a duplicate
-                                let record = format!(
-                                    "{},{},{}\n",
-                                    online_status.ip,
-                                    online_status.client_id,
-                                    online_status.status
-                                );
-
-                                let file_exists = Path::new(file_path).exists();
-                                let mut wtr = match OpenOptions::new()
-                                    .create(true)
-                                    .append(true)
-                                    .open(file_path)
-                                {
-                                    Ok(file) => file,
-                                    Err(e) => {
-                                        eprintln!("Failed to open file: {}", e);
-                                        return;
-                                    }
-                                };
-
-                                if !file_exists {
-                                    if let Err(e) = wtr.write_all(b"uid,client_id,status\n") {
-                                        eprintln!("Failed to write header to file: {}", e);
-                                        return;
-                                    }
-                                }
+                Message::Status { status: online_status, endpoint, signature, .. } => {
+                    let control = control.clone();
+                    tokio::spawn(async move {
+                        status(&control, addr, sender, online_status, endpoint, signature).await;
+                    });
+                }
 
-                                if let Err(e) = wtr.write_all(record.as_bytes()) {
-                                    eprintln!("Failed to write record to file: {}", e);
-                                } else {
-                                    println!("New record appended to CSV: {:?}", online_status);
-                                }
-                            } else {
-                                println!(
-                                    "Duplicate record found for UID/IP: {}. No changes needed.",
-                                    online_status.ip
-                                );
-                            }
+                Message::RequestDos { .. } => {
+                    println!("Received DOS message from {}", addr);
 
-                            // Acknowledge the message
-                            let message_to_client = format!("STATUS_ACK:{}", mysocket);
-                            socket_election
-                                .lock()
-                                .await
-                                .send_to(message_to_client.as_bytes(), addr)
-                                .await
-                                .unwrap();
-                            println!("Ack sent to {}", addr);
-
-                            // Let the homies know
-                            if let Ok(online_status_json) = serde_json::to_string(&online_status) {
-                                for peer in &peers {
-                                    let message_to_send = format!("DIR_OF_SERV:{}", online_status_json);
-                                    socket_election
-                                        .lock()
-                                        .await
-                                        .send_to(message_to_send.as_bytes(), peer)
-                                        .await
-                                        .unwrap();
-                                }
-                            } else {
-                                eprintln!("Failed to serialize OnlineStatus for broadcasting.");
-                            }
+                    // Send the directory of service to the client
+                    let dos_message = Message::Dos {
+                        entries: storage.directory(),
+                    };
+                    if let Err(e) = send_message(&socket_election, &dos_message, addr).await {
+                        eprintln!("Failed to send the directory of service to {}: {}", addr, e);
+                        continue;
+                    }
 
-                            // Process offline requests if the client is online
-                            if online_status.status {
-                                let offline_requests_file = "offline_access_control_requests.csv";
-
-                                if Path::new(offline_requests_file).exists() {
-                                    if let Ok(content) = fs::read_to_string(offline_requests_file) {
-                                        let mut updated_requests = Vec::new();
-                                        let mut found_match = false;
-
-                                        for line in content.lines() {
-                                            if line.starts_with("client_id,image_id,views") {
-                                                updated_requests.push(line.to_string()); // Retain header
-                                                continue;
-                                            }
-
-                                            let parts: Vec<&str> = line.split(',').collect();
-                                            if parts.len() == 3 {
-                                                let client_id = parts[0].to_string();
-                                                let image_id = parts[1].to_string();
-                                                let views = parts[2].to_string();
-
-                                                if client_id == online_status.client_id && !found_match {
-                                                    println!(
-                                                        "Processing offline request for client_id={}, image_id={}, views={}",
-                                                        client_id, image_id, views
-                                                    );
-                                                
-                                                    // Get the IP address of the Offline wanted client from the directory of service
-                                                    let dos_path = "directory_of_service.csv";
-                                                    let mut client_ip = String::new();
-                                                    if Path::new(dos_path).exists() {
-                                                        if let Ok(dos_content) = fs::read_to_string(dos_path) {
-                                                            for dos_line in dos_content.lines() {
-                                                                // Skip the header
-                                                                if dos_line.starts_with("uid,client_id,status") {
-                                                                    continue;
-                                                                }
-                                                
-                                                                let dos_fields: Vec<&str> = dos_line.split(',').collect();
-                                                                if dos_fields.len() == 3 {
-                                                                    if dos_fields[1] == client_id {
-                                                                        client_ip = dos_fields[0].to_string();
-                                                                        break;
-                                                                    }
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                
-                                                    if client_ip.is_empty() {
-                                                        println!(
-                                                            "No IP found for client_id={} in directory of service. Skipping.",
-                                                            client_id
-                                                        );
-                                                    } else {
-                                                        // Clone client_ip to prevent move
-                                                        let client_ip_clone = client_ip.clone();
-                                                
-                                                        // Send CONTROL_UPDATE to the client
-                                                        let update_message = format!(
-                                                            "CONTROL_UPDATE:{}:{}:{}",
-                                                            client_id, image_id, views
-                                                        );
-
-                                                        // print the message to be sent to client
-                                                        println!("Sending CONTROL_UPDATE to client: {}", update_message);
-                                                
-                                                        socket_election
-                                                            .lock()
-                                                            .await
-                                                            .send_to(update_message.as_bytes(), client_ip_clone)
-                                                            .await
-                                                            .unwrap();
-                                                        println!("Sent CONTROL_UPDATE to {}", client_ip);
-                                                
-                                                        found_match = true; // Ensure only one match is processed
-                                                    }
-                                                } else {
-                                                    updated_requests.push(line.to_string()); // Retain non-matching entries
-                                                }
-                                            }
-                                        }
-
-                                        // Rewrite the file with remaining requests
-                                        let mut file = File::create(offline_requests_file)
-                                            .await
-                                            .expect("Failed to recreate offline requests file");
-                                        for request in updated_requests {
-                                            file.write_all(format!("{}\n", request).as_bytes())
-                                                .await
-                                                .expect("Failed to write to offline requests file");
-                                        }
-
-                                        println!("Offline requests file updated.");
-
-                                    }
-                                }
-                            }
-                            if online_status.status {
-                                // Convert peers to Vec<String> for the function call
-                                let peers_for_samples: Vec<String> = peers.iter().map(|&peer| peer.to_string()).collect();
-                            
-                                if let Err(e) = receive_samples(&socket_election, &online_status.client_id, &addr, &peers_for_samples).await {
-                                    eprintln!("Failed to receive samples: {:?}", e);
-                                }
-                            }
+                    // Now send all indexed samples
+                    let samples = storage.samples();
+                    if samples.is_empty() {
+                        match send_message(&socket_election, &Message::NoSamples, addr).await {
+                            Ok(_) => println!("Notified client that no samples are available."),
+                            Err(e) => eprintln!("Failed to send NO_SAMPLES to {}: {}", addr, e),
                         }
-                        Err(e) => eprintln!("Failed to parse OnlineStatus: {}", e),
-                    }
-                } else {
-                    eprintln!("Malformed STATUS message: {}", message);
-                }
-            }
-   
-
-            else if message.starts_with("Request_DOS") {
-                println!("Received DOS message from {}", addr);
-            
-                // Parse the directory of service.csv file and send the info to the client
-                let file = fs::read_to_string("directory_of_service.csv").expect("Unable to read file");
-                let dos_message = format!("DOS:{}", file);
-                socket_election.lock().await.send_to(dos_message.as_bytes(), addr).await.unwrap();
-            
-                // Now send all available samples
-                let samples_dir = "samples";
-                if !Path::new(samples_dir).exists() {
-                    // No samples directory exists
-                    let no_samples_message = "NO_SAMPLES";
-                    socket_election.lock().await.send_to(no_samples_message.as_bytes(), addr).await.unwrap();
-                    println!("Notified client that no samples are available.");
-                } else {
-                    // Iterate through all folders and files in `samples/`
-                    for entry in fs::read_dir(samples_dir).expect("Failed to read samples directory") {
-                        if let Ok(client_folder) = entry {
-                            let client_folder_path = client_folder.path();
-                            if client_folder_path.is_dir() {
-                                let client_id = client_folder.file_name().to_string_lossy().to_string();
-            
-                                for sample in fs::read_dir(client_folder_path).expect("Failed to read client folder") {
-                                    if let Ok(sample_file) = sample {
-                                        let sample_path = sample_file.path();
-                                        if sample_path.is_file() {
-                                            let sample_name = sample_file.file_name().to_string_lossy().to_string();
-            
-                                            // Send metadata first
-                                            let metadata_message = format!("SAMPLE:{}:{}", client_id, sample_name);
-                                            socket_election.lock().await.send_to(metadata_message.as_bytes(), addr).await.unwrap();
-                                            println!("Sent metadata for sample {}:{}", client_id, sample_name);
-            
-                                            // Send the file contents
-                                            let sample_data = fs::read(&sample_path).expect("Failed to read sample file");
-                                            socket_election.lock().await.send_to(&sample_data, addr).await.unwrap();
-                                            println!("Sent file {} to {}", sample_name, addr);
-                                        }
-                                    }
+                    } else {
+                        for sample in samples {
+                            let sample_data = match fs::read(sample.path(&samples_root)) {
+                                Ok(data) => data,
+                                Err(e) => {
+                                    eprintln!("Failed to read sample {}:{}: {}", sample.client_id, sample.image_id, e);
+                                    continue;
                                 }
+                            };
+                            let sample_message = Message::Sample {
+                                client_id: sample.client_id.clone(),
+                                name: sample.file_name(),
+                                data: sample_data,
+                            };
+                            match send_message(&socket_election, &sample_message, addr).await {
+                                Ok(_) => println!("Sent sample {}:{} to {}", sample.client_id, sample.file_name(), addr),
+                                Err(e) => eprintln!("Failed to send sample {}:{} to {}: {}", sample.client_id, sample.file_name(), addr, e),
                             }
                         }
+
+                        // Notify the client that all samples are sent
+                        match send_message(&socket_election, &Message::SamplesDone, addr).await {
+                            Ok(_) => println!("Notified client that all samples are sent."),
+                            Err(e) => eprintln!("Failed to send SAMPLES_DONE to {}: {}", addr, e),
+                        }
                     }
-            
-                    // Notify the client that all samples are sent
-                    let done_message = "SAMPLES_DONE";
-                    socket_election.lock().await.send_to(done_message.as_bytes(), addr).await.unwrap();
-                    println!("Notified client that all samples are sent.");
                 }
-            }
 
-            else if message.starts_with("SAMPLE_SYNC:") {
-                if let Some(metadata) = message.strip_prefix("SAMPLE_SYNC:") {
-                    let parts: Vec<&str> = metadata.split(':').collect();
-                    if parts.len() < 2 {
-                        eprintln!("Invalid SAMPLE_SYNC message: {}", metadata);
+                Message::SampleSync { client_id, image_id, data } => {
+                    // Legacy servers send the image data in a separate datagram
+                    let data = if data.is_empty() {
+                        let (size, addr) = match faults::recv_from(&socket_election, &mut buffer).await {
+                            Ok(result) => result,
+                            Err(e) => {
+                                eprintln!("Failed to receive image data: {:?}", e);
+                                continue;
+                            }
+                        };
+                        println!("Received image data from {}", addr);
+                        buffer[..size].to_vec()
+                    } else {
+                        data
+                    };
+
+                    let sample = SampleEntry { client_id, image_id };
+                    if !sample.is_safe() {
+                        println!("Dropping sample {:?} from {}, not a valid sample name", sample, addr);
                         continue;
                     }
-            
-                    let client_id = parts[0];
-                    let image_name = parts[1];
-            
+
                     // Create the folder for this client
-                    let client_samples_dir = format!("samples/{}", client_id);
-                    std::fs::create_dir_all(&client_samples_dir).expect("Failed to create client samples directory");
-            
-                    // Receive the image data
-                    let mut buffer = [0u8; 4096];
-                    let (size, addr) = match socket_election.lock().await.recv_from(&mut buffer).await {
-                        Ok(result) => result,
-                        Err(e) => {
-                            eprintln!("Failed to receive image data: {:?}", e);
-                            continue;
-                        }
-                    };
-                    println!("Received image data from {}", addr);
-            
-                    let image_path = format!("{}/{}.jpg", client_samples_dir, image_name);
-                    if let Err(e) = std::fs::write(&image_path, &buffer[..size]) {
+                    let client_samples_dir = samples_root.join(&sample.client_id);
+                    if let Err(e) = std::fs::create_dir_all(&client_samples_dir) {
+                        eprintln!("Failed to create {}: {}", client_samples_dir.display(), e);
+                        continue;
+                    }
+                    let image_path = sample.path(&samples_root);
+                    if let Err(e) = std::fs::write(&image_path, &data) {
                         eprintln!("Failed to write image data: {:?}", e);
                         continue;
                     }
-                    println!("Stored image: {}", image_path);
+                    println!("Stored image: {}", image_path.display());
+                    if let Err(e) = storage.add_sample(&sample.client_id, &sample.image_id) {
+                        eprintln!("Failed to index sample: {}", e);
+                    }
                 }
+
+                Message::Join { server, proof } => {
+                    let storage = Arc::clone(&storage);
+                    let replicator = Arc::clone(&replicator);
+                    let samples_root = samples_root.clone();
+                    let config = Arc::clone(&config_election);
+                    tokio::spawn(async move {
+                        let cluster_key = config.cluster_key.as_deref();
+                        let joined =
+                            cluster::serve_join(server, proof, addr, cluster_key, &storage, &samples_root, &replicator);
+                        if let Err(e) = joined.await {
+                            eprintln!("Failed to add server from {}: {}", addr, e);
+                        }
+                    });
+                }
+
+                Message::Leave { id, proof } => match cluster::check_leave(id, &proof, config_election.cluster_key.as_deref()) {
+                    Ok(change) => {
+                        println!("Server {} is leaving", id);
+                        let replicator = Arc::clone(&replicator);
+                        tokio::spawn(async move {
+                            if let Err(e) = replicator.propose(change).await {
+                                eprintln!("Failed to remove server {}: {}", id, e);
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("Refusing LEAVE of server {} from {}: {}", id, addr, e),
+                },
+
+                Message::ServerListQuery => {
+                    let servers = gossiper_control.live_control_addrs();
+                    if let Err(e) = send_message(&socket_election, &Message::ServerList { servers }, addr).await {
+                        eprintln!("Failed to send the server list to {}: {}", addr, e);
+                    }
+                }
+
+                _ => {}
             }
-            
-                      
         }
     });
     let failure_socket_clone = Arc::clone(&failure_socket);
     tokio::spawn(async move {
         let mut buffer = [0u8; 2048];
         loop {
-            let (size, addr) = failure_socket_clone
-                .lock()
-                .await
-                .recv_from(&mut buffer)
-                .await
-                .expect("Failed to receive message");
-
-            let message = String::from_utf8_lossy(&buffer[..size]);
-            println!("Message received from {}: {}", addr, message);
-
-            if message.trim() == "FAIL" {
-                *fail_flag_clone_for_failure.lock().unwrap() = true;
-                println!("This server is down!");
-                thread::sleep(Duration::from_secs(30));
-                println!("Server restored!");
-                *fail_flag_clone_for_failure.lock().unwrap() = false;
+            // Past the fault hook, a crashed server still hears failure_simulation
+            let received = failure_socket_clone.lock().await.recv_from(&mut buffer).await;
+            let (size, addr) = match received {
+                Ok(received) => received,
+                Err(e) => {
+                    eprintln!("Failed to receive on the failure socket: {}", e);
+                    continue;
+                }
+            };
+
+            let message = protocol::decode(&buffer[..size]);
+            println!("Message received from {}: {:?}", addr, message);
+
+            // Failing runs in its own task, so failure_simulation can still
+            // send faults meanwhile
+            let fail_for = |duration: Duration| {
+                let lifecycle = lifecycle_failure.clone();
+                let replicator = Arc::clone(&replicator_failure);
+                let elector = Arc::clone(&elector_failure);
+                tokio::spawn(async move { lifecycle.fail_for(duration, &replicator, &elector).await });
+            };
+            if let Ok(Message::Fail) = message {
+                fail_for(FAIL_DURATION);
+            } else if let Ok(Message::Fault(rule)) = message {
+                println!("Applying {:?} for {}ms", rule.kind, rule.duration_ms);
+                if rule.kind == faults::FaultKind::Crash && rule.peers.is_empty() {
+                    fail_for(Duration::from_millis(rule.duration_ms));
+                }
+                faults.add(rule, std::time::Instant::now());
             }
         }
     });
 
-    let (tx, mut rx): (
-        mpsc::Sender<(Vec<u8>, std::net::SocketAddr)>,
-        mpsc::Receiver<(Vec<u8>, std::net::SocketAddr)>,
-    ) = mpsc::channel(32);
+    let (tx, mut rx) = mpsc::channel::<Upload>(32);
     let socket_clone_client = Arc::clone(&socket_client);
+    let uploads_dir = config.data_path(UPLOADS_DIR);
+    let upload_addr = config.upload_addr;
+    let upload_transfers = transfers;
 
+    let lifecycle_uploads = lifecycle.clone();
     tokio::spawn(async move {
-        let mut buffer = [0u8; 2048];
-        let mut image_data = Vec::new();
-        let mut received_chunks = 0;
-        let mut expected_sequence_num = 0;
-        println!("Server listening on {}", my_address);
-
+        let socket = socket_clone_client.lock().await;
+        println!("Server listening on {}", upload_addr);
+        let transfer_config = TransferConfig::default();
         loop {
-            let (size, addr) = socket_clone_client
-                .lock()
-                .await
-                .recv_from(&mut buffer)
-                .await
-                .unwrap();
-            if size < 4 {
-                continue;
-            }
-
-            if &buffer[4..size] == b"END" {
-                let sequence_num = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-
-                if sequence_num == expected_sequence_num {
-                    let mut file = match std::fs::File::create("received_image.jpg") {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("Failed to create file: {:?}", e);
-                            continue;
-                        }
-                    };
-                    if let Err(e) = file.write_all(&image_data) {
-                        eprintln!("Failed to write to file: {:?}", e);
-                        continue;
+            lifecycle_uploads.until_running().await;
+            tokio::select! {
+                result = receive_uploads(&socket, &uploads_dir, &transfer_config, &upload_transfers, tx.clone()) => {
+                    if let Err(e) = result {
+                        eprintln!("Upload receiver stopped: {:?}", e);
                     }
-                    println!("Image saved successfully!");
-
-                    tx.send((image_data.clone(), addr)).await.unwrap();
-                    image_data.clear();
-                    received_chunks = 0;
-                    expected_sequence_num = 0;
-                    socket_clone_client
-                        .lock()
-                        .await
-                        .send_to(b"END", addr)
-                        .await
-                        .unwrap();
-                    println!("Final ACK sent.");
+                    return;
                 }
-                continue;
-            }
-            let sequence_num = u32::from_be_bytes(buffer[0..4].try_into().unwrap());
-
-            if sequence_num == expected_sequence_num {
-                image_data.extend_from_slice(&buffer[4..size]);
-                received_chunks += 1;
-                expected_sequence_num += 1;
-            } 
-
-            if received_chunks % 10 == 0 {
-                socket_clone_client
-                    .lock()
-                    .await
-                    .send_to(
-                        format!("ACK {}", expected_sequence_num - 1).as_bytes(),
-                        addr,
-                    )
-                    .await
-                    .unwrap();
-                println!("ACK sent for sequence number {}", expected_sequence_num - 1);
+                _ = lifecycle_uploads.until_stopped() => {}
             }
+            // Uploads in progress are lost, clients resend them after a timeout
+            upload_transfers.set_uploads(0);
+            lifecycle_uploads.drain_until_running(&socket).await;
         }
-    }); 
-
+    });
 
     tokio::spawn(async move {
-        while let Some((image_data, client_addr)) = rx.recv().await {
-            // Guess the format and load the image for encryption
-            let format = image::guess_format(&image_data).expect("Failed to guess image format");
-            let original_img =
-                image::load(Cursor::new(image_data.clone()), format).expect("Failed to load image");
-
-            let default_img_path = Path::new("images/mask.jpg");
-            let default_img = image::open(default_img_path).expect("Failed to open default image");
-
-            // Encrypt the image using the steganography crate
-            let start = Instant::now();
-            let mask = file_as_dynamic_image(default_img_path.to_str().unwrap().to_string());
-
-            let encoder = Encoder::new(&image_data, mask);
-            let encrypted_img = encoder.encode_alpha();
-            let duration = start.elapsed();
-            println!("Encryption Time: {:?}", duration);
-
-            let filename = "server1_encryption_times.csv";
-            let file_exists = std::path::Path::new(filename).exists();
-            match OpenOptions::new().append(true).create(true).open(filename) {
-                Ok(mut file) => {
-                    if !file_exists {
-                        if let Err(e) = writeln!(file, "encryption_time") {
-                            eprintln!("Failed to write header: {}", e);
-                            return;
-                        }
-                    }
-
-                    let mut wtr = Writer::from_writer(file);
-                    let duration_in_seconds = format!("{:.2}", duration.as_secs_f64());
-
-                    if let Err(e) = wtr.write_record(&[duration_in_seconds.to_string()]) {
-                        eprintln!("Failed to write record: {}", e);
-                        return;
-                    }
-
-                    if let Err(e) = wtr.flush() {
-                        eprintln!("Failed to flush writer: {}", e);
-                        return;
-                    }
-                }
-                Err(e) => {
-                    eprintln!("Failed to open file: {}", e);
-                }
-            }
-
-            // Save the encrypted image as PNG to avoid lossy compression
-            let encrypted_image_path = "encrypted-image.png";
-            encrypted_img
-                .save(encrypted_image_path)
-                .expect("Failed to save encoded image as PNG");
-            println!("Server: Encrypted image saved as {}", encrypted_image_path);
-
-            // Read the PNG image bytes
-            let encrypted_data =
-                std::fs::read(encrypted_image_path).expect("Failed to read encrypted PNG image");
-
-            // Send encrypted data in chunks
-            let chunk_size = 2044;
-            let total_chunks = (encrypted_data.len() as f64 / chunk_size as f64).ceil() as usize;
-            let mut sequence_num: u32 = 0;
-            let mut ack_buffer = [0u8; 1024];
-            let max_retries = 5;
-            let mut client = String::new();
-            if client_addr.to_string() == format!("{}:9080",client1){
-                client = format!("{}:2005",client1);
-            } else if client_addr.to_string()==format!("{}:7005",client2){
-                client = format!("{}:7001", client2);
-            }
-            for i in 0..total_chunks {
-                let start = i * chunk_size;
-                let end = std::cmp::min(start + chunk_size, encrypted_data.len());
-                let chunk_data = &encrypted_data[start..end];
-
-                let mut chunk = Vec::with_capacity(4 + chunk_data.len());
-                chunk.extend_from_slice(&sequence_num.to_be_bytes());
-                chunk.extend_from_slice(chunk_data);
-
-                socket6
-                    .lock()
-                    .await
-                    .send_to(&chunk, &client)
-                    .await
-                    .expect("Failed to send encrypted image chunk");
-                println!("Sent encrypted chunk {} of {}", i + 1, total_chunks);
-
-                let mut retries = 0;
-                loop {
-                    match timeout(
-                        Duration::from_secs(5),
-                        socket6.lock().await.recv_from(&mut ack_buffer),
-                    )
-                    .await
-                    {
-                        Ok(Ok((ack_size, _))) => {
-                            let ack_message = String::from_utf8_lossy(&ack_buffer[..ack_size]);
-                            if ack_message.starts_with("ACK") {
-                                let ack_num: u32 = ack_message[4..].parse().unwrap();
-                                if ack_num == sequence_num {
-                                    println!("ACK received for sequence number {}", ack_num);
-                                    sequence_num += 1;
-                                    break;
-                                }
-                            } else if ack_message.starts_with("NACK") {
-                                println!("NACK received for sequence number {}", sequence_num);
-                            }
-                        }
-                        Ok(Err(e)) => {
-                            eprintln!("Failed to receive ACK/NACK: {:?}", e);
-                            retries += 1;
-                            if retries >= max_retries {
-                                eprintln!(
-                                    "Max retries reached for chunk {}. Aborting.",
-                                    sequence_num
-                                );
-                                break;
-                            }
-                            println!("Retrying chunk {} after recv_from error", sequence_num);
-                            socket6
-                                .lock()
-                                .await
-                                .send_to(&chunk, &client)
-                                .await
-                                .expect("Failed to resend chunk");
-                        }
-                        Err(_) => {
-                            retries += 1;
-                            if retries >= max_retries {
-                                eprintln!(
-                                    "Max retries reached for chunk {}. Aborting.",
-                                    sequence_num
-                                );
-                                break;
-                            }
-                            println!("Timeout waiting for ACK, resending chunk {}", sequence_num);
-                            socket6
-                                .lock()
-                                .await
-                                .send_to(&chunk, &client)
-                                .await
-                                .expect("Failed to resend chunk");
-                        }
-                    }
-                }
-            }
-
-            // Send "END" message after the last chunk
-            socket6
-                .lock()
-                .await
-                .send_to(b"END", &client)
-                .await
-                .expect("Failed to send END message");
-            println!("Encrypted image transmission completed.");
+        while let Some(upload) = rx.recv().await {
+            // Each upload gets its own task, the pool bounds how many encrypt at once
+            let encryptor = encryptor.clone();
+            let elector = Arc::clone(&elector_jobs);
+            let journal = Arc::clone(&journal);
+            let storage = Arc::clone(&storage_uploads);
+            let config = Arc::clone(&config);
+            tokio::spawn(async move {
+                encrypt_and_return(upload, &encryptor, &elector, &journal, &storage, &config).await;
+            });
         }
     });
 
-    loop {
-        sleep(Duration::from_secs(10)).await;
+    // Runs until Ctrl-C, then hands this server's place back
+    tokio::signal::ctrl_c().await?;
+    let peers = control_peers.lock().unwrap().clone();
+    if let Err(e) = cluster::leave(server_id, cluster_key.as_deref(), &storage_leave, &peers).await {
+        eprintln!("Failed to leave the cluster: {}", e);
     }
+    Ok(())
 }
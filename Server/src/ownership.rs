@@ -0,0 +1,122 @@
+// Who owns which shared image. Shared image IDs are <owner>_<name>, but the
+// ID alone proves nothing: a client claims an image when it uploads its
+// sample, the claim is replicated with the directory, and only the client
+// that claimed it can change the views anyone has on the image.
+use crate::accounts;
+use crate::storage::{Change, ImageOwner, Storage};
+use protocol::SignedGrant;
+
+// The ID the sample `name` of `client_id` is shared under
+pub fn shared_id(client_id: &str, name: &str) -> String {
+    format!("{}_{}", client_id, name)
+}
+
+pub fn claim(owner: &str, image_id: &str) -> Change {
+    Change::ClaimImage(ImageOwner {
+        image_id: image_id.to_string(),
+        owner: owner.to_string(),
+    })
+}
+
+// Whether `sender` may set views on `image_id` with `grant`
+pub fn check_access(storage: &Storage, sender: &str, image_id: &str, grant: Option<&SignedGrant>) -> Result<(), String> {
+    match storage.image_owner(image_id) {
+        Some(owner) if owner == sender => {}
+        Some(owner) => return Err(format!("image {} belongs to client {}", image_id, owner)),
+        None => return Err(format!("image {} has no owner, its sample was never uploaded", image_id)),
+    }
+
+    let Some(grant) = grant else {
+        return Err("access control without the owner's grant".to_string());
+    };
+    if grant.grant.owner != sender || grant.grant.image_id != image_id {
+        return Err("grant was not made by the owner for this image".to_string());
+    }
+    if accounts::public_key(storage, sender).as_deref() != Some(grant.owner_key.as_slice()) {
+        return Err("grant is not signed with the owner's registered key".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Account;
+    use protocol::{Expiry, ViewGrant};
+
+    fn grant(owner: &str, image_id: &str, owner_key: u8) -> SignedGrant {
+        SignedGrant {
+            grant: ViewGrant {
+                image_id: image_id.to_string(),
+                owner: owner.to_string(),
+                viewer: "3".to_string(),
+                views: 2,
+                nonce: 1,
+                revoked: false,
+                expiry: Expiry::Never,
+            },
+            owner_key: vec![owner_key; 32],
+            signature: Vec::new(),
+        }
+    }
+
+    // Accounts for clients "1", "2" and "1_my", with keys of their first byte
+    fn with_accounts() -> (tempfile::TempDir, Storage) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::open(dir.path()).unwrap();
+        for (index, client_id) in ["1", "2", "1_my"].into_iter().enumerate() {
+            let account = Account {
+                client_id: client_id.to_string(),
+                password_hash: "hash".to_string(),
+                public_key: vec![client_id.as_bytes()[0]; 32],
+            };
+            storage.apply_replicated(index as u64 + 1, Change::Register(account)).unwrap();
+        }
+        (dir, storage)
+    }
+
+    #[test]
+    fn unclaimed_images_take_no_access_control() {
+        let (_dir, storage) = with_accounts();
+        // Not even under the sender's own prefix
+        assert!(check_access(&storage, "1", "1_cat", Some(&grant("1", "1_cat", b'1'))).is_err());
+        assert_eq!(storage.image_owner("1_cat"), None);
+
+        storage.apply_replicated(4, claim("1", &shared_id("1", "cat"))).unwrap();
+        assert_eq!(check_access(&storage, "1", "1_cat", Some(&grant("1", "1_cat", b'1'))), Ok(()));
+    }
+
+    #[test]
+    fn only_the_owner_sets_views() {
+        let (_dir, storage) = with_accounts();
+        storage.apply_replicated(4, claim("1", "1_cat")).unwrap();
+        assert_eq!(check_access(&storage, "1", "1_cat", Some(&grant("1", "1_cat", b'1'))), Ok(()));
+
+        // Another client can't update it, even with a grant it made itself
+        assert!(check_access(&storage, "2", "1_cat", Some(&grant("2", "1_cat", b'2'))).is_err());
+
+        // A claim stays with the first owner
+        storage.apply_replicated(5, claim("2", "1_cat")).unwrap();
+        assert_eq!(storage.image_owner("1_cat"), Some("1".to_string()));
+        assert!(check_access(&storage, "2", "1_cat", Some(&grant("2", "1_cat", b'2'))).is_err());
+
+        // The grant has to be the owner's, for this image, under its key
+        assert!(check_access(&storage, "1", "1_cat", None).is_err());
+        assert!(check_access(&storage, "1", "1_cat", Some(&grant("2", "1_cat", b'1'))).is_err());
+        assert!(check_access(&storage, "1", "1_cat", Some(&grant("1", "1_dog", b'1'))).is_err());
+        assert!(check_access(&storage, "1", "1_cat", Some(&grant("1", "1_cat", b'2'))).is_err());
+    }
+
+    #[test]
+    fn underscores_in_ids_do_not_decide_the_owner() {
+        let (_dir, storage) = with_accounts();
+        // Sample "my_cat" of client 1 and sample "cat" of client 1_my share an ID
+        assert_eq!(shared_id("1", "my_cat"), shared_id("1_my", "cat"));
+        storage.apply_replicated(4, claim("1", &shared_id("1", "my_cat"))).unwrap();
+        storage.apply_replicated(5, claim("1_my", &shared_id("1_my", "cat"))).unwrap();
+
+        // The first upload owns it, whichever way the ID splits
+        assert_eq!(check_access(&storage, "1", "1_my_cat", Some(&grant("1", "1_my_cat", b'1'))), Ok(()));
+        assert!(check_access(&storage, "1_my", "1_my_cat", Some(&grant("1_my", "1_my_cat", b'1'))).is_err());
+    }
+}
@@ -0,0 +1,1339 @@
+// Raft-style replication of the directory of service and the pending
+// access-control requests. One server leads a term: it appends changes to
+// its log, sends them to the others, and commits an entry once a majority
+// holds it. Followers that were down or lost messages are brought back in
+// line by the leader stepping back through their log until it matches.
+// Committed changes are applied to `Storage` in log order on every server.
+// Servers the failure detector considers dead get no AppendEntries, and a
+// follower whose leader is dead runs for leader without waiting for its
+// election timeout. A failed server takes no part until it recovers, then
+// follows whoever leads and is caught up once it applied what they committed.
+// Only the servers in the replicated server list take part: messages from
+// any other address are dropped. Once enough entries are applied, the log
+// drops them up to a short tail and keeps a snapshot of the tables instead;
+// a follower behind the dropped entries is sent that snapshot.
+use crate::lifecycle::State;
+use crate::storage::{Change, Storage, Tables};
+use protocol::{faults, LogEntry, Message, MAX_DATAGRAM};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+
+const STATE_FILE: &str = "state.bin";
+const ENTRIES_FILE: &str = "entries.bin";
+// A proposal is sent again if it was not applied after PROPOSE_RETRY, as it
+// or its answer may have been lost, and given up after PROPOSE_TIMEOUT
+const PROPOSE_RETRY: Duration = Duration::from_millis(500);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// How far back the leader looks for a proposal it already appended
+const DUPLICATE_WINDOW: usize = 256;
+// In place of a record length at the start of the entries file: the index and
+// term of the last compacted entry follow
+const BASE_MARKER: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct ReplicationConfig {
+    // How often the leader sends AppendEntries when it has nothing new
+    pub heartbeat: Duration,
+    // A follower that hears nothing for a random time in this range starts
+    // an election
+    pub election_timeout: (Duration, Duration),
+    // Entries per AppendEntries, small enough to fit in one datagram
+    pub max_batch: usize,
+    // Applied entries past the kept tail before the log is compacted
+    pub compact_after: usize,
+    // Applied entries kept when compacting, so a proposal sent again is
+    // still recognized and followers just behind get entries
+    pub log_keep: usize,
+    // Snapshot bytes per InstallSnapshot
+    pub snapshot_chunk: usize,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            heartbeat: Duration::from_millis(150),
+            election_timeout: (Duration::from_millis(1000), Duration::from_millis(2000)),
+            max_batch: 32,
+            compact_after: 1024,
+            log_keep: DUPLICATE_WINDOW,
+            snapshot_chunk: 32 * 1024,
+        }
+    }
+}
+
+// Term and vote, which must survive a restart
+#[derive(Serialize, Deserialize, Default)]
+struct HardState {
+    term: u64,
+    voted_for: Option<SocketAddr>,
+}
+
+// The log on disk: entries appended to one file, rewritten when a conflicting
+// suffix or a compacted prefix is dropped. Entry indexes start at 1.
+struct RaftLog {
+    dir: PathBuf,
+    // Index and term of the last compacted entry, 0 before any was
+    base_index: u64,
+    base_term: u64,
+    entries: Vec<LogEntry>,
+    file: File,
+}
+
+fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(data)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn encode_entry(entry: &LogEntry) -> io::Result<Vec<u8>> {
+    let record = bincode::serialize(entry).map_err(io::Error::other)?;
+    let mut encoded = (record.len() as u32).to_be_bytes().to_vec();
+    encoded.extend_from_slice(&record);
+    Ok(encoded)
+}
+
+impl RaftLog {
+    fn open(dir: &Path) -> io::Result<(Self, HardState)> {
+        fs::create_dir_all(dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(state) => bincode::deserialize(&state).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+
+        // An entry cut short by a crash is dropped
+        let path = dir.join(ENTRIES_FILE);
+        let (mut base_index, mut base_term) = (0, 0);
+        let mut entries = Vec::new();
+        let mut valid_len = 0u64;
+        if let Ok(file) = File::open(&path) {
+            let mut reader = BufReader::new(file);
+            loop {
+                let mut len = [0u8; 4];
+                if reader.read_exact(&mut len).is_err() {
+                    break;
+                }
+                let len = u32::from_be_bytes(len);
+                if len == BASE_MARKER && valid_len == 0 {
+                    let mut base = [0u8; 16];
+                    if reader.read_exact(&mut base).is_err() {
+                        break;
+                    }
+                    let (index, term) = base.split_at(8);
+                    base_index = u64::from_be_bytes(index.try_into().expect("8 bytes"));
+                    base_term = u64::from_be_bytes(term.try_into().expect("8 bytes"));
+                    valid_len = 20;
+                    continue;
+                }
+                let mut record = vec![0u8; len as usize];
+                if reader.read_exact(&mut record).is_err() {
+                    break;
+                }
+                match bincode::deserialize(&record) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => break,
+                }
+                valid_len += 4 + record.len() as u64;
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        file.set_len(valid_len)?;
+
+        let log = RaftLog {
+            dir: dir.to_path_buf(),
+            base_index,
+            base_term,
+            entries,
+            file,
+        };
+        Ok((log, state))
+    }
+
+    fn save_state(&self, state: &HardState) -> io::Result<()> {
+        let state = bincode::serialize(state).map_err(io::Error::other)?;
+        write_atomically(&self.dir.join(STATE_FILE), &state)
+    }
+
+    fn last_index(&self) -> u64 {
+        self.base_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.base_term, |entry| entry.term)
+    }
+
+    // The entry at `index`, unless it was compacted or is not there yet
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        let position = index.checked_sub(self.base_index + 1)?;
+        self.entries.get(position as usize)
+    }
+
+    // Term of the entry at `index`, 0 before the first entry. Unknown for
+    // compacted entries but the last one.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index == self.base_index {
+            true => Some(self.base_term),
+            false => self.entry(index).map(|entry| entry.term),
+        }
+    }
+
+    fn entries_from(&self, index: u64, max: usize) -> Vec<LogEntry> {
+        let start = (index.saturating_sub(self.base_index + 1) as usize).min(self.entries.len());
+        self.entries[start..].iter().take(max).cloned().collect()
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        for entry in entries {
+            encoded.extend(encode_entry(entry)?);
+        }
+        self.file.write_all(&encoded)?;
+        self.file.sync_data()?;
+        self.entries.extend_from_slice(entries);
+        Ok(())
+    }
+
+    // Drops the entry at `index` and everything after it
+    fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        self.entries.truncate((index - self.base_index - 1) as usize);
+        self.rewrite()
+    }
+
+    // Drops the entries up to and including `index`, which must be applied
+    fn compact(&mut self, index: u64) -> io::Result<()> {
+        let Some(term) = self.term_at(index).filter(|_| index > self.base_index) else {
+            return Ok(());
+        };
+        self.entries.drain(..(index - self.base_index) as usize);
+        self.base_index = index;
+        self.base_term = term;
+        self.rewrite()
+    }
+
+    // Starts the log over after `index`, which a snapshot holds
+    fn reset(&mut self, index: u64, term: u64) -> io::Result<()> {
+        self.entries.clear();
+        self.base_index = index;
+        self.base_term = term;
+        self.rewrite()
+    }
+
+    fn rewrite(&mut self) -> io::Result<()> {
+        let mut encoded = Vec::new();
+        if self.base_index > 0 {
+            encoded.extend(BASE_MARKER.to_be_bytes());
+            encoded.extend(self.base_index.to_be_bytes());
+            encoded.extend(self.base_term.to_be_bytes());
+        }
+        for entry in &self.entries {
+            encoded.extend(encode_entry(entry)?);
+        }
+        let path = self.dir.join(ENTRIES_FILE);
+        write_atomically(&path, &encoded)?;
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        Ok(())
+    }
+}
+
+// The serialized tables as of the entry at `index`, of `term`
+struct LogSnapshot {
+    index: u64,
+    term: u64,
+    data: Vec<u8>,
+}
+
+enum Role {
+    Follower,
+    Candidate { votes: HashSet<SocketAddr> },
+    Leader {
+        // Next entry to send to each peer and the last one it is known to hold
+        next: HashMap<SocketAddr, u64>,
+        matched: HashMap<SocketAddr, u64>,
+        // How much of the snapshot the peers behind the log hold
+        offsets: HashMap<SocketAddr, u64>,
+    },
+}
+
+type Outgoing = Vec<(SocketAddr, Message)>;
+
+// The replication state machine of one server. It never touches the network:
+// every call returns the messages to send.
+pub struct Node {
+    id: SocketAddr,
+    peers: Vec<SocketAddr>,
+    config: ReplicationConfig,
+    state: HardState,
+    log: RaftLog,
+    role: Role,
+    leader: Option<SocketAddr>,
+    commit: u64,
+    applied: u64,
+    // Commit index the leader last sent, which may be ahead of our log
+    leader_commit: u64,
+    // Election timeout for followers and candidates, next heartbeat for a leader
+    deadline: Instant,
+    // Changes proposed while no leader was known
+    queued: Vec<Vec<u8>>,
+    // Peers the failure detector considers dead
+    dead: HashSet<SocketAddr>,
+    // What followers behind the compacted log are sent
+    snapshot: Option<LogSnapshot>,
+    // The leader's snapshot while it comes in, and once whole until storage
+    // installed it
+    incoming: Option<(u64, Vec<u8>)>,
+    installed: Option<Vec<u8>>,
+}
+
+impl Node {
+    // `applied` is the last index already in storage, so a restarted server
+    // does not apply those again
+    pub fn open(
+        dir: &Path,
+        id: SocketAddr,
+        peers: Vec<SocketAddr>,
+        config: ReplicationConfig,
+        applied: u64,
+        now: Instant,
+    ) -> io::Result<Self> {
+        let (mut log, state) = RaftLog::open(dir)?;
+        // Stopped between taking a snapshot into the log and into storage:
+        // the leader sends it again
+        if applied < log.base_index {
+            eprintln!("Replication: storage is behind the log, starting the log over");
+            log.reset(0, 0)?;
+        }
+        let mut node = Node {
+            id,
+            peers,
+            config,
+            state,
+            log,
+            role: Role::Follower,
+            leader: None,
+            commit: 0,
+            applied,
+            leader_commit: 0,
+            deadline: now,
+            queued: Vec::new(),
+            dead: HashSet::new(),
+            snapshot: None,
+            incoming: None,
+            installed: None,
+        };
+        node.reset_election_timer(now);
+        Ok(node)
+    }
+
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.leader
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    // Takes a new list of peers once a server joined or left. Changes come
+    // one server at a time, so the old and the new majority overlap.
+    pub fn set_peers(&mut self, peers: Vec<SocketAddr>) {
+        if let Role::Leader { next, matched, offsets } = &mut self.role {
+            let next_index = self.log.last_index() + 1;
+            next.retain(|peer, _| peers.contains(peer));
+            matched.retain(|peer, _| peers.contains(peer));
+            offsets.retain(|peer, _| peers.contains(peer));
+            for peer in &peers {
+                next.entry(*peer).or_insert(next_index);
+                matched.entry(*peer).or_insert(0);
+            }
+        }
+        println!("Replication: peers are now {:?}", peers);
+        self.peers = peers;
+        self.advance_commit();
+    }
+
+    pub fn set_dead(&mut self, dead: HashSet<SocketAddr>, now: Instant) {
+        self.dead = dead;
+        if let Some(leader) = self.leader {
+            if !self.is_leader() && self.dead.contains(&leader) {
+                println!("Replication: leader {} is dead", leader);
+                self.leader = None;
+                self.deadline = now;
+            }
+        }
+    }
+
+    // Back from a failure: steps down if it led and waits for the current
+    // leader, which brings the log up to date
+    pub fn restart(&mut self, now: Instant) {
+        if !matches!(self.role, Role::Follower) {
+            println!("Replication: following in term {}", self.state.term);
+        }
+        self.role = Role::Follower;
+        self.leader = None;
+        self.reset_election_timer(now);
+    }
+
+    // Whether this server applied everything the current leader committed
+    pub fn caught_up(&self) -> bool {
+        self.is_leader() || (self.leader.is_some() && self.applied >= self.leader_commit)
+    }
+
+    fn majority(&self) -> usize {
+        let cluster = self.peers.len() + 1;
+        cluster / 2 + 1
+    }
+
+    fn reset_election_timer(&mut self, now: Instant) {
+        let (min, max) = self.config.election_timeout;
+        let timeout = if max > min {
+            rand::thread_rng().gen_range(min..max)
+        } else {
+            min
+        };
+        self.deadline = now + timeout;
+    }
+
+    fn become_follower(&mut self, term: u64, now: Instant) -> io::Result<()> {
+        if term > self.state.term {
+            self.state = HardState {
+                term,
+                voted_for: None,
+            };
+            self.log.save_state(&self.state)?;
+            self.leader = None;
+        }
+        if !matches!(self.role, Role::Follower) {
+            println!("Replication: following in term {}", term);
+        }
+        self.role = Role::Follower;
+        self.reset_election_timer(now);
+        Ok(())
+    }
+
+    fn become_leader(&mut self, now: Instant) -> io::Result<Outgoing> {
+        println!("Replication: leading term {}", self.state.term);
+        let next_index = self.log.last_index() + 1;
+        self.role = Role::Leader {
+            next: self.peers.iter().map(|peer| (*peer, next_index)).collect(),
+            matched: self.peers.iter().map(|peer| (*peer, 0)).collect(),
+            offsets: HashMap::new(),
+        };
+        self.leader = Some(self.id);
+
+        // A no-op of the new term lets entries of earlier terms commit
+        let mut entries = vec![LogEntry {
+            term: self.state.term,
+            data: Vec::new(),
+        }];
+        entries.extend(self.queued.drain(..).map(|data| LogEntry {
+            term: self.state.term,
+            data,
+        }));
+        self.log.append(&entries)?;
+        self.advance_commit();
+        Ok(self.broadcast_append(now))
+    }
+
+    fn start_election(&mut self, now: Instant) -> io::Result<Outgoing> {
+        self.state = HardState {
+            term: self.state.term + 1,
+            voted_for: Some(self.id),
+        };
+        self.log.save_state(&self.state)?;
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id]),
+        };
+        self.reset_election_timer(now);
+        println!("Replication: starting election for term {}", self.state.term);
+
+        if self.majority() == 1 {
+            return self.become_leader(now);
+        }
+        let request = Message::RequestVote {
+            term: self.state.term,
+            last_index: self.log.last_index(),
+            last_term: self.log.last_term(),
+        };
+        Ok(self.peers.iter().map(|peer| (*peer, request.clone())).collect())
+    }
+
+    fn append_for(&self, peer: SocketAddr) -> Option<Message> {
+        let Role::Leader { next, offsets, .. } = &self.role else {
+            return None;
+        };
+        let next_index = *next.get(&peer)?;
+        // The entries it needs were compacted, it gets the snapshot instead
+        if next_index <= self.log.base_index {
+            let Some(snapshot) = &self.snapshot else {
+                eprintln!("Replication: {} is behind the log and there is no snapshot", peer);
+                return None;
+            };
+            let total = snapshot.data.len();
+            let offset = (offsets.get(&peer).copied().unwrap_or(0) as usize).min(total);
+            let end = (offset + self.config.snapshot_chunk).min(total);
+            return Some(Message::InstallSnapshot {
+                term: self.state.term,
+                last_index: snapshot.index,
+                last_term: snapshot.term,
+                offset: offset as u64,
+                total: total as u64,
+                data: snapshot.data[offset..end].to_vec(),
+            });
+        }
+        let prev_index = next_index - 1;
+        Some(Message::AppendEntries {
+            term: self.state.term,
+            prev_index,
+            prev_term: self.log.term_at(prev_index).unwrap_or(0),
+            entries: self.log.entries_from(next_index, self.config.max_batch),
+            commit: self.commit,
+        })
+    }
+
+    fn broadcast_append(&mut self, now: Instant) -> Outgoing {
+        self.deadline = now + self.config.heartbeat;
+        self.peers
+            .iter()
+            .filter(|peer| !self.dead.contains(peer))
+            .filter_map(|peer| Some((*peer, self.append_for(*peer)?)))
+            .collect()
+    }
+
+    // Commits the newest entry of this term a majority holds
+    fn advance_commit(&mut self) {
+        let Role::Leader { matched, .. } = &self.role else {
+            return;
+        };
+        for index in (self.commit + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != Some(self.state.term) {
+                break;
+            }
+            let holders = 1 + matched.values().filter(|matched| **matched >= index).count();
+            if holders >= self.majority() {
+                self.commit = index;
+                break;
+            }
+        }
+    }
+
+    // Appends a change if this server leads, forwards it to the leader
+    // otherwise, or keeps it until a leader is known
+    pub fn propose(&mut self, data: Vec<u8>, now: Instant) -> io::Result<Outgoing> {
+        if self.is_leader() {
+            // A proposal sent again because its answer was lost
+            if self.log.entries.iter().rev().take(DUPLICATE_WINDOW).any(|entry| entry.data == data) {
+                return Ok(Vec::new());
+            }
+            self.log.append(&[LogEntry {
+                term: self.state.term,
+                data,
+            }])?;
+            self.advance_commit();
+            return Ok(self.broadcast_append(now));
+        }
+        match self.leader {
+            Some(leader) => Ok(vec![(leader, Message::Propose { data })]),
+            None => {
+                if !self.queued.contains(&data) {
+                    self.queued.push(data);
+                }
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    // Heartbeats for a leader, a new election for anyone else whose timer ran out
+    pub fn tick(&mut self, now: Instant) -> io::Result<Outgoing> {
+        if now < self.deadline {
+            return Ok(Vec::new());
+        }
+        if self.is_leader() {
+            return Ok(self.broadcast_append(now));
+        }
+        self.start_election(now)
+    }
+
+    pub fn step(&mut self, from: SocketAddr, message: Message, now: Instant) -> io::Result<Outgoing> {
+        if !self.peers.contains(&from) {
+            eprintln!("Replication: ignoring message from {}, which is not a peer", from);
+            return Ok(Vec::new());
+        }
+        let term = match &message {
+            Message::AppendEntries { term, .. }
+            | Message::AppendReply { term, .. }
+            | Message::RequestVote { term, .. }
+            | Message::Vote { term, .. }
+            | Message::InstallSnapshot { term, .. }
+            | Message::SnapshotReply { term, .. } => Some(*term),
+            _ => None,
+        };
+        if let Some(term) = term {
+            if term > self.state.term {
+                self.become_follower(term, now)?;
+            }
+        }
+
+        match message {
+            Message::AppendEntries { term, prev_index, prev_term, entries, commit } => {
+                self.on_append(from, term, prev_index, prev_term, entries, commit, now)
+            }
+            Message::AppendReply { term, success, last_index } => {
+                Ok(self.on_append_reply(from, term, success, last_index))
+            }
+            Message::InstallSnapshot { term, last_index, last_term, offset, total, data } => {
+                self.on_snapshot(from, term, (last_index, last_term), (offset, total), data, now)
+            }
+            Message::SnapshotReply { term, last_index, offset } => {
+                Ok(self.on_snapshot_reply(from, term, last_index, offset))
+            }
+            Message::RequestVote { term, last_index, last_term } => {
+                let up_to_date = (last_term, last_index) >= (self.log.last_term(), self.log.last_index());
+                let can_vote = self.state.voted_for.is_none_or(|voted_for| voted_for == from);
+                let granted = term == self.state.term && can_vote && up_to_date;
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.log.save_state(&self.state)?;
+                    self.reset_election_timer(now);
+                }
+                let vote = Message::Vote {
+                    term: self.state.term,
+                    granted,
+                };
+                Ok(vec![(from, vote)])
+            }
+            Message::Vote { term, granted } => {
+                let majority = self.majority();
+                if let Role::Candidate { votes } = &mut self.role {
+                    if granted && term == self.state.term {
+                        votes.insert(from);
+                        if votes.len() >= majority {
+                            return self.become_leader(now);
+                        }
+                    }
+                }
+                Ok(Vec::new())
+            }
+            Message::Propose { data } => self.propose(data, now),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn on_append(
+        &mut self,
+        from: SocketAddr,
+        term: u64,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<LogEntry>,
+        commit: u64,
+        now: Instant,
+    ) -> io::Result<Outgoing> {
+        let reply = |term, success, last_index| {
+            vec![(from, Message::AppendReply { term, success, last_index })]
+        };
+        if term < self.state.term {
+            return Ok(reply(self.state.term, false, self.log.last_index()));
+        }
+
+        // Only the leader of the current term sends AppendEntries
+        let mut outgoing = self.follow(from, term, now)?;
+        self.leader_commit = commit;
+
+        // Compacted entries were applied, so they match the leader's
+        let (mut prev_index, mut prev_term, mut entries) = (prev_index, prev_term, entries);
+        if prev_index < self.log.base_index {
+            let skip = (self.log.base_index - prev_index) as usize;
+            entries = entries.into_iter().skip(skip).collect();
+            prev_index = self.log.base_index;
+            prev_term = self.log.base_term;
+        }
+
+        if self.log.term_at(prev_index) != Some(prev_term) {
+            // Resume from our last entry, or from before the conflicting one
+            let hint = self.log.last_index().min(prev_index.saturating_sub(1));
+            outgoing.extend(reply(term, false, hint));
+            return Ok(outgoing);
+        }
+
+        // Skip entries already held and drop a conflicting suffix
+        let mut index = prev_index;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            index += 1;
+            if !new_entries.is_empty() {
+                new_entries.push(entry);
+                continue;
+            }
+            match self.log.term_at(index) {
+                Some(existing) if existing == entry.term => {}
+                Some(_) => {
+                    self.log.truncate_from(index)?;
+                    new_entries.push(entry);
+                }
+                None => new_entries.push(entry),
+            }
+        }
+        self.log.append(&new_entries)?;
+
+        if commit > self.commit {
+            self.commit = commit.min(index);
+        }
+        outgoing.extend(reply(term, true, index));
+        Ok(outgoing)
+    }
+
+    // Follows `from`, the leader of `term`, and hands it what was proposed
+    // while no leader was known
+    fn follow(&mut self, from: SocketAddr, term: u64, now: Instant) -> io::Result<Outgoing> {
+        self.become_follower(term, now)?;
+        let mut outgoing = Vec::new();
+        if self.leader != Some(from) {
+            self.leader = Some(from);
+            println!("Replication: {} leads term {}", from, term);
+            outgoing.extend(self.queued.drain(..).map(|data| (from, Message::Propose { data })));
+        }
+        Ok(outgoing)
+    }
+
+    // Takes in part of the leader's snapshot at `last`, an index and term,
+    // and starts the log over after it once the snapshot is whole. `chunk`
+    // is where the data goes and the snapshot's size.
+    fn on_snapshot(
+        &mut self,
+        from: SocketAddr,
+        term: u64,
+        last: (u64, u64),
+        chunk: (u64, u64),
+        data: Vec<u8>,
+        now: Instant,
+    ) -> io::Result<Outgoing> {
+        let ((last_index, last_term), (offset, total)) = (last, chunk);
+        if term < self.state.term {
+            let reply = Message::AppendReply {
+                term: self.state.term,
+                success: false,
+                last_index: self.log.last_index(),
+            };
+            return Ok(vec![(from, reply)]);
+        }
+        let mut outgoing = self.follow(from, term, now)?;
+        self.leader_commit = self.leader_commit.max(last_index);
+        let installed = Message::AppendReply {
+            term,
+            success: true,
+            last_index,
+        };
+        // Applied already, the leader only has to hear about it
+        if self.applied >= last_index {
+            self.incoming = None;
+            outgoing.push((from, installed));
+            return Ok(outgoing);
+        }
+
+        // A chunk out of order is dropped, one for another snapshot starts over
+        let held = match &mut self.incoming {
+            Some((index, received)) if *index == last_index && received.len() as u64 == offset => {
+                received.extend(data);
+                received.len()
+            }
+            Some((index, received)) if *index == last_index && offset != 0 => received.len(),
+            _ if offset == 0 => {
+                let held = data.len();
+                self.incoming = Some((last_index, data));
+                held
+            }
+            _ => 0,
+        };
+        if (held as u64) < total {
+            let reply = Message::SnapshotReply {
+                term,
+                last_index,
+                offset: held as u64,
+            };
+            outgoing.push((from, reply));
+            return Ok(outgoing);
+        }
+
+        let (_, data) = self.incoming.take().expect("a snapshot is coming in");
+        self.log.reset(last_index, last_term)?;
+        self.commit = self.commit.max(last_index);
+        self.applied = last_index;
+        self.installed = Some(data.clone());
+        // Kept in case this server leads before it compacts its own log
+        self.snapshot = Some(LogSnapshot {
+            index: last_index,
+            term: last_term,
+            data,
+        });
+        println!("Replication: took the snapshot at {} from {}", last_index, from);
+        outgoing.push((from, installed));
+        Ok(outgoing)
+    }
+
+    fn on_snapshot_reply(&mut self, from: SocketAddr, term: u64, last_index: u64, offset: u64) -> Outgoing {
+        if term != self.state.term {
+            return Vec::new();
+        }
+        let current = self.snapshot.as_ref().map(|snapshot| snapshot.index);
+        let Role::Leader { offsets, .. } = &mut self.role else {
+            return Vec::new();
+        };
+        // A reply about an older snapshot starts the current one over
+        let offset = if current == Some(last_index) { offset } else { 0 };
+        offsets.insert(from, offset);
+        self.append_for(from).map(|append| vec![(from, append)]).unwrap_or_default()
+    }
+
+    fn on_append_reply(&mut self, from: SocketAddr, term: u64, success: bool, last_index: u64) -> Outgoing {
+        if term != self.state.term {
+            return Vec::new();
+        }
+        let last_log_index = self.log.last_index();
+        let Role::Leader { next, matched, offsets } = &mut self.role else {
+            return Vec::new();
+        };
+        let (Some(next_index), Some(matched_index)) = (next.get_mut(&from), matched.get_mut(&from)) else {
+            return Vec::new();
+        };
+
+        if success {
+            offsets.remove(&from);
+            *matched_index = (*matched_index).max(last_index);
+            *next_index = *matched_index + 1;
+        } else {
+            // Step back, jumping straight to the follower's hint
+            *next_index = (*next_index - 1).min(last_index + 1).max(1);
+        }
+        let behind = *next_index <= last_log_index;
+        self.advance_commit();
+
+        // Keep sending until the follower has caught up
+        if behind || !success {
+            return self.append_for(from).map(|append| vec![(from, append)]).unwrap_or_default();
+        }
+        Vec::new()
+    }
+
+    // Committed entries not handed out yet, with their index
+    pub fn take_committed(&mut self) -> Vec<(u64, Vec<u8>)> {
+        let mut committed = Vec::new();
+        while self.applied < self.commit {
+            self.applied += 1;
+            if let Some(entry) = self.log.entry(self.applied) {
+                committed.push((self.applied, entry.data.clone()));
+            }
+        }
+        committed
+    }
+
+    // The snapshot taken from the leader, for storage to install before the
+    // entries committed after it
+    pub fn take_installed(&mut self) -> Option<Vec<u8>> {
+        self.installed.take()
+    }
+
+    // Whether enough entries were applied since the log was last compacted
+    pub fn wants_compaction(&self) -> bool {
+        let kept = (self.config.log_keep + self.config.compact_after) as u64;
+        self.applied.saturating_sub(self.log.base_index) >= kept
+    }
+
+    // Keeps `data`, the tables as of the applied entry at `index`, for
+    // followers behind the log, and drops the entries before the kept tail
+    pub fn compact(&mut self, index: u64, data: Vec<u8>) -> io::Result<()> {
+        let Some(term) = self.log.term_at(index).filter(|_| index <= self.applied) else {
+            return Ok(());
+        };
+        self.snapshot = Some(LogSnapshot { index, term, data });
+        let upto = index.saturating_sub(self.config.log_keep as u64);
+        if upto > self.log.base_index {
+            self.log.compact(upto)?;
+            println!("Replication: compacted the log up to {}", upto);
+        }
+        Ok(())
+    }
+}
+
+// What goes into a log entry. The ID tells a proposal apart from an
+// identical change, so one sent again is appended and waited for only once.
+#[derive(Serialize, Deserialize)]
+struct Proposal {
+    id: u64,
+    change: Change,
+}
+
+// Runs a `Node` on its own UDP socket and applies what it commits to storage
+pub struct Replicator {
+    node: Mutex<Node>,
+    socket: UdpSocket,
+    storage: Arc<Storage>,
+    state: Mutex<State>,
+    // Wakes `run` when a deadline may have moved
+    wake: Notify,
+    // IDs of this server's proposals still waited for, and whether they
+    // were applied
+    proposed: Mutex<HashMap<u64, bool>>,
+    // Wakes the waiting proposals whenever entries were applied
+    applied: Notify,
+}
+
+impl Replicator {
+    pub fn new(
+        socket: UdpSocket,
+        peers: Vec<SocketAddr>,
+        dir: &Path,
+        storage: Arc<Storage>,
+        config: ReplicationConfig,
+    ) -> io::Result<Self> {
+        let id = socket.local_addr()?;
+        let mut node = Node::open(dir, id, peers, config, storage.applied(), Instant::now())?;
+        // What was compacted is in storage, which makes the snapshot again
+        if node.log.base_index > 0 {
+            let tables = storage.tables();
+            node.compact(tables.applied, bincode::serialize(&tables).map_err(io::Error::other)?)?;
+        }
+        Ok(Replicator {
+            node: Mutex::new(node),
+            socket,
+            storage,
+            state: Mutex::new(State::Running),
+            wake: Notify::new(),
+            proposed: Mutex::new(HashMap::new()),
+            applied: Notify::new(),
+        })
+    }
+
+    pub fn leader(&self) -> Option<SocketAddr> {
+        self.node.lock().unwrap().leader()
+    }
+
+    pub fn set_peers(&self, peers: Vec<SocketAddr>) {
+        self.node.lock().unwrap().set_peers(peers);
+    }
+
+    // Replication sockets of the servers the failure detector considers dead
+    pub fn set_dead(&self, dead: HashSet<SocketAddr>) {
+        self.node.lock().unwrap().set_dead(dead, Instant::now());
+        self.wake.notify_one();
+    }
+
+    // Stops while the server is failed and restarts once it recovers
+    pub fn set_state(&self, state: State) {
+        let previous = std::mem::replace(&mut *self.state.lock().unwrap(), state);
+        if previous == State::Failed && state != State::Failed {
+            self.node.lock().unwrap().restart(Instant::now());
+        }
+        self.wake.notify_one();
+    }
+
+    fn is_failed(&self) -> bool {
+        *self.state.lock().unwrap() == State::Failed
+    }
+
+    pub fn caught_up(&self) -> bool {
+        self.node.lock().unwrap().caught_up()
+    }
+
+    async fn send_all(&self, outgoing: Outgoing) {
+        for (peer, message) in outgoing {
+            if let Err(e) = faults::send_to(&self.socket, &protocol::encode(&message), peer).await {
+                eprintln!("Replication: failed to send to {}: {}", peer, e);
+            }
+        }
+    }
+
+    // Hands the change to the replicated log, where it reaches storage on
+    // every server once a majority has it. Returns once it is in this
+    // server's storage, sending it again until then.
+    pub async fn propose(&self, change: Change) -> io::Result<()> {
+        let id = rand::random();
+        let data = bincode::serialize(&Proposal { id, change }).map_err(io::Error::other)?;
+        self.proposed.lock().unwrap().insert(id, false);
+        let result = self.until_applied(id, data).await;
+        self.proposed.lock().unwrap().remove(&id);
+        result
+    }
+
+    async fn until_applied(&self, id: u64, data: Vec<u8>) -> io::Result<()> {
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            let outgoing = self.node.lock().unwrap().propose(data.clone(), Instant::now())?;
+            self.send_all(outgoing).await;
+            self.apply_committed()?;
+            let retry = (Instant::now() + PROPOSE_RETRY).min(deadline);
+            loop {
+                let applied = self.applied.notified();
+                if self.proposed.lock().unwrap().get(&id) == Some(&true) {
+                    return Ok(());
+                }
+                tokio::select! {
+                    _ = applied => {}
+                    _ = tokio::time::sleep_until(retry.into()) => break,
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "change was not committed in time"));
+            }
+        }
+    }
+
+    fn apply_committed(&self) -> io::Result<()> {
+        let (installed, committed) = {
+            let mut node = self.node.lock().unwrap();
+            (node.take_installed(), node.take_committed())
+        };
+        if installed.is_none() && committed.is_empty() {
+            return Ok(());
+        }
+        if let Some(data) = installed {
+            let tables: Tables = bincode::deserialize(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad snapshot: {}", e)))?;
+            self.storage.install_replicated(tables)?;
+        }
+        for (index, data) in committed {
+            // The no-op of a new leader only moves the index
+            let proposal = match data.is_empty() {
+                true => None,
+                false => match bincode::deserialize::<Proposal>(&data) {
+                    Ok(proposal) => Some(proposal),
+                    Err(e) => {
+                        eprintln!("Replication: skipping undecodable entry {}: {}", index, e);
+                        None
+                    }
+                },
+            };
+            match proposal {
+                Some(Proposal { id, change }) => {
+                    self.storage.apply_replicated(index, change)?;
+                    if let Some(applied) = self.proposed.lock().unwrap().get_mut(&id) {
+                        *applied = true;
+                    }
+                }
+                None => self.storage.skip_replicated(index)?,
+            }
+        }
+        self.applied.notify_waiters();
+        self.compact_log()
+    }
+
+    // Drops applied entries from the log once there are enough of them
+    fn compact_log(&self) -> io::Result<()> {
+        if !self.node.lock().unwrap().wants_compaction() {
+            return Ok(());
+        }
+        let tables = self.storage.tables();
+        let data = bincode::serialize(&tables).map_err(io::Error::other)?;
+        self.node.lock().unwrap().compact(tables.applied, data)
+    }
+
+    // Only returns if the socket fails
+    pub async fn run(&self) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let deadline = self.node.lock().unwrap().next_deadline();
+            let failed = self.is_failed();
+            let outgoing = tokio::select! {
+                received = faults::recv_from(&self.socket, &mut buffer) => {
+                    let (size, from) = received?;
+                    if self.is_failed() {
+                        continue;
+                    }
+                    match protocol::decode(&buffer[..size]) {
+                        Ok(message) => self.node.lock().unwrap().step(from, message, Instant::now())?,
+                        Err(e) => {
+                            eprintln!("Replication: bad message from {}: {}", from, e);
+                            continue;
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.into()), if !failed => {
+                    self.node.lock().unwrap().tick(Instant::now())?
+                }
+                _ = self.wake.notified() => continue,
+            };
+            self.send_all(outgoing).await;
+            if let Err(e) = self.apply_committed() {
+                eprintln!("Replication: failed to apply committed changes: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::OnlineStatus;
+    use tokio::task::JoinHandle;
+
+    fn fast() -> ReplicationConfig {
+        ReplicationConfig {
+            heartbeat: Duration::from_millis(20),
+            election_timeout: (Duration::from_millis(100), Duration::from_millis(250)),
+            max_batch: 4,
+            compact_after: 6,
+            log_keep: 2,
+            snapshot_chunk: 64,
+        }
+    }
+
+    fn status(client_id: &str, online: bool) -> Change {
+        Change::SetStatus(OnlineStatus {
+            ip: format!("127.0.0.1:70{:0>2}", client_id),
+            status: online,
+            client_id: client_id.to_string(),
+        })
+    }
+
+    struct Server {
+        addr: SocketAddr,
+        dir: PathBuf,
+        storage: Arc<Storage>,
+        replicator: Arc<Replicator>,
+        task: JoinHandle<()>,
+    }
+
+    async fn start(socket: UdpSocket, peers: Vec<SocketAddr>, dir: PathBuf) -> Server {
+        let addr = socket.local_addr().unwrap();
+        let storage = Arc::new(Storage::open(&dir.join("store")).unwrap());
+        let replicator = Arc::new(
+            Replicator::new(socket, peers, &dir.join("raft"), Arc::clone(&storage), fast()).unwrap(),
+        );
+        let running = Arc::clone(&replicator);
+        let task = tokio::spawn(async move {
+            running.run().await.unwrap();
+        });
+        Server { addr, dir, storage, replicator, task }
+    }
+
+    async fn cluster(root: &Path) -> Vec<Server> {
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            sockets.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let addrs: Vec<SocketAddr> = sockets.iter().map(|socket| socket.local_addr().unwrap()).collect();
+
+        let mut servers = Vec::new();
+        for (i, socket) in sockets.into_iter().enumerate() {
+            let peers = addrs.iter().copied().filter(|addr| *addr != addrs[i]).collect();
+            servers.push(start(socket, peers, root.join(format!("server{}", i))).await);
+        }
+        servers
+    }
+
+    async fn wait_for(what: &str, condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    async fn wait_for_leader(servers: &[&Server]) -> SocketAddr {
+        wait_for("a leader", || {
+            let leaders: Vec<_> = servers.iter().map(|server| server.replicator.leader()).collect();
+            leaders[0].is_some() && leaders.iter().all(|leader| *leader == leaders[0])
+        })
+        .await;
+        servers[0].replicator.leader().unwrap()
+    }
+
+    #[tokio::test]
+    async fn three_servers_apply_the_same_changes() {
+        let root = tempfile::tempdir().unwrap();
+        let servers = cluster(root.path()).await;
+        wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+
+        // Proposed on every server, leader or not
+        for (i, server) in servers.iter().enumerate() {
+            server.replicator.propose(status(&i.to_string(), true)).await.unwrap();
+        }
+        servers[0].replicator.propose(status("0", false)).await.unwrap();
+
+        wait_for("all changes on every server", || {
+            servers.iter().all(|server| {
+                let directory = server.storage.directory();
+                directory.len() == 3 && directory.iter().any(|entry| entry.client_id == "0" && !entry.status)
+            })
+        })
+        .await;
+        let directory = servers[0].storage.directory();
+        for server in &servers[1..] {
+            assert_eq!(server.storage.directory(), directory);
+        }
+    }
+
+    #[tokio::test]
+    async fn restarted_server_catches_up() {
+        let root = tempfile::tempdir().unwrap();
+        let mut servers = cluster(root.path()).await;
+        wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+        servers[0].replicator.propose(status("1", true)).await.unwrap();
+        wait_for("the first change everywhere", || {
+            servers.iter().all(|server| server.storage.directory().len() == 1)
+        })
+        .await;
+
+        // Take down a follower and keep changing the directory without it
+        let leader = servers[0].replicator.leader().unwrap();
+        let down = servers.iter().position(|server| server.addr != leader).unwrap();
+        let Server { addr, dir, task, .. } = servers.remove(down);
+        task.abort();
+        let _ = task.await;
+
+        for client in 2..12 {
+            servers[0].replicator.propose(status(&client.to_string(), true)).await.unwrap();
+        }
+        wait_for("the changes on the running servers", || {
+            servers.iter().all(|server| server.storage.directory().len() == 11)
+        })
+        .await;
+
+        // Same address and data directory, as after a crash
+        let peers = servers.iter().map(|server| server.addr).collect();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let restarted = start(socket, peers, dir).await;
+        wait_for("the restarted server to catch up", || restarted.storage.directory().len() == 11).await;
+        assert_eq!(restarted.storage.directory(), servers[0].storage.directory());
+    }
+
+    #[tokio::test]
+    async fn failed_server_resyncs_when_it_recovers() {
+        let root = tempfile::tempdir().unwrap();
+        let servers = cluster(root.path()).await;
+        let leader = wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+        let failed = servers.iter().find(|server| server.addr != leader).unwrap();
+        let running: Vec<&Server> = servers.iter().filter(|server| server.addr != failed.addr).collect();
+
+        failed.replicator.set_state(State::Failed);
+        for client in 1..8 {
+            running[0].replicator.propose(status(&client.to_string(), true)).await.unwrap();
+        }
+        wait_for("the changes on the running servers", || {
+            running.iter().all(|server| server.storage.directory().len() == 7)
+        })
+        .await;
+        assert!(failed.storage.directory().is_empty());
+
+        // It forgets the leader it knew and only counts as caught up once it
+        // heard from the current one and applied what it committed
+        failed.replicator.set_state(State::Recovering);
+        assert!(!failed.replicator.caught_up());
+        wait_for("the recovering server to catch up", || failed.replicator.caught_up()).await;
+        assert_eq!(failed.storage.directory(), running[0].storage.directory());
+    }
+
+    #[tokio::test]
+    async fn added_server_catches_up() {
+        let root = tempfile::tempdir().unwrap();
+        let servers = cluster(root.path()).await;
+        wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+        for client in 1..6 {
+            servers[0].replicator.propose(status(&client.to_string(), true)).await.unwrap();
+        }
+
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let added = start(socket, servers.iter().map(|server| server.addr).collect(), root.path().join("added")).await;
+        for server in &servers {
+            let mut peers: Vec<SocketAddr> =
+                servers.iter().map(|other| other.addr).filter(|other| *other != server.addr).collect();
+            peers.push(addr);
+            server.replicator.set_peers(peers);
+        }
+        wait_for("the added server to catch up", || added.storage.directory().len() == 5).await;
+
+        // And it counts towards the majority from now on
+        servers[1].replicator.propose(status("6", true)).await.unwrap();
+        wait_for("the next change on the added server", || added.storage.directory().len() == 6).await;
+    }
+
+    #[tokio::test]
+    async fn only_peers_take_part() {
+        let root = tempfile::tempdir().unwrap();
+        let servers = cluster(root.path()).await;
+        let leader = wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+
+        // A proposal of an address that is not a peer goes nowhere
+        let outsider = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data = bincode::serialize(&Proposal { id: 1, change: status("9", true) }).unwrap();
+        outsider.send_to(&protocol::encode(&Message::Propose { data }), leader).await.unwrap();
+
+        // While a peer's is applied by the time propose returns
+        servers[0].replicator.propose(status("1", true)).await.unwrap();
+        assert_eq!(servers[0].storage.directory().len(), 1);
+        wait_for("the change on every server", || {
+            servers.iter().all(|server| server.storage.directory().len() == 1)
+        })
+        .await;
+        assert!(servers.iter().all(|server| server.storage.directory()[0].client_id == "1"));
+    }
+
+    #[tokio::test]
+    async fn follower_behind_the_compacted_log_gets_the_snapshot() {
+        let root = tempfile::tempdir().unwrap();
+        let mut servers = cluster(root.path()).await;
+        let leader = wait_for_leader(&servers.iter().collect::<Vec<_>>()).await;
+        let down = servers.iter().position(|server| server.addr != leader).unwrap();
+        let Server { addr, dir, task, .. } = servers.remove(down);
+        task.abort();
+        let _ = task.await;
+
+        for client in 1..21 {
+            servers[0].replicator.propose(status(&client.to_string(), true)).await.unwrap();
+        }
+        let leader = servers.iter().find(|server| server.addr == leader).unwrap();
+        let base = leader.replicator.node.lock().unwrap().log.base_index;
+        assert!(base > 1, "the log was not compacted");
+
+        let peers = servers.iter().map(|server| server.addr).collect();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let restarted = start(socket, peers, dir).await;
+        wait_for("the restarted server to catch up", || restarted.storage.directory().len() == 20).await;
+        assert_eq!(restarted.storage.directory(), leader.storage.directory());
+        assert!(restarted.replicator.node.lock().unwrap().log.base_index > 0);
+
+        // And it keeps up with what comes after the snapshot
+        leader.replicator.propose(status("21", true)).await.unwrap();
+        wait_for("the next change", || restarted.storage.directory().len() == 21).await;
+    }
+
+    #[test]
+    fn compacted_log_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries: Vec<LogEntry> = (1..=5).map(|term| LogEntry { term, data: vec![term as u8] }).collect();
+        let (mut log, _) = RaftLog::open(dir.path()).unwrap();
+        log.append(&entries).unwrap();
+        drop(log);
+
+        // A log from before compaction opens as it did
+        let (mut log, _) = RaftLog::open(dir.path()).unwrap();
+        assert_eq!((log.base_index, log.last_index()), (0, 5));
+        log.compact(3).unwrap();
+        log.append(&[LogEntry { term: 6, data: vec![6] }]).unwrap();
+        drop(log);
+
+        let (log, _) = RaftLog::open(dir.path()).unwrap();
+        assert_eq!((log.base_index, log.base_term), (3, 3));
+        assert_eq!((log.last_index(), log.last_term()), (6, 6));
+        assert_eq!(log.term_at(2), None);
+        assert_eq!(log.term_at(3), Some(3));
+        assert_eq!(log.entry(4).unwrap().data, vec![4]);
+        assert_eq!(log.entries_from(5, 10).len(), 2);
+    }
+}
@@ -0,0 +1,741 @@
+// Server state that used to live in hand-edited CSV files: the directory of
+// service, access-control requests waiting for an offline client, and the
+// index of stored samples. Every change is appended to a log before it is
+// applied; the log is folded into a snapshot (written to a temporary file and
+// renamed over the old one) once it grows, so a crash at any point leaves
+// either the old or the new state on disk.
+//
+// The directory and pending requests only change through the replicated log
+// (see `replication`), which hands committed changes over with their index.
+// The samples index follows the files on this server and stays local. The
+// list of servers in the cluster is replicated as well, so every server
+// agrees on who its peers are, and so are the journal of encryption jobs
+// (see `journal`), the client accounts and sessions (see `accounts`) and the
+// views used on shared images (see `view_counts`).
+use crate::ownership;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use protocol::{ClientEndpoint, OnlineStatus, ServerInfo, SignedGrant};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SNAPSHOT_FILE: &str = "snapshot.bin";
+const LOG_FILE: &str = "log.bin";
+// Changes kept in the log before it is compacted into the snapshot
+const COMPACT_AFTER: usize = 256;
+
+// Access-control update waiting for its target client to come online
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingRequest {
+    pub client_id: String,
+    pub image_id: String,
+    pub views: u32,
+    pub grant: Option<SignedGrant>,
+}
+
+// A sample stored under samples/<client_id>/<image_id>.jpg
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SampleEntry {
+    pub client_id: String,
+    pub image_id: String,
+}
+
+impl SampleEntry {
+    pub fn file_name(&self) -> String {
+        format!("{}.jpg", self.image_id)
+    }
+
+    pub fn path(&self, samples_root: &Path) -> PathBuf {
+        samples_root.join(&self.client_id).join(self.file_name())
+    }
+
+    // Whether `path` stays inside the samples folder
+    pub fn is_safe(&self) -> bool {
+        let safe = |id: &str| !id.is_empty() && id != ".." && !id.contains(['/', '\\']);
+        safe(&self.client_id) && safe(&self.image_id)
+    }
+}
+
+// An upload a leader took on and has not returned to its client yet
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobRecord {
+    pub request_id: u64,
+    // Image socket of the client
+    pub reply_to: String,
+    // The leader that took it on last
+    pub server: u32,
+    // How many leaders took it on
+    pub attempts: u32,
+    // Unix time in seconds of the last attempt
+    pub started: u64,
+}
+
+// A registered client. `password_hash` is an Argon2 hash in PHC format, which
+// includes its salt. `public_key` is the Ed25519 identity the client last
+// logged in with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub client_id: String,
+    pub password_hash: String,
+    pub public_key: Vec<u8>,
+}
+
+// The client that owns a shared image, `image_id` being <owner>_<name>
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ImageOwner {
+    pub image_id: String,
+    pub owner: String,
+}
+
+// The views `viewer` used on `image_id` under the newest grant the servers
+// saw for it, the one with `nonce`. A newer grant starts counting again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewCount {
+    pub image_id: String,
+    pub viewer: String,
+    pub nonce: u64,
+    pub used: u32,
+    // Unix time of the first view under that grant
+    pub first_viewed: Option<u64>,
+}
+
+impl ViewCount {
+    // Moves on to the grant with `nonce` if it is newer
+    fn follow(&mut self, nonce: u64) {
+        if nonce > self.nonce {
+            self.nonce = nonce;
+            self.used = 0;
+            self.first_viewed = None;
+        }
+    }
+}
+
+// A logged in client, until `expires` (seconds since the Unix epoch)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub token: String,
+    pub client_id: String,
+    pub expires: u64,
+}
+
+// The sockets a client reported in its last STATUS
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KnownEndpoint {
+    pub client_id: String,
+    pub endpoint: ClientEndpoint,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Tables {
+    // One entry per client address, in the order they first showed up
+    pub directory: Vec<OnlineStatus>,
+    pub pending: Vec<PendingRequest>,
+    pub samples: Vec<SampleEntry>,
+    // Servers that joined the cluster, in the order they joined
+    pub servers: Vec<ServerInfo>,
+    // Servers that left, so peers from the config files stay out too
+    pub left: Vec<ServerInfo>,
+    pub jobs: Vec<JobRecord>,
+    pub accounts: Vec<Account>,
+    pub sessions: Vec<Session>,
+    pub images: Vec<ImageOwner>,
+    pub views: Vec<ViewCount>,
+    pub endpoints: Vec<KnownEndpoint>,
+    // Index of the last replicated change applied
+    pub applied: u64,
+}
+
+// One entry of the log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Change {
+    // Inserts the client or updates the status of its address
+    SetStatus(OnlineStatus),
+    AddPending(PendingRequest),
+    // Drops the oldest pending request for the client
+    TakePending { client_id: String },
+    AddSample(SampleEntry),
+    // Adds the server or updates its addresses
+    AddServer(ServerInfo),
+    RemoveServer { id: u32 },
+    // A leader takes on a job, again if it is in the journal already
+    StartJob(JobRecord),
+    FinishJob { request_id: u64 },
+    // Ignored if another server gave the client ID away first
+    Register(Account),
+    // The client logged in with a new identity key
+    SetKey { client_id: String, public_key: Vec<u8> },
+    // Also drops the sessions that expired by `now`
+    StartSession { session: Session, now: u64 },
+    // Ignored if the image has an owner already
+    ClaimImage(ImageOwner),
+    // The owner sent the viewer a grant with `nonce`, older ones stop counting
+    NewGrant { image_id: String, viewer: String, nonce: u64 },
+    // The viewer opened the image at `at` under the grant with `nonce`,
+    // which gives it `views`. Ignored once they are used up.
+    RecordView { image_id: String, viewer: String, nonce: u64, views: u32, at: u64 },
+    // Replaces the client's endpoint and any other client's on the same sockets
+    SetEndpoint(KnownEndpoint),
+}
+
+impl Tables {
+    fn apply(&mut self, change: &Change) {
+        match change {
+            Change::SetStatus(status) => {
+                match self.directory.iter_mut().find(|entry| entry.ip == status.ip) {
+                    Some(entry) => entry.status = status.status,
+                    None => self.directory.push(status.clone()),
+                }
+            }
+            // The same request can arrive through more than one server
+            Change::AddPending(request) => {
+                if !self.pending.contains(request) {
+                    self.pending.push(request.clone());
+                }
+            }
+            Change::TakePending { client_id } => {
+                if let Some(i) = self.pending.iter().position(|request| request.client_id == *client_id) {
+                    self.pending.remove(i);
+                }
+            }
+            Change::AddSample(sample) => {
+                if !self.samples.contains(sample) {
+                    self.samples.push(sample.clone());
+                }
+            }
+            Change::AddServer(server) => {
+                self.left.retain(|left| left.id != server.id);
+                match self.servers.iter_mut().find(|known| known.id == server.id) {
+                    Some(known) => *known = server.clone(),
+                    None => self.servers.push(server.clone()),
+                }
+            }
+            Change::RemoveServer { id } => {
+                if let Some(i) = self.servers.iter().position(|server| server.id == *id) {
+                    let server = self.servers.remove(i);
+                    self.left.push(server);
+                }
+            }
+            Change::StartJob(job) => match self.jobs.iter_mut().find(|known| known.request_id == job.request_id) {
+                Some(known) => {
+                    known.reply_to = job.reply_to.clone();
+                    known.server = job.server;
+                    known.attempts += 1;
+                    known.started = job.started;
+                }
+                None => self.jobs.push(job.clone()),
+            },
+            Change::FinishJob { request_id } => self.jobs.retain(|job| job.request_id != *request_id),
+            Change::Register(account) => {
+                if !self.accounts.iter().any(|known| known.client_id == account.client_id) {
+                    self.accounts.push(account.clone());
+                }
+            }
+            Change::SetKey { client_id, public_key } => {
+                if let Some(account) = self.accounts.iter_mut().find(|account| account.client_id == *client_id) {
+                    account.public_key = public_key.clone();
+                }
+            }
+            Change::StartSession { session, now } => {
+                self.sessions.retain(|open| open.expires > *now);
+                self.sessions.push(session.clone());
+            }
+            Change::ClaimImage(claim) => {
+                if !self.images.iter().any(|image| image.image_id == claim.image_id) {
+                    self.images.push(claim.clone());
+                }
+            }
+            Change::NewGrant { image_id, viewer, nonce } => self.view_count(image_id, viewer).follow(*nonce),
+            Change::RecordView { image_id, viewer, nonce, views, at } => {
+                let count = self.view_count(image_id, viewer);
+                count.follow(*nonce);
+                if count.nonce == *nonce && count.used < *views {
+                    count.used += 1;
+                    count.first_viewed.get_or_insert(*at);
+                }
+            }
+            Change::SetEndpoint(known) => {
+                self.endpoints.retain(|other| {
+                    other.client_id != known.client_id
+                        && other.endpoint.image_addr != known.endpoint.image_addr
+                        && other.endpoint.leader_ack_addr != known.endpoint.leader_ack_addr
+                });
+                self.endpoints.push(known.clone());
+            }
+        }
+    }
+
+    fn view_count(&mut self, image_id: &str, viewer: &str) -> &mut ViewCount {
+        let found = self.views.iter().position(|count| count.image_id == image_id && count.viewer == viewer);
+        let i = found.unwrap_or_else(|| {
+            self.views.push(ViewCount {
+                image_id: image_id.to_string(),
+                viewer: viewer.to_string(),
+                nonce: 0,
+                used: 0,
+                first_viewed: None,
+            });
+            self.views.len() - 1
+        });
+        &mut self.views[i]
+    }
+}
+
+struct Inner {
+    tables: Tables,
+    log: File,
+    logged: usize,
+}
+
+pub struct Storage {
+    dir: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Log records are a big-endian length followed by the bincode of the
+// replicated index (0 for local changes) and the Change
+fn read_log(path: &Path) -> io::Result<(Vec<(u64, Change)>, u64)> {
+    let mut changes = Vec::new();
+    let mut valid_len = 0u64;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((changes, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut reader = BufReader::new(file);
+    loop {
+        let mut len = [0u8; 4];
+        if reader.read_exact(&mut len).is_err() {
+            break;
+        }
+        let mut record = vec![0u8; u32::from_be_bytes(len) as usize];
+        if reader.read_exact(&mut record).is_err() {
+            break;
+        }
+        match bincode::deserialize(&record) {
+            Ok(change) => changes.push(change),
+            Err(_) => break,
+        }
+        valid_len += 4 + record.len() as u64;
+    }
+    Ok((changes, valid_len))
+}
+
+impl Storage {
+    // Opens the store in `dir`, replaying the log on top of the snapshot
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut tables = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(snapshot) => bincode::deserialize(&snapshot)
+                .map_err(|e| invalid(format!("Corrupt snapshot in {}: {}", dir.display(), e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Tables::default(),
+            Err(e) => return Err(e),
+        };
+
+        // A record cut short by a crash is dropped
+        let log_path = dir.join(LOG_FILE);
+        let (changes, valid_len) = read_log(&log_path)?;
+        for (index, change) in &changes {
+            tables.apply(change);
+            tables.applied = tables.applied.max(*index);
+        }
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        log.set_len(valid_len)?;
+
+        Ok(Storage {
+            dir: dir.to_path_buf(),
+            inner: Mutex::new(Inner {
+                tables,
+                log,
+                logged: changes.len(),
+            }),
+        })
+    }
+
+    // Whether nothing was ever written to this store
+    pub fn is_empty(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.tables == Tables::default() && inner.logged == 0
+    }
+
+    // Index of the last replicated change in the tables
+    pub fn applied(&self) -> u64 {
+        self.inner.lock().unwrap().tables.applied
+    }
+
+    // Applies the committed change at `index` of the replicated log, once
+    pub fn apply_replicated(&self, index: u64, change: Change) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.tables.applied {
+            return Ok(());
+        }
+        self.apply_locked(&mut inner, index, change)
+    }
+
+    // Moves past a replicated entry that carries no change
+    pub fn skip_replicated(&self, index: u64) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tables.applied = inner.tables.applied.max(index);
+        Ok(())
+    }
+
+    fn apply_locked(&self, inner: &mut Inner, index: u64, change: Change) -> io::Result<()> {
+        let record = bincode::serialize(&(index, &change)).map_err(io::Error::other)?;
+        let mut entry = (record.len() as u32).to_be_bytes().to_vec();
+        entry.extend_from_slice(&record);
+        inner.log.write_all(&entry)?;
+        inner.log.sync_data()?;
+
+        inner.tables.apply(&change);
+        inner.tables.applied = inner.tables.applied.max(index);
+        inner.logged += 1;
+        if inner.logged >= COMPACT_AFTER {
+            self.snapshot(inner)?;
+        }
+        Ok(())
+    }
+
+    // Writes the tables to a new snapshot and empties the log
+    fn snapshot(&self, inner: &mut Inner) -> io::Result<()> {
+        let snapshot = bincode::serialize(&inner.tables).map_err(io::Error::other)?;
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        tmp.write_all(&snapshot)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        inner.log.set_len(0)?;
+        inner.log.sync_data()?;
+        inner.logged = 0;
+        Ok(())
+    }
+
+    // Everything a joining server starts from
+    pub fn tables(&self) -> Tables {
+        self.inner.lock().unwrap().tables.clone()
+    }
+
+    // Replaces the tables with a snapshot taken on another server
+    pub fn install(&self, tables: Tables) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tables = tables;
+        self.snapshot(&mut inner)
+    }
+
+    // Replaces the tables with the replication leader's snapshot, keeping
+    // the samples, which every server indexes for itself
+    pub fn install_replicated(&self, mut tables: Tables) -> io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+        tables.samples = std::mem::take(&mut inner.tables.samples);
+        inner.tables = tables;
+        self.snapshot(&mut inner)
+    }
+
+    // Servers in the cluster and servers that left it
+    pub fn servers(&self) -> (Vec<ServerInfo>, Vec<ServerInfo>) {
+        let inner = self.inner.lock().unwrap();
+        (inner.tables.servers.clone(), inner.tables.left.clone())
+    }
+
+    pub fn directory(&self) -> Vec<OnlineStatus> {
+        self.inner.lock().unwrap().tables.directory.clone()
+    }
+
+    pub fn client(&self, client_id: &str) -> Option<OnlineStatus> {
+        let inner = self.inner.lock().unwrap();
+        inner.tables.directory.iter().find(|entry| entry.client_id == client_id).cloned()
+    }
+
+    pub fn endpoint(&self, client_id: &str) -> Option<ClientEndpoint> {
+        let inner = self.inner.lock().unwrap();
+        let known = inner.tables.endpoints.iter().find(|known| known.client_id == client_id);
+        known.map(|known| known.endpoint.clone())
+    }
+
+    // Client whose ELECT came from `addr`
+    pub fn client_by_image_addr(&self, addr: &SocketAddr) -> Option<ClientEndpoint> {
+        let inner = self.inner.lock().unwrap();
+        let known = inner.tables.endpoints.iter().find(|known| known.endpoint.image_addr == *addr);
+        known.map(|known| known.endpoint.clone())
+    }
+
+    // Client whose image upload came from `addr`
+    pub fn client_by_leader_ack_addr(&self, addr: &SocketAddr) -> Option<ClientEndpoint> {
+        let inner = self.inner.lock().unwrap();
+        let known = inner.tables.endpoints.iter().find(|known| known.endpoint.leader_ack_addr == *addr);
+        known.map(|known| known.endpoint.clone())
+    }
+
+    // Oldest pending request for the client
+    pub fn pending_for(&self, client_id: &str) -> Option<PendingRequest> {
+        let inner = self.inner.lock().unwrap();
+        inner.tables.pending.iter().find(|request| request.client_id == client_id).cloned()
+    }
+
+    pub fn account(&self, client_id: &str) -> Option<Account> {
+        let inner = self.inner.lock().unwrap();
+        inner.tables.accounts.iter().find(|account| account.client_id == client_id).cloned()
+    }
+
+    // Every client ID in use, registered or only seen in the directory
+    pub fn client_ids(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let registered = inner.tables.accounts.iter().map(|account| account.client_id.clone());
+        let listed = inner.tables.directory.iter().map(|entry| entry.client_id.clone());
+        registered.chain(listed).collect()
+    }
+
+    // The client whose session `token` opened, if it hasn't expired by `now`
+    pub fn session_client(&self, token: &str, now: u64) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .tables
+            .sessions
+            .iter()
+            .find(|session| session.token == token && session.expires > now)
+            .map(|session| session.client_id.clone())
+    }
+
+    pub fn image_owner(&self, image_id: &str) -> Option<String> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .tables
+            .images
+            .iter()
+            .find(|image| image.image_id == image_id)
+            .map(|image| image.owner.clone())
+    }
+
+    pub fn view_count(&self, image_id: &str, viewer: &str) -> Option<ViewCount> {
+        let inner = self.inner.lock().unwrap();
+        let mut counts = inner.tables.views.iter();
+        counts.find(|count| count.image_id == image_id && count.viewer == viewer).cloned()
+    }
+
+    pub fn job(&self, request_id: u64) -> Option<JobRecord> {
+        let inner = self.inner.lock().unwrap();
+        inner.tables.jobs.iter().find(|job| job.request_id == request_id).cloned()
+    }
+
+    pub fn jobs(&self) -> Vec<JobRecord> {
+        self.inner.lock().unwrap().tables.jobs.clone()
+    }
+
+    pub fn samples(&self) -> Vec<SampleEntry> {
+        self.inner.lock().unwrap().tables.samples.clone()
+    }
+
+    pub fn add_sample(&self, client_id: &str, image_id: &str) -> io::Result<()> {
+        let sample = SampleEntry {
+            client_id: client_id.to_string(),
+            image_id: image_id.to_string(),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        self.apply_locked(&mut inner, 0, Change::AddSample(sample))
+    }
+}
+
+// One-time import of the CSV files and samples folder older servers kept.
+// The samples index is filled directly; the directory, pending requests and
+// owners of the samples are returned so they can go through the replicated
+// log. The CSV files stay
+// until `finish_import` renames them, once every change has committed, so a
+// crash in between imports them again; all of the changes can be applied twice.
+pub fn import_legacy(
+    storage: &Storage,
+    directory_csv: &Path,
+    offline_csv: &Path,
+    samples_root: &Path,
+) -> io::Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    if let Ok(content) = fs::read_to_string(directory_csv) {
+        let entries = protocol::legacy::parse_dos_csv(&content);
+        println!("Importing {} directory entries from {}", entries.len(), directory_csv.display());
+        changes.extend(entries.into_iter().map(Change::SetStatus));
+    }
+
+    if let Ok(content) = fs::read_to_string(offline_csv) {
+        let mut imported = 0;
+        for line in content.lines().filter(|line| !line.starts_with("client_id,")) {
+            let parts: Vec<&str> = line.split(',').collect();
+            if parts.len() < 3 {
+                continue;
+            }
+            let grant = parts
+                .get(3)
+                .and_then(|column| STANDARD.decode(column.trim()).ok())
+                .and_then(|bytes| SignedGrant::from_bytes(&bytes).ok());
+            changes.push(Change::AddPending(PendingRequest {
+                client_id: parts[0].to_string(),
+                image_id: parts[1].to_string(),
+                views: parts[2].trim().parse().unwrap_or(0),
+                grant,
+            }));
+            imported += 1;
+        }
+        println!("Importing {} pending requests from {}", imported, offline_csv.display());
+    }
+
+    // Samples stay where they are, only the index is built
+    if let Ok(client_folders) = fs::read_dir(samples_root) {
+        for client_folder in client_folders.flatten() {
+            if !client_folder.path().is_dir() {
+                continue;
+            }
+            let client_id = client_folder.file_name().to_string_lossy().to_string();
+            for sample in fs::read_dir(client_folder.path())?.flatten() {
+                let name = sample.file_name().to_string_lossy().to_string();
+                if let Some(image_id) = name.strip_suffix(".jpg") {
+                    storage.add_sample(&client_id, image_id)?;
+                    // Uploaded before owners were recorded
+                    let shared_id = ownership::shared_id(&client_id, image_id);
+                    if storage.image_owner(&shared_id).is_none() {
+                        changes.push(ownership::claim(&client_id, &shared_id));
+                    }
+                }
+            }
+        }
+    }
+    Ok(changes)
+}
+
+// Whether CSV files are left that `import_legacy` would read
+pub fn legacy_left(directory_csv: &Path, offline_csv: &Path) -> bool {
+    directory_csv.exists() || offline_csv.exists()
+}
+
+// Renames the CSV files to *.imported once their changes have committed, so
+// they are not read again
+pub fn finish_import(directory_csv: &Path, offline_csv: &Path) -> io::Result<()> {
+    for path in [directory_csv, offline_csv] {
+        let mut imported = path.as_os_str().to_owned();
+        imported.push(".imported");
+        match fs::rename(path, imported) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DIRECTORY_OF_SERVICE, OFFLINE_REQUESTS};
+
+    #[test]
+    fn sample_names_stay_in_the_samples_folder() {
+        let sample = |client_id: &str, image_id: &str| SampleEntry {
+            client_id: client_id.to_string(),
+            image_id: image_id.to_string(),
+        };
+        assert!(sample("1", "cat").is_safe());
+        assert!(sample("1", "cat..v2").is_safe());
+        for (client_id, image_id) in [("1", "../../etc/cron.d/x"), ("1", "a\\b"), ("..", "cat"), ("", "cat"), ("1/2", "cat")] {
+            assert!(!sample(client_id, image_id).is_safe(), "{}/{}", client_id, image_id);
+        }
+    }
+
+    #[test]
+    fn endpoints_follow_the_last_status() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = Storage::open(root.path()).unwrap();
+        let addr = |port: u16| SocketAddr::from(([127, 0, 0, 1], port));
+        let known = |client_id: &str, image: u16, leader_ack: u16| {
+            Change::SetEndpoint(KnownEndpoint {
+                client_id: client_id.to_string(),
+                endpoint: ClientEndpoint {
+                    image_addr: addr(image),
+                    leader_ack_addr: addr(leader_ack),
+                },
+            })
+        };
+        storage.apply_replicated(1, known("1", 2005, 9080)).unwrap();
+        storage.apply_replicated(2, known("2", 7001, 7005)).unwrap();
+        assert_eq!(storage.client_by_leader_ack_addr(&addr(7005)).unwrap().image_addr, addr(7001));
+        assert_eq!(storage.client_by_image_addr(&addr(2005)).unwrap().leader_ack_addr, addr(9080));
+
+        // A client that moved is only found on its new sockets
+        storage.apply_replicated(3, known("1", 2006, 9081)).unwrap();
+        assert!(storage.client_by_leader_ack_addr(&addr(9080)).is_none());
+        assert_eq!(storage.endpoint("1").unwrap().image_addr, addr(2006));
+
+        // And sockets another client took over are no longer the old one's
+        storage.apply_replicated(4, known("3", 7001, 7005)).unwrap();
+        assert!(storage.endpoint("2").is_none());
+        assert_eq!(Storage::open(root.path()).unwrap().endpoint("3"), storage.endpoint("3"));
+    }
+
+    #[test]
+    fn legacy_files_are_imported_once() {
+        let root = tempfile::tempdir().unwrap();
+        let storage = Storage::open(&root.path().join("store")).unwrap();
+        let directory_csv = root.path().join(DIRECTORY_OF_SERVICE);
+        let offline_csv = root.path().join(OFFLINE_REQUESTS);
+        let samples_root = root.path().join("samples");
+        fs::write(&directory_csv, "uid,client_id,status\n127.0.0.1:8079,1,true\n").unwrap();
+        // The grant column is optional, and one that doesn't decode is dropped
+        fs::write(&offline_csv, "client_id,image_id,views\n2,1_cat,3\n3,1_dog,4,not-base64\nshort,row\n").unwrap();
+        fs::create_dir_all(samples_root.join("1")).unwrap();
+        fs::write(samples_root.join("1").join("cat.jpg"), b"jpeg").unwrap();
+        fs::write(samples_root.join("1").join("notes.txt"), b"text").unwrap();
+        fs::write(samples_root.join("stray.jpg"), b"jpeg").unwrap();
+
+        let changes = import_legacy(&storage, &directory_csv, &offline_csv, &samples_root).unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                Change::SetStatus(OnlineStatus {
+                    ip: "127.0.0.1:8079".to_string(),
+                    status: true,
+                    client_id: "1".to_string(),
+                }),
+                Change::AddPending(PendingRequest {
+                    client_id: "2".to_string(),
+                    image_id: "1_cat".to_string(),
+                    views: 3,
+                    grant: None,
+                }),
+                Change::AddPending(PendingRequest {
+                    client_id: "3".to_string(),
+                    image_id: "1_dog".to_string(),
+                    views: 4,
+                    grant: None,
+                }),
+                ownership::claim("1", "1_cat"),
+            ]
+        );
+        assert_eq!(
+            storage.samples(),
+            vec![SampleEntry {
+                client_id: "1".to_string(),
+                image_id: "cat".to_string(),
+            }]
+        );
+        // Until the changes commit, a restart imports the same again
+        assert!(legacy_left(&directory_csv, &offline_csv));
+        assert_eq!(import_legacy(&storage, &directory_csv, &offline_csv, &samples_root).unwrap(), changes);
+        assert_eq!(storage.samples().len(), 1);
+
+        for (index, change) in changes.into_iter().enumerate() {
+            storage.apply_replicated(index as u64 + 1, change).unwrap();
+        }
+        finish_import(&directory_csv, &offline_csv).unwrap();
+        assert!(!legacy_left(&directory_csv, &offline_csv));
+        assert!(root.path().join(format!("{}.imported", DIRECTORY_OF_SERVICE)).exists());
+        // Renamed, so a second start finds nothing to import
+        assert!(import_legacy(&storage, &directory_csv, &offline_csv, &samples_root)
+            .unwrap()
+            .is_empty());
+        finish_import(&directory_csv, &offline_csv).unwrap();
+    }
+}
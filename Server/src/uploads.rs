@@ -0,0 +1,201 @@
+use crate::load::ActiveTransfers;
+use protocol::transfer::{Completed, Inbox, Received, TransferConfig};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+// An upload that finished reassembling, waiting in its own file for the
+// encryption task
+#[derive(Debug)]
+pub struct Upload {
+    pub from: SocketAddr,
+    pub transfer_id: u32,
+    pub path: PathBuf,
+}
+
+impl Upload {
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        fs::read(&self.path)
+    }
+
+    // Deletes the temporary file once the upload has been handled
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Every (sender, transfer ID) pair gets its own file so concurrent uploads
+// never overwrite each other
+pub fn upload_path(dir: &Path, from: &SocketAddr, transfer_id: u32) -> PathBuf {
+    dir.join(format!("{}_{}_{}.img", from.ip(), from.port(), transfer_id))
+}
+
+pub fn store(dir: &Path, completed: Completed) -> io::Result<Upload> {
+    fs::create_dir_all(dir)?;
+    let path = upload_path(dir, &completed.from, completed.transfer_id);
+    fs::write(&path, &completed.data)?;
+    Ok(Upload {
+        from: completed.from,
+        transfer_id: completed.transfer_id,
+        path,
+    })
+}
+
+// Reassembles uploads from any number of clients at once and hands each
+// finished one to `tx`. Only returns if the encryption task went away.
+pub async fn receive_uploads(
+    socket: &UdpSocket,
+    dir: &Path,
+    config: &TransferConfig,
+    transfers: &ActiveTransfers,
+    tx: mpsc::Sender<Upload>,
+) -> io::Result<()> {
+    let mut inbox = Inbox::new(config);
+
+    loop {
+        let received = inbox.recv(socket).await;
+        transfers.set_uploads(inbox.in_progress());
+        let completed = match received {
+            Ok(Received::Transfer(completed)) => completed,
+            Ok(Received::Message(message, addr)) => {
+                println!("Unexpected message on upload socket from {}: {:?}", addr, message);
+                continue;
+            }
+            // Nothing arrived for a while, keep waiting
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => {
+                eprintln!("Failed to receive upload: {:?}", e);
+                continue;
+            }
+        };
+        println!(
+            "Upload {} from {} complete ({} bytes, {} more in progress)",
+            completed.transfer_id,
+            completed.from,
+            completed.data.len(),
+            inbox.in_progress()
+        );
+
+        let upload = match store(dir, completed) {
+            Ok(upload) => upload,
+            Err(e) => {
+                eprintln!("Failed to store upload: {:?}", e);
+                continue;
+            }
+        };
+        println!("Image saved to {}", upload.path.display());
+
+        if tx.send(upload).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::transfer::{self, Sender};
+    use protocol::Message;
+    use std::time::Instant;
+
+    fn image(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    fn small_chunks() -> TransferConfig {
+        TransferConfig {
+            chunk_size: 100,
+            ..TransferConfig::default()
+        }
+    }
+
+    #[test]
+    fn interleaved_uploads_are_reassembled_independently() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = small_chunks();
+        let client_a: SocketAddr = "127.0.0.1:9080".parse().unwrap();
+        let client_b: SocketAddr = "127.0.0.1:7005".parse().unwrap();
+        let image_a = image(1_050, 1);
+        let image_b = image(730, 2);
+
+        // Same transfer ID from both clients, only the source address differs
+        let mut sender_a = Sender::new(7, &image_a, &config);
+        let mut sender_b = Sender::new(7, &image_b, &config);
+        let now = Instant::now();
+        let chunks_a = sender_a.poll_transmit(now).unwrap();
+        let chunks_b = sender_b.poll_transmit(now).unwrap();
+
+        let mut inbox = Inbox::new(&config);
+        let mut uploads = Vec::new();
+        let mut interleaved = Vec::new();
+        for i in 0..chunks_a.len().max(chunks_b.len()) {
+            if let Some(chunk) = chunks_a.get(i) {
+                interleaved.push((client_a, chunk.clone()));
+            }
+            if let Some(chunk) = chunks_b.get(i) {
+                interleaved.push((client_b, chunk.clone()));
+            }
+        }
+
+        for (from, chunk) in interleaved {
+            let Message::Chunk { transfer_id, seq, total, data } = chunk else {
+                panic!("sender produced {:?}", chunk);
+            };
+            let (_, completed) = inbox.on_chunk(from, transfer_id, seq, total, data, now);
+            if let Some(completed) = completed {
+                uploads.push(store(dir.path(), completed).unwrap());
+            }
+        }
+
+        assert_eq!(uploads.len(), 2);
+        let upload_a = uploads.iter().find(|upload| upload.from == client_a).unwrap();
+        let upload_b = uploads.iter().find(|upload| upload.from == client_b).unwrap();
+        assert_ne!(upload_a.path, upload_b.path);
+        assert_eq!(upload_a.read().unwrap(), image_a);
+        assert_eq!(upload_b.read().unwrap(), image_b);
+    }
+
+    #[tokio::test]
+    async fn concurrent_uploads_reach_the_encryption_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = small_chunks();
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::channel(4);
+
+        let upload_dir = dir.path().to_path_buf();
+        let receiver_config = config.clone();
+        tokio::spawn(async move {
+            receive_uploads(&server, &upload_dir, &receiver_config, &ActiveTransfers::default(), tx)
+                .await
+                .unwrap();
+        });
+
+        let client_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let image_a = image(20_000, 3);
+        let image_b = image(15_000, 4);
+
+        let (sent_a, sent_b) = tokio::join!(
+            transfer::send(&client_a, server_addr, 1, &image_a, &config),
+            transfer::send(&client_b, server_addr, 1, &image_b, &config),
+        );
+        sent_a.unwrap();
+        sent_b.unwrap();
+
+        let mut received = vec![rx.recv().await.unwrap(), rx.recv().await.unwrap()];
+        received.sort_by_key(|upload| upload.from != client_a.local_addr().unwrap());
+        assert_eq!(received[0].read().unwrap(), image_a);
+        assert_eq!(received[1].read().unwrap(), image_b);
+
+        for upload in &received {
+            upload.remove();
+            assert!(!upload.path.exists());
+        }
+    }
+}
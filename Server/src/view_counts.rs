@@ -0,0 +1,203 @@
+// Views on shared images. A viewer asks a server to use up a view before it
+// opens an image, and the count is replicated with the directory, so editing
+// or restoring the viewer's own files gives no views back. The servers check
+// the grant against the key its owner registered, and keep the newest grant
+// the owner sent through them, so an older grant can't be played back after
+// the owner lowered or revoked it. The viewer holds the image's key, so a
+// client changed to skip asking can still decrypt the image; the count is
+// what every unmodified client goes by.
+use crate::accounts;
+use crate::replication::Replicator;
+use crate::storage::{Change, Storage};
+use protocol::{Message, SignedGrant};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+// Why a view was refused, and whether the viewer should delete the image
+#[derive(Debug, PartialEq)]
+pub struct Refusal {
+    pub reason: String,
+    pub gone: bool,
+}
+
+fn refuse(reason: impl Into<String>, gone: bool) -> Refusal {
+    Refusal {
+        reason: reason.into(),
+        gone,
+    }
+}
+
+// The change that uses up a view of `viewer` under `signed` at `now`
+pub fn check(storage: &Storage, viewer: &str, signed: &SignedGrant, now: u64) -> Result<Change, Refusal> {
+    let grant = &signed.grant;
+    if grant.viewer != viewer {
+        return Err(refuse(format!("grant is for client {}", grant.viewer), false));
+    }
+    if storage.image_owner(&grant.image_id).as_deref() != Some(grant.owner.as_str()) {
+        return Err(refuse(format!("client {} does not own image {}", grant.owner, grant.image_id), false));
+    }
+    if accounts::public_key(storage, &grant.owner).as_deref() != Some(signed.owner_key.as_slice()) {
+        return Err(refuse("grant is not signed with the owner's registered key", false));
+    }
+    if let Err(e) = signed.verify() {
+        return Err(refuse(format!("bad grant: {}", e), false));
+    }
+    if grant.revoked {
+        return Err(refuse("the owner revoked the image", true));
+    }
+
+    let (used, first_viewed) = match storage.view_count(&grant.image_id, viewer) {
+        Some(count) if count.nonce > grant.nonce => {
+            return Err(refuse("the owner replaced this grant", false));
+        }
+        Some(count) if count.nonce == grant.nonce => (count.used, count.first_viewed),
+        _ => (0, None),
+    };
+    if grant.expiry.deadline(first_viewed).is_some_and(|deadline| now >= deadline) {
+        return Err(refuse("the grant expired", true));
+    }
+    if used >= grant.views {
+        return Err(refuse("no views left", false));
+    }
+    Ok(Change::RecordView {
+        image_id: grant.image_id.clone(),
+        viewer: viewer.to_string(),
+        nonce: grant.nonce,
+        views: grant.views,
+        at: now,
+    })
+}
+
+// Uses up a view of `viewer` under `signed`, answering with the views left
+pub async fn record(storage: &Storage, replicator: &Replicator, viewer: &str, signed: SignedGrant) -> io::Result<Message> {
+    let image_id = signed.grant.image_id.clone();
+    let change = match check(storage, viewer, &signed, unix_now()) {
+        Ok(change) => change,
+        Err(refusal) => {
+            println!("Refusing a view of {} by client {}: {}", image_id, viewer, refusal.reason);
+            return Ok(Message::ViewRefused {
+                image_id,
+                reason: refusal.reason,
+                gone: refusal.gone,
+            });
+        }
+    };
+    replicator.propose(change).await?;
+    let count = storage
+        .view_count(&image_id, viewer)
+        .ok_or_else(|| io::Error::other("view count missing after it was recorded"))?;
+    Ok(Message::ViewRecorded {
+        image_id,
+        remaining: signed.grant.views.saturating_sub(count.used),
+        first_viewed: count.first_viewed.unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ownership;
+    use crate::storage::Account;
+    use ed25519_dalek::{Signer, SigningKey};
+    use protocol::{Expiry, ViewGrant};
+
+    fn owner_key() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    fn grant(views: u32, nonce: u64, expiry: Expiry) -> SignedGrant {
+        let grant = ViewGrant {
+            image_id: "1_cat".to_string(),
+            owner: "1".to_string(),
+            viewer: "2".to_string(),
+            views,
+            nonce,
+            revoked: false,
+            expiry,
+        };
+        SignedGrant {
+            signature: owner_key().sign(&grant.signing_bytes()).to_bytes().to_vec(),
+            owner_key: owner_key().verifying_key().to_bytes().to_vec(),
+            grant,
+        }
+    }
+
+    fn storage(dir: &std::path::Path) -> Storage {
+        let storage = Storage::open(dir).unwrap();
+        let account = Account {
+            client_id: "1".to_string(),
+            password_hash: "hash".to_string(),
+            public_key: owner_key().verifying_key().to_bytes().to_vec(),
+        };
+        storage.apply_replicated(1, Change::Register(account)).unwrap();
+        storage.apply_replicated(2, ownership::claim("1", "1_cat")).unwrap();
+        storage
+    }
+
+    // Checks and applies one view, as `record` does
+    fn view(storage: &Storage, signed: &SignedGrant, now: u64) -> Result<u32, Refusal> {
+        let change = check(storage, "2", signed, now)?;
+        let index = storage.applied() + 1;
+        storage.apply_replicated(index, change).unwrap();
+        Ok(signed.grant.views - storage.view_count("1_cat", "2").unwrap().used)
+    }
+
+    #[test]
+    fn views_run_out_and_restored_files_dont_help() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path());
+        let signed = grant(2, 10, Expiry::Never);
+        assert_eq!(view(&storage, &signed, 100), Ok(1));
+        assert_eq!(view(&storage, &signed, 101), Ok(0));
+        // The viewer only holds the same grant, whatever its files say
+        assert!(!view(&storage, &signed, 102).unwrap_err().gone);
+
+        // A new grant from the owner counts from zero
+        assert_eq!(view(&storage, &grant(3, 11, Expiry::Never), 103), Ok(2));
+    }
+
+    #[test]
+    fn older_or_forged_grants_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path());
+        storage
+            .apply_replicated(3, Change::NewGrant { image_id: "1_cat".to_string(), viewer: "2".to_string(), nonce: 10 })
+            .unwrap();
+        assert!(view(&storage, &grant(5, 9, Expiry::Never), 100).is_err());
+
+        let mut raised = grant(1, 10, Expiry::Never);
+        raised.grant.views = 50;
+        assert!(view(&storage, &raised, 100).is_err());
+        let mut self_signed = grant(50, 10, Expiry::Never);
+        let viewer_key = SigningKey::from_bytes(&[2; 32]);
+        self_signed.owner_key = viewer_key.verifying_key().to_bytes().to_vec();
+        self_signed.signature = viewer_key.sign(&self_signed.grant.signing_bytes()).to_bytes().to_vec();
+        assert!(view(&storage, &self_signed, 100).is_err());
+
+        // Only the viewer the grant names can use it
+        assert!(check(&storage, "3", &grant(5, 10, Expiry::Never), 100).is_err());
+        assert_eq!(view(&storage, &grant(5, 10, Expiry::Never), 100), Ok(4));
+    }
+
+    #[test]
+    fn expiry_counts_from_the_first_view_on_the_servers() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(dir.path());
+        let signed = grant(5, 10, Expiry::AfterFirstView(60));
+        assert_eq!(view(&storage, &signed, 1000), Ok(4));
+        assert_eq!(view(&storage, &signed, 1059), Ok(3));
+        assert!(view(&storage, &signed, 1060).unwrap_err().gone);
+
+        let mut revoked = grant(0, 11, Expiry::Never);
+        revoked.grant.revoked = true;
+        revoked.signature = owner_key().sign(&revoked.grant.signing_bytes()).to_bytes().to_vec();
+        assert!(view(&storage, &revoked, 1061).unwrap_err().gone);
+    }
+}
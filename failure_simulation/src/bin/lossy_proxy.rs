@@ -0,0 +1,46 @@
+// Runs a lossy proxy in front of a server until Ctrl-C:
+//   cargo run --bin lossy_proxy -- --listen 127.0.0.1:7070 --upstream 127.0.0.1:8080 --loss 0.1 --jitter-ms 20
+// Clients are then pointed at the listen address instead of the server.
+use failure_simulation::proxy::{Proxy, ProxyConfig};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+const USAGE: &str = "usage: lossy_proxy --listen <addr> --upstream <addr> [--loss <share>] \
+[--latency-ms <ms>] [--jitter-ms <ms>] [--reorder <share>] [--reorder-ms <ms>] \
+[--duplicate <share>] [--truncate <share>] [--mtu <bytes>] [--seed <n>]";
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut listen: Option<SocketAddr> = None;
+    let mut upstream: Option<SocketAddr> = None;
+    let mut config = ProxyConfig::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(USAGE)?;
+        let millis = |value: &str| value.parse().map(Duration::from_millis);
+        match flag.as_str() {
+            "--listen" => listen = Some(value.parse()?),
+            "--upstream" => upstream = Some(value.parse()?),
+            "--loss" => config.loss = value.parse()?,
+            "--latency-ms" => config.latency = millis(&value)?,
+            "--jitter-ms" => config.jitter = millis(&value)?,
+            "--reorder" => config.reorder = value.parse()?,
+            "--reorder-ms" => config.reorder_delay = millis(&value)?,
+            "--duplicate" => config.duplicate = value.parse()?,
+            "--truncate" => config.truncate = value.parse()?,
+            "--mtu" => config.mtu = value.parse()?,
+            "--seed" => config.seed = value.parse()?,
+            _ => return Err(USAGE.into()),
+        }
+    }
+    let (Some(listen), Some(upstream)) = (listen, upstream) else {
+        return Err(USAGE.into());
+    };
+
+    let proxy = Proxy::start(listen, upstream, config.clone()).await?;
+    println!("Proxying {} to {} with {:?}", proxy.addr(), upstream, config);
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
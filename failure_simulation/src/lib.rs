@@ -0,0 +1,2 @@
+// The parts of failure_simulation other crates use in their tests
+pub mod proxy;
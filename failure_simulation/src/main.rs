@@ -1,13 +1,27 @@
+mod scenario;
+
 use tokio::net::UdpSocket;
+use protocol::Message;
 use rand::seq::SliceRandom;
+use scenario::Scenario;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // `--scenario <file>` plays a scripted scenario, without it a random
+    // server gets FAIL every minute
+    let mut args = std::env::args().skip(1);
+    match (args.next().as_deref(), args.next()) {
+        (Some("--scenario"), Some(path)) => return Scenario::load(&PathBuf::from(path))?.run().await,
+        (None, _) => {}
+        _ => return Err("usage: failure_simulation [--scenario <file>]".into()),
+    }
+
     // List of target addresses (IP:Port)
-    let targets = vec![
+    let targets = [
         // "10.7.19.179:9000".parse::<SocketAddr>()?, 
         // "10.7.16.113:9001".parse::<SocketAddr>()?,
         // "10.7.17.170:9002".parse::<SocketAddr>()?,
@@ -25,7 +39,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     println!("Starting UDP message sender...");
 
     // Message to send
-    let message = "FAIL";
+    let message = protocol::encode(&Message::Fail);
 
     // Loop to send the message every 60 seconds
     loop {
@@ -34,7 +48,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .expect("Failed to select a random target");
 
         // Send message to the randomly selected target
-        match socket.send_to(message.as_bytes(), target).await {
+        match socket.send_to(&message, target).await {
             Ok(sent) => {
                 println!("Sent {} bytes to {}", sent, target);
             },
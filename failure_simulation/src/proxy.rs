@@ -0,0 +1,253 @@
+// A UDP proxy that makes the network between clients and a server worse on
+// purpose. Datagrams in both directions can be lost, delayed with jitter,
+// held back so later ones overtake them, duplicated, or cut short as by a
+// path with a small MTU. Each client address gets its own socket towards
+// the server, so the server's answers find their way back.
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+
+// Room for any datagram, truncation is up to the proxy
+const MAX_DATAGRAM: usize = 65_536;
+
+// Shares are between 0.0 and 1.0
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub loss: f64,
+    pub latency: Duration,
+    // Each datagram waits `latency` plus up to `jitter`
+    pub jitter: Duration,
+    // Share of datagrams held back another `reorder_delay`
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    pub duplicate: f64,
+    // Share of the datagrams longer than `mtu` that are cut to `mtu` bytes
+    pub truncate: f64,
+    pub mtu: usize,
+    // Seeds the random choices, so a run can be repeated
+    pub seed: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            loss: 0.0,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(50),
+            duplicate: 0.0,
+            truncate: 0.0,
+            mtu: 1500,
+            seed: 0,
+        }
+    }
+}
+
+// What happens to one datagram: the copies that get through, each with its
+// delay. No copies means it was lost.
+pub struct Impairment {
+    config: ProxyConfig,
+    rng: StdRng,
+}
+
+impl Impairment {
+    pub fn new(config: ProxyConfig) -> Self {
+        Impairment {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+        }
+    }
+
+    pub fn apply(&mut self, datagram: &[u8]) -> Vec<(Duration, Vec<u8>)> {
+        if self.rng.gen_bool(self.config.loss) {
+            return Vec::new();
+        }
+        let copies = if self.rng.gen_bool(self.config.duplicate) { 2 } else { 1 };
+        (0..copies)
+            .map(|_| {
+                let mut delay = self.config.latency + self.config.jitter.mul_f64(self.rng.gen::<f64>());
+                if self.rng.gen_bool(self.config.reorder) {
+                    delay += self.config.reorder_delay;
+                }
+                let mut data = datagram.to_vec();
+                if data.len() > self.config.mtu && self.rng.gen_bool(self.config.truncate) {
+                    data.truncate(self.config.mtu);
+                }
+                (delay, data)
+            })
+            .collect()
+    }
+}
+
+// A running proxy, stopped when dropped
+pub struct Proxy {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl Proxy {
+    // Listens on `listen` and forwards to `upstream`
+    pub async fn start(listen: SocketAddr, upstream: SocketAddr, config: ProxyConfig) -> io::Result<Proxy> {
+        check(&config)?;
+        let socket = Arc::new(UdpSocket::bind(listen).await?);
+        let addr = socket.local_addr()?;
+        let impairment = Arc::new(Mutex::new(Impairment::new(config)));
+        let task = tokio::spawn(async move {
+            if let Err(e) = run(socket, upstream, impairment).await {
+                eprintln!("Proxy on {} stopped: {}", addr, e);
+            }
+        });
+        Ok(Proxy { addr, task })
+    }
+
+    // Where clients send to instead of the server
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn check(config: &ProxyConfig) -> io::Result<()> {
+    let shares = [config.loss, config.reorder, config.duplicate, config.truncate];
+    if shares.iter().any(|share| !(0.0..=1.0).contains(share)) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "shares must be between 0 and 1"));
+    }
+    Ok(())
+}
+
+async fn run(socket: Arc<UdpSocket>, upstream: SocketAddr, impairment: Arc<Mutex<Impairment>>) -> io::Result<()> {
+    let mut towards_server: HashMap<SocketAddr, Arc<UdpSocket>> = HashMap::new();
+    let mut readers = Vec::new();
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    let result = loop {
+        let (size, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            // ICMP errors from an earlier datagram
+            Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused) => continue,
+            Err(e) => break Err(e),
+        };
+        let server_side = match towards_server.get(&client) {
+            Some(server_side) => Arc::clone(server_side),
+            None => {
+                let bind: SocketAddr = match upstream {
+                    SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+                    SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+                };
+                let server_side = Arc::new(UdpSocket::bind(bind).await?);
+                towards_server.insert(client, Arc::clone(&server_side));
+                readers.push(tokio::spawn(answer(
+                    Arc::clone(&server_side),
+                    Arc::clone(&socket),
+                    client,
+                    Arc::clone(&impairment),
+                )));
+                server_side
+            }
+        };
+        let copies = impairment.lock().unwrap().apply(&buffer[..size]);
+        forward(&server_side, copies, upstream);
+    };
+    for reader in readers {
+        reader.abort();
+    }
+    result
+}
+
+// Passes what the server sends to `client` back through the listening socket
+async fn answer(
+    server_side: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    impairment: Arc<Mutex<Impairment>>,
+) {
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+    loop {
+        match server_side.recv_from(&mut buffer).await {
+            Ok((size, _)) => {
+                let copies = impairment.lock().unwrap().apply(&buffer[..size]);
+                forward(&socket, copies, client);
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused) => {}
+            Err(e) => {
+                eprintln!("Proxy stopped answering {}: {}", client, e);
+                return;
+            }
+        }
+    }
+}
+
+fn forward(socket: &Arc<UdpSocket>, copies: Vec<(Duration, Vec<u8>)>, to: SocketAddr) {
+    for (delay, data) in copies {
+        let socket = Arc::clone(socket);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            // Lost like any other datagram if it fails
+            let _ = socket.send_to(&data, to).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_impairs_the_same_datagrams() {
+        let config = ProxyConfig {
+            loss: 0.2,
+            jitter: Duration::from_millis(30),
+            reorder: 0.1,
+            duplicate: 0.1,
+            truncate: 0.5,
+            mtu: 4,
+            seed: 3,
+            ..ProxyConfig::default()
+        };
+        let run = || {
+            let mut impairment = Impairment::new(config.clone());
+            (0..500).map(|_| impairment.apply(b"datagram")).collect::<Vec<_>>()
+        };
+        let first = run();
+        assert_eq!(first, run());
+
+        let lost = first.iter().filter(|copies| copies.is_empty()).count();
+        assert!((60..140).contains(&lost), "{} lost", lost);
+        assert!(first.iter().any(|copies| copies.len() == 2));
+        let delivered = first.iter().flatten();
+        assert!(delivered.clone().all(|(delay, _)| *delay <= Duration::from_millis(80)));
+        assert!(delivered.clone().any(|(_, data)| data == b"data"));
+        assert!(delivered.clone().any(|(_, data)| data == b"datagram"));
+    }
+
+    #[tokio::test]
+    async fn forwards_both_ways() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = server.local_addr().unwrap();
+        let proxy = Proxy::start("127.0.0.1:0".parse().unwrap(), upstream, ProxyConfig::default())
+            .await
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut buffer = [0u8; 64];
+
+        client.send_to(b"ping", proxy.addr()).await.unwrap();
+        let (size, from) = server.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..size], b"ping");
+
+        server.send_to(b"pong", from).await.unwrap();
+        let (size, from) = client.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..size], b"pong");
+        assert_eq!(from, proxy.addr());
+    }
+}
@@ -0,0 +1,241 @@
+// Scripted failures. A scenario file (TOML, or YAML for .yaml/.yml) lists
+// the servers and timed events; each event becomes fault rules that are sent
+// to the failure sockets of the servers involved, which apply them to their
+// own traffic until they run out. See scenarios/ for examples.
+use protocol::faults::{FaultKind, FaultRule};
+use protocol::Message;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep_until, Instant};
+
+#[derive(Deserialize, Debug)]
+pub struct Scenario {
+    // Seeds the random drops and duplicates of every event
+    #[serde(default)]
+    pub seed: u64,
+    pub servers: Vec<Server>,
+    pub events: Vec<Event>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Server {
+    pub id: u32,
+    pub failure_addr: SocketAddr,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Event {
+    // Seconds from the start of the scenario
+    pub at: f64,
+    // Seconds the fault lasts
+    pub duration: f64,
+    #[serde(flatten)]
+    pub fault: Fault,
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "fault", rename_all = "snake_case")]
+pub enum Fault {
+    Crash { server: u32 },
+    // The faults between two servers hit both directions
+    Drop { between: (u32, u32), percent: f64 },
+    Delay {
+        between: (u32, u32),
+        ms: u64,
+        #[serde(default)]
+        jitter_ms: u64,
+    },
+    Duplicate { between: (u32, u32), percent: f64 },
+    // Servers in different groups can't reach each other
+    Partition { groups: Vec<Vec<u32>> },
+}
+
+// A rule to send to a server at a point of the scenario
+#[derive(Debug, PartialEq)]
+pub struct Step {
+    pub at: Duration,
+    pub server: u32,
+    pub rule: FaultRule,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Scenario, Box<dyn Error>> {
+        let text = fs::read_to_string(path)?;
+        let scenario: Scenario = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => serde_yaml::from_str(&text)?,
+            _ => toml::from_str(&text)?,
+        };
+        scenario.plan()?;
+        Ok(scenario)
+    }
+
+    // The rules every event turns into, in the order they are sent
+    pub fn plan(&self) -> Result<Vec<Step>, String> {
+        let mut steps = Vec::new();
+        for (index, event) in self.events.iter().enumerate() {
+            if !(event.at >= 0.0 && event.duration > 0.0) {
+                return Err(format!("event {} needs at >= 0 and duration > 0", index));
+            }
+            let at = Duration::from_secs_f64(event.at);
+            let duration_ms = (event.duration * 1000.0) as u64;
+            let mut step = |server: u32, kind: FaultKind, peers: Vec<u32>| {
+                let seed = self.seed ^ ((index as u64) << 32 | server as u64);
+                steps.push(Step {
+                    at,
+                    server,
+                    rule: FaultRule { kind, peers, duration_ms, seed },
+                });
+            };
+            match &event.fault {
+                Fault::Crash { server } => step(*server, FaultKind::Crash, Vec::new()),
+                Fault::Drop { between: (a, b), percent } => {
+                    step(*a, FaultKind::Drop { percent: *percent }, vec![*b]);
+                    step(*b, FaultKind::Drop { percent: *percent }, vec![*a]);
+                }
+                Fault::Delay { between: (a, b), ms, jitter_ms } => {
+                    let kind = FaultKind::Delay { ms: *ms, jitter_ms: *jitter_ms };
+                    step(*a, kind.clone(), vec![*b]);
+                    step(*b, kind, vec![*a]);
+                }
+                Fault::Duplicate { between: (a, b), percent } => {
+                    step(*a, FaultKind::Duplicate { percent: *percent }, vec![*b]);
+                    step(*b, FaultKind::Duplicate { percent: *percent }, vec![*a]);
+                }
+                Fault::Partition { groups } => {
+                    for (group_index, group) in groups.iter().enumerate() {
+                        let others: Vec<u32> = groups
+                            .iter()
+                            .enumerate()
+                            .filter(|(other, _)| *other != group_index)
+                            .flat_map(|(_, servers)| servers.iter().copied())
+                            .collect();
+                        for server in group {
+                            step(*server, FaultKind::Drop { percent: 100.0 }, others.clone());
+                        }
+                    }
+                }
+            }
+        }
+        for step in &steps {
+            if !self.servers.iter().any(|server| server.id == step.server) {
+                return Err(format!("unknown server {}", step.server));
+            }
+        }
+        // Stable, so the rules of one event keep their order
+        steps.sort_by_key(|step| step.at);
+        Ok(steps)
+    }
+
+    pub async fn run(&self) -> Result<(), Box<dyn Error>> {
+        let addrs: BTreeMap<u32, SocketAddr> =
+            self.servers.iter().map(|server| (server.id, server.failure_addr)).collect();
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        let start = Instant::now();
+        for step in self.plan()? {
+            sleep_until(start + step.at).await;
+            let addr = addrs[&step.server];
+            println!(
+                "{:>7.1}s server {}: {:?} with {:?} for {}ms",
+                step.at.as_secs_f64(),
+                step.server,
+                step.rule.kind,
+                step.rule.peers,
+                step.rule.duration_ms
+            );
+            if let Err(e) = socket.send_to(&protocol::encode(&Message::Fault(step.rule)), addr).await {
+                eprintln!("Failed to send to {}: {}", addr, e);
+            }
+        }
+        println!("Scenario finished");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENARIO: &str = r#"
+seed = 5
+
+[[servers]]
+id = 1
+failure_addr = "127.0.0.1:9000"
+
+[[servers]]
+id = 2
+failure_addr = "127.0.0.1:9001"
+
+[[servers]]
+id = 3
+failure_addr = "127.0.0.1:9002"
+
+[[events]]
+at = 10
+duration = 5
+fault = "partition"
+groups = [[1], [2, 3]]
+
+[[events]]
+at = 2.5
+duration = 30
+fault = "drop"
+between = [1, 2]
+percent = 20
+"#;
+
+    #[test]
+    fn events_become_rules_in_time_order() {
+        let scenario: Scenario = toml::from_str(SCENARIO).unwrap();
+        let steps = scenario.plan().unwrap();
+        let summary: Vec<(f64, u32, Vec<u32>)> = steps
+            .iter()
+            .map(|step| (step.at.as_secs_f64(), step.server, step.rule.peers.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (2.5, 1, vec![2]),
+                (2.5, 2, vec![1]),
+                (10.0, 1, vec![2, 3]),
+                (10.0, 2, vec![1]),
+                (10.0, 3, vec![1]),
+            ]
+        );
+        assert_eq!(steps[0].rule.kind, FaultKind::Drop { percent: 20.0 });
+        assert_eq!(steps[0].rule.duration_ms, 30_000);
+        assert_eq!(steps[2].rule.kind, FaultKind::Drop { percent: 100.0 });
+    }
+
+    #[test]
+    fn example_scenario_loads() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/partition.toml");
+        let scenario = Scenario::load(&path).unwrap();
+        assert_eq!(scenario.plan().unwrap().len(), 10);
+    }
+
+    #[test]
+    fn yaml_works_too() {
+        let scenario: Scenario = serde_yaml::from_str(
+            "servers:\n  - { id: 1, failure_addr: \"127.0.0.1:9000\" }\n\
+             events:\n  - { at: 1, duration: 3, fault: crash, server: 1 }\n",
+        )
+        .unwrap();
+        assert_eq!(scenario.events[0].fault, Fault::Crash { server: 1 });
+    }
+
+    #[test]
+    fn unknown_servers_are_rejected() {
+        let scenario: Scenario = toml::from_str(
+            "servers = []\n[[events]]\nat = 1\nduration = 1\nfault = \"crash\"\nserver = 4\n",
+        )
+        .unwrap();
+        assert_eq!(scenario.plan(), Err("unknown server 4".to_string()));
+    }
+}
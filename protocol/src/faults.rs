@@ -0,0 +1,86 @@
+// Fault injection on datagram sockets. A process may install one
+// `FaultHook`; `send_to` and `recv_from` then ask it about every datagram,
+// and transfers go through them too. Without a hook they are the plain
+// socket calls. failure_simulation tells servers which faults to apply with
+// `Message::Fault`.
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FaultKind {
+    // Nothing is sent or received, as if the server was down
+    Crash,
+    Drop { percent: f64 },
+    // Every datagram is held back for `ms`, give or take `jitter_ms`
+    Delay { ms: u64, jitter_ms: u64 },
+    Duplicate { percent: f64 },
+}
+
+// A fault a server applies to its own traffic for `duration_ms`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub kind: FaultKind,
+    // The servers whose traffic is hit, all traffic if empty
+    pub peers: Vec<u32>,
+    pub duration_ms: u64,
+    // Seeds the random choices, so a scenario hits the same datagrams on
+    // every run
+    pub seed: u64,
+}
+
+pub trait FaultHook: Send + Sync {
+    // How long to hold back each copy of a datagram to `peer`. No copies
+    // drops it.
+    fn outgoing(&self, peer: SocketAddr) -> Vec<Duration>;
+    // Whether a datagram from `peer` is let in
+    fn incoming(&self, peer: SocketAddr) -> bool;
+}
+
+static HOOK: OnceLock<Arc<dyn FaultHook>> = OnceLock::new();
+
+// Installs the hook for the rest of the process
+pub fn install(hook: Arc<dyn FaultHook>) -> io::Result<()> {
+    HOOK.set(hook)
+        .map_err(|_| io::Error::new(io::ErrorKind::AlreadyExists, "a fault hook is already installed"))
+}
+
+// `UdpSocket::send_to` through the hook. A dropped datagram counts as sent,
+// as it would on a lossy network.
+pub async fn send_to(socket: &UdpSocket, data: &[u8], peer: SocketAddr) -> io::Result<usize> {
+    let Some(hook) = HOOK.get() else {
+        return socket.send_to(data, peer).await;
+    };
+    for delay in hook.outgoing(peer) {
+        if delay.is_zero() {
+            socket.send_to(data, peer).await?;
+            continue;
+        }
+        // Sent later from a duplicate of the socket, so the caller goes on
+        let duplicate: std::net::UdpSocket = SockRef::from(socket).try_clone()?.into();
+        duplicate.set_nonblocking(true)?;
+        let delayed = UdpSocket::from_std(duplicate)?;
+        let data = data.to_vec();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Err(e) = delayed.send_to(&data, peer).await {
+                eprintln!("Failed to send delayed datagram to {}: {}", peer, e);
+            }
+        });
+    }
+    Ok(data.len())
+}
+
+// `UdpSocket::recv_from` through the hook, skipping what it keeps out
+pub async fn recv_from(socket: &UdpSocket, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    loop {
+        let (size, from) = socket.recv_from(buffer).await?;
+        if HOOK.get().is_none_or(|hook| hook.incoming(from)) {
+            return Ok((size, from));
+        }
+    }
+}
@@ -0,0 +1,251 @@
+// Decoder for the string-prefix messages the servers and clients exchanged
+// before the framed protocol. Only used while old and new binaries coexist.
+// Image chunks are not covered: the old stop-and-wait and batch-ACK schemes
+// cannot interoperate with the transfer engine. Old clients had no sessions
+// or identity keys, so their requests carry an empty token and signature and
+// are turned down.
+use crate::{Message, OnlineStatus};
+
+pub fn decode(datagram: &[u8]) -> Option<Message> {
+    let text = std::str::from_utf8(datagram).ok()?;
+    decode_text(text.trim())
+}
+
+pub fn decode_text(text: &str) -> Option<Message> {
+    match text {
+        "ELECT" => return Some(Message::Elect),
+        "Request_DOS" => return Some(Message::RequestDos { token: String::new() }),
+        "END_SAMPLES" => return Some(Message::EndSamples),
+        "NO_SAMPLES" => return Some(Message::NoSamples),
+        "SAMPLES_DONE" => return Some(Message::SamplesDone),
+        "FAIL" => return Some(Message::Fail),
+        _ => {}
+    }
+
+    if let Some(rest) = text.strip_prefix("LEADER_ACK:") {
+        return Some(Message::LeaderAck {
+            leader: rest.trim().to_string(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("STATUS_ACK:") {
+        return Some(Message::StatusAck {
+            server: rest.to_string(),
+        });
+    }
+    if let Some(json) = text.strip_prefix("STATUS:") {
+        return serde_json::from_str::<OnlineStatus>(json).ok().map(|status| Message::Status {
+            status,
+            endpoint: None,
+            token: String::new(),
+            signature: Default::default(),
+        });
+    }
+    if let Some(json) = text.strip_prefix("DIR_OF_SERV:") {
+        return serde_json::from_str::<OnlineStatus>(json).ok().map(Message::DirOfServ);
+    }
+    if let Some(csv) = text.strip_prefix("DOS:") {
+        return Some(Message::Dos {
+            entries: parse_dos_csv(csv),
+        });
+    }
+    // The sample payload used to follow in a separate raw datagram, so the
+    // data is left empty and the receiver reads it from the next datagram.
+    if let Some(rest) = text.strip_prefix("SAMPLE_UPLOAD:") {
+        let (client_id, image_id) = rest.split_once(':')?;
+        return Some(Message::SampleUpload {
+            client_id: client_id.to_string(),
+            image_id: image_id.to_string(),
+            data: Vec::new(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("SAMPLE_SYNC:") {
+        let (client_id, image_id) = rest.split_once(':')?;
+        return Some(Message::SampleSync {
+            client_id: client_id.to_string(),
+            image_id: image_id.to_string(),
+            data: Vec::new(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("SAMPLE:") {
+        let (client_id, name) = rest.split_once(':')?;
+        return Some(Message::Sample {
+            client_id: client_id.to_string(),
+            name: name.to_string(),
+            data: Vec::new(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("Access_Control_ACK:") {
+        return Some(Message::AccessControlAck {
+            client_ip: rest.to_string(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("Access_Control:") {
+        // <client_id>_<image id, may itself contain '_'>_<views>
+        let (client_id, rest) = rest.split_once('_')?;
+        let (image_id, views) = rest.rsplit_once('_')?;
+        return Some(Message::AccessControl {
+            client_id: client_id.to_string(),
+            image_id: image_id.to_string(),
+            views: views.trim().parse().ok()?,
+            grant: None,
+            token: String::new(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("OFFLINE_WANTED:") {
+        let (client_id, image_id, views) = split_update(rest)?;
+        return Some(Message::OfflineWanted {
+            client_id,
+            image_id,
+            views,
+            grant: None,
+        });
+    }
+    if let Some(rest) = text.strip_prefix("CONTROL_UPDATE:") {
+        let (client_id, image_id, views) = split_update(rest)?;
+        return Some(Message::ControlUpdate {
+            client_id,
+            image_id,
+            views,
+            grant: None,
+        });
+    }
+    if let Some(rest) = text.strip_prefix("REQUEST_IMAGE_FROM") {
+        let (requester_id, image_id) = rest.trim().rsplit_once(':')?;
+        return Some(Message::RequestImage {
+            requester_id: requester_id.to_string(),
+            image_id: image_id.to_string(),
+            token: String::new(),
+            signature: Default::default(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("IMAGE_NOT_FOUND:") {
+        return Some(Message::ImageNotFound {
+            image_id: rest.to_string(),
+        });
+    }
+    if let Some(rest) = text.strip_prefix("TRANSFER_FAILED:") {
+        return Some(Message::TransferFailed {
+            image_id: rest.to_string(),
+        });
+    }
+    None
+}
+
+pub fn parse_dos_csv(csv: &str) -> Vec<OnlineStatus> {
+    csv.lines()
+        .filter(|line| !line.starts_with("uid,client_id,status"))
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            if fields.len() != 3 {
+                return None;
+            }
+            Some(OnlineStatus {
+                ip: fields[0].trim().to_string(),
+                client_id: fields[1].trim().to_string(),
+                status: fields[2].trim() == "true",
+            })
+        })
+        .collect()
+}
+
+fn split_update(rest: &str) -> Option<(String, String, u32)> {
+    let (client_id, rest) = rest.split_once(':')?;
+    let (image_id, views) = rest.rsplit_once(':')?;
+    Some((
+        client_id.to_string(),
+        image_id.to_string(),
+        views.trim().parse().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bare_words_decode() {
+        assert_eq!(decode_text("ELECT"), Some(Message::Elect));
+        assert_eq!(decode_text("FAIL"), Some(Message::Fail));
+        assert_eq!(decode(b"  END_SAMPLES \n"), Some(Message::EndSamples));
+        assert_eq!(decode_text("HELLO"), None);
+        assert_eq!(decode(&[0xff, 0xfe]), None);
+    }
+
+    #[test]
+    fn status_carries_no_session() {
+        let message = decode_text(r#"STATUS:{"ip":"127.0.0.1:8079","status":true,"client_id":"1"}"#).unwrap();
+        let Message::Status { status, token, .. } = message else {
+            panic!("not a status: {:?}", message);
+        };
+        assert_eq!(status.client_id, "1");
+        assert!(status.status);
+        assert!(token.is_empty());
+        assert_eq!(decode_text("STATUS:{not json"), None);
+    }
+
+    #[test]
+    fn access_control_image_ids_keep_their_underscores() {
+        assert_eq!(
+            decode_text("Access_Control:2_1_my_cat_5"),
+            Some(Message::AccessControl {
+                client_id: "2".to_string(),
+                image_id: "1_my_cat".to_string(),
+                views: 5,
+                grant: None,
+                token: String::new(),
+            })
+        );
+        // The ACK shares the prefix and must not be taken for a request
+        assert_eq!(
+            decode_text("Access_Control_ACK:127.0.0.1:8079"),
+            Some(Message::AccessControlAck {
+                client_ip: "127.0.0.1:8079".to_string(),
+            })
+        );
+        assert_eq!(decode_text("Access_Control:2_cat"), None);
+        assert_eq!(decode_text("Access_Control:2_cat_many"), None);
+    }
+
+    #[test]
+    fn updates_split_on_colons() {
+        assert_eq!(
+            decode_text("CONTROL_UPDATE:2:1_cat:3"),
+            Some(Message::ControlUpdate {
+                client_id: "2".to_string(),
+                image_id: "1_cat".to_string(),
+                views: 3,
+                grant: None,
+            })
+        );
+        assert_eq!(
+            decode_text("REQUEST_IMAGE_FROM 127.0.0.1:8079:1_cat"),
+            Some(Message::RequestImage {
+                requester_id: "127.0.0.1:8079".to_string(),
+                image_id: "1_cat".to_string(),
+                token: String::new(),
+                signature: Default::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn dos_csv_skips_the_header_and_bad_rows() {
+        let csv = "uid,client_id,status\n127.0.0.1:8079, 1 ,true\nbroken row\n127.0.0.1:6999,2,false\n";
+        assert_eq!(
+            parse_dos_csv(csv),
+            vec![
+                OnlineStatus {
+                    ip: "127.0.0.1:8079".to_string(),
+                    status: true,
+                    client_id: "1".to_string(),
+                },
+                OnlineStatus {
+                    ip: "127.0.0.1:6999".to_string(),
+                    status: false,
+                    client_id: "2".to_string(),
+                },
+            ]
+        );
+        assert_eq!(decode_text("DOS:"), Some(Message::Dos { entries: Vec::new() }));
+    }
+}
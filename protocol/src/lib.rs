@@ -0,0 +1,523 @@
+use serde::{Deserialize, Serialize};
+use signing::MessageSignature;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+
+pub mod faults;
+pub mod legacy;
+pub mod signing;
+pub mod transfer;
+
+// Every framed datagram starts with MAGIC, the protocol version and the
+// big-endian length of the bincode payload that follows.
+pub const MAGIC: [u8; 2] = *b"P2";
+pub const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = MAGIC.len() + 1 + 4;
+
+// Largest payload a single UDP datagram can carry
+pub const MAX_DATAGRAM: usize = 65507;
+
+// Entry of the directory of service
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OnlineStatus {
+    pub ip: String,
+    pub status: bool,
+    pub client_id: String,
+}
+
+// The two client sockets a server talks to while serving an image request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientEndpoint {
+    // Socket the client sends ELECT from and receives the encrypted image on
+    pub image_addr: SocketAddr,
+    // Socket the client waits for LEADER_ACK on and uploads the image from
+    pub leader_ack_addr: SocketAddr,
+}
+
+// What the owner sends to a viewer in one transfer: the carrier the leader
+// produced and the key the hidden image was sealed with. The key never
+// passes through a server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SharedImage {
+    pub image_id: String,
+    pub key: Vec<u8>,
+    pub image: Vec<u8>,
+}
+
+impl SharedImage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("SharedImage is always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bincode::deserialize(bytes).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+}
+
+// When a grant stops allowing views, besides running out of them. Times are
+// seconds since the Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Expiry {
+    #[default]
+    Never,
+    At(u64),
+    // Seconds after the viewer first opened the image
+    AfterFirstView(u64),
+}
+
+impl Expiry {
+    // When the image expires for a viewer that first opened it at
+    // `first_viewed`, None while that isn't known yet or it never does
+    pub fn deadline(&self, first_viewed: Option<u64>) -> Option<u64> {
+        match *self {
+            Expiry::Never => None,
+            Expiry::At(at) => Some(at),
+            Expiry::AfterFirstView(seconds) => first_viewed.map(|first| first.saturating_add(seconds)),
+        }
+    }
+}
+
+// Views an owner grants one viewer on one image. `nonce` is unique per grant
+// and grows with every new grant, so a viewer can refuse an older one. A
+// revoked grant takes the image away: the viewer deletes its copy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ViewGrant {
+    pub image_id: String,
+    pub owner: String,
+    pub viewer: String,
+    pub views: u32,
+    pub nonce: u64,
+    pub revoked: bool,
+    pub expiry: Expiry,
+}
+
+impl ViewGrant {
+    // The bytes the owner signs
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut bytes = b"P2P-VIEW-GRANT".to_vec();
+        bytes.extend(bincode::serialize(self).expect("ViewGrant is always serializable"));
+        bytes
+    }
+}
+
+// A grant signed with the owner's Ed25519 key, together with that public key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignedGrant {
+    pub grant: ViewGrant,
+    pub owner_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedGrant {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("SignedGrant is always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bincode::deserialize(bytes).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+
+    // Checks the signature against `owner_key`. Whether that is the key the
+    // owner registered is up to the caller.
+    pub fn verify(&self) -> io::Result<()> {
+        signing::verify_raw(&self.owner_key, &self.grant.signing_bytes(), &self.signature)
+    }
+}
+
+// An upload for encryption, sent by a client to the leader's upload socket
+// as one chunked transfer. The client picks `request_id` and keeps it, so a
+// retry sent to another leader is known to be the same request.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptRequest {
+    pub request_id: u64,
+    pub image: Vec<u8>,
+}
+
+impl EncryptRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("EncryptRequest is always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bincode::deserialize(bytes).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+}
+
+// The encrypted image a server sends back to the client's image socket, for
+// the request with the same ID
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptResult {
+    pub request_id: u64,
+    pub image: Vec<u8>,
+}
+
+impl EncryptResult {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("EncryptResult is always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bincode::deserialize(bytes).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+}
+
+// An encryption job the leader hands to another server, sent as one chunked
+// transfer. The server sends the result straight to `reply_to`, the image
+// socket of the client that uploaded it. `job_id` is the client's request ID.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncryptJob {
+    pub job_id: u64,
+    pub reply_to: String,
+    pub image: Vec<u8>,
+}
+
+impl EncryptJob {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("EncryptJob is always serializable")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        bincode::deserialize(bytes).map_err(|e| DecodeError::Malformed(e.to_string()))
+    }
+}
+
+// Where a server takes control, gossip, election and replication messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemberAddrs {
+    pub control: String,
+    pub gossip: String,
+    pub election: String,
+    pub replication: String,
+}
+
+// A member of the server cluster
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub id: u32,
+    pub addrs: MemberAddrs,
+}
+
+// One row of a server's gossip table. `heartbeat` counts up while the
+// server runs and starts over with a new `generation` when it restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GossipEntry {
+    pub id: u32,
+    pub generation: u64,
+    pub heartbeat: u64,
+    pub addrs: MemberAddrs,
+}
+
+// Entry of the log servers replicate the directory of service with. `data`
+// is an encoded server-side change, empty for the no-op a new leader appends.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Message {
+    // A client asks which server leads. Any server that knows answers
+    // LeaderQuery with LeaderAck; Elect, sent by older clients, is only
+    // answered by the leader itself.
+    Elect,
+    LeaderQuery,
+    LeaderAck { leader: String },
+
+    // Bully election between servers, sent from their election sockets. A
+    // server ranks above another with a lower load score, or the same score
+    // and a higher ID. `leader` is the upload address the winner gives clients.
+    Election { term: u64, id: u32, score: u32 },
+    ElectionOk { term: u64, id: u32 },
+    Coordinator { term: u64, id: u32, score: u32, leader: String },
+    // A follower's answer to each COORDINATOR: its current load score and
+    // the address it takes encryption jobs on
+    LoadReport { term: u64, id: u32, score: u32, job_addr: String },
+    // Sent back to the leader once a job's result reached the client
+    JobDone { job_id: u64 },
+    // Failure detection: the sender's whole gossip table
+    Gossip { entries: Vec<GossipEntry> },
+    // Cluster membership: a new server asks any member to add it and is
+    // answered with a snapshot transfer; a server on its way out leaves.
+    // `proof` shows the sender knows the cluster key.
+    Join { server: ServerInfo, proof: MessageSignature },
+    Leave { id: u32, proof: MessageSignature },
+    // Clients ask any server for the control sockets of the live servers
+    ServerListQuery,
+    ServerList { servers: Vec<String> },
+
+    // Accounts. A client registers with a password and gets a new client
+    // ID, or logs in with its ID and password. Both are answered with a
+    // session token, which STATUS, Request_DOS, Access_Control and image
+    // requests have to carry. `public_key` is the client's Ed25519 identity,
+    // kept with the account for checking its signatures.
+    Register { password: String, public_key: Vec<u8> },
+    Login { client_id: String, password: String, public_key: Vec<u8> },
+    LoggedIn { client_id: String, token: String },
+    // Wrong password, unknown client, or a missing or expired session
+    AuthFailed { reason: String },
+    // A client asks whose session a peer's token belongs to
+    CheckSession { token: String },
+    SessionOwner { token: String, client_id: Option<String> },
+    // A client asks for the identity key another client registered
+    KeyQuery { client_id: String },
+    ClientKey { client_id: String, public_key: Option<Vec<u8>> },
+
+    // Directory of service
+    // Signed by the client over `status` and `endpoint`, see `signing`.
+    // `endpoint` tells the servers where to send the client's encrypted
+    // images, old clients leave it out.
+    Status { status: OnlineStatus, endpoint: Option<ClientEndpoint>, token: String, signature: MessageSignature },
+    StatusAck { server: String },
+    DirOfServ(OnlineStatus),
+    RequestDos { token: String },
+    Dos { entries: Vec<OnlineStatus> },
+
+    // Samples
+    SampleUpload { client_id: String, image_id: String, data: Vec<u8> },
+    EndSamples,
+    SampleSync { client_id: String, image_id: String, data: Vec<u8> },
+    Sample { client_id: String, name: String, data: Vec<u8> },
+    NoSamples,
+    SamplesDone,
+
+    // Access control. `grant` is the owner's signature over the new count,
+    // missing only when a legacy client sent the request.
+    AccessControl {
+        client_id: String,
+        image_id: String,
+        views: u32,
+        grant: Option<SignedGrant>,
+        token: String,
+    },
+    AccessControlAck { client_ip: String },
+    OfflineWanted { client_id: String, image_id: String, views: u32, grant: Option<SignedGrant> },
+    ControlUpdate { client_id: String, image_id: String, views: u32, grant: Option<SignedGrant> },
+    // A viewer uses up a view under the owner's grant before opening the
+    // image. The servers keep the count, so the viewer's files can't give
+    // used views back. `first_viewed` starts the clock on grants that expire
+    // after the first view; `gone` tells the viewer to delete the image, which
+    // expired or was revoked.
+    RecordView { grant: SignedGrant, token: String },
+    ViewRecorded { image_id: String, remaining: u32, first_viewed: u64 },
+    ViewRefused { image_id: String, reason: String, gone: bool },
+
+    // Peer to peer image requests
+    // `token` is the requester's session, the owner checks it with a server.
+    // Signed by the requester over (requester_id, image_id).
+    RequestImage { requester_id: String, image_id: String, token: String, signature: MessageSignature },
+    ImageNotFound { image_id: String },
+    TransferFailed { image_id: String },
+    // The owner lost the leader while its image was being encrypted and sent
+    // it again, the requester should keep waiting
+    TransferDelayed { image_id: String, attempt: u32 },
+
+    // Chunked image transfer, see `transfer`
+    Chunk { transfer_id: u32, seq: u32, total: u32, data: Vec<u8> },
+    Ack { transfer_id: u32, seq: u32 },
+    Nack { transfer_id: u32, seq: u32 },
+
+    // Replicated log between servers, sent from their replication sockets
+    AppendEntries { term: u64, prev_index: u64, prev_term: u64, entries: Vec<LogEntry>, commit: u64 },
+    // `last_index` is the follower's last matching entry, or a hint where to
+    // resume from when `success` is false
+    AppendReply { term: u64, success: bool, last_index: u64 },
+    RequestVote { term: u64, last_index: u64, last_term: u64 },
+    Vote { term: u64, granted: bool },
+    // A change a follower hands to the leader for appending
+    Propose { data: Vec<u8> },
+    // Part of the leader's snapshot at `last_index`, for a follower that is
+    // behind the leader's compacted log. `total` is the snapshot's size.
+    InstallSnapshot { term: u64, last_index: u64, last_term: u64, offset: u64, total: u64, data: Vec<u8> },
+    // How much of the snapshot at `last_index` the follower holds. Once it
+    // installed it, the follower answers with AppendReply instead.
+    SnapshotReply { term: u64, last_index: u64, offset: u64 },
+
+    // Failure simulation
+    Fail,
+    // A fault for the server to apply to its own traffic, see `faults`
+    Fault(faults::FaultRule),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    UnsupportedVersion(u8),
+    Truncated { expected: usize, actual: usize },
+    Malformed(String),
+    Unrecognized,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            DecodeError::Truncated { expected, actual } => {
+                write!(f, "truncated message: expected {} bytes, got {}", expected, actual)
+            }
+            DecodeError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+            DecodeError::Unrecognized => write!(f, "unrecognized message"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(e: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+    let payload = bincode::serialize(message).expect("Message is always serializable");
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&MAGIC);
+    frame.push(VERSION);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+// Decodes a framed message, falling back to the legacy text messages for
+// peers that have not been migrated yet.
+pub fn decode(datagram: &[u8]) -> Result<Message, DecodeError> {
+    if !is_framed(datagram) {
+        return legacy::decode(datagram).ok_or(DecodeError::Unrecognized);
+    }
+    if datagram.len() < HEADER_LEN {
+        return Err(DecodeError::Truncated {
+            expected: HEADER_LEN,
+            actual: datagram.len(),
+        });
+    }
+
+    let version = datagram[MAGIC.len()];
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let length = u32::from_be_bytes(datagram[3..HEADER_LEN].try_into().unwrap()) as usize;
+    let payload = &datagram[HEADER_LEN..];
+    if payload.len() < length {
+        return Err(DecodeError::Truncated {
+            expected: HEADER_LEN + length,
+            actual: datagram.len(),
+        });
+    }
+
+    bincode::deserialize(&payload[..length]).map_err(|e| DecodeError::Malformed(e.to_string()))
+}
+
+pub fn is_framed(datagram: &[u8]) -> bool {
+    datagram.starts_with(&MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> Message {
+        Message::Status {
+            status: OnlineStatus {
+                ip: "127.0.0.1:8079".to_string(),
+                status: true,
+                client_id: "1".to_string(),
+            },
+            endpoint: Some(ClientEndpoint {
+                image_addr: "127.0.0.1:2005".parse().unwrap(),
+                leader_ack_addr: "127.0.0.1:9080".parse().unwrap(),
+            }),
+            token: "token".to_string(),
+            signature: Default::default(),
+        }
+    }
+
+    #[test]
+    fn messages_survive_encoding() {
+        let messages = [
+            Message::Elect,
+            status(),
+            Message::Chunk { transfer_id: 7, seq: 3, total: 9, data: vec![0, 1, 2, 255] },
+            Message::AccessControl {
+                client_id: "2".to_string(),
+                image_id: "1_cat".to_string(),
+                views: 4,
+                grant: None,
+                token: String::new(),
+            },
+        ];
+        for message in messages {
+            let frame = encode(&message);
+            assert!(is_framed(&frame));
+            assert_eq!(decode(&frame).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn frames_with_another_version_are_refused() {
+        let mut frame = encode(&Message::Elect);
+        frame[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(decode(&frame), Err(DecodeError::UnsupportedVersion(v)) if v == VERSION + 1));
+    }
+
+    #[test]
+    fn short_frames_are_truncated() {
+        let frame = encode(&status());
+        assert!(matches!(
+            decode(&frame[..HEADER_LEN - 1]),
+            Err(DecodeError::Truncated { expected: HEADER_LEN, .. })
+        ));
+        assert!(matches!(
+            decode(&frame[..frame.len() - 1]),
+            Err(DecodeError::Truncated { expected, actual }) if expected == frame.len() && actual == frame.len() - 1
+        ));
+    }
+
+    #[test]
+    fn garbage_payloads_are_malformed() {
+        let mut frame = MAGIC.to_vec();
+        frame.push(VERSION);
+        frame.extend_from_slice(&4u32.to_be_bytes());
+        frame.extend_from_slice(&[0xff; 4]);
+        assert!(matches!(decode(&frame), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn without_magic_only_legacy_text_decodes() {
+        assert_eq!(decode(b"ELECT\n").unwrap(), Message::Elect);
+        let mut frame = encode(&Message::Elect);
+        frame[0] = b'X';
+        assert!(matches!(decode(&frame), Err(DecodeError::Unrecognized)));
+    }
+
+    #[test]
+    fn grants_verify_against_their_owner_key() {
+        use ed25519_dalek::{Signer, SigningKey};
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let grant = ViewGrant {
+            image_id: "1_cat".to_string(),
+            owner: "1".to_string(),
+            viewer: "2".to_string(),
+            views: 3,
+            nonce: 1,
+            revoked: false,
+            expiry: Expiry::Never,
+        };
+        let signed = SignedGrant {
+            signature: key.sign(&grant.signing_bytes()).to_bytes().to_vec(),
+            owner_key: key.verifying_key().to_bytes().to_vec(),
+            grant,
+        };
+        signed.verify().unwrap();
+
+        let mut raised = signed.clone();
+        raised.grant.views = 30;
+        assert!(raised.verify().is_err());
+        let mut other_key = signed.clone();
+        other_key.owner_key = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes().to_vec();
+        assert!(other_key.verify().is_err());
+    }
+}
@@ -0,0 +1,121 @@
+// Signatures on the control messages clients send. A client signs what the
+// message says, together with the time it signed it, with the Ed25519
+// identity key it registered with the servers. A signature older than
+// MAX_AGE is refused, so a captured message can't be replayed later on.
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Also how far ahead of the verifier's clock a signer may be
+pub const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+// What each signature is for, so one can't be passed off as another
+pub const STATUS: &str = "P2P-STATUS";
+pub const REQUEST_IMAGE: &str = "P2P-REQUEST-IMAGE";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct MessageSignature {
+    // Seconds since the epoch
+    pub signed_at: u64,
+    pub signature: Vec<u8>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse_key(public_key: &[u8]) -> io::Result<VerifyingKey> {
+    let public_key: [u8; 32] = public_key.try_into().map_err(|_| invalid("invalid public key"))?;
+    VerifyingKey::from_bytes(&public_key).map_err(|_| invalid("invalid public key"))
+}
+
+// Whether `public_key` is an Ed25519 public key at all
+pub fn valid_key(public_key: &[u8]) -> bool {
+    parse_key(public_key).is_ok()
+}
+
+fn signing_bytes(purpose: &str, content: &impl Serialize, signed_at: u64) -> Vec<u8> {
+    let mut bytes = purpose.as_bytes().to_vec();
+    bytes.extend(signed_at.to_be_bytes());
+    bytes.extend(bincode::serialize(content).expect("signed content is always serializable"));
+    bytes
+}
+
+pub fn sign(key: &SigningKey, purpose: &str, content: &impl Serialize) -> MessageSignature {
+    let signed_at = unix_now();
+    let signature = key.sign(&signing_bytes(purpose, content, signed_at));
+    MessageSignature {
+        signed_at,
+        signature: signature.to_bytes().to_vec(),
+    }
+}
+
+// Checks an Ed25519 signature over `bytes`, with no time attached
+pub(crate) fn verify_raw(public_key: &[u8], bytes: &[u8], signature: &[u8]) -> io::Result<()> {
+    let public_key = parse_key(public_key)?;
+    let parsed = Signature::from_slice(signature).map_err(|_| invalid("invalid signature"))?;
+    public_key
+        .verify_strict(bytes, &parsed)
+        .map_err(|_| invalid("signature does not match the message"))
+}
+
+// Checks that `signature` was made recently over `content` with the key
+// `public_key` is the public half of
+pub fn verify(
+    public_key: &[u8],
+    purpose: &str,
+    content: &impl Serialize,
+    signature: &MessageSignature,
+) -> io::Result<()> {
+    let now = unix_now();
+    if now.abs_diff(signature.signed_at) > MAX_AGE.as_secs() {
+        return Err(invalid("signature is too old or from the future"));
+    }
+    verify_raw(public_key, &signing_bytes(purpose, content, signature.signed_at), &signature.signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_bind_key_purpose_content_and_time() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let content = ("1".to_string(), "1_0".to_string());
+        let signature = sign(&key, REQUEST_IMAGE, &content);
+        verify(&public_key, REQUEST_IMAGE, &content, &signature).unwrap();
+
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(verify(&other_key, REQUEST_IMAGE, &content, &signature).is_err());
+        assert!(verify(&public_key, STATUS, &content, &signature).is_err());
+        let other_content = ("2".to_string(), "1_0".to_string());
+        assert!(verify(&public_key, REQUEST_IMAGE, &other_content, &signature).is_err());
+        assert!(verify(&public_key[..31], REQUEST_IMAGE, &content, &signature).is_err());
+        assert!(valid_key(&public_key));
+        assert!(!valid_key(&public_key[..31]));
+
+        // Replayed after the window
+        let stale = MessageSignature {
+            signed_at: signature.signed_at - MAX_AGE.as_secs() - 1,
+            ..signature.clone()
+        };
+        assert!(verify(&public_key, REQUEST_IMAGE, &content, &stale).is_err());
+        let resigned = MessageSignature {
+            signed_at: stale.signed_at,
+            signature: key
+                .sign(&signing_bytes(REQUEST_IMAGE, &content, stale.signed_at))
+                .to_bytes()
+                .to_vec(),
+        };
+        assert!(verify(&public_key, REQUEST_IMAGE, &content, &resigned).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+// Sliding-window selective-repeat transfer of a byte buffer over UDP.
+//
+// Every chunk carries the transfer ID, its sequence number and the total
+// number of chunks. The receiver acknowledges each chunk on its own and
+// NACKs the gaps it sees, so the sender only retransmits what was lost
+// instead of whole batches. `Sender` and `Inbox` hold the state and do no
+// I/O themselves; `send`, `receive` and `Inbox::recv` drive them over a
+// tokio socket.
+use crate::{faults, Message, MAX_DATAGRAM};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+#[derive(Debug, Clone)]
+pub struct TransferConfig {
+    // Payload bytes per chunk
+    pub chunk_size: usize,
+    // Chunks that may be in flight without an ACK
+    pub window: usize,
+    // How long the sender waits for an ACK before resending a chunk
+    pub retransmit_timeout: Duration,
+    // Resends of a single chunk before the transfer is given up
+    pub max_retries: u32,
+    // How long a receiver waits for the next datagram before giving up
+    pub idle_timeout: Duration,
+    // How long a finished transfer keeps being acknowledged, in case the
+    // sender missed the last ACKs
+    pub linger: Duration,
+    // Largest transfer either side takes on, in chunks. The receiver
+    // allocates a slot per chunk up front, so the sender's count is checked
+    // against it first.
+    pub max_chunks: u32,
+    // Transfers a receiver reassembles at once, from one sender and in
+    // total. Chunks that would start another are dropped.
+    pub max_transfers_per_sender: usize,
+    pub max_transfers: usize,
+}
+
+impl Default for TransferConfig {
+    fn default() -> Self {
+        TransferConfig {
+            chunk_size: 2044,
+            window: 32,
+            retransmit_timeout: Duration::from_millis(300),
+            max_retries: 10,
+            idle_timeout: Duration::from_secs(10),
+            linger: Duration::from_secs(1),
+            // 128 MiB with the default chunk size
+            max_chunks: 65536,
+            max_transfers_per_sender: 4,
+            max_transfers: 64,
+        }
+    }
+}
+
+static TRANSFER_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// IDs only have to be unique per sending socket, this mixes the clock, the
+// process and a counter so restarted senders don't reuse recent IDs.
+pub fn new_transfer_id() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    let count = TRANSFER_COUNTER.fetch_add(1, Ordering::Relaxed);
+    nanos ^ std::process::id().rotate_left(16) ^ count.wrapping_mul(0x9E37_79B9)
+}
+
+pub struct Sender {
+    transfer_id: u32,
+    chunks: Vec<Vec<u8>>,
+    acked: Vec<bool>,
+    sent_at: Vec<Option<Instant>>,
+    retries: Vec<u32>,
+    // First chunk that has not been acknowledged
+    base: usize,
+    // First chunk that has never been sent
+    next: usize,
+    config: TransferConfig,
+}
+
+impl Sender {
+    pub fn new(transfer_id: u32, data: &[u8], config: &TransferConfig) -> Self {
+        let mut chunks: Vec<Vec<u8>> = data
+            .chunks(config.chunk_size.max(1))
+            .map(<[u8]>::to_vec)
+            .collect();
+        // An empty buffer still goes out as one empty chunk so the receiver sees it
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+        let total = chunks.len();
+
+        Sender {
+            transfer_id,
+            chunks,
+            acked: vec![false; total],
+            sent_at: vec![None; total],
+            retries: vec![0; total],
+            base: 0,
+            next: 0,
+            config: config.clone(),
+        }
+    }
+
+    pub fn transfer_id(&self) -> u32 {
+        self.transfer_id
+    }
+
+    pub fn total_chunks(&self) -> u32 {
+        self.chunks.len() as u32
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.base == self.chunks.len()
+    }
+
+    // Chunks to put on the wire now: unacknowledged chunks whose timer ran out
+    // and new chunks that fit in the window. Fails once a chunk has used up
+    // its retries.
+    pub fn poll_transmit(&mut self, now: Instant) -> io::Result<Vec<Message>> {
+        let mut due = Vec::new();
+
+        for seq in self.base..self.next {
+            if self.acked[seq] {
+                continue;
+            }
+            let expired = self.sent_at[seq]
+                .map(|sent| now.duration_since(sent) >= self.config.retransmit_timeout)
+                .unwrap_or(true);
+            if !expired {
+                continue;
+            }
+            if self.retries[seq] >= self.config.max_retries {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "chunk {} of transfer {} was not acknowledged after {} retries",
+                        seq, self.transfer_id, self.config.max_retries
+                    ),
+                ));
+            }
+            self.retries[seq] += 1;
+            self.sent_at[seq] = Some(now);
+            due.push(self.chunk(seq));
+        }
+
+        while self.next < self.chunks.len() && self.next < self.base + self.config.window.max(1) {
+            self.sent_at[self.next] = Some(now);
+            due.push(self.chunk(self.next));
+            self.next += 1;
+        }
+
+        Ok(due)
+    }
+
+    // When the next retransmission falls due, if anything is in flight
+    pub fn next_deadline(&self) -> Option<Instant> {
+        (self.base..self.next)
+            .filter(|&seq| !self.acked[seq])
+            .filter_map(|seq| self.sent_at[seq])
+            .min()
+            .map(|sent| sent + self.config.retransmit_timeout)
+    }
+
+    // Applies an ACK or NACK for this transfer. A NACK returns the chunk to
+    // resend right away instead of waiting for its timer.
+    pub fn on_message(&mut self, message: &Message, now: Instant) -> Option<Message> {
+        match *message {
+            Message::Ack { transfer_id, seq } if transfer_id == self.transfer_id => {
+                if let Some(acked) = self.acked.get_mut(seq as usize) {
+                    *acked = true;
+                }
+                while self.base < self.chunks.len() && self.acked[self.base] {
+                    self.base += 1;
+                }
+                None
+            }
+            Message::Nack { transfer_id, seq } if transfer_id == self.transfer_id => {
+                let seq = seq as usize;
+                if seq >= self.next || self.acked[seq] || self.retries[seq] >= self.config.max_retries {
+                    return None;
+                }
+                self.retries[seq] += 1;
+                self.sent_at[seq] = Some(now);
+                Some(self.chunk(seq))
+            }
+            _ => None,
+        }
+    }
+
+    fn chunk(&self, seq: usize) -> Message {
+        Message::Chunk {
+            transfer_id: self.transfer_id,
+            seq: seq as u32,
+            total: self.total_chunks(),
+            data: self.chunks[seq].clone(),
+        }
+    }
+}
+
+// A transfer the receiver has finished reassembling
+#[derive(Debug)]
+pub struct Completed {
+    pub from: SocketAddr,
+    pub transfer_id: u32,
+    pub data: Vec<u8>,
+}
+
+// What `Inbox::recv` hands back: a finished transfer, or any other message
+// that arrived on the socket in the meantime
+#[derive(Debug)]
+pub enum Received {
+    Transfer(Completed),
+    Message(Message, SocketAddr),
+}
+
+struct Reassembly {
+    chunks: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    // Highest sequence number seen so far, gaps below it have been NACKed
+    highest: Option<usize>,
+    last_activity: Instant,
+}
+
+// Receiving side of any number of concurrent transfers, keyed by the
+// sender's address and the transfer ID
+pub struct Inbox {
+    config: TransferConfig,
+    active: HashMap<(SocketAddr, u32), Reassembly>,
+    // Finished transfers and their chunk count, kept for `linger` so late
+    // retransmissions still get an ACK
+    finished: HashMap<(SocketAddr, u32), (u32, Instant)>,
+}
+
+impl Inbox {
+    pub fn new(config: &TransferConfig) -> Self {
+        Inbox {
+            config: config.clone(),
+            active: HashMap::new(),
+            finished: HashMap::new(),
+        }
+    }
+
+    // Transfers that have started but not finished
+    pub fn in_progress(&self) -> usize {
+        self.active.len()
+    }
+
+    // Stores one chunk. Returns the ACK/NACKs to send back to `from` and the
+    // finished transfer if this was its last missing chunk.
+    pub fn on_chunk(
+        &mut self,
+        from: SocketAddr,
+        transfer_id: u32,
+        seq: u32,
+        total: u32,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> (Vec<Message>, Option<Completed>) {
+        let key = (from, transfer_id);
+        let (seq, total) = (seq as usize, total as usize);
+
+        if let Some((finished_total, _)) = self.finished.get(&key) {
+            if seq < *finished_total as usize {
+                return (vec![Message::Ack { transfer_id, seq: seq as u32 }], None);
+            }
+            return (Vec::new(), None);
+        }
+
+        if total == 0 || seq >= total {
+            return (Vec::new(), None);
+        }
+        if total > self.config.max_chunks as usize {
+            eprintln!(
+                "Refusing transfer {} from {}: {} chunks, at most {} allowed",
+                transfer_id, from, total, self.config.max_chunks
+            );
+            return (Vec::new(), None);
+        }
+        // Chunks are at most `chunk_size`, so a transfer fits in what its
+        // chunk count allows
+        if data.len() > self.config.chunk_size {
+            eprintln!(
+                "Dropping chunk {} of transfer {} from {}: {} bytes, at most {} allowed",
+                seq,
+                transfer_id,
+                from,
+                data.len(),
+                self.config.chunk_size
+            );
+            return (Vec::new(), None);
+        }
+        if !self.active.contains_key(&key) {
+            let from_sender = self.active.keys().filter(|(sender, _)| *sender == from).count();
+            if from_sender >= self.config.max_transfers_per_sender || self.active.len() >= self.config.max_transfers {
+                eprintln!(
+                    "Refusing transfer {} from {}: {} transfers from it and {} in total in progress",
+                    transfer_id,
+                    from,
+                    from_sender,
+                    self.active.len()
+                );
+                return (Vec::new(), None);
+            }
+        }
+
+        let reassembly = self.active.entry(key).or_insert_with(|| Reassembly {
+            chunks: vec![None; total],
+            missing: total,
+            highest: None,
+            last_activity: now,
+        });
+        // A sender never changes the chunk count of a transfer
+        if reassembly.chunks.len() != total {
+            return (Vec::new(), None);
+        }
+        reassembly.last_activity = now;
+
+        let mut replies = Vec::new();
+        if reassembly.chunks[seq].is_none() {
+            reassembly.chunks[seq] = Some(data);
+            reassembly.missing -= 1;
+        }
+        replies.push(Message::Ack { transfer_id, seq: seq as u32 });
+
+        // NACK each gap once, when a later chunk shows it up
+        let first_unseen = reassembly.highest.map(|highest| highest + 1).unwrap_or(0);
+        if seq > first_unseen {
+            for gap in first_unseen..seq {
+                if reassembly.chunks[gap].is_none() {
+                    replies.push(Message::Nack { transfer_id, seq: gap as u32 });
+                }
+            }
+        }
+        if reassembly.highest.is_none_or(|highest| seq > highest) {
+            reassembly.highest = Some(seq);
+        }
+
+        if reassembly.missing > 0 {
+            return (replies, None);
+        }
+
+        let reassembly = self.active.remove(&key).expect("transfer is active");
+        self.finished.insert(key, (total as u32, now));
+        let data = reassembly.chunks.into_iter().flatten().flatten().collect();
+        (replies, Some(Completed { from, transfer_id, data }))
+    }
+
+    // Forgets transfers that stalled for longer than the idle timeout and
+    // finished transfers older than the linger time
+    pub fn expire(&mut self, now: Instant) {
+        let idle_timeout = self.config.idle_timeout;
+        let linger = self.config.linger;
+        self.active.retain(|(from, transfer_id), reassembly| {
+            let alive = now.duration_since(reassembly.last_activity) < idle_timeout;
+            if !alive {
+                eprintln!(
+                    "Dropping stalled transfer {} from {} ({} chunks missing)",
+                    transfer_id, from, reassembly.missing
+                );
+            }
+            alive
+        });
+        self.finished
+            .retain(|_, (_, finished_at)| now.duration_since(*finished_at) < linger);
+    }
+
+    // Receives until a transfer finishes or a non-transfer message arrives.
+    // Fails with TimedOut if the socket stays quiet for the idle timeout.
+    pub async fn recv(&mut self, socket: &UdpSocket) -> io::Result<Received> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (size, from) = match timeout(self.config.idle_timeout, faults::recv_from(socket, &mut buffer)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    self.expire(Instant::now());
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no datagram received within the idle timeout",
+                    ));
+                }
+            };
+            let now = Instant::now();
+            self.expire(now);
+
+            let message = match crate::decode(&buffer[..size]) {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("Ignoring undecodable datagram from {}: {}", from, e);
+                    continue;
+                }
+            };
+
+            match message {
+                Message::Chunk { transfer_id, seq, total, data } => {
+                    let (replies, completed) = self.on_chunk(from, transfer_id, seq, total, data, now);
+                    for reply in replies {
+                        faults::send_to(socket, &crate::encode(&reply), from).await?;
+                    }
+                    if let Some(completed) = completed {
+                        return Ok(Received::Transfer(completed));
+                    }
+                }
+                other => return Ok(Received::Message(other, from)),
+            }
+        }
+    }
+
+    // Keeps acknowledging retransmitted chunks of finished transfers until
+    // the socket has been quiet for the linger time. Anything else is dropped.
+    pub async fn linger(&mut self, socket: &UdpSocket) -> io::Result<()> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        while let Ok(result) = timeout(self.config.linger, faults::recv_from(socket, &mut buffer)).await {
+            let (size, from) = result?;
+            if let Ok(Message::Chunk { transfer_id, seq, total, data }) = crate::decode(&buffer[..size]) {
+                let (replies, _) = self.on_chunk(from, transfer_id, seq, total, data, Instant::now());
+                for reply in replies {
+                    faults::send_to(socket, &crate::encode(&reply), from).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Sends `data` to `peer` and returns once every chunk has been acknowledged.
+// Datagrams from other addresses or for other transfers are ignored.
+pub async fn send(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    transfer_id: u32,
+    data: &[u8],
+    config: &TransferConfig,
+) -> io::Result<()> {
+    let mut sender = Sender::new(transfer_id, data, config);
+    if sender.total_chunks() > config.max_chunks {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} bytes take more than {} chunks", data.len(), config.max_chunks),
+        ));
+    }
+    let mut buffer = vec![0u8; MAX_DATAGRAM];
+
+    while !sender.is_complete() {
+        for chunk in sender.poll_transmit(Instant::now())? {
+            faults::send_to(socket, &crate::encode(&chunk), peer).await?;
+        }
+
+        let wait = sender
+            .next_deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .unwrap_or(config.retransmit_timeout);
+
+        match timeout(wait, faults::recv_from(socket, &mut buffer)).await {
+            Ok(Ok((size, from))) if from == peer => {
+                if let Ok(message) = crate::decode(&buffer[..size]) {
+                    if let Some(chunk) = sender.on_message(&message, Instant::now()) {
+                        faults::send_to(socket, &crate::encode(&chunk), peer).await?;
+                    }
+                }
+            }
+            Ok(Ok(_)) => {}
+            // ICMP errors from an earlier datagram, the retransmit timer covers them
+            Ok(Err(e)) if matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionRefused) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Receives a single transfer, from `from` if given or from anyone otherwise,
+// then lingers so the sender sees its last ACKs.
+pub async fn receive(
+    socket: &UdpSocket,
+    from: Option<SocketAddr>,
+    config: &TransferConfig,
+) -> io::Result<Completed> {
+    let mut inbox = Inbox::new(config);
+    loop {
+        match inbox.recv(socket).await? {
+            Received::Transfer(completed) if from.is_none_or(|from| from == completed.from) => {
+                inbox.linger(socket).await?;
+                return Ok(completed);
+            }
+            Received::Transfer(completed) => {
+                eprintln!(
+                    "Ignoring transfer {} from unexpected sender {}",
+                    completed.transfer_id, completed.from
+                );
+            }
+            Received::Message(message, sender) => {
+                eprintln!("Ignoring {:?} from {} while receiving a transfer", message, sender);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> TransferConfig {
+        TransferConfig {
+            chunk_size: 4,
+            window: 8,
+            max_retries: 2,
+            max_chunks: 16,
+            ..TransferConfig::default()
+        }
+    }
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:7000".parse().unwrap()
+    }
+
+    // Hands a chunk to the inbox
+    fn deliver(inbox: &mut Inbox, chunk: &Message, now: Instant) -> (Vec<Message>, Option<Completed>) {
+        match chunk.clone() {
+            Message::Chunk { transfer_id, seq, total, data } => inbox.on_chunk(peer(), transfer_id, seq, total, data, now),
+            other => panic!("not a chunk: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn chunks_in_any_order_are_reassembled() {
+        let data: Vec<u8> = (0..30).collect();
+        let now = Instant::now();
+        let mut sender = Sender::new(7, &data, &config());
+        let mut chunks = sender.poll_transmit(now).unwrap();
+        assert_eq!(chunks.len(), 8);
+        chunks.reverse();
+
+        let mut inbox = Inbox::new(&config());
+        let mut completed = None;
+        for chunk in &chunks {
+            let (replies, done) = deliver(&mut inbox, chunk, now);
+            for reply in &replies {
+                sender.on_message(reply, now);
+            }
+            completed = completed.or(done);
+        }
+        assert!(sender.is_complete());
+        assert_eq!(completed.unwrap().data, data);
+        assert_eq!(inbox.in_progress(), 0);
+    }
+
+    #[test]
+    fn duplicate_chunks_are_acknowledged_once_stored() {
+        let now = Instant::now();
+        let mut sender = Sender::new(7, b"abcdefgh", &config());
+        let chunks = sender.poll_transmit(now).unwrap();
+        let mut inbox = Inbox::new(&config());
+
+        let (replies, done) = deliver(&mut inbox, &chunks[0], now);
+        assert_eq!(replies, vec![Message::Ack { transfer_id: 7, seq: 0 }]);
+        assert!(done.is_none());
+        let (replies, done) = deliver(&mut inbox, &chunks[0], now);
+        assert_eq!(replies, vec![Message::Ack { transfer_id: 7, seq: 0 }]);
+        assert!(done.is_none());
+
+        let (_, done) = deliver(&mut inbox, &chunks[1], now);
+        assert_eq!(done.unwrap().data, b"abcdefgh");
+        // A late copy after the end still gets its ACK, but no second transfer
+        let (replies, done) = deliver(&mut inbox, &chunks[1], now);
+        assert_eq!(replies, vec![Message::Ack { transfer_id: 7, seq: 1 }]);
+        assert!(done.is_none());
+    }
+
+    #[test]
+    fn missing_chunks_are_nacked_and_resent() {
+        let now = Instant::now();
+        let mut sender = Sender::new(7, b"abcdefghijkl", &config());
+        let chunks = sender.poll_transmit(now).unwrap();
+        let mut inbox = Inbox::new(&config());
+
+        // Chunk 1 is lost, chunk 2 shows the gap up
+        for chunk in [&chunks[0], &chunks[2]] {
+            for reply in deliver(&mut inbox, chunk, now).0 {
+                if let Some(resent) = sender.on_message(&reply, now) {
+                    assert_eq!(resent, chunks[1]);
+                    let (_, done) = deliver(&mut inbox, &resent, now);
+                    assert_eq!(done.unwrap().data, b"abcdefghijkl");
+                }
+            }
+        }
+        assert_eq!(inbox.in_progress(), 0);
+
+        // Lost again, the timer resends it until the retries run out
+        let mut sender = Sender::new(8, b"abcd", &config());
+        sender.poll_transmit(now).unwrap();
+        let later = now + config().retransmit_timeout;
+        assert_eq!(sender.next_deadline(), Some(later));
+        assert_eq!(sender.poll_transmit(later).unwrap().len(), 1);
+        assert_eq!(sender.poll_transmit(later + config().retransmit_timeout).unwrap().len(), 1);
+        assert!(sender.poll_transmit(later + 2 * config().retransmit_timeout).is_err());
+    }
+
+    #[test]
+    fn transfers_above_the_chunk_limit_are_refused() {
+        let now = Instant::now();
+        let mut inbox = Inbox::new(&config());
+        let (replies, done) = inbox.on_chunk(peer(), 7, 0, u32::MAX, b"abcd".to_vec(), now);
+        assert!(replies.is_empty());
+        assert!(done.is_none());
+        assert_eq!(inbox.in_progress(), 0);
+
+        // Right at the limit is fine
+        let (replies, _) = inbox.on_chunk(peer(), 8, 0, 16, b"abcd".to_vec(), now);
+        assert_eq!(replies, vec![Message::Ack { transfer_id: 8, seq: 0 }]);
+        assert_eq!(inbox.in_progress(), 1);
+    }
+
+    #[test]
+    fn chunks_above_the_chunk_size_are_dropped() {
+        let now = Instant::now();
+        let mut inbox = Inbox::new(&config());
+        let (replies, done) = inbox.on_chunk(peer(), 7, 0, 1, b"abcde".to_vec(), now);
+        assert!(replies.is_empty());
+        assert!(done.is_none());
+        assert_eq!(inbox.in_progress(), 0);
+
+        let (replies, done) = inbox.on_chunk(peer(), 7, 0, 1, b"abcd".to_vec(), now);
+        assert_eq!(replies, vec![Message::Ack { transfer_id: 7, seq: 0 }]);
+        assert_eq!(done.unwrap().data, b"abcd");
+    }
+
+    #[test]
+    fn concurrent_transfers_are_capped() {
+        let config = TransferConfig {
+            max_transfers_per_sender: 2,
+            max_transfers: 3,
+            ..config()
+        };
+        let other: SocketAddr = "127.0.0.1:7001".parse().unwrap();
+        let now = Instant::now();
+        let mut inbox = Inbox::new(&config);
+        for transfer_id in 1..=2 {
+            let (replies, _) = inbox.on_chunk(peer(), transfer_id, 0, 2, b"abcd".to_vec(), now);
+            assert_eq!(replies.len(), 1);
+        }
+
+        // A third from the same sender is refused, one from another is not
+        let (replies, _) = inbox.on_chunk(peer(), 3, 0, 2, b"abcd".to_vec(), now);
+        assert!(replies.is_empty());
+        let (replies, _) = inbox.on_chunk(other, 4, 0, 2, b"abcd".to_vec(), now);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(inbox.in_progress(), 3);
+
+        // Now the inbox is full for everyone
+        let (replies, _) = inbox.on_chunk(other, 5, 0, 2, b"abcd".to_vec(), now);
+        assert!(replies.is_empty());
+
+        // Transfers already going on still get their chunks, and a finished
+        // one makes room
+        let (_, done) = inbox.on_chunk(peer(), 1, 1, 2, b"efgh".to_vec(), now);
+        assert_eq!(done.unwrap().data, b"abcdefgh");
+        let (replies, _) = inbox.on_chunk(peer(), 3, 0, 2, b"abcd".to_vec(), now);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(inbox.in_progress(), 3);
+    }
+
+    #[tokio::test]
+    async fn send_refuses_more_than_the_chunk_limit() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let data = vec![0u8; 17 * 4];
+        let error = send(&socket, peer(), 7, &data, &config()).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}